@@ -0,0 +1,77 @@
+//! Reads from PRG and CHR ROM, the hot path of every fetch, timed against the page-based layout
+//! (`Vec<Vec<u8>>`) which `Cart` used to keep. Run with `cargo bench --bench cart_reads`.
+//!
+//! This doesn't use the unstable `#[bench]` harness, so it times the reads itself and prints how
+//! long each took.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rusty_nes::{Cart, Rom};
+
+const PRG_PAGE_SIZE: usize = 16 * 1024;
+const CHR_PAGE_SIZE: usize = 8 * 1024;
+const PRG_PAGES: usize = 8;
+const CHR_PAGES: usize = 16;
+const READS: usize = 50_000_000;
+
+/// An MMC1 image with every byte numbered, and the same ROM split into pages as they used to be
+/// stored
+fn roms() -> (Cart, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut image = vec![
+        b'N',
+        b'E',
+        b'S',
+        0x1a,
+        PRG_PAGES as u8,
+        CHR_PAGES as u8,
+        0x10,
+    ];
+    image.resize(16, 0);
+    let data = (0..PRG_PAGES * PRG_PAGE_SIZE + CHR_PAGES * CHR_PAGE_SIZE).map(|i| (i % 251) as u8);
+    image.extend(data);
+
+    let (prg, chr) = image[16..].split_at(PRG_PAGES * PRG_PAGE_SIZE);
+    let prg_pages = prg.chunks(PRG_PAGE_SIZE).map(<[u8]>::to_vec).collect();
+    let chr_pages = chr.chunks(CHR_PAGE_SIZE).map(<[u8]>::to_vec).collect();
+    let cart = Rom::from_bytes(image)
+        .and_then(Rom::into_cart)
+        .expect("benchmark ROM should load");
+    (cart, prg_pages, chr_pages)
+}
+
+/// Time `READS` reads at addresses spread over the whole ROM, like a mapper switching banks
+fn time(name: &str, len: usize, read: impl Fn(usize) -> u8) -> Duration {
+    let start = Instant::now();
+    let mut sum = 0u32;
+    let mut address = 0usize;
+    for _ in 0..READS {
+        // A large odd step visits every address before repeating
+        address = (address + 40_503) % len;
+        sum = sum.wrapping_add(read(black_box(address)) as u32);
+    }
+    black_box(sum);
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>8.2?} ({:.2} ns/read)",
+        name,
+        elapsed,
+        elapsed.as_nanos() as f64 / READS as f64
+    );
+    elapsed
+}
+
+fn main() {
+    let (cart, prg_pages, chr_pages) = roms();
+    let prg_len = cart.prg_rom_len();
+    let chr_len = cart.chr_rom_len();
+
+    time("PRG flat", prg_len, |i| cart.prg_rom_byte(i));
+    time("PRG pages", prg_len, |i| {
+        prg_pages[i / PRG_PAGE_SIZE][i % PRG_PAGE_SIZE]
+    });
+    time("CHR flat", chr_len, |i| cart.chr_rom_byte(i));
+    time("CHR pages", chr_len, |i| {
+        chr_pages[i / CHR_PAGE_SIZE][i % CHR_PAGE_SIZE]
+    });
+}
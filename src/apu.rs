@@ -1,16 +1,599 @@
+use crate::region::Region;
+use crate::state::{StateReader, StateResult, StateWriter};
+
+/// Rate of the samples the APU generates, which are resampled to the rate the frontend plays at
+pub const SAMPLE_RATE: u32 = 44_100;
+
+/// How long a volume change takes to fade in, so that a step change doesn't click
+const VOLUME_RAMP_SAMPLES: f32 = SAMPLE_RATE as f32 * 0.005;
+
+/// Master volume of the mixer output. Muting keeps the volume setting, so unmuting restores it.
+#[derive(Debug, Clone)]
+struct Gain {
+    volume: f32,
+    muted: bool,
+
+    /// Gain applied to the last sample, which moves linearly towards the target
+    current: f32,
+}
+
+impl Gain {
+    fn target(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+
+    fn apply(&mut self, sample: f32) -> f32 {
+        let step = 1.0 / VOLUME_RAMP_SAMPLES;
+        let target = self.target();
+        self.current = if self.current < target {
+            (self.current + step).min(target)
+        } else {
+            (self.current - step).max(target)
+        };
+        sample * self.current
+    }
+}
+
+/// Values loaded into a length counter, indexed by the top 5 bits of the channel's length
+/// register
+///
+/// See: <https://www.nesdev.org/wiki/APU_Length_Counter>
+#[rustfmt::skip]
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences a channel once it counts down to zero, unless halted
+#[derive(Debug, Default, Clone)]
+struct LengthCounter {
+    counter: u8,
+    halted: bool,
+
+    /// Cleared by `$4015`, which also zeroes the counter and stops it being loaded
+    enabled: bool,
+}
+
+impl LengthCounter {
+    /// A write to the channel's length register, whose top 5 bits index `LENGTH_TABLE`
+    fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    /// Clocked by the frame counter on every half frame
+    fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+/// CPU cycles between the DMC's output bits, indexed by the rate in `$4010`
+const DMC_RATES_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const DMC_RATES_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// The delta modulation channel (DMC), which plays 1-bit delta samples read from memory. The
+/// memory reader fetches each byte into the sample buffer by DMA (see `System::take_dmc_dma`),
+/// and the output unit shifts it out a bit at a time, moving the output level up or down.
+///
+/// See: <https://www.nesdev.org/wiki/APU_DMC>
+#[derive(Debug, Clone)]
+struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    rate_index: u8,
+    output_level: u8,
+
+    /// Where samples start, and their length in bytes, from `$4012` and `$4013`
+    sample_address: u16,
+    sample_length: u16,
+
+    /// Next byte of the sample for the memory reader to fetch, and how many are left
+    current_address: u16,
+    bytes_remaining: u16,
+
+    /// Set when a sample finishes without looping, if IRQs are enabled
+    irq_flag: bool,
+
+    /// The last byte fetched, waiting for the output unit to take it
+    sample_buffer: Option<u8>,
+
+    /// CPU cycles until the next output bit
+    timer: u16,
+
+    /// The byte being played, the bits of it left, and whether the sample buffer was empty
+    /// when it was due, which leaves the output level alone until the next byte
+    shift_register: u8,
+    bits_remaining: u8,
+    silent: bool,
+}
+
+impl Dmc {
+    fn new(region: Region) -> Self {
+        let rates = match region {
+            Region::Pal => &DMC_RATES_PAL,
+            Region::Ntsc | Region::Dendy => &DMC_RATES_NTSC,
+        };
+        // As if 0 had been written to every register
+        Self {
+            rates,
+            irq_enabled: false,
+            looping: false,
+            rate_index: 0,
+            output_level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            irq_flag: false,
+            sample_buffer: None,
+            timer: rates[0],
+            shift_register: 0,
+            bits_remaining: 8,
+            silent: true,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4010 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.looping = value & 0x40 != 0;
+                self.rate_index = value & 0x0f;
+            }
+            0x4011 => self.output_level = value & 0x7f,
+            0x4012 => self.sample_address = 0xc000 | (value as u16) << 6,
+            0x4013 => self.sample_length = (value as u16) << 4 | 1,
+            _ => {}
+        }
+    }
+
+    /// Bit 4 of `$4015`. Enabling starts the sample again if it has finished, but carries on
+    /// with one that is still playing; disabling stops it.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.current_address = self.sample_address;
+            self.bytes_remaining = self.sample_length;
+        }
+    }
+
+    /// Address of the next sample byte to fetch, if a sample is playing and the sample buffer
+    /// is empty
+    fn next_address(&self) -> Option<u16> {
+        (self.bytes_remaining > 0 && self.sample_buffer.is_none()).then_some(self.current_address)
+    }
+
+    /// A byte fetched from `next_address`. The address wraps around from `$FFFF` to `$8000`.
+    fn load_sample(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.current_address = self.sample_address;
+                self.bytes_remaining = self.sample_length;
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Run for a CPU cycle
+    fn clock(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rates[self.rate_index as usize] - 1;
+
+        if !self.silent {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.shift_register = value;
+                    self.silent = false;
+                }
+                None => self.silent = true,
+            }
+        }
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool("irq_enabled", self.irq_enabled);
+        state.bool("looping", self.looping);
+        state.u8("rate_index", self.rate_index);
+        state.u8("output_level", self.output_level);
+        state.u16("sample_address", self.sample_address);
+        state.u16("sample_length", self.sample_length);
+        state.u16("current_address", self.current_address);
+        state.u16("bytes_remaining", self.bytes_remaining);
+        state.bool("irq_flag", self.irq_flag);
+        state.bool("has_sample", self.sample_buffer.is_some());
+        state.u8("sample_buffer", self.sample_buffer.unwrap_or_default());
+        state.u16("timer", self.timer);
+        state.u8("shift_register", self.shift_register);
+        state.u8("bits_remaining", self.bits_remaining);
+        state.bool("silent", self.silent);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.rate_index = state.u8()?;
+        self.output_level = state.u8()?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        self.irq_flag = state.bool()?;
+        let has_sample = state.bool()?;
+        let sample = state.u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.timer = state.u16()?;
+        self.shift_register = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        self.silent = state.bool()?;
+        Ok(())
+    }
+}
+
 /// Audio Processing Unit (APU)
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
-pub struct APU {}
+#[derive(Debug, Clone)]
+pub struct APU {
+    /// Output samples not yet handed to the frontend
+    samples: Vec<f32>,
+
+    gain: Gain,
+
+    /// Length counters of the pulse 1, pulse 2, triangle and noise channels, in the order of
+    /// their bits in `$4015`
+    length_counters: [LengthCounter; 4],
+
+    dmc: Dmc,
+}
 
 impl APU {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(region: Region) -> Self {
+        Self {
+            samples: Vec::new(),
+            gain: Gain {
+                volume: 1.0,
+                muted: false,
+                current: 1.0,
+            },
+            length_counters: Default::default(),
+            dmc: Dmc::new(region),
+        }
     }
 
-    pub fn read_address(&self, _address: u16) -> u8 {
-        0
+    /// Enter the state after the console's reset button is pressed, which is as if $00 were
+    /// written to $4015, silencing all the channels. The volume settings are kept.
+    ///
+    /// See: <https://www.nesdev.org/wiki/CPU_power_up_state#After_reset>
+    pub fn reset(&mut self) {
+        self.write_address(0x4015, 0);
     }
 
-    pub fn write_address(&self, _address: u16, _value: u8) {}
+    /// Save the channels, but not the volume settings or samples waiting to be played
+    pub fn write_state(&self, state: &mut StateWriter) {
+        for (i, counter) in self.length_counters.iter().enumerate() {
+            state.section(&format!("length_counters[{i}]"), |state| {
+                state.u8("counter", counter.counter);
+                state.bool("halted", counter.halted);
+                state.bool("enabled", counter.enabled);
+            });
+        }
+        state.section("dmc", |state| self.dmc.write_state(state));
+    }
+
+    pub fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        for counter in self.length_counters.iter_mut() {
+            counter.counter = state.u8()?;
+            counter.halted = state.bool()?;
+            counter.enabled = state.bool()?;
+        }
+        self.dmc.read_state(state)
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Queue a sample from the mixer, scaled by the master volume. Samples are still generated
+    /// while muted, so anything timed by them is unaffected.
+    #[allow(dead_code)] // TODO: none of the channels are implemented yet
+    fn push_sample(&mut self, sample: f32) {
+        let sample = self.gain.apply(sample);
+        self.samples.push(sample);
+    }
+
+    /// Master volume, from 0.0 to 1.0
+    pub fn volume(&self) -> f32 {
+        self.gain.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.gain.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.gain.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.gain.muted = muted;
+    }
+
+    /// Clock every channel's length counter, as the frame counter does on each half frame
+    #[allow(dead_code)] // TODO: the frame counter isn't implemented yet
+    fn clock_length_counters(&mut self) {
+        for counter in self.length_counters.iter_mut() {
+            counter.clock();
+        }
+    }
+
+    /// Run for as long as the CPU took to run `cycles` cycles. Only the DMC is clocked so far.
+    pub fn tick(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.dmc.clock();
+        }
+    }
+
+    /// Address of the next byte of the DMC's sample, if it is waiting for one to be fetched
+    pub fn dmc_address(&self) -> Option<u16> {
+        self.dmc.next_address()
+    }
+
+    /// Hand the DMC the byte it was waiting for, from `dmc_address`
+    pub fn load_dmc_sample(&mut self, value: u8) {
+        self.dmc.load_sample(value);
+    }
+
+    /// Whether the DMC is asserting the CPU's IRQ line, at the end of a sample
+    pub fn irq_pending(&self) -> bool {
+        self.dmc.irq_flag
+    }
+
+    pub fn read_address(&self, address: u16) -> u8 {
+        match address {
+            // Status: whether each channel's length counter is still running, whether the DMC
+            // is still playing a sample, and its interrupt flag
+            0x4015 => {
+                let counters = self
+                    .length_counters
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, counter)| counter.is_active())
+                    .fold(0, |status, (channel, _)| status | (1 << channel));
+                let dmc_active = (self.dmc.bytes_remaining > 0) as u8;
+                counters | dmc_active << 4 | (self.dmc.irq_flag as u8) << 7
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write_address(&mut self, address: u16, value: u8) {
+        match address {
+            // The halt flag is bit 5 for pulse and noise, and shares bit 7 with the linear
+            // counter control flag for triangle
+            0x4000 | 0x4004 | 0x400c => {
+                let channel = ((address - 0x4000) / 4) as usize;
+                self.length_counters[channel].halted = value & 0x20 != 0;
+            }
+            0x4008 => self.length_counters[2].halted = value & 0x80 != 0,
+            0x4003 | 0x4007 | 0x400b | 0x400f => {
+                let channel = ((address - 0x4000) / 4) as usize;
+                self.length_counters[channel].load(value);
+            }
+            0x4010..=0x4013 => self.dmc.write_register(address, value),
+            0x4015 => {
+                for (channel, counter) in self.length_counters.iter_mut().enumerate() {
+                    counter.set_enabled(value & (1 << channel) != 0);
+                }
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The length counter values from nesdev, in order of the top 5 bits of the length register
+    #[rustfmt::skip]
+    const HARDWARE_LENGTHS: [u8; 32] = [
+        10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+        12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+    ];
+
+    #[test]
+    fn volume_ramps_linearly() {
+        let mut gain = Gain {
+            volume: 1.0,
+            muted: false,
+            current: 1.0,
+        };
+        gain.volume = 0.0;
+        let ramp: Vec<f32> = (0..300).map(|_| gain.apply(1.0)).collect();
+        for (index, &sample) in ramp.iter().enumerate() {
+            let expected = (1.0 - (index + 1) as f32 / VOLUME_RAMP_SAMPLES).max(0.0);
+            assert!(
+                (sample - expected).abs() < 1e-4,
+                "sample {index} was {sample}, expected {expected}"
+            );
+        }
+        // Silent once the ramp is over, and not before
+        let end = VOLUME_RAMP_SAMPLES.ceil() as usize;
+        assert!(ramp[end - 2] > 0.0);
+        assert_eq!(ramp[end - 1], 0.0);
+    }
+
+    #[test]
+    fn mute_keeps_volume() {
+        let mut apu = APU::new(Region::Ntsc);
+        apu.set_volume(0.6);
+        apu.gain.current = 0.6;
+        apu.set_muted(true);
+        assert!(apu.muted());
+        assert_eq!(apu.volume(), 0.6);
+        let ramp = VOLUME_RAMP_SAMPLES.ceil() as usize;
+        for _ in 0..ramp {
+            apu.gain.apply(1.0);
+        }
+        assert_eq!(apu.gain.apply(1.0), 0.0);
+
+        // Unmuting fades back in to the volume from before
+        apu.set_muted(false);
+        for _ in 0..ramp {
+            apu.gain.apply(1.0);
+        }
+        assert_eq!(apu.gain.apply(1.0), 0.6);
+    }
+
+    #[test]
+    fn length_table() {
+        let mut apu = APU::new(Region::Ntsc);
+        apu.write_address(0x4015, 0x0f);
+        for (index, &length) in HARDWARE_LENGTHS.iter().enumerate() {
+            for (channel, address) in [0x4003, 0x4007, 0x400b, 0x400f].into_iter().enumerate() {
+                // The low 3 bits are the timer's, and don't affect the length
+                apu.write_address(address, (index as u8) << 3 | 0x7);
+                assert_eq!(
+                    apu.length_counters[channel].counter, length,
+                    "index {index} of channel {channel}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn length_counter_halt() {
+        let mut apu = APU::new(Region::Ntsc);
+        apu.write_address(0x4015, 0x0f);
+        // Pulse 1 halted, and triangle halted through its linear counter control flag
+        apu.write_address(0x4000, 0x20);
+        apu.write_address(0x4008, 0x80);
+        for address in [0x4003, 0x4007, 0x400b, 0x400f] {
+            // A length of 2
+            apu.write_address(address, 0x18);
+        }
+
+        apu.clock_length_counters();
+        apu.clock_length_counters();
+        let counters = apu
+            .length_counters
+            .each_ref()
+            .map(|counter| counter.counter);
+        assert_eq!(counters, [2, 0, 2, 0]);
+        assert_eq!(apu.read_address(0x4015), 0b0101);
+
+        // Un-halting lets it count down again
+        apu.write_address(0x4000, 0);
+        apu.clock_length_counters();
+        assert_eq!(apu.length_counters[0].counter, 1);
+    }
+
+    #[test]
+    fn disabled_length_counters_dont_load() {
+        let mut apu = APU::new(Region::Ntsc);
+        apu.write_address(0x4015, 0x01);
+        apu.write_address(0x4003, 0x08);
+        apu.write_address(0x4007, 0x08);
+        assert_eq!(apu.read_address(0x4015), 0x01);
+
+        // Disabling clears the counter too
+        apu.write_address(0x4015, 0);
+        assert_eq!(apu.length_counters[0].counter, 0);
+    }
+
+    /// Fetch the DMC's next sample byte, as its DMA would, and empty the sample buffer again so
+    /// the fetch after it is due straight away. Returns the address fetched from.
+    fn fetch_dmc_sample(apu: &mut APU) -> Option<u16> {
+        let address = apu.dmc_address()?;
+        apu.load_dmc_sample(0);
+        apu.dmc.sample_buffer = None;
+        Some(address)
+    }
+
+    #[test]
+    fn dmc_enable() {
+        let mut apu = APU::new(Region::Ntsc);
+        // A 17 byte sample at $C400
+        apu.write_address(0x4012, 0x10);
+        apu.write_address(0x4013, 0x01);
+        assert_eq!(apu.dmc_address(), None);
+
+        // Enabling starts the sample from its address
+        apu.write_address(0x4015, 0x10);
+        assert_eq!(apu.read_address(0x4015), 0x10);
+        assert_eq!(fetch_dmc_sample(&mut apu), Some(0xc400));
+        assert_eq!(fetch_dmc_sample(&mut apu), Some(0xc401));
+
+        // Enabling again while it plays carries on where it was, even if the sample has moved
+        apu.write_address(0x4012, 0x20);
+        apu.write_address(0x4015, 0x10);
+        assert_eq!(fetch_dmc_sample(&mut apu), Some(0xc402));
+
+        // Disabling stops it, and enabling again restarts it from the new address
+        apu.write_address(0x4015, 0);
+        assert_eq!(apu.read_address(0x4015), 0);
+        assert_eq!(apu.dmc_address(), None);
+        apu.write_address(0x4015, 0x10);
+        assert_eq!(fetch_dmc_sample(&mut apu), Some(0xc800));
+
+        // As does enabling it after the sample has finished
+        let fetched = std::iter::from_fn(|| fetch_dmc_sample(&mut apu)).count();
+        assert_eq!(fetched, 16);
+        assert_eq!(apu.read_address(0x4015), 0);
+        apu.write_address(0x4015, 0x10);
+        assert_eq!(fetch_dmc_sample(&mut apu), Some(0xc800));
+    }
+
+    #[test]
+    fn dmc_timer_follows_region() {
+        assert_eq!(Dmc::new(Region::Ntsc).timer, DMC_RATES_NTSC[0]);
+        assert_eq!(Dmc::new(Region::Pal).timer, DMC_RATES_PAL[0]);
+    }
 }
@@ -0,0 +1,685 @@
+//! A minimal assembler for the official 6502 instructions, for writing small test programs as
+//! text rather than raw bytes.
+//!
+//! Supports the usual syntax for each addressing mode, `$hex`, `%binary` and decimal numbers,
+//! `label:` definitions, `; comments`, and the `.org`, `.byte` and `.word` directives. Numbers
+//! written with at most 2 hex digits (or less than 256 in decimal/binary) use zero page
+//! addressing where the instruction supports it, and labels always use absolute addressing
+//! (except for branches, which are always relative). The program starts at `$8000` unless
+//! `.org` says otherwise.
+//!
+//! ```
+//! let program = rusty_nes::assemble("LDA #$01\nSTA $00").unwrap();
+//! assert_eq!(program, vec![0xa9, 0x01, 0x85, 0x00]);
+//! ```
+//!
+//! `disassemble` goes the other way, for the debugger.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line number of the source
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+const DEFAULT_ORIGIN: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    const ALL: [Mode; 13] = [
+        Mode::Implied,
+        Mode::Accumulator,
+        Mode::Immediate,
+        Mode::ZeroPage,
+        Mode::ZeroPageX,
+        Mode::ZeroPageY,
+        Mode::Absolute,
+        Mode::AbsoluteX,
+        Mode::AbsoluteY,
+        Mode::Indirect,
+        Mode::IndirectX,
+        Mode::IndirectY,
+        Mode::Relative,
+    ];
+
+    fn length(self) -> u16 {
+        match self {
+            Mode::Implied | Mode::Accumulator => 1,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+/// Every mnemonic `opcode` knows
+#[rustfmt::skip]
+const MNEMONICS: [&str; 56] = [
+    "adc", "and", "asl", "bcc", "bcs", "beq", "bit", "bmi", "bne", "bpl", "brk", "bvc", "bvs",
+    "clc", "cld", "cli", "clv", "cmp", "cpx", "cpy", "dec", "dex", "dey", "eor", "inc", "inx",
+    "iny", "jmp", "jsr", "lda", "ldx", "ldy", "lsr", "nop", "ora", "pha", "php", "pla", "plp",
+    "rol", "ror", "rti", "rts", "sbc", "sec", "sed", "sei", "sta", "stx", "sty", "tax", "tay",
+    "tsx", "txa", "txs", "tya",
+];
+
+/// Opcode of every official instruction, by mnemonic and addressing mode
+///
+/// See: <https://www.nesdev.org/obelisk-6502-guide/reference.html>
+fn opcode(mnemonic: &str, mode: Mode) -> Option<u8> {
+    use Mode::*;
+
+    // The ALU instructions all share the same layout
+    let alu = |base: u8| match mode {
+        Immediate => Some(base + 0x08),
+        ZeroPage => Some(base + 0x04),
+        ZeroPageX => Some(base + 0x14),
+        Absolute => Some(base + 0x0c),
+        AbsoluteX => Some(base + 0x1c),
+        AbsoluteY => Some(base + 0x18),
+        IndirectX => Some(base),
+        IndirectY => Some(base + 0x10),
+        _ => None,
+    };
+    // As do the shifts and rotates
+    let shift = |base: u8| match mode {
+        Accumulator => Some(base + 0x0a),
+        ZeroPage => Some(base + 0x06),
+        ZeroPageX => Some(base + 0x16),
+        Absolute => Some(base + 0x0e),
+        AbsoluteX => Some(base + 0x1e),
+        _ => None,
+    };
+    let implied = |opcode: u8| (mode == Implied).then_some(opcode);
+    let relative = |opcode: u8| (mode == Relative).then_some(opcode);
+
+    match mnemonic {
+        "ora" => alu(0x01),
+        "and" => alu(0x21),
+        "eor" => alu(0x41),
+        "adc" => alu(0x61),
+        "sta" if mode != Immediate => alu(0x81),
+        "lda" => alu(0xa1),
+        "cmp" => alu(0xc1),
+        "sbc" => alu(0xe1),
+
+        "asl" => shift(0x00),
+        "rol" => shift(0x20),
+        "lsr" => shift(0x40),
+        "ror" => shift(0x60),
+
+        "bpl" => relative(0x10),
+        "bmi" => relative(0x30),
+        "bvc" => relative(0x50),
+        "bvs" => relative(0x70),
+        "bcc" => relative(0x90),
+        "bcs" => relative(0xb0),
+        "bne" => relative(0xd0),
+        "beq" => relative(0xf0),
+
+        "bit" => match mode {
+            ZeroPage => Some(0x24),
+            Absolute => Some(0x2c),
+            _ => None,
+        },
+        "jmp" => match mode {
+            Absolute => Some(0x4c),
+            Indirect => Some(0x6c),
+            _ => None,
+        },
+        "jsr" => (mode == Absolute).then_some(0x20),
+        "cpx" | "cpy" => {
+            let base = if mnemonic == "cpx" { 0xe0 } else { 0xc0 };
+            match mode {
+                Immediate => Some(base),
+                ZeroPage => Some(base + 0x04),
+                Absolute => Some(base + 0x0c),
+                _ => None,
+            }
+        }
+        "dec" | "inc" => {
+            let base = if mnemonic == "dec" { 0xc6 } else { 0xe6 };
+            match mode {
+                ZeroPage => Some(base),
+                ZeroPageX => Some(base + 0x10),
+                Absolute => Some(base + 0x08),
+                AbsoluteX => Some(base + 0x18),
+                _ => None,
+            }
+        }
+        "ldx" => match mode {
+            Immediate => Some(0xa2),
+            ZeroPage => Some(0xa6),
+            ZeroPageY => Some(0xb6),
+            Absolute => Some(0xae),
+            AbsoluteY => Some(0xbe),
+            _ => None,
+        },
+        "ldy" => match mode {
+            Immediate => Some(0xa0),
+            ZeroPage => Some(0xa4),
+            ZeroPageX => Some(0xb4),
+            Absolute => Some(0xac),
+            AbsoluteX => Some(0xbc),
+            _ => None,
+        },
+        "stx" => match mode {
+            ZeroPage => Some(0x86),
+            ZeroPageY => Some(0x96),
+            Absolute => Some(0x8e),
+            _ => None,
+        },
+        "sty" => match mode {
+            ZeroPage => Some(0x84),
+            ZeroPageX => Some(0x94),
+            Absolute => Some(0x8c),
+            _ => None,
+        },
+
+        "brk" => implied(0x00),
+        "php" => implied(0x08),
+        "clc" => implied(0x18),
+        "plp" => implied(0x28),
+        "sec" => implied(0x38),
+        "rti" => implied(0x40),
+        "pha" => implied(0x48),
+        "cli" => implied(0x58),
+        "rts" => implied(0x60),
+        "pla" => implied(0x68),
+        "sei" => implied(0x78),
+        "dey" => implied(0x88),
+        "txa" => implied(0x8a),
+        "tya" => implied(0x98),
+        "txs" => implied(0x9a),
+        "tay" => implied(0xa8),
+        "tax" => implied(0xaa),
+        "clv" => implied(0xb8),
+        "tsx" => implied(0xba),
+        "iny" => implied(0xc8),
+        "dex" => implied(0xca),
+        "cld" => implied(0xd8),
+        "inx" => implied(0xe8),
+        "nop" => implied(0xea),
+        "sed" => implied(0xf8),
+        _ => None,
+    }
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    opcode(mnemonic, Mode::Relative).is_some()
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    /// A literal, and whether it was written short enough to be a zero page address
+    Number(u16, bool),
+    Label(String),
+}
+
+/// The syntactic shape of an operand, before the mnemonic decides the addressing mode
+#[derive(Debug, Clone)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Value),
+    Direct(Value),
+    DirectX(Value),
+    DirectY(Value),
+    Indirect(Value),
+    IndirectX(Value),
+    IndirectY(Value),
+}
+
+#[derive(Debug)]
+enum Item {
+    Instruction {
+        mnemonic: String,
+        mode: Mode,
+        value: Option<Value>,
+    },
+    Bytes(Vec<Value>),
+    Words(Vec<Value>),
+}
+
+struct Line {
+    number: usize,
+    address: u16,
+    item: Item,
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> AsmResult<T> {
+    Err(AsmError {
+        line,
+        message: message.into(),
+    })
+}
+
+fn parse_value(text: &str, line: usize) -> AsmResult<Value> {
+    let text = text.trim();
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix('%') {
+        (binary, 2)
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        (text, 10)
+    } else if !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Ok(Value::Label(text.to_string()));
+    } else {
+        return error(line, format!("invalid operand '{text}'"));
+    };
+
+    match u16::from_str_radix(digits, radix) {
+        Ok(number) => {
+            let short = if radix == 16 {
+                digits.len() <= 2
+            } else {
+                number < 0x100
+            };
+            Ok(Value::Number(number, short))
+        }
+        Err(_) => error(line, format!("invalid number '{text}'")),
+    }
+}
+
+fn parse_operand(text: &str, line: usize) -> AsmResult<Operand> {
+    let text = text.trim();
+    let upper = text.to_ascii_uppercase();
+    if text.is_empty() {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_value(value, line)?));
+    }
+    if text.starts_with('(') {
+        let without_spaces: String = upper.split_whitespace().collect();
+        if let Some(inner) = without_spaces.strip_suffix(",X)") {
+            return Ok(Operand::IndirectX(parse_value(
+                &text[1..inner.len()],
+                line,
+            )?));
+        }
+        if let Some(inner) = without_spaces.strip_suffix("),Y") {
+            return Ok(Operand::IndirectY(parse_value(
+                &text[1..inner.len()],
+                line,
+            )?));
+        }
+        if let Some(inner) = without_spaces.strip_suffix(')') {
+            return Ok(Operand::Indirect(parse_value(&text[1..inner.len()], line)?));
+        }
+        return error(line, format!("invalid indirect operand '{text}'"));
+    }
+    if let Some((value, index)) = text.rsplit_once(',') {
+        let value = parse_value(value, line)?;
+        return match index.trim().to_ascii_uppercase().as_str() {
+            "X" => Ok(Operand::DirectX(value)),
+            "Y" => Ok(Operand::DirectY(value)),
+            _ => error(line, format!("invalid index register '{}'", index.trim())),
+        };
+    }
+    Ok(Operand::Direct(parse_value(text, line)?))
+}
+
+/// Pick the addressing mode for an instruction, preferring zero page when the operand is short
+/// enough and the instruction has a zero page form
+fn choose_mode(mnemonic: &str, operand: Operand, line: usize) -> AsmResult<(Mode, Option<Value>)> {
+    if !MNEMONICS.contains(&mnemonic) {
+        return error(line, format!("unknown mnemonic '{mnemonic}'"));
+    }
+    let is_short = |value: &Value| matches!(value, Value::Number(_, true));
+    let prefer_zero_page = |value: Value, zero_page: Mode, absolute: Mode| {
+        if is_short(&value) && opcode(mnemonic, zero_page).is_some() {
+            (zero_page, Some(value))
+        } else {
+            (absolute, Some(value))
+        }
+    };
+
+    let (mode, value) = match operand {
+        // Shifts and rotates can omit the `A`
+        Operand::None if opcode(mnemonic, Mode::Accumulator).is_some() => (Mode::Accumulator, None),
+        Operand::None => (Mode::Implied, None),
+        Operand::Accumulator => (Mode::Accumulator, None),
+        Operand::Immediate(value) => (Mode::Immediate, Some(value)),
+        Operand::Direct(value) if is_branch(mnemonic) => (Mode::Relative, Some(value)),
+        Operand::Direct(value) => prefer_zero_page(value, Mode::ZeroPage, Mode::Absolute),
+        Operand::DirectX(value) => prefer_zero_page(value, Mode::ZeroPageX, Mode::AbsoluteX),
+        Operand::DirectY(value) => prefer_zero_page(value, Mode::ZeroPageY, Mode::AbsoluteY),
+        Operand::Indirect(value) => (Mode::Indirect, Some(value)),
+        Operand::IndirectX(value) => (Mode::IndirectX, Some(value)),
+        Operand::IndirectY(value) => (Mode::IndirectY, Some(value)),
+    };
+
+    if opcode(mnemonic, mode).is_none() {
+        return error(
+            line,
+            format!("'{mnemonic}' does not support {mode:?} addressing"),
+        );
+    }
+    Ok((mode, value))
+}
+
+fn resolve(value: &Value, labels: &HashMap<String, u16>, line: usize) -> AsmResult<u16> {
+    match value {
+        Value::Number(number, _) => Ok(*number),
+        Value::Label(label) => match labels.get(label) {
+            Some(&address) => Ok(address),
+            None => error(line, format!("undefined label '{label}'")),
+        },
+    }
+}
+
+fn resolve_byte(value: &Value, labels: &HashMap<String, u16>, line: usize) -> AsmResult<u8> {
+    let number = resolve(value, labels, line)?;
+    u8::try_from(number).or_else(|_| error(line, format!("${number:04x} does not fit in a byte")))
+}
+
+/// Assemble a program, returning the bytes from the first origin onwards
+pub fn assemble(source: &str) -> AsmResult<Vec<u8>> {
+    // First pass: parse every line and work out the address of each label
+    let mut labels = HashMap::new();
+    let mut lines = Vec::new();
+    let mut address = DEFAULT_ORIGIN;
+    let mut origin = None;
+
+    for (index, raw_line) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut text = raw_line.split(';').next().unwrap_or("").trim();
+
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if labels.insert(label.to_string(), address).is_some() {
+                return error(number, format!("label '{label}' is defined twice"));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (keyword, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let keyword = keyword.to_ascii_lowercase();
+        let item = match keyword.as_str() {
+            ".org" => {
+                let Value::Number(new_address, _) = parse_value(rest, number)? else {
+                    return error(number, ".org needs a number");
+                };
+                address = new_address;
+                continue;
+            }
+            ".byte" => Item::Bytes(
+                rest.split(',')
+                    .map(|value| parse_value(value, number))
+                    .collect::<AsmResult<_>>()?,
+            ),
+            ".word" => Item::Words(
+                rest.split(',')
+                    .map(|value| parse_value(value, number))
+                    .collect::<AsmResult<_>>()?,
+            ),
+            mnemonic => {
+                let operand = parse_operand(rest, number)?;
+                let (mode, value) = choose_mode(mnemonic, operand, number)?;
+                Item::Instruction {
+                    mnemonic: mnemonic.to_string(),
+                    mode,
+                    value,
+                }
+            }
+        };
+
+        origin.get_or_insert(address);
+        let length = match &item {
+            Item::Instruction { mode, .. } => mode.length(),
+            Item::Bytes(values) => values.len() as u16,
+            Item::Words(values) => 2 * values.len() as u16,
+        };
+        lines.push(Line {
+            number,
+            address,
+            item,
+        });
+        address = address.wrapping_add(length);
+    }
+
+    // Second pass: emit the bytes, now that every label is known
+    let origin = origin.unwrap_or(DEFAULT_ORIGIN);
+    let mut output: Vec<u8> = Vec::new();
+    for line in lines {
+        let offset = line.address.wrapping_sub(origin) as usize;
+        if offset < output.len() {
+            return error(line.number, "code overlaps earlier code");
+        }
+        output.resize(offset, 0);
+
+        match line.item {
+            Item::Bytes(values) => {
+                for value in values {
+                    output.push(resolve_byte(&value, &labels, line.number)?);
+                }
+            }
+            Item::Words(values) => {
+                for value in values {
+                    let word = resolve(&value, &labels, line.number)?;
+                    output.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            Item::Instruction {
+                mnemonic,
+                mode,
+                value,
+            } => {
+                // `choose_mode` already checked this exists
+                output.push(opcode(&mnemonic, mode).unwrap_or_default());
+                let Some(value) = value else {
+                    continue;
+                };
+                match mode {
+                    Mode::Relative => {
+                        let target = resolve(&value, &labels, line.number)?;
+                        let next = line.address.wrapping_add(2);
+                        let distance = target.wrapping_sub(next) as i16;
+                        let Ok(distance) = i8::try_from(distance) else {
+                            return error(line.number, "branch target is out of range");
+                        };
+                        output.push(distance as u8);
+                    }
+                    Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => {
+                        let word = resolve(&value, &labels, line.number)?;
+                        output.extend_from_slice(&word.to_le_bytes());
+                    }
+                    _ => output.push(resolve_byte(&value, &labels, line.number)?),
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Disassemble the instruction at `address`, reading memory with `read`. Returns the
+/// instruction as it would be written for `assemble`, e.g. `lda ($10),y`, and its length in
+/// bytes. Unofficial opcodes come out as a `.byte` directive.
+pub fn disassemble(address: u16, read: impl Fn(u16) -> u8) -> (String, u16) {
+    let byte = read(address);
+    let found = MNEMONICS.iter().find_map(|&mnemonic| {
+        Mode::ALL
+            .into_iter()
+            .find(|&mode| opcode(mnemonic, mode) == Some(byte))
+            .map(|mode| (mnemonic, mode))
+    });
+    let Some((mnemonic, mode)) = found else {
+        return (format!(".byte ${:02x}", byte), 1);
+    };
+
+    let low = read(address.wrapping_add(1));
+    let word = u16::from_le_bytes([low, read(address.wrapping_add(2))]);
+    let operand = match mode {
+        Mode::Implied => String::new(),
+        Mode::Accumulator => "a".to_string(),
+        Mode::Immediate => format!("#${:02x}", low),
+        Mode::ZeroPage => format!("${:02x}", low),
+        Mode::ZeroPageX => format!("${:02x},x", low),
+        Mode::ZeroPageY => format!("${:02x},y", low),
+        Mode::Absolute => format!("${:04x}", word),
+        Mode::AbsoluteX => format!("${:04x},x", word),
+        Mode::AbsoluteY => format!("${:04x},y", word),
+        Mode::Indirect => format!("(${:04x})", word),
+        Mode::IndirectX => format!("(${:02x},x)", low),
+        Mode::IndirectY => format!("(${:02x}),y", low),
+        Mode::Relative => {
+            let target = address.wrapping_add(2).wrapping_add(low as i8 as u16);
+            format!("${:04x}", target)
+        }
+    };
+    let text = if operand.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operand)
+    };
+    (text, mode.length())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addressing_modes() {
+        let cases: [(&str, &[u8]); 14] = [
+            ("clc", &[0x18]),
+            ("asl a", &[0x0a]),
+            ("lsr", &[0x4a]),
+            ("lda #$01", &[0xa9, 0x01]),
+            ("lda $10", &[0xa5, 0x10]),
+            ("lda $10,x", &[0xb5, 0x10]),
+            ("ldx $10,y", &[0xb6, 0x10]),
+            ("lda $1234", &[0xad, 0x34, 0x12]),
+            ("lda $0010", &[0xad, 0x10, 0x00]),
+            ("lda $1234,x", &[0xbd, 0x34, 0x12]),
+            ("lda $1234,y", &[0xb9, 0x34, 0x12]),
+            ("jmp ($1234)", &[0x6c, 0x34, 0x12]),
+            ("lda ($10,x)", &[0xa1, 0x10]),
+            ("lda ($10),y", &[0xb1, 0x10]),
+        ];
+        for (source, bytes) in cases {
+            assert_eq!(assemble(source).unwrap(), bytes, "{source}");
+        }
+    }
+
+    #[test]
+    fn zero_page_only_when_supported() {
+        // There is no zero page,y form of lda, nor a zero page jmp
+        assert_eq!(assemble("lda $10,y").unwrap(), [0xb9, 0x10, 0x00]);
+        assert_eq!(assemble("jmp $10").unwrap(), [0x4c, 0x10, 0x00]);
+        // Decimal and binary numbers under 256 are short too
+        assert_eq!(assemble("lda 16\nlda %11").unwrap(), [0xa5, 16, 0xa5, 3]);
+    }
+
+    #[test]
+    fn labels_and_branches() {
+        let program = assemble(
+            "start: ldx #$03
+             loop:  dex        ; comment
+                    bne loop
+                    beq end
+                    jmp start
+             end:   rts",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            [0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xf0, 0x03, 0x4c, 0x00, 0x80, 0x60]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let program = assemble(
+            ".org $c000
+             lda #$01
+             .byte $02, 3, %100
+             .org $c008
+             .word $1234, here
+             here: nop",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            [0xa9, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x34, 0x12, 0x0c, 0xc0, 0xea]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let message = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            message("nop\nfoo $10"),
+            AsmError {
+                line: 2,
+                message: "unknown mnemonic 'foo'".into()
+            }
+        );
+        assert_eq!(message("jmp nowhere").line, 1);
+        assert_eq!(message("a: nop\na: nop").line, 2);
+        assert_eq!(message("ldx $10,x").line, 1);
+        assert_eq!(message("lda #$123").line, 1);
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let sources = [
+            "brk",
+            "asl a",
+            "lda #$01",
+            "lda $10",
+            "lda $10,x",
+            "ldx $10,y",
+            "lda $1234",
+            "lda $1234,x",
+            "lda $1234,y",
+            "jmp ($1234)",
+            "lda ($10,x)",
+            "lda ($10),y",
+            "bne $8010",
+        ];
+        for source in sources {
+            let bytes = assemble(source).unwrap();
+            let read = |address: u16| bytes.get(address as usize - 0x8000).copied().unwrap_or(0);
+            assert_eq!(
+                disassemble(0x8000, read),
+                (source.to_string(), bytes.len() as u16)
+            );
+        }
+        assert_eq!(disassemble(0x8000, |_| 0x02), (".byte $02".to_string(), 1));
+    }
+}
@@ -1,31 +1,175 @@
 use std::{
-    fmt::Debug,
-    fs::File,
-    io::{BufReader, Read},
+    fmt::{Debug, Display},
+    io::ErrorKind,
+    path::Path,
 };
 
+use crate::mapper::new_mapper;
+use crate::png::crc32;
+use crate::region::Region;
+use crate::romdb::RomDatabase;
+
+#[derive(Debug)]
 pub enum CartLoadError {
+    /// Has no bytes at all
+    EmptyFile,
     FileNotARom,
     FileNotFound,
     IoError(std::io::Error),
+    UnsupportedMapper(u8),
+    /// Shorter than the header, or than the ROM sizes in the header say it should be
+    TruncatedRom {
+        expected: usize,
+        actual: usize,
+    },
 }
 
+impl Display for CartLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartLoadError::EmptyFile => write!(f, "ROM file is empty"),
+            CartLoadError::FileNotARom => write!(f, "not a valid ROM file"),
+            CartLoadError::FileNotFound => write!(f, "ROM file not found"),
+            CartLoadError::IoError(err) => write!(f, "IO error: {}", err),
+            CartLoadError::UnsupportedMapper(number) => {
+                write!(f, "mapper {} is not supported", number)
+            }
+            CartLoadError::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM file is truncated: expected {} bytes, but it is {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartLoadError {}
+
 pub type CartLoadResult<T> = Result<T, CartLoadError>;
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct Cart {
     prg_rom: usize,
     chr_rom: usize,
     mirroring: Mirroring,
+    battery_present: bool,
 
     // Currently unused:
-    battery_present: bool,
     trainer_present: bool,
     hard_wired_four_screen_mode: bool,
 
     mapper: u8,
-    pub prg_rom_pages: Vec<Vec<u8>>,
-    pub chr_rom_pages: Vec<Vec<u8>>,
+    submapper: u8,
+    region: Region,
+    kind: CartKind,
+    /// All the pages of each ROM back to back, so reads don't have to go through a page first
+    prg_rom_data: Box<[u8]>,
+    chr_rom_data: Box<[u8]>,
+}
+
+impl Cart {
+    /// An NROM cart with no PRG or CHR ROM, which reads `$FF` everywhere in PRG ROM, for testing
+    /// the rest of the console without a ROM file
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn empty() -> Self {
+        Cart {
+            prg_rom: 0,
+            chr_rom: 0,
+            mirroring: Mirroring::Horizontal,
+            battery_present: false,
+            trainer_present: false,
+            hard_wired_four_screen_mode: false,
+            mapper: 0,
+            submapper: 0,
+            region: Region::default(),
+            kind: CartKind::Console,
+            prg_rom_data: Box::new([]),
+            chr_rom_data: Box::new([]),
+        }
+    }
+
+    pub fn mapper_number(&self) -> u8 {
+        self.mapper
+    }
+
+    /// The variant of the mapper, from an NES 2.0 header or the ROM database, or else 0
+    pub fn submapper_number(&self) -> u8 {
+        self.submapper
+    }
+
+    /// Number of 16 KiB banks of PRG ROM
+    pub fn prg_banks(&self) -> usize {
+        self.prg_rom
+    }
+
+    /// Byte at an offset into a 16 KiB bank of PRG ROM, or `$FF` past the end (see
+    /// `prg_rom_byte`)
+    pub fn prg_byte(&self, bank: usize, offset: usize) -> u8 {
+        self.prg_rom_byte(bank * PRG_ROM_PAGE_SIZE + offset)
+    }
+
+    /// Number of 8 KiB banks of CHR ROM, which is 0 if the cart has CHR RAM instead
+    pub fn chr_banks(&self) -> usize {
+        self.chr_rom
+    }
+
+    /// Byte at an offset into an 8 KiB bank of CHR ROM, or 0 past the end (see `chr_rom_byte`)
+    pub fn chr_byte(&self, bank: usize, offset: usize) -> u8 {
+        self.chr_rom_byte(bank * CHR_ROM_PAGE_SIZE + offset)
+    }
+
+    /// Size of PRG ROM in bytes
+    pub fn prg_rom_len(&self) -> usize {
+        self.prg_rom_data.len()
+    }
+
+    /// Byte of PRG ROM at an offset from the start of the first page, or `$FF` if the cart has
+    /// no PRG ROM
+    pub fn prg_rom_byte(&self, index: usize) -> u8 {
+        self.prg_rom_data.get(index).copied().unwrap_or(0xff)
+    }
+
+    /// Size of CHR ROM in bytes, which is 0 if the cart has CHR RAM instead
+    pub fn chr_rom_len(&self) -> usize {
+        self.chr_rom_data.len()
+    }
+
+    /// Byte of CHR ROM at an offset from the start of the first page, or 0 if the cart has no
+    /// CHR ROM
+    pub fn chr_rom_byte(&self, index: usize) -> u8 {
+        self.chr_rom_data.get(index).copied().unwrap_or(0)
+    }
+
+    /// CRC-32 of the PRG ROM followed by the CHR ROM, which identifies the game regardless of
+    /// its header
+    pub fn crc32(&self) -> u32 {
+        crc32(&[&self.prg_rom_data[..], &self.chr_rom_data[..]].concat())
+    }
+
+    /// How the nametables are mirrored as the cart is wired. When it's `MapperControlled`, the
+    /// mapper sets it at runtime (see `Mapper::mirroring`), which the PPU follows.
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Whether the cart's PRG RAM is kept in a save file (see `CPU::battery_ram`)
+    pub fn has_battery(&self) -> bool {
+        self.battery_present
+    }
+
+    pub fn kind(&self) -> CartKind {
+        self.kind
+    }
+
+    /// The console the game is meant for, if the header says; otherwise NTSC
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub(crate) fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
 }
 
 impl Debug for Cart {
@@ -39,77 +183,494 @@ impl Debug for Cart {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
-    HorizontalOrMapperControlled,
+    Horizontal,
     Vertical,
+    /// All four nametable addresses show the same one of the console's two nametables, as
+    /// selected by mappers like AxROM
+    SingleScreenLower,
+    SingleScreenUpper,
+    /// Each nametable address has its own nametable, using 2 KiB of extra VRAM on the cart, as
+    /// on Rad Racer II and Gauntlet. The mapper can't change it.
+    ///
+    /// See: <https://www.nesdev.org/wiki/Mirroring#4-Screen>
+    FourScreen,
+    /// Set by the mapper (see `Mapper::mirroring`) rather than wired on the board, so the
+    /// header's mirroring bit means nothing
+    MapperControlled,
+}
+
+/// The hardware a ROM was dumped from
+///
+/// See: <https://www.nesdev.org/wiki/NES_2.0#Byte_13_(Vs._hardware)>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartKind {
+    Console,
+    /// The arcade version of the NES, with its own PPUs and copy protection
+    VsSystem(VsPpu, VsSystem),
+    Playchoice10,
+}
+
+/// The PPU in a VS System cabinet, which decides its palette (and for the RC2C05s, swaps
+/// PPUCTRL and PPUMASK and returns an ID from PPUSTATUS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsPpu {
+    Rp2c03b,
+    Rp2c03g,
+    Rp2c04_0001,
+    Rp2c04_0002,
+    Rp2c04_0003,
+    Rp2c04_0004,
+    Rc2c03b,
+    Rc2c03c,
+    Rc2c05_01,
+    Rc2c05_02,
+    Rc2c05_03,
+    Rc2c05_04,
+    Rc2c05_05,
+    /// iNES 1.0 headers don't say, and NES 2.0 reserves the other values
+    Unknown,
+}
+
+/// The VS System board, and the copy protection it has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsSystem {
+    Unisystem,
+    UnisystemRbiBaseball,
+    UnisystemTkoBoxing,
+    UnisystemSuperXevious,
+    UnisystemIceClimberJapan,
+    DualSystem,
+    DualSystemRaidOnBungelingBay,
+    /// iNES 1.0 headers don't say, and NES 2.0 reserves the other values
+    Unknown,
+}
+
+const HEADER_SIZE: usize = 16;
+/// Size of the banks counted by `Cart::prg_banks`
+const PRG_ROM_PAGE_SIZE: usize = 16 * 1024;
+/// Size of the banks counted by `Cart::chr_banks`
+const CHR_ROM_PAGE_SIZE: usize = 8 * 1024;
+
+/// An iNES ROM image whose header has been validated, but which hasn't been split into pages
+/// yet. Useful for inspecting a ROM without loading it.
+///
+/// See: <https://www.nesdev.org/wiki/INES>
+#[derive(Debug, Clone)]
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn from_bytes(data: Vec<u8>) -> CartLoadResult<Self> {
+        if data.is_empty() {
+            return Err(CartLoadError::EmptyFile);
+        }
+        if !data.starts_with(b"NES\x1a") {
+            return Err(CartLoadError::FileNotARom);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(CartLoadError::TruncatedRom {
+                expected: HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+        Ok(Self { data })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> CartLoadResult<Self> {
+        let data = std::fs::read(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => CartLoadError::FileNotFound,
+            _ => CartLoadError::IoError(err),
+        })?;
+        Self::from_bytes(data)
+    }
+
+    pub fn mapper_number(&self) -> u8 {
+        (self.data[6] >> 4) | (self.data[7] & 0xf0)
+    }
+
+    /// The variant of the mapper, which only NES 2.0 headers give
+    pub fn submapper_number(&self) -> u8 {
+        if self.is_nes2() {
+            self.data[8] >> 4
+        } else {
+            0
+        }
+    }
+
+    fn prg_rom_pages(&self) -> usize {
+        self.data[4] as usize
+    }
+
+    fn chr_rom_pages(&self) -> usize {
+        self.data[5] as usize
+    }
+
+    pub fn prg_size_kb(&self) -> usize {
+        self.prg_rom_pages() * PRG_ROM_PAGE_SIZE / 1024
+    }
+
+    pub fn chr_size_kb(&self) -> usize {
+        self.chr_rom_pages() * CHR_ROM_PAGE_SIZE / 1024
+    }
+
+    /// Whether the header is in the NES 2.0 format, which extends iNES with more fields
+    ///
+    /// See: <https://www.nesdev.org/wiki/NES_2.0>
+    pub fn is_nes2(&self) -> bool {
+        self.data[7] & 0x0c == 0x08
+    }
+
+    /// The TV system from the NES 2.0 timing byte. iNES 1.0 headers don't reliably say, so
+    /// they are assumed to be NTSC.
+    pub fn region(&self) -> Region {
+        if !self.is_nes2() {
+            return Region::Ntsc;
+        }
+        match self.data[12] & 0x3 {
+            // Multi-region games run on either, so pick the more common
+            0 | 2 => Region::Ntsc,
+            1 => Region::Pal,
+            _ => Region::Dendy,
+        }
+    }
+
+    /// Whether this is a home console, VS System or PlayChoice-10 ROM, from byte 7 (and for VS
+    /// System ROMs with an NES 2.0 header, which PPU and board from byte 13)
+    pub fn kind(&self) -> CartKind {
+        match self.data[7] & 0x3 {
+            1 => {
+                let (ppu, system) = if self.is_nes2() {
+                    (self.data[13] & 0xf, self.data[13] >> 4)
+                } else {
+                    (0xf, 0xf)
+                };
+                let ppu = match ppu {
+                    0 => VsPpu::Rp2c03b,
+                    1 => VsPpu::Rp2c03g,
+                    2 => VsPpu::Rp2c04_0001,
+                    3 => VsPpu::Rp2c04_0002,
+                    4 => VsPpu::Rp2c04_0003,
+                    5 => VsPpu::Rp2c04_0004,
+                    6 => VsPpu::Rc2c03b,
+                    7 => VsPpu::Rc2c03c,
+                    8 => VsPpu::Rc2c05_01,
+                    9 => VsPpu::Rc2c05_02,
+                    10 => VsPpu::Rc2c05_03,
+                    11 => VsPpu::Rc2c05_04,
+                    12 => VsPpu::Rc2c05_05,
+                    _ => VsPpu::Unknown,
+                };
+                let system = match system {
+                    0 => VsSystem::Unisystem,
+                    1 => VsSystem::UnisystemRbiBaseball,
+                    2 => VsSystem::UnisystemTkoBoxing,
+                    3 => VsSystem::UnisystemSuperXevious,
+                    4 => VsSystem::UnisystemIceClimberJapan,
+                    5 => VsSystem::DualSystem,
+                    6 => VsSystem::DualSystemRaidOnBungelingBay,
+                    _ => VsSystem::Unknown,
+                };
+                CartKind::VsSystem(ppu, system)
+            }
+            2 => CartKind::Playchoice10,
+            // 3 is an NES 2.0 extended console type, which are all famiclones
+            _ => CartKind::Console,
+        }
+    }
+
+    /// Whether the cart keeps its PRG RAM powered with a battery, e.g. for saved games
+    pub fn has_battery(&self) -> bool {
+        self.data[6] & 0x2 == 0x2
+    }
+
+    /// Whether the ROM has a 512 byte trainer, which some copiers loaded at `$7000`
+    pub fn has_trainer(&self) -> bool {
+        self.data[6] & 0x4 == 0x4
+    }
+
+    /// The mirroring the header says the board is wired for, which mappers that control it
+    /// ignore
+    pub fn mirroring(&self) -> Mirroring {
+        if self.data[6] & 0x8 == 0x8 {
+            Mirroring::FourScreen
+        } else if self.data[6] & 0x1 == 0x1 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    /// Copy out the ROM, with the header corrected if the ROM is in the builtin `RomDatabase`
+    pub fn into_cart(self) -> CartLoadResult<Cart> {
+        self.into_cart_with(RomDatabase::builtin())
+    }
+
+    fn into_cart_with(self, database: RomDatabase) -> CartLoadResult<Cart> {
+        let contents = &self.data;
+        let mut mapper = self.mapper_number();
+        let mut submapper = self.submapper_number();
+        let mut prg_rom = self.prg_rom_pages();
+        let mut chr_rom = self.chr_rom_pages();
+        let prg_rom_end = contents
+            .len()
+            .min(HEADER_SIZE + prg_rom * PRG_ROM_PAGE_SIZE);
+        if let Some(info) = database.lookup(&contents[HEADER_SIZE..prg_rom_end]) {
+            mapper = info.mapper;
+            submapper = info.submapper;
+            prg_rom = info.prg_size as usize / PRG_ROM_PAGE_SIZE;
+            chr_rom = info.chr_size as usize / CHR_ROM_PAGE_SIZE;
+        }
+        let battery_present = self.has_battery();
+        let trainer_present = self.has_trainer();
+        let hard_wired_four_screen_mode = contents[6] & 0x8 == 0x8;
+
+        // Without any PRG ROM there would be nothing at the reset vector
+        if prg_rom == 0 {
+            return Err(CartLoadError::FileNotARom);
+        }
+        let Some(mapper_state) = new_mapper(mapper, chr_rom > 0) else {
+            return Err(CartLoadError::UnsupportedMapper(mapper));
+        };
+        // Four-screen VRAM on the cart takes over from whatever the mapper sets
+        let mirroring = match self.mirroring() {
+            Mirroring::FourScreen => Mirroring::FourScreen,
+            _ if mapper_state.mirroring().is_some() => Mirroring::MapperControlled,
+            mirroring => mirroring,
+        };
+        let expected = HEADER_SIZE + prg_rom * PRG_ROM_PAGE_SIZE + chr_rom * CHR_ROM_PAGE_SIZE;
+        if contents.len() < expected {
+            return Err(CartLoadError::TruncatedRom {
+                expected,
+                actual: contents.len(),
+            });
+        }
+
+        let chr_rom_start = HEADER_SIZE + prg_rom * PRG_ROM_PAGE_SIZE;
+        let prg_rom_data = contents[HEADER_SIZE..chr_rom_start].into();
+        let chr_rom_data = contents[chr_rom_start..expected].into();
+
+        Ok(Cart {
+            prg_rom,
+            chr_rom,
+            mirroring,
+            battery_present,
+            trainer_present,
+            hard_wired_four_screen_mode,
+            mapper,
+            submapper,
+            region: self.region(),
+            kind: self.kind(),
+            prg_rom_data,
+            chr_rom_data,
+        })
+    }
 }
 
 /// Load contents of file to Cart
 pub fn load_to_cart(filename: String) -> CartLoadResult<Cart> {
-    let file = match File::open(filename) {
-        Ok(file) => file,
-        Err(_) => {
-            return Err(CartLoadError::FileNotFound);
-        }
-    };
-    let mut buf_reader = BufReader::new(file);
-    let mut contents: Vec<u8> = Vec::new();
-    if let Err(err) = buf_reader.read_to_end(&mut contents) {
-        return Err(CartLoadError::IoError(err));
-    }
-
-    // Check that this is a valid ROM file
-    if &contents[0..3] != b"NES" || contents[3] != 0x1a {
-        return Err(CartLoadError::FileNotARom);
-    }
-
-    let prg_rom = contents[4] as usize;
-    let chr_rom = contents[5] as usize;
-    let mirroring = match (contents[6]) & 0x1 {
-        0 => Mirroring::HorizontalOrMapperControlled,
-        1 => Mirroring::Vertical,
-        _ => Mirroring::HorizontalOrMapperControlled, // TODO: should this be necessary?
-    };
-    let battery_present = contents[6] & 0x2 == 0x2;
-    let trainer_present = contents[6] & 0x3 == 0x3;
-    let hard_wired_four_screen_mode = contents[6] & 0x4 == 0x4;
-
-    let mut mapper = contents[6] >> 4;
-    mapper += contents[7] & 0xf0;
-
-    // TODO: convert prg_rom_pages/chr_rom_pages for-loops
-    let prg_rom_page_size = 16 * 1024;
-    let mut prg_rom_pages = Vec::new();
-    let mut current_start = 16;
-    for page in 0..prg_rom {
-        let mut current_page = Vec::new();
-        for offset in 0..prg_rom_page_size {
-            current_page.push(contents[current_start + page * prg_rom_page_size + offset]);
-        }
-        prg_rom_pages.push(current_page);
-    }
-
-    let chr_rom_page_size = 8 * 1024;
-    let mut chr_rom_pages = Vec::new();
-    current_start += prg_rom_page_size * prg_rom;
-    for page in 0..chr_rom {
-        let mut current_page = Vec::new();
-        for offset in 0..chr_rom_page_size {
-            current_page.push(contents[current_start + page * chr_rom_page_size + offset]);
-        }
-        chr_rom_pages.push(current_page);
-    }
-
-    Ok(Cart {
-        prg_rom,
-        chr_rom,
-        mirroring,
-        battery_present,
-        trainer_present,
-        hard_wired_four_screen_mode,
-        mapper,
-        prg_rom_pages,
-        chr_rom_pages,
-    })
+    Rom::from_file(filename)?.into_cart()
+}
+
+/// Load an iNES ROM image which is already in memory to Cart
+pub fn load_cart_from_bytes(contents: Vec<u8>) -> CartLoadResult<Cart> {
+    Rom::from_bytes(contents)?.into_cart()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::romdb::{sha1, RomInfo};
+
+    /// An iNES image of `prg_pages` and `chr_pages` of zeroes, with the given mapper
+    fn image(mapper: u8, prg_pages: u8, chr_pages: u8) -> Vec<u8> {
+        let mut image = vec![b'N', b'E', b'S', 0x1a, prg_pages, chr_pages, mapper << 4];
+        image.resize(
+            HEADER_SIZE
+                + prg_pages as usize * PRG_ROM_PAGE_SIZE
+                + chr_pages as usize * CHR_ROM_PAGE_SIZE,
+            0,
+        );
+        image
+    }
+
+    #[test]
+    fn short_files() {
+        assert!(matches!(
+            Rom::from_bytes(vec![]),
+            Err(CartLoadError::EmptyFile)
+        ));
+        // Too short for the magic number, or with the wrong one
+        for data in [&b"NE"[..], b"NES\x1b", b"hello, world!"] {
+            assert!(matches!(
+                Rom::from_bytes(data.to_vec()),
+                Err(CartLoadError::FileNotARom)
+            ));
+        }
+        assert!(matches!(
+            Rom::from_bytes(b"NES\x1a\x01".to_vec()),
+            Err(CartLoadError::TruncatedRom {
+                expected: 16,
+                actual: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_rom() {
+        let mut data = image(0, 2, 1);
+        data.truncate(data.len() - 1);
+        let err = load_cart_from_bytes(data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ROM file is truncated: expected 40976 bytes, but it is 40975"
+        );
+    }
+
+    #[test]
+    fn accessors() {
+        // Number every byte, so a read from the wrong page or offset shows
+        let mut data = image(0, 2, 1);
+        for (i, byte) in data[HEADER_SIZE..].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let cart = load_cart_from_bytes(data.clone()).unwrap();
+        let (prg, chr) = data[HEADER_SIZE..].split_at(2 * PRG_ROM_PAGE_SIZE);
+
+        assert_eq!(cart.prg_banks(), 2);
+        assert_eq!(cart.chr_banks(), 1);
+        for (bank, page) in prg.chunks(PRG_ROM_PAGE_SIZE).enumerate() {
+            for offset in [0, 1, 0x1234, PRG_ROM_PAGE_SIZE - 1] {
+                assert_eq!(cart.prg_byte(bank, offset), page[offset]);
+            }
+        }
+        for offset in [0, 1, 0x1234, CHR_ROM_PAGE_SIZE - 1] {
+            assert_eq!(cart.chr_byte(0, offset), chr[offset]);
+        }
+    }
+
+    #[test]
+    fn reads_match_pages() {
+        let mut data = image(1, 4, 2);
+        for (i, byte) in data[HEADER_SIZE..].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let cart = load_cart_from_bytes(data.clone()).unwrap();
+
+        // The ROM split into pages, as it used to be stored
+        let (prg, chr) = data[HEADER_SIZE..].split_at(4 * PRG_ROM_PAGE_SIZE);
+        let prg_pages: Vec<Vec<u8>> = prg.chunks(PRG_ROM_PAGE_SIZE).map(Vec::from).collect();
+        let chr_pages: Vec<Vec<u8>> = chr.chunks(CHR_ROM_PAGE_SIZE).map(Vec::from).collect();
+
+        assert_eq!(cart.prg_rom_len(), 4 * PRG_ROM_PAGE_SIZE);
+        for i in 0..cart.prg_rom_len() {
+            let expected = prg_pages[i / PRG_ROM_PAGE_SIZE][i % PRG_ROM_PAGE_SIZE];
+            assert_eq!(cart.prg_rom_byte(i), expected);
+        }
+        assert_eq!(cart.chr_rom_len(), 2 * CHR_ROM_PAGE_SIZE);
+        for i in 0..cart.chr_rom_len() {
+            let expected = chr_pages[i / CHR_ROM_PAGE_SIZE][i % CHR_ROM_PAGE_SIZE];
+            assert_eq!(cart.chr_rom_byte(i), expected);
+        }
+
+        // Reading past the end gives $FF or 0 rather than panicking
+        assert_eq!(cart.prg_byte(4, 0), 0xff);
+        assert_eq!(cart.chr_byte(2, 0), 0);
+    }
+
+    #[test]
+    fn region_from_header() {
+        let region = |nes2: bool, timing: u8| {
+            let mut data = image(0, 1, 1);
+            if nes2 {
+                data[7] = 0x08;
+            }
+            data[12] = timing;
+            Rom::from_bytes(data).unwrap().region()
+        };
+        assert_eq!(region(true, 0), Region::Ntsc);
+        assert_eq!(region(true, 1), Region::Pal);
+        assert_eq!(region(true, 2), Region::Ntsc);
+        assert_eq!(region(true, 3), Region::Dendy);
+        // iNES 1.0 headers don't have the timing byte
+        assert_eq!(region(false, 3), Region::Ntsc);
+    }
+
+    #[test]
+    fn mirroring_from_header() {
+        let mirroring = |mapper: u8, flags: u8| {
+            let mut data = image(mapper, 1, 1);
+            data[6] |= flags;
+            load_cart_from_bytes(data).unwrap().mirroring()
+        };
+        assert_eq!(mirroring(0, 0), Mirroring::Horizontal);
+        assert_eq!(mirroring(0, 0x01), Mirroring::Vertical);
+        // Mappers which switch it themselves ignore the header's bit
+        for mapper in [1, 7] {
+            assert_eq!(mirroring(mapper, 0), Mirroring::MapperControlled);
+            assert_eq!(mirroring(mapper, 0x01), Mirroring::MapperControlled);
+        }
+        // Except for four-screen VRAM on the cart
+        assert_eq!(mirroring(0, 0x08), Mirroring::FourScreen);
+        assert_eq!(mirroring(1, 0x08), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn database_overrides_header() {
+        // An NROM header on what is really MMC1 with CHR RAM, followed by junk
+        let mut data = image(0, 1, 1);
+        data[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(b"PRG!");
+        data.extend_from_slice(b"junk");
+        let prg_rom = &data[HEADER_SIZE..HEADER_SIZE + PRG_ROM_PAGE_SIZE];
+        let hash: String = sha1(prg_rom).iter().map(|b| format!("{:02x}", b)).collect();
+        let info = RomInfo {
+            mapper: 1,
+            submapper: 5,
+            prg_size: PRG_ROM_PAGE_SIZE as u32,
+            chr_size: 0,
+        };
+        let database = RomDatabase::new(Box::leak(Box::new([(&*hash.leak(), info)])));
+
+        let cart = Rom::from_bytes(data.clone())
+            .unwrap()
+            .into_cart_with(database)
+            .unwrap();
+        assert_eq!((cart.mapper_number(), cart.submapper_number()), (1, 5));
+        assert_eq!((cart.prg_rom_len(), cart.chr_rom_len()), (0x4000, 0));
+        assert_eq!(cart.mirroring(), Mirroring::MapperControlled);
+        assert_eq!(cart.prg_rom_byte(0), b'P');
+
+        // Only the PRG ROM is hashed, so other CHR ROM doesn't stop it matching
+        let mut other_chr = data.clone();
+        other_chr[HEADER_SIZE + PRG_ROM_PAGE_SIZE] = 0xff;
+        let cart = Rom::from_bytes(other_chr)
+            .unwrap()
+            .into_cart_with(database)
+            .unwrap();
+        assert_eq!(cart.mapper_number(), 1);
+
+        // But other PRG ROM does
+        data[HEADER_SIZE] = b'p';
+        let cart = Rom::from_bytes(data.clone())
+            .unwrap()
+            .into_cart_with(database)
+            .unwrap();
+        assert_eq!((cart.mapper_number(), cart.submapper_number()), (0, 0));
+        assert_eq!(cart.chr_rom_len(), 0x2000);
+        // The builtin database doesn't know it either
+        assert_eq!(load_cart_from_bytes(data).unwrap().mapper_number(), 0);
+    }
+
+    #[test]
+    fn zero_prg_pages() {
+        // There would be nothing for the mapper to read the reset vector from
+        for mapper in [0, 1, 7] {
+            assert!(matches!(
+                load_cart_from_bytes(image(mapper, 0, 1)),
+                Err(CartLoadError::FileNotARom)
+            ));
+        }
+    }
 }
@@ -0,0 +1,384 @@
+//! The config file, which keeps settings between runs so they needn't be passed on the command
+//! line every time. Flags on the command line take precedence over the file.
+//!
+//! The file is TOML, with a key for each setting named after its flag (e.g. `--run-ahead` is
+//! `run_ahead`). Every key is optional, and unknown keys are ignored with a warning so that a file
+//! written by a newer version still loads.
+
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::filter::FilterKind;
+use crate::speed::{MAX_SPEED, MIN_SPEED};
+use crate::window::{Overscan, WindowSize, MAX_SCALE};
+use crate::{AUDIO_BUFFER_SIZES, SAMPLE_RATES};
+
+/// Written to the default config path on the first run, as a list of the settings available.
+/// Everything is commented out, so it loads as `Config::default()`.
+pub const DEFAULT_CONFIG: &str = r#"# rusty-nes settings, used whenever the flag of the same name isn't given on the command line.
+# Uncomment a line to change a setting from its default.
+
+# Colours to display the picture with: a .pal file, or builtin:ntsc or builtin:pal
+# palette = "builtin:ntsc"
+
+# Post-processing filter: none, scanlines or ntsc
+# filter = "none"
+
+# How much the scanlines filter darkens alternate lines, from 0.0 to 1.0
+# scanline_intensity = 0.5
+
+# Size of the window, as a multiple of the NES's 256x240 picture
+# scale = 3
+
+# Size of the window, e.g. "1024x768", which takes precedence over scale
+# window_size = "768x720"
+
+# Cover the whole screen, instead of opening a window
+# fullscreen = false
+
+# Scanlines to crop from the top and bottom of the picture, as "LINES" or "TOP,BOTTOM"
+# overscan = "0"
+
+# Speed to run at, as a multiple of normal
+# speed = 1.0
+
+# Master volume, from 0 to 100
+# volume = 100
+
+# Whether to play sound
+# audio = true
+
+# Rate to play sound at, in Hz, e.g. 48000 to match the sound card
+# sample_rate = 44100
+
+# Size of the audio buffer in samples, a power of two. Smaller has less latency, but may crackle.
+# audio_buffer = 1024
+
+# Whether to draw only 8 sprites per scanline, as the console does, which makes sprites flicker
+# sprite_limit = true
+
+# Frames to run ahead to hide the game's input lag, or 0 to turn run-ahead off
+# run_ahead = 1
+
+# Directory to keep savestates and battery saves in, instead of next to the ROM
+# save_dir = "/home/me/nes-saves"
+"#;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    FileNotFound,
+    IoError(std::io::Error),
+    /// Not valid TOML, or a setting has the wrong type
+    Parse(toml::de::Error),
+    /// A setting is out of range
+    InvalidValue {
+        key: &'static str,
+        message: String,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::FileNotFound => write!(f, "config file not found"),
+            ConfigError::IoError(err) => write!(f, "IO error: {}", err),
+            ConfigError::Parse(err) => write!(f, "invalid config file: {}", err.message()),
+            ConfigError::InvalidValue { key, message } => {
+                write!(f, "invalid config file: {}: {}", key, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+/// Settings from the config file or the command line. `None` means the setting wasn't given,
+/// and so falls back to the next source (see `Config::or`), and then to the default.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub palette: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub filter: Option<FilterKind>,
+    pub scanline_intensity: Option<f32>,
+    pub scale: Option<u32>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub window_size: Option<WindowSize>,
+    pub fullscreen: Option<bool>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub overscan: Option<Overscan>,
+    pub speed: Option<f32>,
+    pub volume: Option<u8>,
+    pub audio: Option<bool>,
+    pub sample_rate: Option<u32>,
+    pub audio_buffer: Option<u16>,
+    pub sprite_limit: Option<bool>,
+    pub run_ahead: Option<usize>,
+    pub save_dir: Option<PathBuf>,
+
+    /// Keys this version doesn't know about
+    #[serde(flatten)]
+    unknown: toml::Table,
+}
+
+/// Deserialize a string setting with the same parsing as its flag
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> ConfigResult<Self> {
+        let config: Self = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> ConfigResult<Self> {
+        let text = std::fs::read_to_string(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => ConfigError::FileNotFound,
+            _ => ConfigError::IoError(err),
+        })?;
+        Self::from_toml(&text)
+    }
+
+    /// Load the config file at `path`, or if there isn't one, write `DEFAULT_CONFIG` there for
+    /// next time and use the defaults
+    pub fn load_or_create(path: &Path) -> ConfigResult<Self> {
+        match Self::from_file(path) {
+            Err(ConfigError::FileNotFound) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).map_err(ConfigError::IoError)?;
+                }
+                std::fs::write(path, DEFAULT_CONFIG).map_err(ConfigError::IoError)?;
+                Ok(Self::default())
+            }
+            result => result,
+        }
+    }
+
+    /// Check the settings which have a limited range, as their flags do
+    fn validate(&self) -> ConfigResult<()> {
+        let invalid = |key, message: String| Err(ConfigError::InvalidValue { key, message });
+        if let Some(intensity) = self.scanline_intensity {
+            if !(0.0..=1.0).contains(&intensity) {
+                return invalid("scanline_intensity", "must be between 0.0 and 1.0".into());
+            }
+        }
+        if let Some(scale) = self.scale {
+            if !(1..=MAX_SCALE).contains(&scale) {
+                return invalid("scale", format!("must be between 1 and {MAX_SCALE}"));
+            }
+        }
+        if let Some(speed) = self.speed {
+            if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+                return invalid(
+                    "speed",
+                    format!("must be between {MIN_SPEED} and {MAX_SPEED}"),
+                );
+            }
+        }
+        if let Some(volume) = self.volume {
+            if volume > 100 {
+                return invalid("volume", "must be between 0 and 100".into());
+            }
+        }
+        if let Some(rate) = self.sample_rate {
+            if !SAMPLE_RATES.contains(&rate) {
+                let (min, max) = (SAMPLE_RATES.start(), SAMPLE_RATES.end());
+                return invalid("sample_rate", format!("must be between {min} and {max} Hz"));
+            }
+        }
+        if let Some(samples) = self.audio_buffer {
+            if let Err(message) = check_audio_buffer(samples) {
+                return invalid("audio_buffer", message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys in the file which aren't settings, e.g. from a typo
+    pub fn unknown_keys(&self) -> impl Iterator<Item = &str> {
+        self.unknown.keys().map(String::as_str)
+    }
+
+    /// Combine with settings from a lower precedence source, which fill in any settings not
+    /// given here
+    pub fn or(self, fallback: Config) -> Config {
+        // The window size and scale are alternatives, so whichever the higher precedence source
+        // gives wins, even over the other one from the fallback
+        let (scale, window_size) = if self.scale.is_some() || self.window_size.is_some() {
+            (self.scale, self.window_size)
+        } else {
+            (fallback.scale, fallback.window_size)
+        };
+        Config {
+            palette: self.palette.or(fallback.palette),
+            filter: self.filter.or(fallback.filter),
+            scanline_intensity: self.scanline_intensity.or(fallback.scanline_intensity),
+            scale,
+            window_size,
+            fullscreen: self.fullscreen.or(fallback.fullscreen),
+            overscan: self.overscan.or(fallback.overscan),
+            speed: self.speed.or(fallback.speed),
+            volume: self.volume.or(fallback.volume),
+            audio: self.audio.or(fallback.audio),
+            sample_rate: self.sample_rate.or(fallback.sample_rate),
+            audio_buffer: self.audio_buffer.or(fallback.audio_buffer),
+            sprite_limit: self.sprite_limit.or(fallback.sprite_limit),
+            run_ahead: self.run_ahead.or(fallback.run_ahead),
+            save_dir: self.save_dir.or(fallback.save_dir),
+            unknown: toml::Table::new(),
+        }
+    }
+
+    /// Initial size of the window, from `window_size` or else `scale`
+    pub fn window_size(&self) -> WindowSize {
+        self.window_size
+            .or(self.scale.map(WindowSize::scaled))
+            .unwrap_or_default()
+    }
+}
+
+/// Where the config file is kept when `--config` isn't given: `rusty-nes/config.toml` in the
+/// platform's config directory, if it can be found from the environment
+pub fn default_config_path() -> Option<PathBuf> {
+    let env_dir = |var| {
+        std::env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    };
+    let config_dir = if cfg!(windows) {
+        env_dir("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        env_dir("XDG_CONFIG_HOME").or_else(|| env_dir("HOME").map(|home| home.join(".config")))
+    }?;
+    Some(config_dir.join("rusty-nes").join("config.toml"))
+}
+
+/// Check an audio buffer size is one SDL accepts, as `--audio-buffer` does too
+pub fn check_audio_buffer(samples: u16) -> Result<u16, String> {
+    if AUDIO_BUFFER_SIZES.contains(&samples) && samples.is_power_of_two() {
+        Ok(samples)
+    } else {
+        Err(format!(
+            "must be a power of two between {} and {} samples",
+            AUDIO_BUFFER_SIZES.start(),
+            AUDIO_BUFFER_SIZES.end()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_or_create_writes_default() {
+        let dir = std::env::temp_dir().join(format!("rusty_nes_config_{}", std::process::id()));
+        let path = dir.join("rusty-nes").join("config.toml");
+        assert_eq!(Config::load_or_create(&path).unwrap(), Config::default());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), DEFAULT_CONFIG);
+
+        // Then it's there to load next time, with everything still commented out
+        let loaded = Config::load_or_create(&path);
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(loaded.unwrap(), Config::default());
+    }
+
+    #[test]
+    fn partial_file() {
+        let config =
+            Config::from_toml("filter = \"scanlines\"\nwindow_size = \"1024x768\"\nvolume = 50\n")
+                .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                filter: Some(FilterKind::Scanlines),
+                window_size: Some("1024x768".parse().unwrap()),
+                volume: Some(50),
+                ..Config::default()
+            }
+        );
+        assert_eq!(config.unknown_keys().count(), 0);
+
+        assert!(matches!(
+            Config::from_toml("volume = \"loud\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::from_toml("volume = 101"),
+            Err(ConfigError::InvalidValue { key: "volume", .. })
+        ));
+        assert!(matches!(
+            Config::from_toml("filter = \"sepia\""),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_kept_to_warn_about() {
+        let config =
+            Config::from_toml("scale = 2\nshader = \"crt\"\n[input]\nturbo = true\n").unwrap();
+        assert_eq!(config.scale, Some(2));
+        let mut unknown: Vec<&str> = config.unknown_keys().collect();
+        unknown.sort();
+        assert_eq!(unknown, vec!["input", "shader"]);
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let file = Config::from_toml("speed = 2.0\nvolume = 50\naudio = false\n").unwrap();
+        let flags = Config {
+            volume: Some(80),
+            ..Config::default()
+        };
+        let config = flags.or(file);
+        assert_eq!(config.volume, Some(80));
+        assert_eq!(config.speed, Some(2.0));
+        assert_eq!(config.audio, Some(false));
+        assert_eq!(config.fullscreen, None);
+    }
+
+    #[test]
+    fn scale_and_window_size_are_alternatives() {
+        let file = Config::from_toml("window_size = \"1024x768\"").unwrap();
+        let flags = Config {
+            scale: Some(2),
+            ..Config::default()
+        };
+
+        // A scale on the command line beats a window size from the file
+        let config = flags.clone().or(file.clone());
+        assert_eq!(config.window_size, None);
+        assert_eq!(config.window_size(), WindowSize::scaled(2));
+
+        // Either from the file is used when the command line gives neither
+        let config = Config::default().or(file);
+        assert_eq!(config.window_size(), "1024x768".parse().unwrap());
+        let config = Config::default().or(flags);
+        assert_eq!(config.window_size(), WindowSize::scaled(2));
+
+        // And a window size beats a scale from the same source
+        let both = Config {
+            scale: Some(2),
+            window_size: Some("1024x768".parse().unwrap()),
+            ..Config::default()
+        };
+        assert_eq!(both.window_size(), "1024x768".parse().unwrap());
+        assert_eq!(Config::default().window_size(), WindowSize::default());
+    }
+}
@@ -0,0 +1,267 @@
+//! Devices connected to the controller ports, read through `$4016` and `$4017`.
+//!
+//! Every device sees the same writes to `$4016` (the strobe/output latch), and is read through
+//! the register for its own port.
+//!
+//! See: <https://www.nesdev.org/wiki/Input_devices>
+
+use std::fmt::Debug;
+
+use crate::frontend::InputState;
+
+pub trait InputDevice: Debug {
+    /// Pick this device's state out of the input polled by the frontend
+    fn set_input(&mut self, input: &InputState);
+
+    /// Set the buttons held directly (see `buttons`), for devices which have them
+    fn set_buttons(&mut self, _buttons: u8) {}
+
+    /// The buttons held, for devices which have them, e.g. to record them in a movie
+    fn buttons(&self) -> u8 {
+        0
+    }
+
+    /// A write to `$4016`
+    fn write(&mut self, value: u8);
+
+    /// A read of this device's port. Only the low 5 bits are driven by the device.
+    fn read(&mut self) -> u8;
+
+    /// What `read` would return, without advancing the device
+    fn peek(&self) -> u8;
+
+    /// Copy the device, for savestates
+    fn box_clone(&self) -> Box<dyn InputDevice>;
+}
+
+impl Clone for Box<dyn InputDevice> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// The standard NES controller: an 8-bit shift register loaded from the buttons while the
+/// strobe is high
+///
+/// See: <https://www.nesdev.org/wiki/Standard_controller>
+#[derive(Debug, Clone)]
+pub struct StandardController {
+    player: usize,
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl StandardController {
+    pub fn new(player: usize) -> Self {
+        Self {
+            player,
+            buttons: 0,
+            shift: 0,
+            strobe: false,
+        }
+    }
+}
+
+impl InputDevice for StandardController {
+    fn set_input(&mut self, input: &InputState) {
+        self.set_buttons(input.buttons[self.player]);
+    }
+
+    fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        // While the strobe is high the shift register keeps reloading
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 0x1 == 0x1;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.peek();
+        }
+        // After all 8 buttons have been read, official controllers return 1
+        let bit = self.peek();
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x1
+        } else {
+            self.shift & 0x1
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+}
+
+/// Names of the keys of the Family BASIC keyboard, indexed by `[row][column * 4 + bit]`,
+/// where `bit` 0 is reported in bit 1 of `$4017`
+///
+/// See: <https://www.nesdev.org/wiki/Family_BASIC_Keyboard>
+#[rustfmt::skip]
+const KEY_MATRIX: [[&str; 8]; 9] = [
+    ["]", "[", "RETURN", "F8", "STOP", "YEN", "RSHIFT", "KANA"],
+    [";", ":", "@", "F7", "^", "-", "/", "_"],
+    ["K", "L", "O", "F6", "0", "P", ",", "."],
+    ["J", "U", "I", "F5", "8", "9", "N", "M"],
+    ["H", "G", "Y", "F4", "6", "7", "V", "B"],
+    ["D", "R", "T", "F3", "4", "5", "C", "F"],
+    ["A", "S", "W", "F2", "3", "E", "Z", "X"],
+    ["CTR", "Q", "ESC", "F1", "2", "1", "GRPH", "LSHIFT"],
+    ["LEFT", "RIGHT", "UP", "CLR HOME", "INS", "DEL", "SPACE", "DOWN"],
+];
+
+pub const FAMICOM_KEY_COUNT: usize = 72;
+
+/// Index of a Family BASIC key by its name in `KEY_MATRIX`, for use as a bit of
+/// `InputState::keyboard`
+pub fn famicom_key_index(name: &str) -> Option<usize> {
+    KEY_MATRIX.iter().flatten().position(|&key| key == name)
+}
+
+/// The Family BASIC keyboard, which plugs into the Famicom expansion port and is read through
+/// `$4017`.
+///
+/// Writes to `$4016` drive the matrix scan: bit 0 resets to row 0, bit 1 selects the column
+/// (moving from column 1 back to column 0 advances to the next row), and bit 2 enables the
+/// keyboard. Reads return the 4 keys of the selected row and column in bits 1-4, with a 0 bit
+/// meaning pressed.
+#[derive(Debug, Clone)]
+pub struct FamiBasicKeyboard {
+    keys: [bool; FAMICOM_KEY_COUNT],
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl FamiBasicKeyboard {
+    pub fn new() -> Self {
+        Self {
+            keys: [false; FAMICOM_KEY_COUNT],
+            row: 0,
+            column: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl Default for FamiBasicKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputDevice for FamiBasicKeyboard {
+    fn set_input(&mut self, input: &InputState) {
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            *key = input.keyboard & (1 << index) != 0;
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0x4 == 0x4;
+
+        let column = ((value >> 1) & 0x1) as usize;
+        if value & 0x1 == 0x1 {
+            self.row = 0;
+            self.column = 0;
+            return;
+        }
+        if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+    }
+
+    fn read(&mut self) -> u8 {
+        self.peek()
+    }
+
+    fn peek(&self) -> u8 {
+        // Past the last row the keyboard returns all zeroes, which software uses to detect it
+        if !self.enabled || self.row >= KEY_MATRIX.len() {
+            return 0;
+        }
+
+        let first_key = self.row * 8 + self.column * 4;
+        let mut data = 0x1e;
+        for bit in 0..4 {
+            if self.keys[first_key + bit] {
+                data &= !(1 << (bit + 1));
+            }
+        }
+        data
+    }
+
+    fn box_clone(&self) -> Box<dyn InputDevice> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::buttons;
+    use crate::testing;
+
+    #[test]
+    fn standard_controller_bits() {
+        let mut controller = StandardController::new(0);
+        controller.set_buttons(buttons::A | buttons::START | buttons::RIGHT);
+        controller.write(1);
+        controller.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        // A, B, Select, Start, Up, Down, Left, Right, then 1s
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn standard_controller_strobe() {
+        let mut controller = StandardController::new(0);
+        controller.write(1);
+        // While the strobe is high, reads keep returning A, which follows the buttons
+        controller.set_buttons(buttons::A);
+        assert_eq!([controller.read(), controller.read()], [1, 1]);
+        controller.set_buttons(buttons::B);
+        assert_eq!(controller.read(), 0);
+        controller.write(0);
+        assert_eq!([controller.read(), controller.read()], [0, 1]);
+    }
+
+    #[test]
+    fn set_player_input() {
+        let mut cpu = testing::cpu(
+            "lda #$01
+             sta $4016
+             lda #$00
+             sta $4016
+             ldx #$00
+             loop: lda $4016
+             and #$01
+             sta $10,x
+             inx
+             cpx #$08
+             bne loop",
+        );
+        cpu.set_player_input(0, buttons::B | buttons::UP | buttons::LEFT);
+        testing::run(&mut cpu, 5 + 6 * 8);
+        let bits: Vec<u8> = (0x10..0x18).map(|address| cpu.peek_byte(address)).collect();
+        assert_eq!(bits, [0, 1, 0, 0, 1, 0, 1, 0]);
+    }
+}
@@ -1,12 +1,458 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::cart::{self, Cart, CartLoadResult, Mirroring};
+use crate::controller::InputDevice;
+use crate::filter::Frame;
+use crate::frontend::InputState;
+use crate::movie::{Movie, MovieResult};
+use crate::palette::Palette;
+use crate::region::Region;
+use crate::state::{StateField, StateReader, StateResult, StateWriter};
+use crate::system::{Quirks, System};
+use crate::wav::WavWriter;
+
+#[derive(Debug)]
+pub enum CpuError {
+    UnknownOpcode {
+        opcode: u8,
+        pc: u16,
+    },
+    /// The CPU is stuck, as found by the `JamPolicy` check
+    Jammed {
+        kind: JamKind,
+        pc: u16,
+    },
+    /// A `BRK` reached with `CPU::set_stop_on_brk` on
+    Break {
+        pc: u16,
+    },
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { opcode, pc } => {
+                write!(f, "unknown opcode {:02x} at ${:04x}", opcode, pc)
+            }
+            CpuError::Jammed {
+                kind: JamKind::SelfLoop,
+                pc,
+            } => write!(
+                f,
+                "CPU jammed at ${:04x}: looping on one instruction with no interrupt enabled to \
+                 leave it",
+                pc
+            ),
+            CpuError::Jammed {
+                kind: JamKind::OpenBus,
+                pc,
+            } => write!(f, "CPU jammed at ${:04x}: running from open bus", pc),
+            CpuError::Break { pc } => write!(f, "BRK at ${:04x}", pc),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+pub type CpuResult<T> = Result<T, CpuError>;
+
+/// Description of a single executed instruction, along with the state of the CPU and PPU
+/// before it ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeTrace {
+    pub pc: u16,
+    pub opcode: u8,
+    /// The two bytes following the opcode, whether or not the instruction uses them
+    pub operand_bytes: [u8; 2],
+    pub mnemonic: String,
+    /// The operand as written in assembly (see `asm::disassemble`), e.g. `#$05`, `($10),y` or
+    /// `a`
+    pub operand: String,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub status: u8,
+    pub cycles: u64,
+    /// The pre-render scanline is -1
+    pub ppu_scanline: i16,
+    pub ppu_dot: u16,
+    /// Frames the PPU had completed
+    pub ppu_frame: u64,
+}
+
+/// Which columns a nestest-style trace has after the disassembly. The default is the same as
+/// nestest's golden log; turning columns off keeps long traces smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceColumns {
+    /// `A:00 X:00 Y:00 P:24 SP:FD`
+    pub registers: bool,
+    /// `PPU:  0, 21`, the scanline and dot
+    pub ppu: bool,
+    /// `FRAME:0`
+    pub frame: bool,
+    /// `CYC:7`
+    pub cycles: bool,
+}
+
+impl Default for TraceColumns {
+    fn default() -> Self {
+        Self {
+            registers: true,
+            ppu: true,
+            frame: false,
+            cycles: true,
+        }
+    }
+}
+
+impl FromStr for TraceColumns {
+    type Err = String;
+
+    /// A comma separated list of the columns to include, e.g. `registers,ppu,frame`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns = Self {
+            registers: false,
+            ppu: false,
+            frame: false,
+            cycles: false,
+        };
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "registers" => columns.registers = true,
+                "ppu" => columns.ppu = true,
+                "frame" => columns.frame = true,
+                "cycles" => columns.cycles = true,
+                _ => {
+                    return Err(format!(
+                        "unknown column '{name}' (expected some of: registers, ppu, frame, cycles)"
+                    ))
+                }
+            }
+        }
+        Ok(columns)
+    }
+}
+
+impl OpcodeTrace {
+    /// Format the trace like the nestest golden log, e.g.
+    /// `C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+    ///
+    /// See: <https://www.qmtpro.com/~nes/misc/nestest.log>
+    pub fn nestest_line(&self) -> String {
+        self.nestest_line_with(TraceColumns::default())
+    }
+
+    /// Format the trace like `nestest_line`, with only the given columns after the disassembly
+    pub fn nestest_line_with(&self, columns: TraceColumns) -> String {
+        let length = CPU::instruction_length(self.opcode) as usize;
+        let instruction_bytes = std::iter::once(self.opcode)
+            .chain(self.operand_bytes)
+            .take(length)
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let disassembly = format!("{} {}", self.mnemonic, self.operand).to_uppercase();
+        let mut line = format!(
+            "{:04X}  {:<8}  {:<32}",
+            self.pc,
+            instruction_bytes,
+            disassembly.trim_end()
+        );
+        if columns.registers {
+            line += &format!(
+                "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ",
+                self.a, self.x, self.y, self.status, self.s
+            );
+        }
+        if columns.ppu {
+            line += &format!("PPU:{:>3},{:>3} ", self.ppu_scanline, self.ppu_dot);
+        }
+        if columns.frame {
+            line += &format!("FRAME:{} ", self.ppu_frame);
+        }
+        if columns.cycles {
+            line += &format!("CYC:{}", self.cycles);
+        }
+        line.truncate(line.trim_end().len());
+        line
+    }
+}
+
+impl Display for OpcodeTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let length = CPU::instruction_length(self.opcode) as usize;
+        let instruction_bytes = std::iter::once(self.opcode)
+            .chain(self.operand_bytes)
+            .take(length)
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .filter(|&(bit, _)| bit != 2 && bit != 3)
+            .map(|(bit, name)| {
+                if self.status & (0x80 >> bit) != 0 {
+                    name
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        write!(
+            f,
+            "{:04x}  {:<8}    a: {:02x} x: {:02x} y: {:02x} s: {:02x}    {}    {}",
+            self.pc, instruction_bytes, self.a, self.x, self.y, self.s, flags, self.mnemonic
+        )?;
+        if !self.operand.is_empty() {
+            write!(f, " {}", self.operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// The registers and flags, e.g. for the debugger
+impl Display for CPU {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(bit, name)| {
+                if self.status() & (0x80 >> bit) != 0 {
+                    name
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        write!(
+            f,
+            "pc: {:04x}  a: {:02x} x: {:02x} y: {:02x} s: {:02x}  {}  cycles: {}",
+            self.pc,
+            self.a,
+            self.x,
+            self.y,
+            self.s,
+            flags,
+            self.cycles()
+        )
+    }
+}
+
+/// What to do when the CPU fetches an opcode that isn't implemented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownOpcodePolicy {
+    /// Panic, which is the most useful behaviour while developing the CPU
+    Panic,
+    /// Return a `CpuError::UnknownOpcode` from `run_opcode`
+    Error,
+    /// Log a warning and skip over the instruction as if it were a NOP of the same length
+    Nop,
+}
+
+impl FromStr for UnknownOpcodePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "panic" => Ok(UnknownOpcodePolicy::Panic),
+            "error" => Ok(UnknownOpcodePolicy::Error),
+            "nop" => Ok(UnknownOpcodePolicy::Nop),
+            _ => Err(format!(
+                "unknown policy '{s}' (expected one of: panic, error, nop)"
+            )),
+        }
+    }
+}
+
+/// Frames the CPU must spend looping on one instruction, which no interrupt can break it out of,
+/// before `JamPolicy` reports it
+const JAM_FRAMES: u64 = 60;
+
+/// What to do when the CPU gets stuck (see `JamKind`), which usually means a game has crashed
+/// or been emulated wrongly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JamPolicy {
+    /// Don't check
+    Off,
+    /// Log a warning each time it happens, and carry on
+    Warn,
+    /// Return a `CpuError::Jammed` from `run_opcode`
+    Error,
+}
+
+impl FromStr for JamPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(JamPolicy::Off),
+            "warn" => Ok(JamPolicy::Warn),
+            "error" => Ok(JamPolicy::Error),
+            _ => Err(format!(
+                "unknown policy '{s}' (expected one of: off, warn, error)"
+            )),
+        }
+    }
+}
+
+/// How the CPU can get stuck
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JamKind {
+    /// Jumping or branching to the same instruction for `JAM_FRAMES` frames, with neither NMIs
+    /// nor IRQs enabled to break out of it. Waiting for an NMI this way is fine.
+    SelfLoop,
+    /// The PC has run into memory with nothing there to run, e.g. the PPU registers
+    OpenBus,
+}
+
+/// Ways the CPU can be interrupted, including by the program itself with `BRK`
+///
+/// See: <https://www.nesdev.org/wiki/CPU_interrupts>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+impl Display for Interrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Interrupt::Nmi => "NMI",
+            Interrupt::Irq => "IRQ",
+            Interrupt::Brk => "BRK",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Interrupt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nmi" => Ok(Interrupt::Nmi),
+            "irq" => Ok(Interrupt::Irq),
+            "brk" => Ok(Interrupt::Brk),
+            _ => Err(format!(
+                "unknown interrupt '{s}' (expected one of: nmi, irq, brk)"
+            )),
+        }
+    }
+}
+
+/// The stack pointer wrapping around page one, which usually means the stack has overflowed
+/// into (or underflowed from) whatever else the game keeps there. See `CPU::set_stack_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackWrap {
+    /// The instruction which pushed or pulled, or which was interrupted
+    pub pc: u16,
+    /// Whether S wrapped from $00 to $FF on a push, rather than from $FF to $00 on a pull
+    pub overflow: bool,
+    /// Pushes minus pulls since the check was enabled or the game last set S with `TXS`
+    pub depth: i32,
+}
+
+impl Display for StackWrap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, from, to) = if self.overflow {
+            ("overflow", 0x00, 0xff)
+        } else {
+            ("underflow", 0xff, 0x00)
+        };
+        write!(
+            f,
+            "stack {} at ${:04x}: S wrapped from ${:02x} to ${:02x} (depth {})",
+            kind, self.pc, from, to, self.depth
+        )
+    }
+}
+
+/// A read of RAM which hadn't been written since power on, found by `CPU::set_uninit_check`.
+/// Games which do this can behave differently on each console (and emulator).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UninitRead {
+    pub address: u16,
+    /// The instruction which read it, or which was running when DMA did
+    pub pc: u16,
+}
+
+impl Display for UninitRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "read of uninitialized ${:04x} at ${:04x}",
+            self.address, self.pc
+        )
+    }
+}
+
+/// Where `CPU::set_trace_writer` sends the trace. Copies of the CPU (e.g. savestates) share
+/// the same writer.
+#[derive(Clone)]
+struct TraceSink {
+    writer: Rc<RefCell<dyn Write>>,
 
-use crate::cart::CartLoadResult;
-use crate::system::System;
+    /// Instructions left to trace before stopping, if limited
+    remaining: Option<u64>,
+
+    columns: TraceColumns,
+}
+
+impl TraceSink {
+    /// Write a line of the trace, returning whether to keep tracing
+    fn write(&mut self, trace: &OpcodeTrace) -> bool {
+        let mut writer = self.writer.borrow_mut();
+        if let Err(err) = writeln!(writer, "{}", trace.nestest_line_with(self.columns)) {
+            eprintln!("Stopped tracing: {}", err);
+            return false;
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                let _ = writer.flush();
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Whether `CPU::run_frame` is recording a movie or playing one back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MovieMode {
+    Recording,
+    Playing,
+}
+
+/// Number of instructions remembered for `CPU::recent_pcs`
+const RECENT_PC_COUNT: usize = 16;
+
+/// Number of instructions `CPU::step_back` can undo
+const INSTRUCTION_HISTORY_LEN: usize = 100;
+
+/// Frames `step_over` runs a subroutine for before giving up on it returning
+const STEP_OVER_FRAME_LIMIT: u64 = 60;
+
+/// A snapshot of the whole machine, taken by `CPU::save_state`
+#[derive(Clone)]
+pub struct SaveState(Box<CPU>);
 
 /// The 2A03 NES CPU core, which is based on the 6502 processor
 ///
 /// See: <https://www.nesdev.org/wiki/CPU_registers>
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct CPU {
     /// Accumulator
     a: u8,
@@ -28,7 +474,6 @@ pub struct CPU {
     zero: bool,
     interrupt_disable: bool,
     decimal: bool,
-    break_flag: bool,
     overflow: bool,
     negative: bool,
 
@@ -38,20 +483,109 @@ pub struct CPU {
     /// Clock
     clock: u64,
 
-    /// Helper for storing debug state
-    debug_state: String,
-    debug_enabled: bool,
+    /// Where `cycles` counts from, moved by `reset_cycles` so that `clock` keeps the parity
+    /// OAM DMA depends on
+    cycles_start: u64,
+
+    /// Instructions run since power up
+    instructions: u64,
+
+    /// Cycles left that the CPU is halted for while OAM DMA copies `oam_dma_page` to OAM
+    ///
+    /// See: <https://www.nesdev.org/wiki/DMA#OAM_DMA>
+    oam_dma_stall: u16,
+    oam_dma_page: u8,
+
+    /// What the last instruction did besides running, for the debugger to break on
+    last_interrupt: Option<Interrupt>,
+    last_ppu_write: Option<u16>,
+    stack_wrap: Option<StackWrap>,
+
+    /// Whether to watch for the stack wrapping around, and pushes minus pulls since the check's
+    /// baseline. Neither is saved in savestates.
+    stack_check: bool,
+    stack_depth: i32,
+
+    /// Reads found by the uninitialized RAM check, oldest first
+    uninit_reads: Vec<UninitRead>,
+
+    /// Where to write a trace of every instruction in the debugger's format, if anywhere.
+    /// Shared by copies of the CPU, like `trace_sink`.
+    debug_writer: Option<Rc<RefCell<Box<dyn Write + Send>>>>,
+
+    /// Helpers for building an `OpcodeTrace`
+    tracing: bool,
+    disassembly: String,
+
+    unknown_opcode_policy: UnknownOpcodePolicy,
+    stop_on_brk: bool,
+
+    jam_policy: JamPolicy,
+    /// Frame when the CPU started looping on one instruction, if it is
+    jam_loop_start: Option<u64>,
+    /// Whether the current jam has been warned about already
+    jam_reported: bool,
+
+    trace_sink: Option<TraceSink>,
+
+    /// Where `take_audio_samples` also writes the samples, if recording. Shared by copies of
+    /// the CPU, like `trace_sink`.
+    audio_dump: Option<Rc<RefCell<WavWriter<BufWriter<File>>>>>,
+
+    /// Movie being recorded or played back by `run_frame`. Shared by copies of the CPU, like
+    /// `audio_dump`.
+    movie: Option<(MovieMode, Rc<RefCell<Movie>>)>,
+
+    /// Snapshots from before each of the last few instructions, oldest first, when stepping
+    /// back is enabled. Shared by copies of the CPU, so the snapshots don't contain copies of
+    /// each other.
+    instruction_history: Option<Rc<RefCell<VecDeque<SaveState>>>>,
+
+    /// Number of times each opcode has been executed, when profiling is enabled
+    opcode_counts: Option<Box<[u64; 256]>>,
+
+    /// CRC-32 of each frame completed by `run_frame`, when logging is enabled
+    frame_hashes: Option<Vec<u32>>,
+
+    /// Whether input only comes from the movie (see `set_deterministic`)
+    deterministic: bool,
+
+    /// Addresses of the last few instructions, oldest first from `recent_pcs_next`
+    recent_pcs: [u16; RECENT_PC_COUNT],
+    recent_pcs_next: usize,
 }
 
 impl CPU {
-    /// Create a new CPU, in the power up state
+    /// Create a new CPU, in the power up state. If `debug_enabled`, a trace of every
+    /// instruction is printed to stdout (see `set_debug_writer`).
     ///
     /// See: <https://www.nesdev.org/wiki/CPU_power_up_state>
     pub fn new(filename: String, debug_enabled: bool) -> CartLoadResult<Self> {
-        let system = System::new(filename)?;
+        Ok(Self::from_system(System::new(filename)?, debug_enabled))
+    }
+
+    /// Create a new CPU from a ROM image which is already in memory, without touching the
+    /// filesystem
+    pub fn from_bytes(rom: Vec<u8>, debug_enabled: bool) -> CartLoadResult<Self> {
+        let cart = cart::load_cart_from_bytes(rom)?;
+        Ok(Self::from_system(System::from_cart(cart), debug_enabled))
+    }
+
+    /// Create a new CPU with no ROM (see `System::new_empty`), e.g. to run instructions poked
+    /// into RAM
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_empty() -> Self {
+        Self::from_system(System::new_empty(), false)
+    }
+
+    fn from_system(mut system: System, debug_enabled: bool) -> Self {
         let reset_vector = system.read_word(0xfffc);
+        let debug_writer = debug_enabled.then(|| {
+            let stdout: Box<dyn Write + Send> = Box::new(io::stdout());
+            Rc::new(RefCell::new(stdout))
+        });
 
-        Ok(Self {
+        Self {
             a: 0,
             x: 0,
             y: 0,
@@ -61,249 +595,1129 @@ impl CPU {
             zero: false,
             interrupt_disable: true,
             decimal: false,
-            break_flag: false,
             overflow: false,
             negative: false,
             system,
             clock: 0,
-            debug_state: "".to_string(), // this should always be updated before debugging anyway
-            debug_enabled,
-        })
+            cycles_start: 0,
+            instructions: 0,
+            oam_dma_stall: 0,
+            oam_dma_page: 0,
+            last_interrupt: None,
+            last_ppu_write: None,
+            stack_wrap: None,
+            stack_check: false,
+            stack_depth: 0,
+            uninit_reads: Vec::new(),
+            debug_writer,
+            tracing: false,
+            disassembly: String::new(),
+            unknown_opcode_policy: UnknownOpcodePolicy::Panic,
+            stop_on_brk: false,
+            jam_policy: JamPolicy::Off,
+            jam_loop_start: None,
+            jam_reported: false,
+            trace_sink: None,
+            audio_dump: None,
+            movie: None,
+            instruction_history: None,
+            opcode_counts: None,
+            frame_hashes: None,
+            deterministic: false,
+            recent_pcs: [reset_vector; RECENT_PC_COUNT],
+            recent_pcs_next: 0,
+        }
     }
 
-    fn save_debug_state(&mut self) {
-        if !self.debug_enabled {
-            return;
-        }
+    /// Turn the console off and on again, with `cart` inserted (or the current cart, if `None`).
+    /// Settings like the unknown opcode policy and volume are kept.
+    pub fn power_cycle(&mut self, cart: Option<Cart>) {
+        let system = self.system.power_cycled(cart);
+        let mut cpu = Self::from_system(system, false);
+        cpu.debug_writer = self.debug_writer.take();
+        cpu.unknown_opcode_policy = self.unknown_opcode_policy;
+        cpu.stop_on_brk = self.stop_on_brk;
+        cpu.jam_policy = self.jam_policy;
+        cpu.stack_check = self.stack_check;
+        cpu.trace_sink = self.trace_sink.take();
+        cpu.audio_dump = self.audio_dump.take();
+        cpu.movie = self.movie.take();
+        cpu.set_step_back(self.instruction_history.is_some());
+        cpu.opcode_counts = self.opcode_counts.take();
+        cpu.frame_hashes = self.frame_hashes.take();
+        cpu.deterministic = self.deterministic;
+        *self = cpu;
+    }
 
-        let counters = format!(
-            "{:04x}    a: {:02x} x: {:02x} y: {:02x} s: {:02x}",
-            self.pc, self.a, self.x, self.y, self.s
-        );
-        let flags = format!(
-            "{}{}{}{}{}{}",
-            if self.negative { "N" } else { "-" },
-            if self.overflow { "V" } else { "-" },
-            if self.decimal { "D" } else { "-" },
-            if self.interrupt_disable { "I" } else { "-" },
-            if self.zero { "Z" } else { "-" },
-            if self.carry { "C" } else { "-" }
-        );
-        self.debug_state = format!("{counters}    {flags}");
+    /// The console being emulated, which decides the timing
+    pub fn region(&self) -> Region {
+        self.system.region()
     }
 
+    /// How the nametables are mirrored now (see `System::mirroring`)
+    pub fn mirroring(&self) -> Mirroring {
+        self.system.mirroring()
+    }
+
+    /// Emulate a console from `region` whatever the ROM's header says, e.g. for an iNES ROM
+    /// made for a PAL console. The console is turned off and on again (see `power_cycle`).
+    pub fn set_region(&mut self, region: Region) {
+        let mut cart = self.cart().clone();
+        cart.set_region(region);
+        self.power_cycle(Some(cart));
+    }
+
+    /// The 2 KiB of RAM inside the console, at `$0000-$07FF`
+    pub fn ram(&self) -> &[u8] {
+        self.system.ram()
+    }
+
+    /// The cart's PRG RAM, if it has a battery to keep it, which is what a `.sav` file holds
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.system.battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.system.load_battery_ram(data)
+    }
+
+    /// The cart currently inserted
+    pub fn cart(&self) -> &Cart {
+        self.system.cart()
+    }
+
+    /// The name of the board the cart's mapper emulates, e.g. `MMC1`
+    pub fn mapper_name(&self) -> &'static str {
+        self.system.mapper_name()
+    }
+
+    /// Take a snapshot of the CPU and everything attached to it. The ROM is shared rather than
+    /// copied, so this is cheap enough to do every frame.
+    pub fn save_state(&self) -> SaveState {
+        SaveState(Box::new(self.clone()))
+    }
+
+    /// Rewind to a snapshot taken by `save_state`
+    pub fn load_state(&mut self, state: &SaveState) {
+        *self = (*state.0).clone();
+    }
+
+    /// Serialise the machine for a savestate file, which `load_state_bytes` can restore in a
+    /// later session with the same ROM
+    pub fn save_state_bytes(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.cart().crc32());
+        self.write_state(&mut state);
+        state.finish()
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.section("cpu", |state| {
+            state.u8("a", self.a);
+            state.u8("x", self.x);
+            state.u8("y", self.y);
+            state.u16("pc", self.pc);
+            state.u8("s", self.s);
+            state.u8("status", self.status());
+            state.u64("clock", self.clock);
+            state.u16("oam_dma_stall", self.oam_dma_stall);
+            state.u8("oam_dma_page", self.oam_dma_page);
+        });
+        self.system.write_state(state);
+    }
+
+    /// Every field `save_state_bytes` would save, by name, e.g. to see where two runs diverged
+    /// with `diff_states`. Written by the same code, so it always matches the file format.
+    pub fn state_fields(&self) -> Vec<StateField> {
+        let mut state = StateWriter::new(self.cart().crc32()).with_fields();
+        self.write_state(&mut state);
+        state.finish_fields()
+    }
+
+    /// The fields of a savestate file for this ROM (see `state_fields`), without changing this
+    /// machine
+    pub fn state_file_fields(&self, bytes: &[u8]) -> StateResult<Vec<StateField>> {
+        let mut cpu = self.clone();
+        cpu.load_state_bytes(bytes)?;
+        Ok(cpu.state_fields())
+    }
+
+    /// Restore a savestate file written by `save_state_bytes`. If it can't be loaded, e.g.
+    /// because it was saved with a different ROM, the machine is left as it was.
+    pub fn load_state_bytes(&mut self, bytes: &[u8]) -> StateResult<()> {
+        let mut state = StateReader::new(bytes, self.cart().crc32())?;
+        let mut cpu = self.clone();
+        cpu.a = state.u8()?;
+        cpu.x = state.u8()?;
+        cpu.y = state.u8()?;
+        cpu.pc = state.u16()?;
+        cpu.s = state.u8()?;
+        cpu.set_status(state.u8()?);
+        cpu.clock = state.u64()?;
+        cpu.oam_dma_stall = state.u16()?;
+        cpu.oam_dma_page = state.u8()?;
+        cpu.system.read_state(&mut state)?;
+        state.finish()?;
+        cpu.recent_pcs = [cpu.pc; RECENT_PC_COUNT];
+        *self = cpu;
+        Ok(())
+    }
+
+    /// Jump to the reset vector, as when the console's reset button is pressed. The PPU, APU and
+    /// mapper are reset too (see `System::reset`). RAM and the registers are kept, except that
+    /// I is set and S goes down by 3, so repeated resets move the stack further down.
+    ///
+    /// See: <https://www.nesdev.org/wiki/CPU_power_up_state#After_reset>
+    pub fn reset(&mut self) {
+        self.system.reset();
+        // Reset runs like an interrupt, but with the pushes turned into reads
+        self.s = self.s.wrapping_sub(3);
+        self.interrupt_disable = true;
+        self.pc = self.system.read_word(0xfffc);
+    }
+
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.unknown_opcode_policy = policy;
+    }
+
+    /// Stop with `CpuError::Break` on reaching a `BRK`, rather than running it, for test programs
+    /// which use it to mark their end. The PC is left on the `BRK`.
+    pub fn set_stop_on_brk(&mut self, enabled: bool) {
+        self.stop_on_brk = enabled;
+    }
+
+    /// Check for the CPU getting stuck (see `JamKind`) after every instruction, which is off by
+    /// default
+    pub fn set_jam_policy(&mut self, policy: JamPolicy) {
+        self.jam_policy = policy;
+        self.jam_loop_start = None;
+        self.jam_reported = false;
+    }
+
+    /// Disassembly of the current instruction, only recorded while tracing
     #[inline]
-    fn debug_opcode<S: Into<String> + Display>(&self, opcode_info: S) {
-        if !self.debug_enabled {
+    fn debug_opcode<S: Into<String>>(&mut self, opcode_info: S) {
+        if !self.tracing {
             return;
         }
-        println!("{}    {}", self.debug_state, opcode_info);
+        self.disassembly = opcode_info.into();
     }
 
     #[inline]
-    fn debug_opcode_with_address(&self, opcode_name: &str, address: u16) {
+    fn debug_opcode_with_address(&mut self, opcode_name: &str, address: u16) {
+        if !self.tracing {
+            return;
+        }
         self.debug_opcode(format!("{} ${:0>4x}", opcode_name, address));
     }
 
-    pub fn run_opcode(&mut self) {
-        // Save debug state before altering the counters/registers
-        self.save_debug_state();
+    /// Status register as it would be pushed by PHP, but without the B flag
+    fn status(&self) -> u8 {
+        let flags = [
+            (self.negative, 0x80),
+            (self.overflow, 0x40),
+            (true, 0x20), // always 1
+            (self.decimal, 0x08),
+            (self.interrupt_disable, 0x04),
+            (self.zero, 0x02),
+            (self.carry, 0x01),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |status, (_, bit)| status | bit)
+    }
+
+    /// Run one instruction, or while OAM DMA has the CPU halted, one cycle of the DMA
+    pub fn run_opcode(&mut self) -> CpuResult<()> {
+        if let Some((address, cycles)) = self.system.take_dmc_dma() {
+            self.dmc_dma(address, cycles);
+        } else if self.oam_dma_stall > 0 {
+            self.oam_dma_cycle();
+        } else if self.debug_writer.is_none() && self.trace_sink.is_none() {
+            self.step()?;
+        } else {
+            let trace = self.run_opcode_with_trace()?;
+            let debug_result = match &self.debug_writer {
+                Some(writer) => writeln!(writer.borrow_mut(), "{}", trace),
+                None => Ok(()),
+            };
+            if let Err(err) = debug_result {
+                eprintln!("Stopped writing the debug log: {}", err);
+                self.debug_writer = None;
+            }
+            if let Some(sink) = &mut self.trace_sink {
+                if !sink.write(&trace) {
+                    self.trace_sink = None;
+                }
+            }
+        }
+        if let Some(wrap) = self.stack_wrap {
+            self.warn(wrap);
+        }
+        for address in self.system.take_uninit_reads() {
+            let read = UninitRead {
+                address,
+                pc: self.instruction_pc(),
+            };
+            self.warn(read);
+            self.uninit_reads.push(read);
+        }
+        Ok(())
+    }
+
+    /// Report a problem found by one of the checks in the trace, after the instruction which
+    /// caused it, or on stderr if nothing is being traced
+    fn warn(&mut self, message: impl Display) {
+        let mut written = false;
+        if let Some(writer) = &self.debug_writer {
+            written |= writeln!(writer.borrow_mut(), "warning: {}", message).is_ok();
+        }
+        if let Some(sink) = &self.trace_sink {
+            written |= writeln!(sink.writer.borrow_mut(), "warning: {}", message).is_ok();
+        }
+        if !written {
+            eprintln!("warning: {}", message);
+        }
+    }
+
+    /// Address of the instruction run most recently
+    fn instruction_pc(&self) -> u16 {
+        self.recent_pcs[(self.recent_pcs_next + RECENT_PC_COUNT - 1) % RECENT_PC_COUNT]
+    }
+
+    /// Watch for reads of RAM (or the cart's PRG RAM) which hasn't been written since power on,
+    /// which are reported in the trace and collected in `uninit_reads`, once per address
+    pub fn set_uninit_check(&mut self, enabled: bool) {
+        self.system.set_uninit_check(enabled);
+        self.uninit_reads.clear();
+    }
+
+    pub fn uninit_check(&self) -> bool {
+        self.system.uninit_check()
+    }
+
+    /// Reads found by `set_uninit_check`, oldest first
+    pub fn uninit_reads(&self) -> &[UninitRead] {
+        &self.uninit_reads
+    }
+
+    /// Watch for the stack pointer wrapping around page one, reporting it in the trace (see
+    /// `StackWrap`) and through `last_stack_wrap` for the debugger to break on. The depth
+    /// counted from here is reset whenever the game sets S with `TXS`.
+    pub fn set_stack_check(&mut self, enabled: bool) {
+        self.stack_check = enabled;
+        self.stack_depth = 0;
+    }
+
+    pub fn stack_check(&self) -> bool {
+        self.stack_check
+    }
+
+    /// Write a nestest-style line (see `OpcodeTrace::nestest_line`) for every instruction run
+    /// from now on, stopping after `limit` instructions if given. The writer should be buffered.
+    pub fn set_trace_writer(&mut self, writer: Box<dyn Write>, limit: Option<u64>) {
+        self.trace_sink = (limit != Some(0)).then(|| TraceSink {
+            writer: Rc::new(RefCell::new(writer)),
+            remaining: limit,
+            columns: TraceColumns::default(),
+        });
+    }
+
+    /// Write the trace (see `set_trace_writer`) to a file through a buffer, replacing the file
+    /// if it exists
+    pub fn set_trace_file(&mut self, path: impl AsRef<Path>, limit: Option<u64>) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.set_trace_writer(Box::new(BufWriter::new(file)), limit);
+        Ok(())
+    }
+
+    /// Choose the columns in the trace set by `set_trace_writer`, which has nestest's columns
+    /// until this is called
+    pub fn set_trace_columns(&mut self, columns: TraceColumns) {
+        if let Some(sink) = &mut self.trace_sink {
+            sink.columns = columns;
+        }
+    }
+
+    /// Write the debugger's trace of every instruction (see `OpcodeTrace`'s `Display`) to
+    /// `writer` from now on, instead of stdout. The writer should be buffered.
+    pub fn set_debug_writer(&mut self, writer: Box<dyn Write + Send>) {
+        self.debug_writer = Some(Rc::new(RefCell::new(writer)));
+    }
+
+    /// Whether every instruction is being written out, to the debug writer or a trace (see
+    /// `set_trace_file`)
+    pub fn is_tracing(&self) -> bool {
+        self.debug_writer.is_some() || self.trace_sink.is_some()
+    }
+
+    /// Flush the trace and debug writers, e.g. before exiting
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        if let Some(writer) = &self.debug_writer {
+            writer.borrow_mut().flush()?;
+        }
+        match &self.trace_sink {
+            Some(sink) => sink.writer.borrow_mut().flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Run one instruction, and describe it along with the state of the CPU before it ran
+    pub fn run_opcode_with_trace(&mut self) -> CpuResult<OpcodeTrace> {
+        // Peek so that tracing can't change the behaviour of the program being traced
+        let (ppu_scanline, ppu_dot) = self.system.ppu_position();
+        let (disassembly, _) = self.disassemble(self.pc);
+        let mut trace = OpcodeTrace {
+            pc: self.pc,
+            opcode: self.peek_byte(self.pc),
+            operand_bytes: [
+                self.peek_byte(self.pc.wrapping_add(1)),
+                self.peek_byte(self.pc.wrapping_add(2)),
+            ],
+            mnemonic: String::new(),
+            operand: String::new(),
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            status: self.status(),
+            cycles: self.cycles(),
+            ppu_scanline,
+            ppu_dot,
+            ppu_frame: self.system.ppu_frame(),
+        };
+
+        self.tracing = true;
+        let result = self.step();
+        self.tracing = false;
+        result?;
+
+        // The handlers describe operands by their effective address, so take how the operand was
+        // written from the disassembler, as long as it agrees on what ran
+        let executed = std::mem::take(&mut self.disassembly);
+        let (mnemonic, operand) = executed.split_once(' ').unwrap_or((&executed, ""));
+        let (written_mnemonic, written_operand) =
+            disassembly.split_once(' ').unwrap_or((&disassembly, ""));
+        trace.mnemonic = mnemonic.to_string();
+        trace.operand = if written_mnemonic == mnemonic {
+            written_operand.to_string()
+        } else {
+            operand.to_string()
+        };
+        Ok(trace)
+    }
+
+    fn step(&mut self) -> CpuResult<()> {
+        if let Some(history) = &self.instruction_history {
+            let snapshot = self.save_state();
+            let mut history = history.borrow_mut();
+            if history.len() == INSTRUCTION_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(snapshot);
+        }
+
+        let clock_before = self.clock;
+        self.last_interrupt = None;
+        self.stack_wrap = None;
+        self.recent_pcs[self.recent_pcs_next] = self.pc;
+        self.recent_pcs_next = (self.recent_pcs_next + 1) % RECENT_PC_COUNT;
 
         let opcode = self.system.read_byte(self.pc);
+        if let Some(counts) = &mut self.opcode_counts {
+            counts[opcode as usize] += 1;
+        }
+        let result = self.execute_opcode(opcode);
+        self.instructions += 1;
+        self.last_ppu_write = self.system.take_ppu_write();
+        if let Some(page) = self.system.take_oam_dma() {
+            // A cycle to halt, another if the DMA would start on a write cycle, then 256 reads
+            // and writes
+            self.oam_dma_page = page;
+            self.oam_dma_stall = if self.clock % 2 == 1 { 514 } else { 513 };
+            self.system.set_dma_active(true);
+        }
+        if result.is_ok() && self.system.take_nmi() {
+            self.interrupt(Interrupt::Nmi);
+        } else if result.is_ok() && !self.interrupt_disable && self.system.irq_pending() {
+            self.interrupt(Interrupt::Irq);
+        }
+
+        self.system.tick(self.clock - clock_before);
+
+        if result.is_ok() && self.jam_policy != JamPolicy::Off {
+            return self.check_jam(self.instruction_pc());
+        }
+        result
+    }
+
+    /// Whether the instruction at `pc`, which just ran, has left the CPU stuck
+    fn check_jam(&mut self, pc: u16) -> CpuResult<()> {
+        let frame = self.system.ppu_frame();
+        let can_escape =
+            self.last_interrupt.is_some() || self.system.nmi_enabled() || !self.interrupt_disable;
+        let kind = if !self.system.is_executable(self.pc) {
+            Some(JamKind::OpenBus)
+        } else if self.pc == pc && !can_escape {
+            let start = *self.jam_loop_start.get_or_insert(frame);
+            (frame - start >= JAM_FRAMES).then_some(JamKind::SelfLoop)
+        } else {
+            self.jam_loop_start = None;
+            None
+        };
+        let Some(kind) = kind else {
+            self.jam_reported = false;
+            return Ok(());
+        };
+
+        let err = CpuError::Jammed { kind, pc: self.pc };
+        match self.jam_policy {
+            JamPolicy::Error => Err(err),
+            _ if self.jam_reported => Ok(()),
+            _ => {
+                self.jam_reported = true;
+                self.warn(err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Run a cycle of OAM DMA. The last 512 cycles alternate between reading a byte of the page
+    /// and writing it to OAMDATA.
+    fn oam_dma_cycle(&mut self) {
+        self.oam_dma_stall -= 1;
+        let remaining = self.oam_dma_stall;
+        if remaining < 512 && remaining.is_multiple_of(2) {
+            let offset = 255 - (remaining / 2) as u8;
+            let value = self
+                .system
+                .read_byte(u16::from_le_bytes([offset, self.oam_dma_page]));
+            self.system.write_byte(0x2004, value);
+        }
+        self.last_interrupt = None;
+        self.stack_wrap = None;
+        self.last_ppu_write = self.system.take_ppu_write();
+        if remaining == 0 {
+            self.system.set_dma_active(false);
+        }
+        self.clock += 1;
+        self.system.tick(1);
+    }
+
+    /// Stall while the DMC fetches a sample byte, which can happen part way through OAM DMA
+    fn dmc_dma(&mut self, address: u16, cycles: u8) {
+        let value = self.system.read_byte(address);
+        self.system.load_dmc_sample(value);
+        self.last_interrupt = None;
+        self.stack_wrap = None;
+        self.clock += cycles as u64;
+        self.system.tick(cycles as u64);
+    }
+
+    /// Whether the CPU is halted for OAM or DMC DMA, so `run_opcode` will run some of it rather
+    /// than an instruction
+    pub fn dma_stalled(&self) -> bool {
+        self.oam_dma_stall > 0 || self.system.dmc_dma_pending()
+    }
+
+    /// Run one instruction, or if it's a `JSR`, run until the subroutine returns to the
+    /// instruction after it. Returns false if the subroutine hasn't returned after a second of
+    /// emulation, e.g. as it never does, leaving the CPU wherever it had got to.
+    pub fn step_over(&mut self) -> CpuResult<bool> {
+        if self.peek_byte(self.pc) != 0x20 {
+            self.run_opcode()?;
+            return Ok(true);
+        }
+        // Break at the return address, but only at this depth of the stack, in case the
+        // subroutine calls itself
+        let return_address = self.pc.wrapping_add(3);
+        let stack_pointer = self.s;
+        let give_up_frame = self.system.ppu_frame() + STEP_OVER_FRAME_LIMIT;
+        self.run_opcode()?;
+        while self.pc != return_address || self.s != stack_pointer {
+            if self.system.ppu_frame() >= give_up_frame {
+                return Ok(false);
+            }
+            self.run_opcode()?;
+        }
+        Ok(true)
+    }
+
+    /// Run until the PPU has finished the current frame
+    pub fn run_frame(&mut self) -> CpuResult<()> {
+        self.run_frame_until(|_| false).map(|_| ())
+    }
+
+    /// Run until the PPU has finished the current frame, unless `stop` returns true after an
+    /// instruction, e.g. for a breakpoint. Returns whether it stopped early, in which case
+    /// calling it again finishes the frame.
+    pub fn run_frame_until(&mut self, mut stop: impl FnMut(&CPU) -> bool) -> CpuResult<bool> {
+        let frame = self.system.ppu_frame();
+        match &self.movie {
+            Some((MovieMode::Playing, movie)) => {
+                let buttons = movie.borrow().frame(frame);
+                if let Some(buttons) = buttons.or(self.deterministic.then_some([0; 2])) {
+                    self.system.set_player_input(0, buttons[0]);
+                    self.system.set_player_input(1, buttons[1]);
+                }
+            }
+            Some((MovieMode::Recording, movie)) => {
+                let buttons = [self.system.player_input(0), self.system.player_input(1)];
+                movie.borrow_mut().record_frame(frame, buttons);
+            }
+            None => {}
+        }
+        while self.system.ppu_frame() == frame {
+            self.run_opcode()?;
+            // The next instruction isn't about to run until any DMA has finished
+            if !self.dma_stalled() && stop(self) {
+                return Ok(true);
+            }
+        }
+        if let Some(hashes) = &mut self.frame_hashes {
+            hashes.push(crate::png::crc32(self.system.ppu_framebuffer()));
+        }
+        Ok(false)
+    }
+
+    /// Record the buttons held on each frame run from now on into a movie, which should start
+    /// at power on. See `finish_movie`.
+    pub fn record_movie(&mut self) {
+        let movie = Movie::new(self.cart().crc32(), self.region());
+        self.movie = Some((MovieMode::Recording, Rc::new(RefCell::new(movie))));
+    }
+
+    /// Play back a movie from power on: on each frame it covers, its buttons replace the input
+    /// from the frontend. Fails if it was recorded with a different ROM or region.
+    pub fn play_movie(&mut self, movie: Movie) -> MovieResult<()> {
+        movie.check(self.cart().crc32(), self.region())?;
+        self.movie = Some((MovieMode::Playing, Rc::new(RefCell::new(movie))));
+        Ok(())
+    }
+
+    /// Stop recording or playing back the movie, and return it. A recording ends with the last
+    /// frame run.
+    pub fn finish_movie(&mut self) -> Option<Movie> {
+        let (mode, movie) = self.movie.take()?;
+        let mut movie = movie.borrow().clone();
+        if mode == MovieMode::Recording {
+            movie.truncate(self.system.ppu_frame());
+        }
+        Some(movie)
+    }
+
+    /// Run exactly one frame, for lockstep use by an external driver, and return the completed
+    /// framebuffer. This contains one palette index per pixel, row by row; see `rgb_framebuffer`
+    /// for the colours.
+    pub fn step_frame(&mut self) -> CpuResult<&[u8]> {
+        self.run_frame()?;
+        Ok(self.system.ppu_framebuffer())
+    }
+
+    /// Address of the next instruction to run
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Disassemble the instruction at `address` (see `asm::disassemble`), reading memory
+    /// with `peek_byte`
+    pub fn disassemble(&self, address: u16) -> (String, u16) {
+        crate::asm::disassemble(address, |address| self.peek_byte(address))
+    }
+
+    /// CPU cycles run since power up, or since the last `reset_cycles`
+    pub fn cycles(&self) -> u64 {
+        self.clock.saturating_sub(self.cycles_start)
+    }
+
+    /// Instructions run since power up, including any which failed
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Restart the cycle count from zero, e.g. to time a routine. This doesn't affect emulation.
+    pub fn reset_cycles(&mut self) {
+        self.cycles_start = self.clock;
+    }
+
+    /// Remember the state before each of the last 100 instructions, so `step_back` can undo
+    /// them. This copies the whole machine on every instruction, so is only for debugging.
+    pub fn set_step_back(&mut self, enabled: bool) {
+        self.instruction_history = enabled.then(Default::default);
+    }
+
+    /// Undo the last instruction, if stepping back is enabled and there is one to undo.
+    /// Returns whether it was undone.
+    pub fn step_back(&mut self) -> bool {
+        let snapshot = match &self.instruction_history {
+            Some(history) => history.borrow_mut().pop_back(),
+            None => None,
+        };
+        match snapshot {
+            Some(snapshot) => {
+                self.load_state(&snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Addresses of the last few instructions executed (or attempted), oldest first
+    pub fn recent_pcs(&self) -> Vec<u16> {
+        let (newer, older) = self.recent_pcs.split_at(self.recent_pcs_next);
+        older.iter().chain(newer).copied().collect()
+    }
+
+    /// Start or stop counting how many times each opcode is executed. Starting again resets the
+    /// counts.
+    pub fn set_opcode_counting(&mut self, enabled: bool) {
+        self.opcode_counts = enabled.then(|| Box::new([0; 256]));
+    }
+
+    /// Number of times each opcode has been executed, indexed by opcode, if counting is enabled
+    pub fn opcode_counts(&self) -> Option<&[u64; 256]> {
+        self.opcode_counts.as_deref()
+    }
+
+    /// Start or stop logging a hash of every frame, for comparing runs in regression tests.
+    /// Starting again clears the log. It's off by default, as savestates copy the whole log.
+    pub fn set_frame_hash_logging(&mut self, enabled: bool) {
+        self.frame_hashes = enabled.then(Vec::new);
+    }
+
+    /// CRC-32 of the framebuffer (see `step_frame`) after each frame run since logging was
+    /// enabled, oldest first
+    pub fn frame_hash_log(&self) -> &[u32] {
+        self.frame_hashes.as_deref().unwrap_or_default()
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.system.set_quirks(quirks);
+    }
+
+    /// Read memory for display in the debugger, without the side effects of a real read
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        self.system.read_byte_no_side_effects(address)
+    }
+
+    /// Addresses in `range` where `pattern` starts, e.g. to find where a game keeps the number
+    /// of lives. Memory is read with `peek_byte`, so searching has no side effects.
+    pub fn search_memory(&self, pattern: &[u8], range: RangeInclusive<u16>) -> Vec<u16> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        let start = *range.start();
+        let memory: Vec<u8> = range.map(|address| self.peek_byte(address)).collect();
+        memory
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(offset, _)| start + offset as u16)
+            .collect()
+    }
+
+    /// Modify memory from the debugger, without the side effects of a real write
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
+        self.system.write_byte_raw(address, value);
+    }
+
+    /// Replace the device plugged into a controller port (0 or 1)
+    pub fn connect_input_device(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.system.connect(port, device);
+    }
+
+    /// Set the buttons held on a player's controller (0 or 1), e.g. from a script or movie
+    /// rather than a frontend. See `buttons` for the bit layout.
+    pub fn set_player_input(&mut self, player: usize, buttons: u8) {
+        self.system.set_player_input(player, buttons);
+    }
+
+    /// Update the connected input devices with input polled by a frontend
+    pub fn set_input(&mut self, input: &InputState) {
+        if !self.deterministic {
+            self.system.set_input(input);
+        }
+    }
+
+    /// Ignore input from `set_input`, so that the buttons only come from the movie being played
+    /// back (if any), and none are held past its end. The rest of the emulation is deterministic
+    /// anyway: RAM powers on zeroed, the CPU and PPU always start in step, and nothing depends on
+    /// the host's clock or random numbers. Two runs of a ROM with the same movie then produce the
+    /// same frames, which `set_frame_hash_logging` can check.
+    pub fn set_deterministic(&mut self, enabled: bool) {
+        self.deterministic = enabled;
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// The first nametable as ASCII art, one character per tile
+    pub fn nametable_text(&self) -> String {
+        self.system.nametable_text()
+    }
+
+    /// Scanline (-1 for pre-render) and dot the PPU is about to draw
+    pub fn ppu_position(&self) -> (i16, u16) {
+        self.system.ppu_position()
+    }
+
+    /// The interrupt taken by the last instruction, or straight after it
+    pub fn last_interrupt(&self) -> Option<Interrupt> {
+        self.last_interrupt
+    }
+
+    /// The stack wrap caused by the last instruction, if the stack check is enabled
+    pub fn last_stack_wrap(&self) -> Option<StackWrap> {
+        self.stack_wrap
+    }
+
+    /// The PPU register written by the last instruction or DMA cycle, e.g. `0x2001` for
+    /// PPUMASK
+    pub fn last_ppu_write(&self) -> Option<u16> {
+        self.last_ppu_write
+    }
+
+    /// Number of frames the PPU has completed since power up
+    pub fn frame_count(&self) -> u64 {
+        self.system.ppu_frame()
+    }
+
+    /// Change the colours used by `frame`
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.system.set_palette(palette);
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.system.sprite_limit()
+    }
+
+    /// Whether to draw only 8 sprites per scanline, as the console does. Turning the limit off
+    /// stops games' sprites flickering, but the sprite overflow flag still reads as if it were
+    /// on, so games run the same.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.system.set_sprite_limit(enabled);
+    }
+
+    /// The last frame completed by the PPU
+    pub fn frame(&self) -> Frame {
+        self.system.ppu_rgb_frame()
+    }
+
+    /// The CRC-32 of the last frame's palette indices, as logged by `set_frame_hash_logging`,
+    /// which doesn't depend on the palette
+    pub fn frame_hash(&self) -> u32 {
+        crate::png::crc32(self.system.ppu_framebuffer())
+    }
+
+    /// The last frame as the colours shown on screen, 3 bytes (RGB) per pixel row by row, for
+    /// tools which don't need a `Frame`
+    pub fn rgb_framebuffer(&self) -> Vec<u8> {
+        self.frame().pixels
+    }
+
+    /// Take the audio samples generated since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        let samples = self.system.take_audio_samples();
+        let result = match &self.audio_dump {
+            Some(dump) => dump.borrow_mut().write_samples(&samples),
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            eprintln!("Stopped recording audio: {}", err);
+            self.audio_dump = None;
+        }
+        samples
+    }
+
+    /// Record every sample handed out by `take_audio_samples` to a WAV file
+    pub fn set_audio_dump(&mut self, file: File) -> io::Result<()> {
+        let writer = WavWriter::new(BufWriter::new(file))?;
+        self.audio_dump = Some(Rc::new(RefCell::new(writer)));
+        Ok(())
+    }
+
+    /// Finish the WAV file's header and flush it, e.g. before exiting
+    pub fn finish_audio_dump(&mut self) -> io::Result<()> {
+        match &self.audio_dump {
+            Some(dump) => dump.borrow_mut().finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Master volume, from 0.0 to 1.0. Changes are ramped in over a few milliseconds.
+    pub fn volume(&self) -> f32 {
+        self.system.volume()
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.system.set_volume(volume);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.system.muted()
+    }
+
+    /// Silence the audio output. Samples are still generated, so timing is unaffected.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.system.set_muted(muted);
+    }
+
+    fn execute_opcode(&mut self, opcode: u8) -> CpuResult<()> {
         match opcode {
+            0x00 if self.stop_on_brk => return Err(CpuError::Break { pc: self.pc }),
             0x00 => self.brk(),
-            0x01 => self.ora(opcode),
+            0x01 => self.ora(opcode)?,
             0x04 => self.nop(),
-            0x05 => self.ora(opcode),
-            0x06 => self.asl(opcode),
+            0x05 => self.ora(opcode)?,
+            0x06 => self.asl(opcode)?,
             0x08 => self.php(),
+            0x09 => self.ora(opcode)?,
+            0x0a => self.asl(opcode)?,
             0x0c => self.nop(),
-            0x0d => self.ora(opcode),
-            0x0e => self.asl(opcode),
+            0x0d => self.ora(opcode)?,
+            0x0e => self.asl(opcode)?,
 
             0x10 => self.bpl(),
-            0x11 => self.ora(opcode),
+            0x11 => self.ora(opcode)?,
             0x14 => self.nop(),
-            0x15 => self.ora(opcode),
-            0x16 => self.asl(opcode),
+            0x15 => self.ora(opcode)?,
+            0x16 => self.asl(opcode)?,
             0x18 => self.clc(),
-            0x19 => self.ora(opcode),
+            0x19 => self.ora(opcode)?,
             0x1a => self.nop(),
             0x1c => self.nop(),
-            0x1d => self.ora(opcode),
-            0x1e => self.asl(opcode),
+            0x1d => self.ora(opcode)?,
+            0x1e => self.asl(opcode)?,
 
             0x20 => self.jsr(),
-            0x21 => self.and(opcode),
-            0x24 => self.bit(opcode),
-            0x25 => self.and(opcode),
-            0x26 => self.rol(opcode),
+            0x21 => self.and(opcode)?,
+            0x24 => self.bit(opcode)?,
+            0x25 => self.and(opcode)?,
+            0x26 => self.rol(opcode)?,
             0x28 => self.plp(),
-            0x29 => self.and(opcode),
-            0x2a => self.rol(opcode),
-            0x2c => self.bit(opcode),
-            0x2d => self.and(opcode),
-            0x2e => self.rol(opcode),
+            0x29 => self.and(opcode)?,
+            0x2a => self.rol(opcode)?,
+            0x2c => self.bit(opcode)?,
+            0x2d => self.and(opcode)?,
+            0x2e => self.rol(opcode)?,
 
             0x30 => self.bmi(),
-            0x31 => self.and(opcode),
+            0x31 => self.and(opcode)?,
             0x34 => self.nop(),
-            0x35 => self.and(opcode),
-            0x36 => self.rol(opcode),
+            0x35 => self.and(opcode)?,
+            0x36 => self.rol(opcode)?,
             0x38 => self.sec(),
-            0x39 => self.and(opcode),
+            0x39 => self.and(opcode)?,
             0x3a => self.nop(),
             0x3c => self.nop(),
-            0x3d => self.and(opcode),
-            0x3e => self.rol(opcode),
+            0x3d => self.and(opcode)?,
+            0x3e => self.rol(opcode)?,
 
             0x40 => self.rti(),
-            0x41 => self.eor(opcode),
+            0x41 => self.eor(opcode)?,
             0x44 => self.nop(),
-            0x45 => self.eor(opcode),
-            0x46 => self.rol(opcode),
+            0x45 => self.eor(opcode)?,
+            0x46 => self.lsr(opcode)?,
             0x48 => self.pha(),
-            0x49 => self.eor(opcode),
-            0x4a => self.rol(opcode),
-            0x4c => self.bit(opcode),
-            0x4d => self.and(opcode),
-            0x4e => self.rol(opcode),
+            0x49 => self.eor(opcode)?,
+            0x4a => self.lsr(opcode)?,
+            0x4c => self.jmp(opcode)?,
+            0x4d => self.eor(opcode)?,
+            0x4e => self.lsr(opcode)?,
 
             0x50 => self.bvc(),
-            0x51 => self.eor(opcode),
+            0x51 => self.eor(opcode)?,
             0x54 => self.nop(),
-            0x55 => self.eor(opcode),
-            0x56 => self.lsr(opcode),
+            0x55 => self.eor(opcode)?,
+            0x56 => self.lsr(opcode)?,
             0x58 => self.cli(),
-            0x59 => self.eor(opcode),
+            0x59 => self.eor(opcode)?,
             0x5a => self.nop(),
             0x5c => self.nop(),
-            0x5d => self.eor(opcode),
-            0x5e => self.lsr(opcode),
+            0x5d => self.eor(opcode)?,
+            0x5e => self.lsr(opcode)?,
 
             0x60 => self.rts(),
-            0x61 => self.adc(opcode),
+            0x61 => self.adc(opcode)?,
             0x64 => self.nop(),
-            0x65 => self.adc(opcode),
-            0x66 => self.ror(opcode),
+            0x65 => self.adc(opcode)?,
+            0x66 => self.ror(opcode)?,
             0x68 => self.pla(),
-            0x69 => self.adc(opcode),
-            0x6a => self.ror(opcode),
-            0x6c => self.jmp(opcode),
-            0x6d => self.adc(opcode),
-            0x6e => self.ror(opcode),
+            0x69 => self.adc(opcode)?,
+            0x6a => self.ror(opcode)?,
+            0x6c => self.jmp(opcode)?,
+            0x6d => self.adc(opcode)?,
+            0x6e => self.ror(opcode)?,
 
             0x70 => self.bvs(),
-            0x71 => self.adc(opcode),
+            0x71 => self.adc(opcode)?,
             0x74 => self.nop(),
-            0x75 => self.adc(opcode),
-            0x76 => self.ror(opcode),
+            0x75 => self.adc(opcode)?,
+            0x76 => self.ror(opcode)?,
             0x78 => self.sei(),
-            0x79 => self.adc(opcode),
+            0x79 => self.adc(opcode)?,
             0x7a => self.nop(),
             0x7c => self.nop(),
-            0x7d => self.adc(opcode),
-            0x7e => self.ror(opcode),
+            0x7d => self.adc(opcode)?,
+            0x7e => self.ror(opcode)?,
 
             0x80 => self.nop(),
-            0x81 => self.sta(opcode),
+            0x81 => self.sta(opcode)?,
             0x82 => self.nop(),
-            0x84 => self.sty(opcode),
-            0x85 => self.sta(opcode),
-            0x86 => self.stx(opcode),
+            0x84 => self.sty(opcode)?,
+            0x85 => self.sta(opcode)?,
+            0x86 => self.stx(opcode)?,
             0x88 => self.dey(),
             0x89 => self.nop(),
             0x8a => self.txa(),
-            0x8c => self.sty(opcode),
-            0x8d => self.sta(opcode),
-            0x8e => self.stx(opcode),
+            0x8c => self.sty(opcode)?,
+            0x8d => self.sta(opcode)?,
+            0x8e => self.stx(opcode)?,
 
             0x90 => self.bcc(),
-            0x91 => self.sta(opcode),
-            0x94 => self.sty(opcode),
-            0x95 => self.sta(opcode),
-            0x96 => self.stx(opcode),
+            0x91 => self.sta(opcode)?,
+            0x94 => self.sty(opcode)?,
+            0x95 => self.sta(opcode)?,
+            0x96 => self.stx(opcode)?,
             0x98 => self.tya(),
-            0x99 => self.sta(opcode),
+            0x99 => self.sta(opcode)?,
             0x9a => self.txs(),
-            0x9d => self.sta(opcode),
-
-            0xa0 => self.ldy(opcode),
-            0xa1 => self.lda(opcode),
-            0xa2 => self.ldx(opcode),
-            0xa4 => self.ldy(opcode),
-            0xa5 => self.lda(opcode),
-            0xa6 => self.ldx(opcode),
+            0x9d => self.sta(opcode)?,
+
+            0xa0 => self.ldy(opcode)?,
+            0xa1 => self.lda(opcode)?,
+            0xa2 => self.ldx(opcode)?,
+            0xa4 => self.ldy(opcode)?,
+            0xa5 => self.lda(opcode)?,
+            0xa6 => self.ldx(opcode)?,
             0xa8 => self.tay(),
-            0xa9 => self.lda(opcode),
+            0xa9 => self.lda(opcode)?,
             0xaa => self.tax(),
-            0xac => self.ldy(opcode),
-            0xad => self.lda(opcode),
-            0xae => self.ldx(opcode),
+            0xac => self.ldy(opcode)?,
+            0xad => self.lda(opcode)?,
+            0xae => self.ldx(opcode)?,
 
             0xb0 => self.bcs(),
-            0xb1 => self.lda(opcode),
-            0xb4 => self.ldy(opcode),
-            0xb5 => self.lda(opcode),
-            0xb6 => self.ldx(opcode),
+            0xb1 => self.lda(opcode)?,
+            0xb4 => self.ldy(opcode)?,
+            0xb5 => self.lda(opcode)?,
+            0xb6 => self.ldx(opcode)?,
             0xb8 => self.clv(),
-            0xb9 => self.lda(opcode),
+            0xb9 => self.lda(opcode)?,
             0xba => self.tsx(),
-            0xbc => self.ldy(opcode),
-            0xbd => self.lda(opcode),
-            0xbe => self.ldx(opcode),
+            0xbc => self.ldy(opcode)?,
+            0xbd => self.lda(opcode)?,
+            0xbe => self.ldx(opcode)?,
 
-            0xc0 => self.cpy(opcode),
-            0xc1 => self.cmp(opcode),
+            0xc0 => self.cpy(opcode)?,
+            0xc1 => self.cmp(opcode)?,
             0xc2 => self.nop(),
-            0xc4 => self.cpy(opcode),
-            0xc5 => self.cmp(opcode),
-            0xc6 => self.dec(opcode),
+            0xc4 => self.cpy(opcode)?,
+            0xc5 => self.cmp(opcode)?,
+            0xc6 => self.dec(opcode)?,
             0xc8 => self.iny(),
-            0xc9 => self.cmp(opcode),
+            0xc9 => self.cmp(opcode)?,
             0xca => self.dex(),
-            0xcc => self.cpy(opcode),
-            0xcd => self.cmp(opcode),
-            0xce => self.dec(opcode),
+            0xcc => self.cpy(opcode)?,
+            0xcd => self.cmp(opcode)?,
+            0xce => self.dec(opcode)?,
 
             0xd0 => self.bne(),
-            0xd1 => self.cmp(opcode),
+            0xd1 => self.cmp(opcode)?,
             0xd4 => self.nop(),
-            0xd5 => self.cmp(opcode),
-            0xd6 => self.dec(opcode),
+            0xd5 => self.cmp(opcode)?,
+            0xd6 => self.dec(opcode)?,
             0xd8 => self.cld(),
-            0xd9 => self.cmp(opcode),
+            0xd9 => self.cmp(opcode)?,
             0xda => self.nop(),
             0xdc => self.nop(),
-            0xdd => self.cmp(opcode),
-            0xde => self.dec(opcode),
+            0xdd => self.cmp(opcode)?,
+            0xde => self.dec(opcode)?,
 
-            0xe0 => self.cpx(opcode),
-            0xe1 => self.sbc(opcode),
+            0xe0 => self.cpx(opcode)?,
+            0xe1 => self.sbc(opcode)?,
             0xe2 => self.nop(),
-            0xe4 => self.cpx(opcode),
-            0xe5 => self.sbc(opcode),
-            0xe6 => self.inc(opcode),
+            0xe4 => self.cpx(opcode)?,
+            0xe5 => self.sbc(opcode)?,
+            0xe6 => self.inc(opcode)?,
             0xe8 => self.inx(),
-            0xe9 => self.sbc(opcode),
+            0xe9 => self.sbc(opcode)?,
             0xea => self.nop(),
-            0xec => self.cpx(opcode),
-            0xed => self.sbc(opcode),
-            0xee => self.inc(opcode),
+            0xec => self.cpx(opcode)?,
+            0xed => self.sbc(opcode)?,
+            0xee => self.inc(opcode)?,
 
             0xf0 => self.beq(),
-            0xf1 => self.sbc(opcode),
+            0xf1 => self.sbc(opcode)?,
             0xf4 => self.nop(),
-            0xf5 => self.sbc(opcode),
-            0xf6 => self.inc(opcode),
+            0xf5 => self.sbc(opcode)?,
+            0xf6 => self.inc(opcode)?,
             0xf8 => self.sed(),
-            0xf9 => self.sbc(opcode),
+            0xf9 => self.sbc(opcode)?,
             0xfa => self.nop(),
             0xfc => self.nop(),
-            0xfd => self.sbc(opcode),
-            0xfe => self.inc(opcode),
+            0xfd => self.sbc(opcode)?,
+            0xfe => self.inc(opcode)?,
+
+            _ => return self.unknown_opcode(opcode),
+        }
+        Ok(())
+    }
+
+    fn unknown_opcode(&mut self, opcode: u8) -> CpuResult<()> {
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Panic => panic!("Unknown opcode {:02x}", opcode),
+            UnknownOpcodePolicy::Error => Err(CpuError::UnknownOpcode {
+                opcode,
+                pc: self.pc,
+            }),
+            UnknownOpcodePolicy::Nop => {
+                self.warn(format!(
+                    "unknown opcode {:02x} at ${:04x}, skipping it as a nop",
+                    opcode, self.pc
+                ));
+                self.debug_opcode(format!("??? ({:02x})", opcode));
+
+                self.clock += 2;
+                self.pc = self.pc.wrapping_add(Self::instruction_length(opcode));
+                Ok(())
+            }
+        }
+    }
 
-            _ => panic!("Unknown opcode {:02x}", opcode),
+    /// The error for an opcode passed to a handler for other instructions, which would be a bug
+    /// in `execute_opcode`'s dispatch rather than in the program being run
+    fn misdispatched(&self, opcode: u8) -> CpuError {
+        CpuError::UnknownOpcode {
+            opcode,
+            pc: self.pc,
+        }
+    }
+
+    /// Length in bytes of any instruction, derived from the addressing mode encoded in the
+    /// `aaabbbcc` bit pattern of its opcode
+    ///
+    /// See: <https://www.nesdev.org/wiki/CPU_unofficial_opcodes>
+    fn instruction_length(opcode: u8) -> u16 {
+        let aaa = opcode >> 5;
+        let bbb = (opcode >> 2) & 0x7;
+        let cc = opcode & 0x3;
+        match bbb {
+            // #immediate or (zero page,x), except for brk/jsr/rti/rts
+            0 if cc == 0 && aaa == 1 => 3,
+            0 if cc == 0 && aaa < 4 => 1,
+            0 => 2,
+            // zero page, zero page,x, and (zero page),y/relative
+            1 | 4 | 5 => 2,
+            // #immediate for the ALU ops, otherwise implied/accumulator
+            2 if cc & 0x1 == 1 => 2,
+            2 => 1,
+            // absolute,y for the ALU ops, otherwise implied
+            6 if cc & 0x1 == 1 => 3,
+            6 => 1,
+            // absolute and absolute,x
+            _ => 3,
         }
     }
 
@@ -312,33 +1726,35 @@ impl CPU {
         self.pc + 1
     }
 
-    fn general_zero_page(&self, to_add: u8) -> u16 {
+    fn general_zero_page(&mut self, to_add: u8) -> u16 {
         let next_address = self.immediate();
-        (self.system.read_byte(next_address) + to_add) as u16
+        // Indexing wraps around within the zero page
+        self.system.read_byte(next_address).wrapping_add(to_add) as u16
     }
 
-    fn zero_page(&self) -> u16 {
+    fn zero_page(&mut self) -> u16 {
         self.general_zero_page(0)
     }
 
-    fn zero_page_x(&self) -> u16 {
+    fn zero_page_x(&mut self) -> u16 {
         self.general_zero_page(self.x)
     }
 
-    fn zero_page_y(&self) -> u16 {
+    fn zero_page_y(&mut self) -> u16 {
         self.general_zero_page(self.y)
     }
 
-    fn indirect_zero_page_x(&self) -> u16 {
-        self.system.read_word(self.zero_page_x())
+    fn indirect_zero_page_x(&mut self) -> u16 {
+        let address = self.zero_page_x();
+        self.system.read_word_zero_page(address as u8)
     }
 
     fn indirect_zero_page_y(&mut self, extra_clock_for_page_fault: bool) -> u16 {
         let address = self.zero_page();
 
-        let pre_index = self.system.read_word(address);
+        let pre_index = self.system.read_word_zero_page(address as u8);
         let page1 = pre_index >> 8;
-        let indirect_address = pre_index + self.y as u16;
+        let indirect_address = pre_index.wrapping_add(self.y as u16);
         let page2 = indirect_address >> 8;
         if extra_clock_for_page_fault && page1 != page2 {
             self.clock += 1;
@@ -347,16 +1763,21 @@ impl CPU {
         indirect_address
     }
 
-    fn absolute(&self) -> u16 {
+    fn absolute(&mut self) -> u16 {
         let next_address = self.immediate();
         self.system.read_word(next_address)
     }
 
+    fn indirect_absolute(&mut self) -> u16 {
+        let address = self.absolute();
+        self.system.read_word(address)
+    }
+
     fn absolute_x(&mut self, extra_clock_for_page_fault: bool) -> u16 {
         let mut address = self.absolute();
         let page1 = address >> 8;
 
-        address += self.x as u16;
+        address = address.wrapping_add(self.x as u16);
         let page2 = address >> 8;
         if extra_clock_for_page_fault && page1 != page2 {
             self.clock += 1;
@@ -369,7 +1790,7 @@ impl CPU {
         let mut address = self.absolute();
         let page1 = address >> 8;
 
-        address += self.y as u16;
+        address = address.wrapping_add(self.y as u16);
         let page2 = address >> 8;
         if extra_clock_for_page_fault && page1 != page2 {
             self.clock += 1;
@@ -387,9 +1808,24 @@ impl CPU {
         self.zero = value == 0;
     }
 
+    /// Add to the accumulator for ADC and SBC. Overflow is set when the result's sign is wrong
+    /// for adding two signed numbers, i.e. both inputs have the same sign and the result doesn't.
+    ///
+    /// See: <https://www.nesdev.org/wiki/Instruction_reference#ADC>
+    fn add_with_carry(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.carry as u16;
+        let result = sum as u8;
+        self.overflow = (self.a ^ result) & (value ^ result) & 0x80 != 0;
+        self.carry = sum > 0xff;
+        self.a = result;
+
+        self.test_negative(self.a);
+        self.test_zero(self.a);
+    }
+
     // Logical and arithmetic commands -----------------------------------------------------------
     /// bitwise OR with Accumulator
-    fn ora(&mut self, opcode: u8) {
+    fn ora(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0x09 => (self.immediate(), 2, 2),
             0x05 => (self.zero_page(), 3, 2),
@@ -399,7 +1835,7 @@ impl CPU {
             0x0d => (self.absolute(), 4, 3),
             0x1d => (self.absolute_x(true), 4, 3),
             0x19 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -409,10 +1845,11 @@ impl CPU {
         self.a |= self.system.read_byte(intermediate_address);
         self.test_negative(self.a);
         self.test_zero(self.a);
+        Ok(())
     }
 
     /// bitwise AND with accumulator
-    fn and(&mut self, opcode: u8) {
+    fn and(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0x29 => (self.immediate(), 2, 2),
             0x25 => (self.zero_page(), 3, 2),
@@ -422,20 +1859,21 @@ impl CPU {
             0x2d => (self.absolute(), 4, 3),
             0x3d => (self.absolute_x(true), 4, 3),
             0x39 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
-        self.debug_opcode(format!("and {}", intermediate_address));
+        self.debug_opcode_with_address("and", intermediate_address);
 
         self.a &= self.system.read_byte(intermediate_address);
         self.test_negative(self.a);
         self.test_zero(self.a);
+        Ok(())
     }
 
     /// bitwise Exclusive OR
-    fn eor(&mut self, opcode: u8) {
+    fn eor(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0x49 => (self.immediate(), 2, 2),
             0x45 => (self.zero_page(), 3, 2),
@@ -445,7 +1883,7 @@ impl CPU {
             0x4d => (self.absolute(), 4, 3),
             0x5d => (self.absolute_x(true), 4, 3),
             0x59 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -455,10 +1893,11 @@ impl CPU {
         self.a ^= self.system.read_byte(intermediate_address);
         self.test_negative(self.a);
         self.test_zero(self.a);
+        Ok(())
     }
 
     /// ADd with Carry
-    fn adc(&mut self, opcode: u8) {
+    fn adc(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0x69 => (self.immediate(), 2, 2),
             0x65 => (self.zero_page(), 3, 2),
@@ -468,25 +1907,20 @@ impl CPU {
             0x6d => (self.absolute(), 4, 3),
             0x7d => (self.absolute_x(true), 4, 3),
             0x79 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("adc", intermediate_address);
 
-        let intermediate =
-            self.a as i16 + self.system.read_byte(intermediate_address) as i16 + !self.carry as i16;
-        self.overflow = !(-128..=127).contains(&intermediate);
-        self.carry = (intermediate as u16) & 0xff00 != 0;
-        self.a = intermediate as u8;
-
-        self.test_negative(self.a);
-        self.test_zero(self.a);
+        let value = self.system.read_byte(intermediate_address);
+        self.add_with_carry(value);
+        Ok(())
     }
 
     /// SuBtract with Carry
-    fn sbc(&mut self, opcode: u8) {
+    fn sbc(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xe9 => (self.immediate(), 2, 2),
             0xe5 => (self.zero_page(), 3, 2),
@@ -496,25 +1930,21 @@ impl CPU {
             0xed => (self.absolute(), 4, 3),
             0xfd => (self.absolute_x(true), 4, 3),
             0xf9 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("sbc", intermediate_address);
 
-        let intermediate =
-            self.a as i16 - self.system.read_byte(intermediate_address) as i16 - !self.carry as i16;
-        self.overflow = !(-128..=127).contains(&intermediate);
-        self.carry = (intermediate as u16) & 0xff00 != 0;
-        self.a = intermediate as u8;
-
-        self.test_negative(self.a);
-        self.test_zero(self.a);
+        // Subtracting is adding the ones' complement, with the carry as "not borrow"
+        let value = self.system.read_byte(intermediate_address);
+        self.add_with_carry(!value);
+        Ok(())
     }
 
     /// CoMPare accumulator
-    fn cmp(&mut self, opcode: u8) {
+    fn cmp(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xc9 => (self.immediate(), 2, 2),
             0xc5 => (self.zero_page(), 3, 2),
@@ -524,7 +1954,7 @@ impl CPU {
             0xcd => (self.absolute(), 4, 3),
             0xdd => (self.absolute_x(true), 4, 3),
             0xd9 => (self.absolute_y(true), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -535,64 +1965,68 @@ impl CPU {
         self.negative = (intermediate & 0x80) == 0x80;
         self.zero = intermediate == 0;
         self.carry = intermediate >= 0;
+        Ok(())
     }
 
     /// ComPare X register
-    fn cpx(&mut self, opcode: u8) {
+    fn cpx(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
-            0xc0 => (self.immediate(), 2, 2),
-            0xc4 => (self.zero_page(), 3, 2),
-            0xcc => (self.absolute(), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0xe0 => (self.immediate(), 2, 2),
+            0xe4 => (self.zero_page(), 3, 2),
+            0xec => (self.absolute(), 4, 3),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("cpx", intermediate_address);
 
-        let intermediate = self.y as i16 - self.system.read_byte(intermediate_address) as i16;
+        let intermediate = self.x as i16 - self.system.read_byte(intermediate_address) as i16;
         self.negative = intermediate & 0x80 == 0x80;
         self.zero = intermediate == 0;
         self.carry = intermediate >= 0;
+        Ok(())
     }
 
     /// ComPare Y register
-    fn cpy(&mut self, opcode: u8) {
+    fn cpy(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
-            0xe0 => (self.immediate(), 2, 2),
-            0xe4 => (self.zero_page(), 3, 2),
-            0xec => (self.absolute(), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0xc0 => (self.immediate(), 2, 2),
+            0xc4 => (self.zero_page(), 3, 2),
+            0xcc => (self.absolute(), 4, 3),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("cpy", intermediate_address);
 
-        let intermediate = self.x as i16 - self.system.read_byte(intermediate_address) as i16;
+        let intermediate = self.y as i16 - self.system.read_byte(intermediate_address) as i16;
         self.negative = intermediate & 0x80 == 0x80;
         self.zero = intermediate == 0;
         self.carry = intermediate >= 0;
+        Ok(())
     }
 
     /// DECrement memory
-    fn dec(&mut self, opcode: u8) {
+    fn dec(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xc6 => (self.zero_page(), 5, 2),
             0xd6 => (self.zero_page_x(), 6, 2),
             0xce => (self.absolute(), 6, 3),
             0xde => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("dec", intermediate_address);
 
-        let intermediate = self.system.read_byte(intermediate_address) - 1;
+        let intermediate = self.system.read_byte(intermediate_address).wrapping_sub(1);
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     /// DEcrement X
@@ -602,7 +2036,7 @@ impl CPU {
         self.clock += 2;
         self.pc += 1;
 
-        self.x -= 1;
+        self.x = self.x.wrapping_sub(1);
         self.test_negative(self.x);
         self.test_zero(self.x);
     }
@@ -614,39 +2048,40 @@ impl CPU {
         self.clock += 2;
         self.pc += 1;
 
-        self.y -= 1;
+        self.y = self.y.wrapping_sub(1);
         self.test_negative(self.y);
         self.test_zero(self.y);
     }
 
     /// INCrement memory
-    fn inc(&mut self, opcode: u8) {
+    fn inc(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xe6 => (self.zero_page(), 5, 2),
             0xf6 => (self.zero_page_x(), 6, 2),
             0xee => (self.absolute(), 6, 3),
             0xfe => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
         self.debug_opcode_with_address("inc", intermediate_address);
 
-        let intermediate = self.system.read_byte(intermediate_address) + 1;
+        let intermediate = self.system.read_byte(intermediate_address).wrapping_add(1);
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     /// INcrement X
     fn inx(&mut self) {
-        self.debug_opcode("inc");
+        self.debug_opcode("inx");
 
         self.clock += 2;
         self.pc += 1;
 
-        self.x += 1;
+        self.x = self.x.wrapping_add(1);
         self.test_negative(self.x);
         self.test_zero(self.x);
     }
@@ -658,13 +2093,13 @@ impl CPU {
         self.clock += 2;
         self.pc += 1;
 
-        self.y += 1;
+        self.y = self.y.wrapping_add(1);
         self.test_negative(self.y);
         self.test_zero(self.y);
     }
 
     /// Arithmetic Shift Left
-    fn asl(&mut self, opcode: u8) {
+    fn asl(&mut self, opcode: u8) -> CpuResult<()> {
         // Dealing with the accumulator directly doesn't fit the pattern well, so handle separately
         if opcode == 0x0a {
             self.debug_opcode("asl A");
@@ -675,7 +2110,7 @@ impl CPU {
             self.test_zero(self.a);
             self.clock += 2;
             self.pc += 1;
-            return;
+            return Ok(());
         }
 
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
@@ -683,12 +2118,12 @@ impl CPU {
             0x16 => (self.zero_page_x(), 6, 2),
             0x0e => (self.absolute(), 6, 3),
             0x1e => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
-        self.debug_opcode_with_address("asl {}", intermediate_address);
+        self.debug_opcode_with_address("asl", intermediate_address);
 
         let mut intermediate = self.system.read_byte(intermediate_address);
         self.carry = (intermediate & 0x80) == 0x80;
@@ -696,10 +2131,11 @@ impl CPU {
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     /// ROtate Left
-    fn rol(&mut self, opcode: u8) {
+    fn rol(&mut self, opcode: u8) -> CpuResult<()> {
         let carry_value = self.carry as u8;
 
         // Dealing with the accumulator directly doesn't fit the pattern well, so handle separately
@@ -707,12 +2143,12 @@ impl CPU {
             self.debug_opcode("rol A");
 
             self.carry = self.a & 0x80 == 0x80;
-            self.a <<= 1 + carry_value;
+            self.a = (self.a << 1) | carry_value;
             self.test_negative(self.a);
             self.test_zero(self.a);
             self.clock += 2;
             self.pc += 1;
-            return;
+            return Ok(());
         }
 
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
@@ -720,23 +2156,24 @@ impl CPU {
             0x36 => (self.zero_page_x(), 6, 2),
             0x2e => (self.absolute(), 6, 3),
             0x3e => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
-        self.debug_opcode_with_address("rol {}", intermediate_address);
+        self.debug_opcode_with_address("rol", intermediate_address);
 
         let mut intermediate = self.system.read_byte(intermediate_address);
         self.carry = (intermediate & 0x80) == 0x80;
-        intermediate <<= 1 + carry_value;
+        intermediate = (intermediate << 1) | carry_value;
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     ///Logical Shift Right
-    fn lsr(&mut self, opcode: u8) {
+    fn lsr(&mut self, opcode: u8) -> CpuResult<()> {
         // Dealing with the accumulator directly doesn't fit the pattern well, so handle separately
         if opcode == 0x4a {
             self.debug_opcode("lsr A");
@@ -747,7 +2184,7 @@ impl CPU {
             self.test_zero(self.a);
             self.clock += 2;
             self.pc += 1;
-            return;
+            return Ok(());
         }
 
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
@@ -755,12 +2192,12 @@ impl CPU {
             0x56 => (self.zero_page_x(), 6, 2),
             0x4e => (self.absolute(), 6, 3),
             0x5e => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
 
-        self.debug_opcode_with_address("lsr {}", intermediate_address);
+        self.debug_opcode_with_address("lsr", intermediate_address);
 
         let mut intermediate = self.system.read_byte(intermediate_address);
         self.carry = (intermediate & 0x01) == 0x01;
@@ -768,10 +2205,11 @@ impl CPU {
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     /// ROtate Right
-    fn ror(&mut self, opcode: u8) {
+    fn ror(&mut self, opcode: u8) -> CpuResult<()> {
         let carry_value: u8 = if self.carry { 0x80 } else { 0 };
 
         // Dealing with the accumulator directly doesn't fit the pattern well, so handle separately
@@ -779,12 +2217,12 @@ impl CPU {
             self.debug_opcode("ror A");
 
             self.carry = self.a & 0x01 == 0x01;
-            self.a >>= 1;
+            self.a = (self.a >> 1) | carry_value;
             self.test_negative(self.a);
             self.test_zero(self.a);
             self.clock += 2;
             self.pc += 1;
-            return;
+            return Ok(());
         }
 
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
@@ -792,7 +2230,7 @@ impl CPU {
             0x76 => (self.zero_page_x(), 6, 2),
             0x6e => (self.absolute(), 6, 3),
             0x7e => (self.absolute_x(false), 7, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -801,25 +2239,26 @@ impl CPU {
 
         let mut intermediate = self.system.read_byte(intermediate_address);
         self.carry = (intermediate & 0x01) == 0x01;
-        intermediate >>= 1 + carry_value;
+        intermediate = (intermediate >> 1) | carry_value;
         self.test_negative(intermediate);
         self.test_zero(intermediate);
         self.system.write_byte(intermediate_address, intermediate);
+        Ok(())
     }
 
     // Move commands -----------------------------------------------------------------------------
     /// LoaD Accumulator
-    fn lda(&mut self, opcode: u8) {
+    fn lda(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xa9 => (self.immediate(), 2, 2),
             0xa5 => (self.zero_page(), 3, 2),
             0xb5 => (self.zero_page_x(), 4, 2),
             0xad => (self.absolute(), 4, 3),
-            0xbd => (self.absolute_x(true), 6, 3),
-            0xb9 => (self.absolute_y(true), 4, 2),
-            0xa1 => (self.indirect_zero_page_x(), 6, 4),
-            0xb1 => (self.indirect_zero_page_y(true), 6, 2),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0xbd => (self.absolute_x(true), 4, 3),
+            0xb9 => (self.absolute_y(true), 4, 3),
+            0xa1 => (self.indirect_zero_page_x(), 6, 2),
+            0xb1 => (self.indirect_zero_page_y(true), 5, 2),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -831,17 +2270,18 @@ impl CPU {
         self.test_zero(intermediate);
 
         self.a = intermediate;
+        Ok(())
     }
 
     /// LoaD X register
-    fn ldx(&mut self, opcode: u8) {
+    fn ldx(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xa2 => (self.immediate(), 2, 2),
             0xa6 => (self.zero_page(), 3, 2),
             0xb6 => (self.zero_page_y(), 4, 2),
             0xae => (self.absolute(), 4, 3),
-            0xbe => (self.absolute_y(true), 4, 2),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0xbe => (self.absolute_y(true), 4, 3),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -853,17 +2293,18 @@ impl CPU {
         self.test_zero(intermediate);
 
         self.x = intermediate;
+        Ok(())
     }
 
     /// LoaD Y register
-    fn ldy(&mut self, opcode: u8) {
+    fn ldy(&mut self, opcode: u8) -> CpuResult<()> {
         let (intermediate_address, clock_increment, pc_increment) = match opcode {
             0xa0 => (self.immediate(), 2, 2),
             0xa4 => (self.zero_page(), 3, 2),
             0xb4 => (self.zero_page_x(), 4, 2),
-            0x8c => (self.absolute(), 4, 3),
-            0xbc => (self.absolute_x(true), 4, 2),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0xac => (self.absolute(), 4, 3),
+            0xbc => (self.absolute_x(true), 4, 3),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -875,10 +2316,13 @@ impl CPU {
         self.test_zero(intermediate);
 
         self.y = intermediate;
+        Ok(())
     }
 
     /// STore Accumulator
-    fn sta(&mut self, opcode: u8) {
+    fn sta(&mut self, opcode: u8) -> CpuResult<()> {
+        // The indexed modes always take the cycle that loads only take when the index crosses a
+        // page, as the CPU can't undo a write to the wrong page like it can a read
         let (address, clock_increment, pc_increment) = match opcode {
             0x85 => (self.zero_page(), 3, 2),
             0x95 => (self.zero_page_x(), 4, 2),
@@ -887,7 +2331,7 @@ impl CPU {
             0x99 => (self.absolute_y(false), 5, 3),
             0x81 => (self.indirect_zero_page_x(), 6, 2),
             0x91 => (self.indirect_zero_page_y(false), 6, 2),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -895,15 +2339,16 @@ impl CPU {
         self.debug_opcode_with_address("sta", address);
 
         self.system.write_byte(address, self.a);
+        Ok(())
     }
 
     /// STore X register
-    fn stx(&mut self, opcode: u8) {
+    fn stx(&mut self, opcode: u8) -> CpuResult<()> {
         let (address, clock_increment, pc_increment) = match opcode {
             0x86 => (self.zero_page(), 3, 2),
             0x96 => (self.zero_page_y(), 4, 2),
             0x8e => (self.absolute(), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -911,15 +2356,16 @@ impl CPU {
         self.debug_opcode_with_address("stx", address);
 
         self.system.write_byte(address, self.x);
+        Ok(())
     }
 
     /// STore Y register
-    fn sty(&mut self, opcode: u8) {
+    fn sty(&mut self, opcode: u8) -> CpuResult<()> {
         let (address, clock_increment, pc_increment) = match opcode {
             0x84 => (self.zero_page(), 3, 2),
-            0x94 => (self.zero_page_y(), 4, 2),
+            0x94 => (self.zero_page_x(), 4, 2),
             0x8c => (self.absolute(), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -927,6 +2373,7 @@ impl CPU {
         self.debug_opcode_with_address("sty", address);
 
         self.system.write_byte(address, self.y);
+        Ok(())
     }
 
     /// Transfer A to X
@@ -1002,6 +2449,8 @@ impl CPU {
         self.pc += 1;
 
         self.s = self.x;
+        // Games set S on purpose, so this is the new baseline for the stack check
+        self.stack_depth = 0;
     }
 
     /// PuLl Accumulator
@@ -1011,8 +2460,7 @@ impl CPU {
         self.clock += 4;
         self.pc += 1;
 
-        self.s += 1;
-        let intermediate = self.system.read_byte(0x100 + self.s as u16);
+        let intermediate = self.pull_byte();
 
         self.test_negative(intermediate);
         self.test_zero(intermediate);
@@ -1027,28 +2475,60 @@ impl CPU {
         self.clock += 3;
         self.pc += 1;
 
-        self.system.write_byte(0x100 + self.s as u16, self.a);
-        self.s -= 1;
+        self.push_byte(self.a);
     }
 
     /// Pull status from System
     fn pull_status(&mut self) {
-        self.s += 1;
-        let intermediate = self.system.read_byte(0x100 + self.s as u16);
+        let intermediate = self.pull_byte();
+        self.set_status(intermediate);
+    }
 
-        self.negative = intermediate & 0x80 == 0x80;
-        self.overflow = intermediate & 0x40 == 0x40;
-        self.decimal = intermediate & 0x08 == 0x08;
-        self.interrupt_disable = intermediate & 0x04 == 0x04;
-        self.zero = intermediate & 0x02 == 0x02;
-        self.carry = intermediate & 0x01 == 0x01;
+    /// Set the flags from a status byte, ignoring the B flag and unused bit
+    fn set_status(&mut self, status: u8) {
+        self.negative = status & 0x80 == 0x80;
+        self.overflow = status & 0x40 == 0x40;
+        self.decimal = status & 0x08 == 0x08;
+        self.interrupt_disable = status & 0x04 == 0x04;
+        self.zero = status & 0x02 == 0x02;
+        self.carry = status & 0x01 == 0x01;
+    }
+
+    /// Pull a word pushed by `push_word`
+    fn pull_word(&mut self) -> u16 {
+        let low = self.pull_byte();
+        let high = self.pull_byte();
+        u16::from_le_bytes([low, high])
+    }
+
+    /// Push a byte onto the stack, which wraps around within page one
+    fn push_byte(&mut self, value: u8) {
+        self.system.write_byte(0x100 + self.s as u16, value);
+        self.stack_depth = self.stack_depth.wrapping_add(1);
+        if self.s == 0x00 {
+            self.stack_wrapped(true);
+        }
+        self.s = self.s.wrapping_sub(1);
     }
 
-    /// Pull program counter
-    fn pull_pc(&mut self) {
-        self.s += 1;
-        self.system.read_word(0x100u16 + self.s as u16);
-        self.s += 1;
+    /// Pull a byte from the stack, which wraps around within page one
+    fn pull_byte(&mut self) -> u8 {
+        self.stack_depth = self.stack_depth.wrapping_sub(1);
+        if self.s == 0xff {
+            self.stack_wrapped(false);
+        }
+        self.s = self.s.wrapping_add(1);
+        self.system.read_byte(0x100 + self.s as u16)
+    }
+
+    fn stack_wrapped(&mut self, overflow: bool) {
+        if self.stack_check {
+            self.stack_wrap = Some(StackWrap {
+                pc: self.instruction_pc(),
+                overflow,
+                depth: self.stack_depth,
+            });
+        }
     }
 
     /// PuLl Processor status
@@ -1061,47 +2541,24 @@ impl CPU {
         self.pull_status();
     }
 
-    /// Push status to System
-    fn push_status(&mut self) {
-        let mut intermediate: u8 = 0;
-        if self.negative {
-            intermediate |= 0x80;
-        }
-        if self.overflow {
-            intermediate |= 0x40;
-        }
-        intermediate |= 0x02; // always 1
-        if self.break_flag {
-            intermediate |= 0x10;
-        }
-        if self.decimal {
-            intermediate |= 0x08;
-        }
-        if self.interrupt_disable {
-            intermediate |= 0x04;
-        }
-        if self.zero {
-            intermediate |= 0x02;
-        }
-        if self.carry {
-            intermediate |= 0x01;
-        }
-
-        self.system.write_byte(0x100 + self.s as u16, intermediate);
-        self.s -= 1;
+    /// Push the status to the stack. The B flag isn't a real flag, only a bit in the pushed copy:
+    /// set by `BRK` and `PHP`, and clear for NMIs and IRQs, which is how a handler shared by IRQs
+    /// and `BRK` tells them apart.
+    ///
+    /// See: <https://www.nesdev.org/wiki/Status_flags#The_B_flag>
+    fn push_status(&mut self, break_flag: bool) {
+        let b = if break_flag { 0x10 } else { 0 };
+        self.push_byte(self.status() | b);
     }
 
     /// Push word to System
     fn push_word(&mut self, value: u16) {
         // TODO: What order should this push the bytes?
         let first_byte = (value >> 8) as u8;
-        self.system.write_byte(0x100u16 + self.s as u16, first_byte);
-        self.s -= 1;
+        self.push_byte(first_byte);
 
         let second_byte = (value & 0xff) as u8;
-        self.system
-            .write_byte(0x100u16 + self.s as u16, second_byte);
-        self.s -= 1;
+        self.push_byte(second_byte);
     }
 
     /// PusH Processor status
@@ -1111,7 +2568,7 @@ impl CPU {
         self.clock += 3;
         self.pc += 1;
 
-        self.push_status();
+        self.push_status(true);
     }
 
     // Jump/Flag commands ------------------------------------------------------------------------
@@ -1124,8 +2581,7 @@ impl CPU {
         self.pc += 2;
 
         let prev_page = self.pc >> 8;
-        // TODO: test this
-        self.pc = (self.pc as i16 + address as i16) as u16;
+        self.pc = self.pc.wrapping_add_signed(address as i16);
 
         self.debug_opcode_with_address(opcode_name, self.pc);
 
@@ -1141,8 +2597,13 @@ impl CPU {
         if condition {
             self.branch(opcode_name);
         } else {
+            let offset = self.system.read_byte(self.immediate()) as i8;
             self.clock += 2;
             self.pc += 2;
+
+            // Traced with the target, as when the branch is taken
+            let target = self.pc.wrapping_add_signed(offset as i16);
+            self.debug_opcode_with_address(opcode_name, target);
         }
     }
 
@@ -1192,12 +2653,33 @@ impl CPU {
 
         self.clock += 7;
 
-        self.push_word(self.pc);
+        // The byte after BRK is skipped over on return, so it can be used to say why it broke
+        self.push_word(self.pc.wrapping_add(2));
+        self.push_status(true);
 
         let break_address = 0xfffe;
         self.pc = self.system.read_word(break_address);
-        self.break_flag = true;
         self.interrupt_disable = true;
+        self.last_interrupt = Some(Interrupt::Brk);
+    }
+
+    /// Take an NMI or IRQ, between instructions. Like BRK, but the status is pushed with the B
+    /// flag clear.
+    ///
+    /// See: <https://www.nesdev.org/wiki/CPU_interrupts>
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        self.clock += 7;
+
+        self.push_word(self.pc);
+        self.push_status(false);
+
+        let vector = match interrupt {
+            Interrupt::Nmi => 0xfffa,
+            Interrupt::Irq | Interrupt::Brk => 0xfffe,
+        };
+        self.pc = self.system.read_word(vector);
+        self.interrupt_disable = true;
+        self.last_interrupt = Some(interrupt);
     }
 
     /// ReTurn from Interrupt
@@ -1206,7 +2688,8 @@ impl CPU {
 
         self.clock += 6;
         self.pull_status();
-        self.pull_pc();
+        // Unlike RTS, the pushed address is the next instruction itself
+        self.pc = self.pull_word();
     }
 
     /// Jump to SubRoutine
@@ -1226,29 +2709,31 @@ impl CPU {
         self.debug_opcode("rts");
 
         self.clock += 6;
-        self.pull_pc()
+        // JSR pushes the address of its last byte, so the next instruction is one on
+        self.pc = self.pull_word().wrapping_add(1);
     }
 
     /// JuMP
-    fn jmp(&mut self, opcode: u8) {
+    fn jmp(&mut self, opcode: u8) -> CpuResult<()> {
         let (address, clock_increment) = match opcode {
-            0x24 => (self.absolute(), 3),
-            0x2c => (self.system.read_word(self.absolute()), 5), // Indirect absolute (ind)
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            0x4c => (self.absolute(), 3),
+            0x6c => (self.indirect_absolute(), 5),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
 
         self.debug_opcode_with_address("jmp", address);
 
         self.pc = address;
+        Ok(())
     }
 
     /// test BITs
-    fn bit(&mut self, opcode: u8) {
+    fn bit(&mut self, opcode: u8) -> CpuResult<()> {
         let (address, clock_increment, pc_increment) = match opcode {
             0x24 => (self.zero_page(), 3, 2),
             0x2c => (self.absolute(), 4, 3),
-            _ => panic!("Unknown opcode {:02x}", opcode),
+            _ => return Err(self.misdispatched(opcode)),
         };
         self.clock += clock_increment;
         self.pc += pc_increment;
@@ -1259,6 +2744,7 @@ impl CPU {
         self.zero = value & self.a == 0;
         self.negative = value & 0x80 == 0x80;
         self.overflow = value & 0x40 == 0x40;
+        Ok(())
     }
 
     /// CLear Carry
@@ -1332,3 +2818,905 @@ impl CPU {
         self.pc += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Difference, StateValue};
+    use crate::testing;
+
+    #[test]
+    fn unknown_opcode_policy_error() {
+        let mut cpu = testing::cpu("lda #1\n.byte $02");
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Error);
+        testing::run(&mut cpu, 1);
+        let result = cpu.run_opcode();
+        assert!(matches!(
+            result,
+            Err(CpuError::UnknownOpcode {
+                opcode: 0x02,
+                pc: 0x8002
+            })
+        ));
+        assert_eq!(cpu.pc(), 0x8002);
+    }
+
+    #[test]
+    fn unknown_opcode_policy_nop() {
+        // Skipped as a 2 byte instruction, going by the opcode's addressing mode bits
+        let mut cpu = testing::cpu(".byte $02, $00\nlda #1");
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Nop);
+        let trace = testing::SharedBuffer::default();
+        cpu.set_trace_writer(Box::new(trace.clone()), None);
+        testing::run(&mut cpu, 2);
+        cpu.flush_trace().unwrap();
+        assert_eq!(cpu.pc(), 0x8004);
+        assert_eq!(cpu.a, 1);
+
+        // The warning goes with the trace, rather than to stderr
+        assert!(trace
+            .text()
+            .contains("warning: unknown opcode 02 at $8000, skipping it as a nop\n"));
+    }
+
+    #[test]
+    fn handler_rejects_other_opcodes() {
+        let mut cpu = testing::cpu("nop");
+        assert!(matches!(
+            cpu.ora(0x02),
+            Err(CpuError::UnknownOpcode { opcode: 0x02, .. })
+        ));
+        assert!(matches!(
+            cpu.jmp(0x24),
+            Err(CpuError::UnknownOpcode { opcode: 0x24, .. })
+        ));
+        assert_eq!((cpu.pc(), cpu.cycles()), (0x8000, 0));
+    }
+
+    /// Every official opcode, with its mnemonic, how far it moves the PC (0 for jumps) and its
+    /// cycles when run by `official_opcodes` (no page crossed, and branches on the flags at
+    /// power on, which only has I set)
+    ///
+    /// See: <https://www.nesdev.org/obelisk-6502-guide/reference.html>
+    #[rustfmt::skip]
+    const OFFICIAL_OPCODES: [(u8, &str, u16, u64); 151] = [
+        (0x69, "adc", 2, 2), (0x65, "adc", 2, 3), (0x75, "adc", 2, 4), (0x6d, "adc", 3, 4),
+        (0x7d, "adc", 3, 4), (0x79, "adc", 3, 4), (0x61, "adc", 2, 6), (0x71, "adc", 2, 5),
+        (0x29, "and", 2, 2), (0x25, "and", 2, 3), (0x35, "and", 2, 4), (0x2d, "and", 3, 4),
+        (0x3d, "and", 3, 4), (0x39, "and", 3, 4), (0x21, "and", 2, 6), (0x31, "and", 2, 5),
+        (0x0a, "asl", 1, 2), (0x06, "asl", 2, 5), (0x16, "asl", 2, 6), (0x0e, "asl", 3, 6),
+        (0x1e, "asl", 3, 7),
+        (0x90, "bcc", 0, 3), (0xb0, "bcs", 2, 2), (0xf0, "beq", 2, 2), (0x30, "bmi", 2, 2),
+        (0xd0, "bne", 0, 3), (0x10, "bpl", 0, 3), (0x50, "bvc", 0, 3), (0x70, "bvs", 2, 2),
+        (0x24, "bit", 2, 3), (0x2c, "bit", 3, 4),
+        (0x00, "brk", 0, 7),
+        (0x18, "clc", 1, 2), (0xd8, "cld", 1, 2), (0x58, "cli", 1, 2), (0xb8, "clv", 1, 2),
+        (0xc9, "cmp", 2, 2), (0xc5, "cmp", 2, 3), (0xd5, "cmp", 2, 4), (0xcd, "cmp", 3, 4),
+        (0xdd, "cmp", 3, 4), (0xd9, "cmp", 3, 4), (0xc1, "cmp", 2, 6), (0xd1, "cmp", 2, 5),
+        (0xe0, "cpx", 2, 2), (0xe4, "cpx", 2, 3), (0xec, "cpx", 3, 4),
+        (0xc0, "cpy", 2, 2), (0xc4, "cpy", 2, 3), (0xcc, "cpy", 3, 4),
+        (0xc6, "dec", 2, 5), (0xd6, "dec", 2, 6), (0xce, "dec", 3, 6), (0xde, "dec", 3, 7),
+        (0xca, "dex", 1, 2), (0x88, "dey", 1, 2),
+        (0x49, "eor", 2, 2), (0x45, "eor", 2, 3), (0x55, "eor", 2, 4), (0x4d, "eor", 3, 4),
+        (0x5d, "eor", 3, 4), (0x59, "eor", 3, 4), (0x41, "eor", 2, 6), (0x51, "eor", 2, 5),
+        (0xe6, "inc", 2, 5), (0xf6, "inc", 2, 6), (0xee, "inc", 3, 6), (0xfe, "inc", 3, 7),
+        (0xe8, "inx", 1, 2), (0xc8, "iny", 1, 2),
+        (0x4c, "jmp", 0, 3), (0x6c, "jmp", 0, 5),
+        (0x20, "jsr", 0, 6),
+        (0xa9, "lda", 2, 2), (0xa5, "lda", 2, 3), (0xb5, "lda", 2, 4), (0xad, "lda", 3, 4),
+        (0xbd, "lda", 3, 4), (0xb9, "lda", 3, 4), (0xa1, "lda", 2, 6), (0xb1, "lda", 2, 5),
+        (0xa2, "ldx", 2, 2), (0xa6, "ldx", 2, 3), (0xb6, "ldx", 2, 4), (0xae, "ldx", 3, 4),
+        (0xbe, "ldx", 3, 4),
+        (0xa0, "ldy", 2, 2), (0xa4, "ldy", 2, 3), (0xb4, "ldy", 2, 4), (0xac, "ldy", 3, 4),
+        (0xbc, "ldy", 3, 4),
+        (0x4a, "lsr", 1, 2), (0x46, "lsr", 2, 5), (0x56, "lsr", 2, 6), (0x4e, "lsr", 3, 6),
+        (0x5e, "lsr", 3, 7),
+        (0xea, "nop", 1, 2),
+        (0x09, "ora", 2, 2), (0x05, "ora", 2, 3), (0x15, "ora", 2, 4), (0x0d, "ora", 3, 4),
+        (0x1d, "ora", 3, 4), (0x19, "ora", 3, 4), (0x01, "ora", 2, 6), (0x11, "ora", 2, 5),
+        (0x48, "pha", 1, 3), (0x08, "php", 1, 3), (0x68, "pla", 1, 4), (0x28, "plp", 1, 4),
+        (0x2a, "rol", 1, 2), (0x26, "rol", 2, 5), (0x36, "rol", 2, 6), (0x2e, "rol", 3, 6),
+        (0x3e, "rol", 3, 7),
+        (0x6a, "ror", 1, 2), (0x66, "ror", 2, 5), (0x76, "ror", 2, 6), (0x6e, "ror", 3, 6),
+        (0x7e, "ror", 3, 7),
+        (0x40, "rti", 0, 6), (0x60, "rts", 0, 6),
+        (0xe9, "sbc", 2, 2), (0xe5, "sbc", 2, 3), (0xf5, "sbc", 2, 4), (0xed, "sbc", 3, 4),
+        (0xfd, "sbc", 3, 4), (0xf9, "sbc", 3, 4), (0xe1, "sbc", 2, 6), (0xf1, "sbc", 2, 5),
+        (0x38, "sec", 1, 2), (0xf8, "sed", 1, 2), (0x78, "sei", 1, 2),
+        (0x85, "sta", 2, 3), (0x95, "sta", 2, 4), (0x8d, "sta", 3, 4), (0x9d, "sta", 3, 5),
+        (0x99, "sta", 3, 5), (0x81, "sta", 2, 6), (0x91, "sta", 2, 6),
+        (0x86, "stx", 2, 3), (0x96, "stx", 2, 4), (0x8e, "stx", 3, 4),
+        (0x84, "sty", 2, 3), (0x94, "sty", 2, 4), (0x8c, "sty", 3, 4),
+        (0xaa, "tax", 1, 2), (0xa8, "tay", 1, 2), (0xba, "tsx", 1, 2), (0x8a, "txa", 1, 2),
+        (0x9a, "txs", 1, 2), (0x98, "tya", 1, 2),
+    ];
+
+    #[test]
+    fn official_opcodes() {
+        for (opcode, mnemonic, length, cycles) in OFFICIAL_OPCODES {
+            // Operands of $10 and $0210, which are RAM
+            let mut cpu = CPU::from_bytes(testing::nrom(&[opcode, 0x10, 0x02]), false).unwrap();
+            let trace = cpu
+                .run_opcode_with_trace()
+                .unwrap_or_else(|err| panic!("{:02x} failed: {}", opcode, err));
+            assert_eq!(trace.mnemonic, mnemonic, "mnemonic of {:02x}", opcode);
+            assert_eq!(cpu.cycles(), cycles, "cycles of {:02x}", opcode);
+            if length > 0 {
+                assert_eq!(cpu.pc(), 0x8000 + length, "length of {:02x}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let mut cpu = testing::cpu("lda #$05\nsta $10\nlda ($10),y\nloop: jmp loop");
+        let trace = cpu.run_opcode_with_trace().unwrap();
+        assert_eq!(
+            trace,
+            OpcodeTrace {
+                pc: 0x8000,
+                opcode: 0xa9,
+                operand_bytes: [0x05, 0x85],
+                mnemonic: "lda".into(),
+                operand: "#$05".into(),
+                a: 0,
+                x: 0,
+                y: 0,
+                s: 0xfd,
+                status: 0x24,
+                cycles: 0,
+                ppu_scanline: 0,
+                ppu_dot: 0,
+                ppu_frame: 0,
+            }
+        );
+        let lines: Vec<String> = (0..3)
+            .map(|_| cpu.run_opcode_with_trace().unwrap().nestest_line())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "8002  85 10     STA $10                         A:05 X:00 Y:00 P:24 SP:FD PPU:  0,  6 CYC:2",
+                "8004  B1 10     LDA ($10),Y                     A:05 X:00 Y:00 P:24 SP:FD PPU:  0, 15 CYC:5",
+                "8006  4C 06 80  JMP $8006                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 30 CYC:10",
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_file() {
+        let path = std::env::temp_dir().join(format!("rusty_nes_{}.trace", std::process::id()));
+        let mut cpu = testing::cpu("lda #$05\nsta $10\nlda ($10),y\nloop: jmp loop");
+        cpu.set_trace_file(&path, Some(3)).unwrap();
+        testing::run(&mut cpu, 4);
+        cpu.flush_trace().unwrap();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // nestest's format, and only as many lines as the limit
+        assert_eq!(
+            trace.lines().collect::<Vec<_>>(),
+            [
+                "8000  A9 05     LDA #$05                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+                "8002  85 10     STA $10                         A:05 X:00 Y:00 P:24 SP:FD PPU:  0,  6 CYC:2",
+                "8004  B1 10     LDA ($10),Y                     A:05 X:00 Y:00 P:24 SP:FD PPU:  0, 15 CYC:5",
+            ]
+        );
+    }
+
+    #[test]
+    fn opcode_counts() {
+        let mut cpu = testing::cpu(
+            "ldx #$03
+             loop: dex
+             bne loop
+             end: jmp end",
+        );
+        assert_eq!(cpu.opcode_counts(), None);
+        cpu.set_opcode_counting(true);
+        testing::run(&mut cpu, 9);
+
+        let counts = cpu.opcode_counts().unwrap();
+        let executed: Vec<(usize, u64)> = (0..256)
+            .filter(|&opcode| counts[opcode] > 0)
+            .map(|opcode| (opcode, counts[opcode]))
+            .collect();
+        assert_eq!(executed, [(0x4c, 2), (0xa2, 1), (0xca, 3), (0xd0, 3)]);
+
+        // Starting again resets them
+        cpu.set_opcode_counting(true);
+        assert!(cpu.opcode_counts().unwrap().iter().all(|&count| count == 0));
+        cpu.set_opcode_counting(false);
+        assert_eq!(cpu.opcode_counts(), None);
+    }
+
+    #[test]
+    fn reset_cycles() {
+        let mut cpu = testing::cpu("lda #$01\nsta $0200\ninc $0200\nnop");
+        testing::run(&mut cpu, 2);
+        assert_eq!(cpu.cycles(), 2 + 4);
+
+        cpu.reset_cycles();
+        assert_eq!(cpu.cycles(), 0);
+        testing::run(&mut cpu, 2);
+        assert_eq!(cpu.cycles(), 6 + 2);
+        // Only the count restarts, not the instructions
+        assert_eq!(cpu.instructions(), 4);
+        assert_eq!(cpu.clock, 14);
+    }
+
+    #[test]
+    fn decimal_flag_round_trip() {
+        let mut cpu = testing::cpu("sed\nphp\ncld\nplp");
+        testing::run(&mut cpu, 3);
+        // The pushed copy has D, as well as B and the unused bit, which PHP always sets
+        assert_eq!(cpu.peek_byte(0x01fd), 0x3c);
+        assert!(!cpu.decimal);
+        testing::run(&mut cpu, 1);
+        assert!(cpu.decimal);
+        assert_eq!(cpu.status() & 0x08, 0x08);
+
+        // And through an interrupt handler which clears it
+        let mut cpu = testing::cpu(
+            "sed
+             brk
+             .byte $00
+             done: jmp done
+             handler: cld
+             rti
+             .org $fffc
+             .word $8000, handler",
+        );
+        testing::run(&mut cpu, 3);
+        assert!(!cpu.decimal);
+        testing::run(&mut cpu, 1);
+        assert!(cpu.decimal);
+        assert_eq!(cpu.pc(), 0x8003);
+    }
+
+    /// Run `source`, which ends by writing `$4014`, then the DMA, returning how many cycles the
+    /// DMA took
+    fn oam_dma_cycles(source: &str) -> u64 {
+        let mut cpu = testing::cpu(source);
+        for i in 0..=255 {
+            cpu.poke_byte(0x0200 + i, i as u8 ^ 0xa5);
+        }
+        while !cpu.dma_stalled() {
+            cpu.run_opcode().unwrap();
+        }
+        let start = cpu.cycles();
+        while cpu.dma_stalled() {
+            cpu.run_opcode().unwrap();
+        }
+        for i in [0, 1, 0x80, 0xff] {
+            cpu.poke_byte(0x2003, i);
+            assert_eq!(cpu.peek_byte(0x2004), i ^ 0xa5, "OAM byte {i:02x}");
+        }
+        cpu.cycles() - start
+    }
+
+    #[test]
+    fn oam_dma() {
+        // An extra cycle to line up with a read when the DMA starts on an odd cycle
+        assert_eq!(oam_dma_cycles("ldx #2\nstx $4014"), 513);
+        assert_eq!(oam_dma_cycles("ldx $ff\nldx #2\nstx $4014"), 514);
+    }
+
+    #[test]
+    fn dmc_dma_stall() {
+        // The fastest rate, which fetches a byte every 432 cycles, with OAM DMA running nearly
+        // all the time, so that some of the fetches happen during it
+        let mut cpu = testing::cpu(
+            "lda #$0f
+             sta $4010
+             lda #0
+             sta $4012
+             lda #$ff
+             sta $4013
+             lda #$10
+             sta $4015
+             ldx #2
+             loop: stx $4014
+             jmp loop",
+        );
+        let mut stalls = Vec::new();
+        while !stalls.iter().any(|&(during_oam_dma, _)| during_oam_dma) {
+            assert!(cpu.cycles() < 4000, "stalled {stalls:?}");
+            if !cpu.system.dmc_dma_pending() {
+                cpu.run_opcode().unwrap();
+                continue;
+            }
+            let (start, pc, oam_dma_stall) = (cpu.cycles(), cpu.pc(), cpu.oam_dma_stall);
+            cpu.run_opcode().unwrap();
+            // The fetch runs in place of an instruction or a cycle of the OAM DMA
+            assert_eq!((cpu.pc(), cpu.oam_dma_stall), (pc, oam_dma_stall));
+            stalls.push((oam_dma_stall > 0, cpu.cycles() - start));
+        }
+        // Four cycles, except that the OAM DMA has already halted the CPU
+        let (during_oam_dma, before) = stalls.split_last().unwrap();
+        assert_eq!(*during_oam_dma, (true, 2));
+        assert!(!before.is_empty());
+        assert!(
+            before.iter().all(|&stall| stall == (false, 4)),
+            "{stalls:?}"
+        );
+    }
+
+    /// Cycles taken by the instruction `source` ends with, with X and Y set to `index` and the
+    /// pointer at `$00` to `$02F0`
+    fn store_cycles(source: &str, index: u8) -> u64 {
+        let mut cpu = testing::cpu(&format!(
+            "ldx #{index}\nldy #{index}\nlda #$f0\nsta $00\nlda #$02\nsta $01\n{source}"
+        ));
+        testing::run(&mut cpu, 6);
+        let start = cpu.cycles();
+        testing::run(&mut cpu, 1);
+        cpu.cycles() - start
+    }
+
+    #[test]
+    fn store_page_cross_cycles() {
+        // Stores always take the cycle that reads would only take when the index crosses a page
+        for (source, cycles) in [("sta $02f0,x", 5), ("sta $02f0,y", 5), ("sta ($00),y", 6)] {
+            for index in [0x01, 0x20] {
+                assert_eq!(store_cycles(source, index), cycles, "{source} with {index}");
+            }
+        }
+        // Unlike loads
+        assert_eq!(store_cycles("lda $02f0,x", 0x01), 4);
+        assert_eq!(store_cycles("lda $02f0,x", 0x20), 5);
+        assert_eq!(store_cycles("lda ($00),y", 0x20), 6);
+
+        // And the rest of STA's addressing modes
+        for (source, cycles) in [
+            ("sta $10", 3),
+            ("sta $10,x", 4),
+            ("sta $0210", 4),
+            ("sta ($00,x)", 6),
+        ] {
+            assert_eq!(store_cycles(source, 0x20), cycles, "{source}");
+        }
+    }
+
+    #[test]
+    fn uninit_reads() {
+        // $0300 is read before it's written, twice, and $0301 after
+        let mut cpu = testing::cpu(
+            "lda $0300
+             sta $0301
+             lda $0301
+             lda $0300
+             lda $6010
+             lda $2002",
+        );
+        cpu.set_uninit_check(true);
+        let trace = testing::SharedBuffer::default();
+        cpu.set_trace_writer(Box::new(trace.clone()), None);
+        testing::run(&mut cpu, 6);
+        cpu.flush_trace().unwrap();
+
+        // Each address is reported once, including PRG RAM but not registers
+        let reads = [
+            UninitRead {
+                address: 0x0300,
+                pc: 0x8000,
+            },
+            UninitRead {
+                address: 0x6010,
+                pc: 0x800c,
+            },
+        ];
+        assert_eq!(cpu.uninit_reads(), reads);
+        let warnings: Vec<String> = trace
+            .text()
+            .lines()
+            .filter(|line| line.starts_with("warning"))
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "warning: read of uninitialized $0300 at $8000",
+                "warning: read of uninitialized $6010 at $800c"
+            ]
+        );
+
+        // Without the check, nothing is collected
+        let mut cpu = testing::cpu("lda $0300");
+        testing::run(&mut cpu, 1);
+        assert_eq!(cpu.uninit_reads(), []);
+    }
+
+    #[test]
+    fn brk_pushes() {
+        let mut cpu = testing::cpu(
+            "lda #0
+             php
+             brk
+             .byte $ff
+             done: jmp done
+             handler: rti
+             .org $fffc
+             .word $8000, handler",
+        );
+        testing::run(&mut cpu, 3);
+        // The address after BRK's padding byte, and the status with B set, as PHP pushes it
+        assert_eq!(cpu.peek_byte(0x01fd), 0x36);
+        assert_eq!(cpu.peek_byte(0x01fc), 0x80);
+        assert_eq!(cpu.peek_byte(0x01fb), 0x05);
+        assert_eq!(cpu.peek_byte(0x01fa), 0x36);
+        // B isn't a real flag, so it isn't in the status itself
+        assert_eq!(cpu.status(), 0x26);
+        testing::run(&mut cpu, 1);
+        assert_eq!(cpu.pc(), 0x8005);
+
+        // Interrupts push the status with B clear
+        let mut cpu = testing::cpu(
+            "lda #0
+             sta $e001
+             cli
+             loop: jmp loop
+             handler: jmp handler
+             .org $fffc
+             .word $8000, handler",
+        );
+        cpu.system.set_mapper(Box::new(testing::IrqMapper::new()));
+        testing::run(&mut cpu, 5);
+        assert_eq!(cpu.pc(), 0x8009);
+        assert_eq!(cpu.peek_byte(0x01fb) & 0x30, 0x20);
+    }
+
+    #[test]
+    fn irq_returns_to_interrupted_instruction() {
+        let mut cpu = testing::cpu(
+            "sta $e001
+             cli
+             inx
+             inx
+             inx
+             loop: jmp loop
+             handler: sta $e000
+             rti
+             .org $fffc
+             .word $8000, handler",
+        );
+        cpu.system.set_mapper(Box::new(testing::IrqMapper::new()));
+        while cpu.pc() != 0x800a {
+            testing::run(&mut cpu, 1);
+        }
+        let interrupted = u16::from_le_bytes([cpu.peek_byte(0x01fc), cpu.peek_byte(0x01fd)]);
+        assert!(
+            (0x8004..=0x8006).contains(&interrupted),
+            "{interrupted:04x}"
+        );
+
+        // RTI doesn't add 1 to the address like RTS, so every INX runs once
+        testing::run(&mut cpu, 2);
+        assert_eq!(cpu.pc(), interrupted);
+        while cpu.pc() != 0x8007 {
+            testing::run(&mut cpu, 1);
+        }
+        assert_eq!(cpu.x, 3);
+    }
+
+    /// Run the instruction at the PC, returning how many cycles it took
+    fn cycles_of_next(cpu: &mut CPU) -> u64 {
+        let start = cpu.cycles();
+        testing::run(cpu, 1);
+        cpu.cycles() - start
+    }
+
+    #[test]
+    fn pull_instructions() {
+        let mut cpu = testing::cpu("lda #$00\npha\nlda #$80\npha\nlda #$01\npla\npla");
+        testing::run(&mut cpu, 5);
+        assert_eq!(cpu.s, 0xfb);
+        assert_eq!(cycles_of_next(&mut cpu), 4);
+        assert_eq!(
+            (cpu.a, cpu.s, cpu.negative, cpu.zero),
+            (0x80, 0xfc, true, false)
+        );
+        assert_eq!(cycles_of_next(&mut cpu), 4);
+        assert_eq!(
+            (cpu.a, cpu.s, cpu.negative, cpu.zero),
+            (0x00, 0xfd, false, true)
+        );
+
+        // Every flag comes back, but B and bit 5 of the pulled byte are ignored
+        for (pushed, status) in [(0xff, 0xef), (0xcf, 0xef), (0x30, 0x20), (0x00, 0x20)] {
+            let mut cpu = testing::cpu(&format!("lda #{pushed}\npha\nlda #$7f\nplp"));
+            testing::run(&mut cpu, 3);
+            assert_eq!(cycles_of_next(&mut cpu), 4);
+            assert_eq!(cpu.status(), status, "pulled {pushed:02x}");
+            assert_eq!((cpu.a, cpu.s), (0x7f, 0xfd));
+        }
+        let mut cpu = testing::cpu("lda #$c3\npha\nplp");
+        testing::run(&mut cpu, 3);
+        assert_eq!(
+            (cpu.negative, cpu.overflow, cpu.decimal),
+            (true, true, false)
+        );
+        assert_eq!(
+            (cpu.interrupt_disable, cpu.zero, cpu.carry),
+            (false, true, true)
+        );
+    }
+
+    #[test]
+    fn stop_on_brk() {
+        let mut cpu = testing::cpu("lda #1\nbrk\n.byte $00\nlda #2");
+        cpu.set_stop_on_brk(true);
+        testing::run(&mut cpu, 1);
+        let cycles = cpu.cycles();
+        assert!(matches!(
+            cpu.run_opcode(),
+            Err(CpuError::Break { pc: 0x8002 })
+        ));
+        // Stopped at the BRK, without running it
+        assert_eq!((cpu.pc(), cpu.s, cpu.a), (0x8002, 0xfd, 1));
+        assert_eq!(cpu.cycles(), cycles);
+        assert!(matches!(
+            cpu.run_frame(),
+            Err(CpuError::Break { pc: 0x8002 })
+        ));
+
+        // Otherwise it goes through the IRQ vector as usual
+        cpu.set_stop_on_brk(false);
+        testing::run(&mut cpu, 1);
+        assert_eq!((cpu.pc(), cpu.s), (0xeaea, 0xfa));
+    }
+
+    #[test]
+    fn diff_states_names_changed_field() {
+        let mut cpu = testing::cpu("lda #1");
+        testing::run(&mut cpu, 1);
+        let before = cpu.state_fields();
+        assert!(crate::diff_states(&before, &cpu.state_fields()).is_empty());
+
+        cpu.x = 0x42;
+        let differences = crate::diff_states(&before, &cpu.state_fields());
+        assert_eq!(
+            differences,
+            vec![Difference::Value {
+                field: "cpu.x".into(),
+                a: StateValue::U8(0),
+                b: StateValue::U8(0x42),
+            }]
+        );
+
+        // A byte of RAM is named by its field and offset, including when read back from a file
+        cpu.x = 0;
+        cpu.poke_byte(0x0234, 0x99);
+        let after = cpu.state_file_fields(&cpu.save_state_bytes()).unwrap();
+        let differences = crate::diff_states(&before, &after);
+        assert_eq!(
+            differences,
+            vec![Difference::Bytes {
+                field: "scratch_ram".into(),
+                changes: vec![(0x234, 0, 0x99)],
+            }]
+        );
+        assert_eq!(differences[0].field(), "scratch_ram");
+    }
+
+    #[test]
+    fn reset_moves_stack_down() {
+        let mut cpu = testing::cpu("cli\nlda #1\nldx #2");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.s, cpu.interrupt_disable), (0xfd, false));
+
+        cpu.reset();
+        assert_eq!((cpu.s, cpu.interrupt_disable), (0xfa, true));
+        assert_eq!((cpu.pc(), cpu.a, cpu.x), (0x8000, 1, 2));
+        cpu.reset();
+        assert_eq!((cpu.s, cpu.interrupt_disable), (0xf7, true));
+        assert_eq!((cpu.pc(), cpu.a, cpu.x), (0x8000, 1, 2));
+    }
+
+    #[test]
+    fn zero_page_pointer_wrap() {
+        // A pointer at $FF takes its high byte from $00, not $0100
+        let setup = "lda #$34
+                     sta $ff
+                     lda #$12
+                     sta $00
+                     lda #$99
+                     sta $0100
+                     lda #$56
+                     sta $0234
+                     lda #$00";
+        let mut cpu = testing::cpu(&format!("{setup}\nldx #0\nlda ($ff,x)"));
+        testing::run(&mut cpu, 11);
+        assert_eq!(cpu.a, 0x56);
+
+        // As does one at $FF after adding X
+        let mut cpu = testing::cpu(&format!("{setup}\nldx #$80\nlda ($7f,x)"));
+        testing::run(&mut cpu, 11);
+        assert_eq!(cpu.a, 0x56);
+
+        // And for (zp),Y
+        let mut cpu = testing::cpu(&format!("{setup}\nldy #0\nlda ($ff),y"));
+        testing::run(&mut cpu, 11);
+        assert_eq!(cpu.a, 0x56);
+    }
+
+    /// Run `source` with jams as errors for up to `frames` frames, returning the first error
+    /// and the frame it happened on
+    fn run_until_jammed(source: &str, frames: u64) -> Option<(CpuError, u64)> {
+        let mut cpu = testing::cpu(source);
+        cpu.set_jam_policy(JamPolicy::Error);
+        (0..frames).find_map(|_| cpu.run_frame().err().map(|err| (err, cpu.frame_count())))
+    }
+
+    #[test]
+    fn jams() {
+        // Nothing can leave the loop with IRQs off and NMIs disabled
+        let (err, frame) = run_until_jammed("sei\nloop: jmp loop", 100).unwrap();
+        assert!(matches!(
+            err,
+            CpuError::Jammed {
+                kind: JamKind::SelfLoop,
+                pc: 0x8001
+            }
+        ));
+        assert!(
+            (JAM_FRAMES..JAM_FRAMES + 2).contains(&frame),
+            "frame {frame}"
+        );
+
+        // Waiting for vblank with NMIs enabled is fine
+        let wait_for_nmi = "
+                lda #$80
+                sta $2000
+            loop:
+                jmp loop
+            nmi:
+                rti
+                .org $fffa
+                .word nmi, $8000, $8000
+        ";
+        assert!(run_until_jammed(wait_for_nmi, 3 * JAM_FRAMES).is_none());
+
+        // $5000 has nothing mapped on NROM
+        let (err, _) = run_until_jammed("jmp $5000", 1).unwrap();
+        assert!(matches!(
+            err,
+            CpuError::Jammed {
+                kind: JamKind::OpenBus,
+                pc: 0x5000
+            }
+        ));
+    }
+
+    /// Wraps S from $00 to $FF with the second push, then from $FF to $00 with the second pull
+    /// after moving S with `TXS`
+    const STACK_WRAPS: &str = "
+        ldx #$01
+        txs
+        pha
+        pha
+        pha
+        ldx #$fe
+        txs
+        pla
+        pla
+    ";
+
+    #[test]
+    fn stack_wrap() {
+        let mut cpu = testing::cpu(STACK_WRAPS);
+        cpu.set_stack_check(true);
+        let trace = testing::SharedBuffer::default();
+        cpu.set_trace_writer(Box::new(trace.clone()), None);
+        let mut wraps = Vec::new();
+        for _ in 0..9 {
+            cpu.run_opcode().unwrap();
+            wraps.extend(cpu.last_stack_wrap());
+        }
+        cpu.flush_trace().unwrap();
+
+        // The depth counts from the last TXS
+        assert_eq!(
+            wraps,
+            [
+                StackWrap {
+                    pc: 0x8004,
+                    overflow: true,
+                    depth: 2
+                },
+                StackWrap {
+                    pc: 0x800a,
+                    overflow: false,
+                    depth: -2
+                }
+            ]
+        );
+        let warnings: Vec<String> = trace
+            .text()
+            .lines()
+            .filter(|line| line.starts_with("warning"))
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "warning: stack overflow at $8004: S wrapped from $00 to $ff (depth 2)",
+                "warning: stack underflow at $800a: S wrapped from $ff to $00 (depth -2)"
+            ]
+        );
+
+        // Nothing is reported with the check off
+        let mut cpu = testing::cpu(STACK_WRAPS);
+        for _ in 0..9 {
+            cpu.run_opcode().unwrap();
+            assert_eq!(cpu.last_stack_wrap(), None);
+        }
+    }
+
+    #[test]
+    fn mapper_irq_line() {
+        let mut cpu = testing::cpu(
+            "sta $e001
+             cli
+             loop: jmp loop
+             handler: inc $20
+             lda $20
+             cmp #3
+             bne done
+             sta $e000
+             done: rti
+             .org $fffc
+             .word $8000, handler",
+        );
+        cpu.system.set_mapper(Box::new(testing::IrqMapper::new()));
+
+        // The line is ignored while I is set
+        testing::run(&mut cpu, 1);
+        assert_eq!(cpu.pc(), 0x8003);
+        // It is level-triggered, so the interrupt is taken again after each RTI until the
+        // handler acknowledges it, on its third run
+        testing::run(&mut cpu, 2);
+        assert!(cpu.interrupt_disable);
+        testing::run(&mut cpu, 100);
+        assert_eq!(cpu.peek_byte(0x20), 3);
+        assert!(!cpu.system.irq_pending());
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        // Adding two positive numbers which overflow into a negative one
+        let mut cpu = testing::cpu("clc\nlda #$50\nadc #$50");
+        testing::run(&mut cpu, 3);
+        assert_eq!(
+            (cpu.a, cpu.overflow, cpu.carry, cpu.negative),
+            (0xa0, true, false, true)
+        );
+
+        let mut cpu = testing::cpu("sec\nlda #$50\nsbc #$b0");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.a, cpu.overflow, cpu.carry), (0xa0, true, false));
+
+        let mut cpu = testing::cpu("sec\nlda #$05\nsbc #$03");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.a, cpu.overflow, cpu.carry), (0x02, false, true));
+    }
+
+    #[test]
+    fn shifts_and_rotates() {
+        let mut cpu = testing::cpu("sec\nlda #$80\nrol a");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.a, cpu.carry), (0x01, true));
+
+        let mut cpu = testing::cpu("sec\nlda #$02\nsta $10\nror $10\nlda $10");
+        testing::run(&mut cpu, 5);
+        assert_eq!((cpu.a, cpu.carry), (0x81, false));
+
+        let mut cpu = testing::cpu("lda #$03\nlsr a");
+        testing::run(&mut cpu, 2);
+        assert_eq!((cpu.a, cpu.carry), (0x01, true));
+    }
+
+    #[test]
+    fn compare_index_registers() {
+        let mut cpu = testing::cpu("ldx #$05\nldy #$04\ncpx #$05");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.zero, cpu.carry, cpu.negative), (true, true, false));
+
+        let mut cpu = testing::cpu("ldx #$05\nldy #$04\ncpy #$05");
+        testing::run(&mut cpu, 3);
+        assert_eq!((cpu.zero, cpu.carry, cpu.negative), (false, false, true));
+    }
+
+    #[test]
+    fn step_frame() {
+        let mut cpu = testing::cpu("loop: jmp loop");
+        assert_eq!(cpu.step_frame().unwrap().len(), 256 * 240);
+        let (scanline, _) = cpu.ppu_position();
+        assert_eq!(cpu.frame_count(), 1);
+
+        cpu.step_frame().unwrap();
+        cpu.step_frame().unwrap();
+        assert_eq!(cpu.frame_count(), 3);
+        // Each frame stops at the first instruction of the next
+        assert_eq!(cpu.ppu_position().0, scanline);
+    }
+
+    /// Draw tile 1, a solid block in colour 1, at `nametable_address`, and turn on the
+    /// background
+    fn draw_tile(nametable_address: u16) -> CPU {
+        testing::cpu(&format!(
+            "vblank1: bit $2002
+             bpl vblank1
+             vblank2: bit $2002
+             bpl vblank2
+
+             lda #$00
+             sta $2006
+             lda #$10
+             sta $2006
+             lda #$ff
+             ldx #$08
+             pattern: sta $2007
+             dex
+             bne pattern
+
+             lda #$3f
+             sta $2006
+             lda #$01
+             sta $2006
+             lda #$16
+             sta $2007
+
+             lda #${:02x}
+             sta $2006
+             lda #${:02x}
+             sta $2006
+             lda #$01
+             sta $2007
+
+             lda #$00
+             sta $2000
+             sta $2005
+             sta $2005
+             lda #$0a
+             sta $2001
+             loop: jmp loop",
+            nametable_address >> 8,
+            nametable_address & 0xff
+        ))
+    }
+
+    #[test]
+    fn frame_hash() {
+        let mut top_left = draw_tile(0x2000);
+        let mut next_to_it = draw_tile(0x2001);
+        for _ in 0..4 {
+            top_left.step_frame().unwrap();
+            next_to_it.step_frame().unwrap();
+        }
+        let pixels = |cpu: &CPU| cpu.system.ppu_framebuffer()[6..10].to_vec();
+        assert_eq!(pixels(&top_left), [0x16, 0x16, 0x00, 0x00]);
+        assert_eq!(pixels(&next_to_it), [0x00, 0x00, 0x16, 0x16]);
+        assert_ne!(top_left.frame_hash(), next_to_it.frame_hash());
+
+        // The same picture hashes the same
+        let hash = top_left.frame_hash();
+        top_left.step_frame().unwrap();
+        assert_eq!(top_left.frame_hash(), hash);
+    }
+
+    #[test]
+    fn jmp() {
+        let mut cpu = testing::cpu(
+            "jmp absolute
+             .org $8010
+             absolute: jmp ($0200)",
+        );
+        cpu.poke_byte(0x0200, 0x34);
+        cpu.poke_byte(0x0201, 0x12);
+        testing::run(&mut cpu, 1);
+        assert_eq!((cpu.pc(), cpu.cycles()), (0x8010, 3));
+        testing::run(&mut cpu, 1);
+        assert_eq!((cpu.pc(), cpu.cycles()), (0x1234, 8));
+    }
+}
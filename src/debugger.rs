@@ -0,0 +1,1080 @@
+//! An interactive debugger, driven by commands read a line at a time, e.g. `b c000` to break at
+//! `$C000` then `c` to continue until it's reached. `run_repl` reads them from any reader, and
+//! `StdinRepl` reads them from stdin while the window keeps running.
+//!
+//! Addresses are hex, with or without a `$`, or the name of a register like `PPUCTRL` or of an
+//! interrupt handler (`reset`, `nmi` or `irq`). Besides addresses, running can break on events
+//! like an NMI being taken or the PPU reaching a scanline, for chasing timing bugs.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+
+use crate::cpu::{CpuResult, Interrupt, StackWrap, CPU};
+
+const HELP: &str = "\
+s             step one instruction
+n             step over a subroutine call
+<             step back (undoes single steps since the last n, f or c)
+f             run to the end of the frame
+c             continue until a breakpoint, watchpoint or event
+b ADDR        break when the instruction at ADDR is about to run
+w ADDR        break when the value at ADDR changes
+on nmi|irq|brk
+              break when the interrupt is taken
+on line N [DOT]
+              break when the PPU reaches scanline N (-1 for pre-render) at DOT
+on write REG  break on the first write to a PPU register in each frame, e.g. on write ppumask
+on stack      break when the stack pointer wraps around page one
+l             list the breakpoints, watchpoints and events
+del ADDR      remove the breakpoint or watchpoint at ADDR
+del on EVENT  remove an event, e.g. del on line 241
+del all       remove every breakpoint, watchpoint and event
+r             show the registers
+d ADDR [LEN]  dump LEN bytes (64 by default) from ADDR
+u [ADDR]      disassemble from ADDR (the next instruction by default)
+m ADDR VALUE  change the byte at ADDR
+/ BYTES       search RAM for bytes, e.g. / a9 01
+uninit        list reads of RAM before it was written (starts checking if not already)
+q             quit";
+
+/// Instructions shown by `u`
+const DISASSEMBLY_LINES: usize = 10;
+
+/// Bytes shown by `d` without a length
+const DEFAULT_DUMP_LEN: u16 = 64;
+
+/// Spacing of `ppu_time`'s lines and dots, enough for every region
+const PPU_TIME_DOTS: u64 = 512;
+const PPU_TIME_LINES: u64 = 512;
+
+/// Names which can be used in place of an address
+const REGISTER_SYMBOLS: [(&str, u16); 11] = [
+    ("ppuctrl", 0x2000),
+    ("ppumask", 0x2001),
+    ("ppustatus", 0x2002),
+    ("oamaddr", 0x2003),
+    ("oamdata", 0x2004),
+    ("ppuscroll", 0x2005),
+    ("ppuaddr", 0x2006),
+    ("ppudata", 0x2007),
+    ("oamdma", 0x4014),
+    ("joy1", 0x4016),
+    ("joy2", 0x4017),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step,
+    StepOver,
+    StepBack,
+    Frame,
+    Continue,
+    Break(u16),
+    Watch(u16),
+    BreakOn(Event),
+    List,
+    Delete(Delete),
+    Registers,
+    Dump { address: u16, len: u16 },
+    Disassemble(Option<u16>),
+    Poke { address: u16, value: u8 },
+    Search(Vec<u8>),
+    UninitReads,
+    Help,
+    Quit,
+}
+
+/// What `del` removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delete {
+    /// The breakpoint and watchpoint at an address, whichever are set
+    Address(u16),
+    Event(Event),
+    All,
+}
+
+/// Something other than reaching an address to break on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    Interrupt(Interrupt),
+    /// The PPU reaching a dot of a scanline, with -1 for the pre-render line
+    Scanline {
+        scanline: i16,
+        dot: u16,
+    },
+    /// The first write in a frame to a PPU register, from $2000 to $2007
+    PpuWrite(u16),
+    /// The stack pointer wrapping around (see `CPU::set_stack_check`)
+    StackWrap,
+}
+
+/// In the form `on` takes, e.g. `line 241 1`
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Interrupt(interrupt) => write!(f, "{}", interrupt.to_string().to_lowercase()),
+            Event::Scanline { scanline, dot } => write!(f, "line {} {}", scanline, dot),
+            Event::PpuWrite(register) => write!(f, "write ${:04x}", register),
+            Event::StackWrap => write!(f, "stack"),
+        }
+    }
+}
+
+/// Why running stopped part way through a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint { address: u16, old: u8, new: u8 },
+    Interrupt(Interrupt),
+    Scanline { scanline: i16, dot: u16 },
+    PpuWrite(u16),
+    StackWrap(StackWrap),
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at ${:04x}", address),
+            StopReason::Watchpoint { address, old, new } => write!(
+                f,
+                "watchpoint: ${:04x} changed from {:02x} to {:02x}",
+                address, old, new
+            ),
+            StopReason::Interrupt(interrupt) => write!(f, "{} taken", interrupt),
+            StopReason::Scanline { scanline, dot } => {
+                write!(f, "PPU reached scanline {} dot {}", scanline, dot)
+            }
+            StopReason::PpuWrite(register) => {
+                write!(f, "first write to ${:04x} this frame", register)
+            }
+            StopReason::StackWrap(wrap) => write!(f, "{}", wrap),
+        }
+    }
+}
+
+/// What to do after a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Wait for the next command
+    Pause,
+    /// Run until a breakpoint or watchpoint is hit
+    Continue,
+    Quit,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+
+    /// Watched addresses, and the value each had when last checked
+    watchpoints: BTreeMap<u16, u8>,
+
+    events: BTreeSet<Event>,
+
+    /// Where the PPU was when last checked (see `ppu_time`), to tell when it passes a scanline
+    last_ppu_time: u64,
+
+    /// PPU registers written so far in `ppu_writes_frame`, a bit for each
+    ppu_writes_seen: u8,
+    ppu_writes_frame: u64,
+
+    /// Lower case names which can be used in place of addresses
+    symbols: HashMap<String, u16>,
+
+    /// Whether the CPU is keeping history for `<`
+    stepping_back: bool,
+}
+
+impl Debugger {
+    pub fn new(cpu: &CPU) -> Self {
+        let mut symbols: HashMap<String, u16> = REGISTER_SYMBOLS
+            .iter()
+            .map(|&(name, address)| (name.to_string(), address))
+            .collect();
+        for (name, vector) in [("nmi", 0xfffa), ("reset", 0xfffc), ("irq", 0xfffe)] {
+            let handler = u16::from_le_bytes([cpu.peek_byte(vector), cpu.peek_byte(vector + 1)]);
+            symbols.insert(name.to_string(), handler);
+        }
+        Self {
+            symbols,
+            ..Default::default()
+        }
+    }
+
+    /// Addresses to break at, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Addresses watched for changes, in order
+    pub fn watchpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = Event> + '_ {
+        self.events.iter().copied()
+    }
+
+    /// Remove a breakpoint, returning whether there was one
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        self.watchpoints.remove(&address).is_some()
+    }
+
+    pub fn remove_event(&mut self, event: Event) -> bool {
+        self.events.remove(&event)
+    }
+
+    /// Remove every breakpoint, watchpoint and event
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.events.clear();
+    }
+
+    /// Parse an address: a symbol, or hex with or without a `$` or `0x`
+    fn address(&self, text: &str) -> Result<u16, String> {
+        if let Some(&address) = self.symbols.get(&text.to_ascii_lowercase()) {
+            return Ok(address);
+        }
+        let digits = text
+            .strip_prefix('$')
+            .or_else(|| text.strip_prefix("0x"))
+            .unwrap_or(text);
+        u16::from_str_radix(digits, 16).map_err(|_| format!("'{text}' is not an address"))
+    }
+
+    /// Parse the arguments of `on`
+    fn event(&self, args: &[&str]) -> Result<Event, String> {
+        let number = |index: usize, name: &str| -> Result<i16, String> {
+            let text = args.get(index).ok_or(format!("expected a {name}"))?;
+            text.parse()
+                .map_err(|_| format!("'{text}' is not a {name}"))
+        };
+        match args.first().copied() {
+            Some("line") => {
+                let scanline = number(1, "scanline")?;
+                let dot = if args.len() > 2 { number(2, "dot")? } else { 0 };
+                if !(-1..=310).contains(&scanline) || !(0..=340).contains(&dot) {
+                    return Err("expected a scanline from -1 to 310 and a dot up to 340".into());
+                }
+                Ok(Event::Scanline {
+                    scanline,
+                    dot: dot as u16,
+                })
+            }
+            Some("write") => {
+                let text = args.get(1).ok_or("expected a PPU register")?;
+                match self.address(text)? {
+                    address @ 0x2000..=0x3fff => Ok(Event::PpuWrite(0x2000 | address & 0x7)),
+                    _ => Err(format!("'{text}' is not a PPU register")),
+                }
+            }
+            Some("stack") => Ok(Event::StackWrap),
+            Some(interrupt) => interrupt.parse().map(Event::Interrupt),
+            None => Err("'on' needs an event: nmi, irq, brk, line, write or stack".into()),
+        }
+    }
+
+    pub fn parse(&self, line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let address = |index: usize| match args.get(index) {
+            Some(text) => self.address(text),
+            None => Err(format!("'{command}' needs an address")),
+        };
+        let command = match command {
+            "s" => Command::Step,
+            "n" => Command::StepOver,
+            "<" => Command::StepBack,
+            "f" => Command::Frame,
+            "c" => Command::Continue,
+            "b" => Command::Break(address(0)?),
+            "w" => Command::Watch(address(0)?),
+            "on" => Command::BreakOn(self.event(&args)?),
+            "l" => Command::List,
+            "del" => Command::Delete(match args.first().copied() {
+                Some("all") => Delete::All,
+                Some("on") => Delete::Event(self.event(&args[1..])?),
+                _ => Delete::Address(address(0)?),
+            }),
+            "r" => Command::Registers,
+            "d" => {
+                let len = match args.get(1) {
+                    Some(text) => text
+                        .parse()
+                        .map_err(|_| format!("'{text}' is not a length"))?,
+                    None => DEFAULT_DUMP_LEN,
+                };
+                Command::Dump {
+                    address: address(0)?,
+                    len,
+                }
+            }
+            "u" if args.is_empty() => Command::Disassemble(None),
+            "u" => Command::Disassemble(Some(address(0)?)),
+            "m" => {
+                let value = args.get(1).ok_or("'m' needs a value")?;
+                let value = u8::from_str_radix(value.trim_start_matches('$'), 16)
+                    .map_err(|_| format!("'{value}' is not a hex byte"))?;
+                Command::Poke {
+                    address: address(0)?,
+                    value,
+                }
+            }
+            "/" if !args.is_empty() => {
+                let pattern = args
+                    .iter()
+                    .map(|byte| u8::from_str_radix(byte, 16))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "expected hex bytes to search for, e.g. / a9 01".to_string())?;
+                Command::Search(pattern)
+            }
+            "uninit" => Command::UninitReads,
+            "h" | "?" | "help" => Command::Help,
+            "q" => Command::Quit,
+            _ => return Err(format!("unknown command '{}' (h for help)", line.trim())),
+        };
+        Ok(command)
+    }
+
+    /// Run a command, writing its output to `out`. Emulation errors are written out too, as
+    /// the user can still look around after one.
+    pub fn execute(
+        &mut self,
+        cpu: &mut CPU,
+        command: Command,
+        out: &mut impl Write,
+    ) -> io::Result<Action> {
+        // Stepping back snapshots every instruction, so it's only on for single steps, and
+        // anything running further forgets them
+        match command {
+            Command::Step => self.set_step_back(cpu, true),
+            Command::StepOver | Command::Frame | Command::Continue => {
+                self.set_step_back(cpu, false)
+            }
+            _ => {}
+        }
+        match command {
+            Command::Step => {
+                let mut result = cpu.run_opcode();
+                while result.is_ok() && cpu.dma_stalled() {
+                    result = cpu.run_opcode();
+                }
+                self.finish_run(cpu, result.map(|()| None), out)?;
+            }
+            Command::StepOver => {
+                let result = cpu.step_over();
+                if let Ok(false) = result {
+                    writeln!(out, "The subroutine hasn't returned after a second")?;
+                }
+                self.finish_run(cpu, result.map(|_| None), out)?;
+            }
+            Command::StepBack => {
+                if cpu.step_back() {
+                    self.print_location(cpu, out)?;
+                } else {
+                    writeln!(out, "There are no steps to undo")?;
+                }
+            }
+            Command::Frame => {
+                let result = self.run_frame(cpu);
+                self.finish_run(cpu, result, out)?;
+            }
+            Command::Continue => return Ok(Action::Continue),
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+            }
+            Command::Watch(address) => {
+                self.watchpoints.insert(address, cpu.peek_byte(address));
+            }
+            Command::BreakOn(event) => {
+                if event == Event::StackWrap && !cpu.stack_check() {
+                    cpu.set_stack_check(true);
+                }
+                self.events.insert(event);
+            }
+            Command::List => {
+                if self.breakpoints.is_empty()
+                    && self.watchpoints.is_empty()
+                    && self.events.is_empty()
+                {
+                    writeln!(out, "No breakpoints, watchpoints or events")?;
+                }
+                for address in self.breakpoints() {
+                    writeln!(out, "break ${:04x}", address)?;
+                }
+                for address in self.watchpoints() {
+                    writeln!(out, "watch ${:04x}", address)?;
+                }
+                for event in self.events() {
+                    writeln!(out, "on {}", event)?;
+                }
+            }
+            Command::Delete(Delete::Address(address)) => {
+                let removed_break = self.remove_breakpoint(address);
+                let removed_watch = self.remove_watchpoint(address);
+                if !removed_break && !removed_watch {
+                    writeln!(out, "Nothing is set at ${:04x}", address)?;
+                }
+            }
+            Command::Delete(Delete::Event(event)) => {
+                if !self.remove_event(event) {
+                    writeln!(out, "Not breaking on {}", event)?;
+                }
+            }
+            Command::Delete(Delete::All) => self.clear(),
+            Command::Registers => writeln!(out, "{}", cpu)?,
+            Command::Dump { address, len } => {
+                for line_start in (0..len as u32).step_by(16) {
+                    let bytes: Vec<String> = (line_start..(line_start + 16).min(len as u32))
+                        .map(|offset| {
+                            let byte = cpu.peek_byte(address.wrapping_add(offset as u16));
+                            format!("{:02x}", byte)
+                        })
+                        .collect();
+                    let start = address.wrapping_add(line_start as u16);
+                    writeln!(out, "{:04x}: {}", start, bytes.join(" "))?;
+                }
+            }
+            Command::Disassemble(address) => {
+                let mut address = address.unwrap_or(cpu.pc());
+                for _ in 0..DISASSEMBLY_LINES {
+                    let (text, len) = cpu.disassemble(address);
+                    writeln!(out, "{:04x}  {}", address, text)?;
+                    address = address.wrapping_add(len);
+                }
+            }
+            Command::Poke { address, value } => cpu.poke_byte(address, value),
+            Command::Search(pattern) => {
+                let found = cpu.search_memory(&pattern, 0x0000..=0x07ff);
+                if found.is_empty() {
+                    writeln!(out, "Not found in RAM")?;
+                }
+                for address in found {
+                    writeln!(out, "Found at ${:04x}", address)?;
+                }
+            }
+            Command::UninitReads => {
+                if !cpu.uninit_check() {
+                    cpu.set_uninit_check(true);
+                    writeln!(out, "Checking for reads of uninitialized RAM from now on")?;
+                } else if cpu.uninit_reads().is_empty() {
+                    writeln!(out, "No reads of uninitialized RAM")?;
+                }
+                for read in cpu.uninit_reads() {
+                    writeln!(out, "{}", read)?;
+                }
+            }
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Quit => return Ok(Action::Quit),
+        }
+        Ok(Action::Pause)
+    }
+
+    fn set_step_back(&mut self, cpu: &mut CPU, enabled: bool) {
+        if self.stepping_back != enabled {
+            cpu.set_step_back(enabled);
+            self.stepping_back = enabled;
+        }
+    }
+
+    /// Run the rest of the frame, stopping early at a breakpoint or watchpoint
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> CpuResult<Option<StopReason>> {
+        self.last_ppu_time = ppu_time(cpu.frame_count(), cpu.ppu_position());
+        let mut reason = None;
+        cpu.run_frame_until(|cpu| {
+            reason = self.check(cpu);
+            reason.is_some()
+        })?;
+        Ok(reason)
+    }
+
+    /// Whether a breakpoint, watchpoint or event has been hit
+    fn check(&mut self, cpu: &CPU) -> Option<StopReason> {
+        let event = self.check_events(cpu);
+        if event.is_some() {
+            return event;
+        }
+        for (&address, old) in self.watchpoints.iter_mut() {
+            let new = cpu.peek_byte(address);
+            if new != *old {
+                let reason = StopReason::Watchpoint {
+                    address,
+                    old: *old,
+                    new,
+                };
+                *old = new;
+                return Some(reason);
+            }
+        }
+        let pc = cpu.pc();
+        self.breakpoints
+            .contains(&pc)
+            .then_some(StopReason::Breakpoint(pc))
+    }
+
+    /// Whether the last instruction set off any of `events`
+    fn check_events(&mut self, cpu: &CPU) -> Option<StopReason> {
+        let frame = cpu.frame_count();
+        let (previous, now) = (self.last_ppu_time, ppu_time(frame, cpu.ppu_position()));
+        self.last_ppu_time = now;
+        let first_write = cpu.last_ppu_write().filter(|register| {
+            if frame != self.ppu_writes_frame {
+                self.ppu_writes_frame = frame;
+                self.ppu_writes_seen = 0;
+            }
+            let bit = 1 << (register & 0x7);
+            let first = self.ppu_writes_seen & bit == 0;
+            self.ppu_writes_seen |= bit;
+            first
+        });
+
+        self.events.iter().find_map(|&event| match event {
+            Event::Interrupt(interrupt) => (cpu.last_interrupt() == Some(interrupt))
+                .then_some(StopReason::Interrupt(interrupt)),
+            Event::Scanline { scanline, dot } => {
+                // The PPU runs several dots per instruction, and may have passed into the next
+                // frame since the last check
+                let passed = [frame, frame.saturating_sub(1)].iter().any(|&frame| {
+                    let time = ppu_time(frame, (scanline, dot));
+                    previous < time && time <= now
+                });
+                passed.then_some(StopReason::Scanline { scanline, dot })
+            }
+            Event::PpuWrite(register) => {
+                (first_write == Some(register)).then_some(StopReason::PpuWrite(register))
+            }
+            Event::StackWrap => cpu.last_stack_wrap().map(StopReason::StackWrap),
+        })
+    }
+
+    /// Report how running went, and where the CPU has got to
+    fn finish_run(
+        &mut self,
+        cpu: &CPU,
+        result: CpuResult<Option<StopReason>>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        match result {
+            Ok(Some(reason)) => writeln!(out, "Stopped: {}", reason)?,
+            Ok(None) => {
+                // Changes made while stepping shouldn't stop the next `c`
+                for (&address, value) in self.watchpoints.iter_mut() {
+                    *value = cpu.peek_byte(address);
+                }
+            }
+            Err(err) => writeln!(out, "Error: {}", err)?,
+        }
+        self.print_location(cpu, out)
+    }
+
+    /// Show the next instruction
+    fn print_location(&self, cpu: &CPU, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{:04x}  {}", cpu.pc(), cpu.disassemble(cpu.pc()).0)
+    }
+
+    /// Run until a breakpoint or watchpoint is hit, or emulation fails
+    fn continue_running(&mut self, cpu: &mut CPU, out: &mut impl Write) -> io::Result<()> {
+        loop {
+            let result = self.run_frame(cpu);
+            if !matches!(result, Ok(None)) {
+                return self.finish_run(cpu, result, out);
+            }
+        }
+    }
+
+    /// Read and run commands from `input` until it ends or the user quits, e.g. a script
+    pub fn run_repl(
+        &mut self,
+        cpu: &mut CPU,
+        input: impl BufRead,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        self.print_location(cpu, out)?;
+        prompt(out)?;
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                match self.parse(&line) {
+                    Ok(command) => match self.execute(cpu, command, out)? {
+                        Action::Pause => {}
+                        Action::Continue => self.continue_running(cpu, out)?,
+                        Action::Quit => return Ok(()),
+                    },
+                    Err(message) => writeln!(out, "{}", message)?,
+                }
+            }
+            prompt(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// A count of dots which increases as the PPU runs, for telling whether it has passed a position.
+/// Lines are `PPU_TIME_DOTS` apart and frames `PPU_TIME_LINES`, with the pre-render line last.
+fn ppu_time(frame: u64, (scanline, dot): (i16, u16)) -> u64 {
+    let line = if scanline < 0 {
+        PPU_TIME_LINES - 1
+    } else {
+        scanline as u64
+    };
+    (frame * PPU_TIME_LINES + line) * PPU_TIME_DOTS + dot as u64
+}
+
+fn prompt(out: &mut impl Write) -> io::Result<()> {
+    write!(out, "> ")?;
+    out.flush()
+}
+
+/// The debugger reading commands from stdin while the window runs, e.g. for
+/// `RunOptions::debug_repl`. Lines are read on another thread, so the window stays responsive
+/// while waiting for them.
+pub struct StdinRepl {
+    debugger: Debugger,
+    lines: Receiver<String>,
+    out: Box<dyn Write>,
+
+    /// Running frames after a `c`, rather than waiting for commands
+    running: bool,
+}
+
+impl std::fmt::Debug for StdinRepl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StdinRepl")
+            .field("debugger", &self.debugger)
+            .field("running", &self.running)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StdinRepl {
+    /// Start paused at the next instruction
+    pub fn new(cpu: &CPU) -> Self {
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in io::stdin().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self::with_lines(cpu, lines, Box::new(io::stdout()))
+    }
+
+    /// Take commands from `lines` and write to `out` instead, e.g. for a script
+    fn with_lines(cpu: &CPU, lines: Receiver<String>, mut out: Box<dyn Write>) -> Self {
+        let debugger = Debugger::new(cpu);
+        let _ = writeln!(
+            out,
+            "Debugger: h for help, or a blank line to pause after c"
+        );
+        let _ = debugger.print_location(cpu, &mut out);
+        let _ = prompt(&mut out);
+        Self {
+            debugger,
+            lines,
+            out,
+            running: false,
+        }
+    }
+
+    /// Run any commands typed since the last call, then run a frame if continuing. Returns
+    /// false once the user quits.
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> CpuResult<bool> {
+        let out = &mut self.out;
+        // Once stdin closes, carry on as the user last asked
+        while let Ok(line) = self.lines.try_recv() {
+            if line.trim().is_empty() {
+                if self.running {
+                    self.running = false;
+                    let _ = self.debugger.print_location(cpu, out);
+                }
+            } else {
+                let action = match self.debugger.parse(&line) {
+                    Ok(command) => self.debugger.execute(cpu, command, out),
+                    Err(message) => writeln!(out, "{}", message).map(|()| Action::Pause),
+                };
+                match action {
+                    Ok(Action::Continue) => self.running = true,
+                    Ok(Action::Quit) => return Ok(false),
+                    Ok(Action::Pause) | Err(_) => {}
+                }
+            }
+            if !self.running {
+                let _ = prompt(out);
+            }
+        }
+
+        if self.running {
+            let result = self.debugger.run_frame(cpu);
+            if !matches!(result, Ok(None)) {
+                self.running = false;
+                if let Err(err) = result {
+                    return Err(err);
+                }
+                let _ = self.debugger.finish_run(cpu, result, out);
+                let _ = prompt(out);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Run a debugger command, and return what it printed
+    fn execute(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> String {
+        let command = debugger.parse(line).unwrap();
+        let mut out = Vec::new();
+        debugger.execute(cpu, command, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn search_memory() {
+        let mut cpu = testing::cpu("lda #$de\nsta $0123\nlda #$ad\nsta $0124\nsta $0300");
+        testing::run(&mut cpu, 5);
+        assert_eq!(cpu.search_memory(&[0xde, 0xad], 0x0000..=0x07ff), [0x0123]);
+        // RAM is mirrored up to $1FFF
+        assert_eq!(
+            cpu.search_memory(&[0xde, 0xad], 0x0000..=0x1fff),
+            [0x0123, 0x0923, 0x1123, 0x1923]
+        );
+        assert_eq!(
+            cpu.search_memory(&[0xad], 0x0000..=0x07ff),
+            [0x0124, 0x0300]
+        );
+        assert_eq!(cpu.search_memory(&[], 0x0000..=0x07ff), []);
+
+        let mut debugger = Debugger::new(&cpu);
+        assert_eq!(
+            debugger.parse("/ de ad").unwrap(),
+            Command::Search(vec![0xde, 0xad])
+        );
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "/ de ad"),
+            "Found at $0123\n"
+        );
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "/ be ef"),
+            "Not found in RAM\n"
+        );
+        assert!(debugger.parse("/ xyz").is_err());
+    }
+
+    #[test]
+    fn list_and_delete_breakpoints() {
+        let mut cpu = testing::cpu("nop");
+        let mut debugger = Debugger::new(&cpu);
+        for line in ["b 8000", "b 8010", "w 10", "on nmi", "on line 241 1"] {
+            assert_eq!(execute(&mut debugger, &mut cpu, line), "");
+        }
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "l"),
+            "break $8000\nbreak $8010\nwatch $0010\non nmi\non line 241 1\n"
+        );
+
+        assert_eq!(execute(&mut debugger, &mut cpu, "del 8000"), "");
+        assert_eq!(execute(&mut debugger, &mut cpu, "del on line 241 1"), "");
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "l"),
+            "break $8010\nwatch $0010\non nmi\n"
+        );
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [0x8010]);
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), [0x0010]);
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "del 1234"),
+            "Nothing is set at $1234\n"
+        );
+
+        assert_eq!(execute(&mut debugger, &mut cpu, "del all"), "");
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "l"),
+            "No breakpoints, watchpoints or events\n"
+        );
+        assert_eq!(debugger.events().count(), 0);
+    }
+
+    /// Run frames until the debugger stops, which it must within a few
+    fn run_until_stop(debugger: &mut Debugger, cpu: &mut CPU) -> StopReason {
+        (0..3)
+            .find_map(|_| debugger.run_frame(cpu).unwrap())
+            .expect("debugger should stop")
+    }
+
+    /// A console running `program` from `$8000`, with interrupt handlers which loop
+    fn interrupts_cpu(program: &str) -> CPU {
+        testing::cpu(&format!(
+            "{program}
+            loop:
+                jmp loop
+            nmi:
+                jmp nmi
+            irq:
+                jmp irq
+                .org $fffa
+                .word nmi, $8000, irq
+            "
+        ))
+    }
+
+    #[test]
+    fn break_on_nmi() {
+        let mut cpu = interrupts_cpu("lda #$80\nsta $2000");
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "on nmi");
+        let stop = run_until_stop(&mut debugger, &mut cpu);
+        assert_eq!(stop, StopReason::Interrupt(Interrupt::Nmi));
+        assert_eq!(cpu.pc(), debugger.address("nmi").unwrap());
+        // Vblank starts at dot 1 of scanline 241. The NMI is taken once the instruction in
+        // progress finishes, and its 7 cycles take another 21 dots.
+        assert_eq!(cpu.ppu_position(), (241, 37));
+        assert_eq!(cpu.frame_count(), 0);
+    }
+
+    #[test]
+    fn break_on_irq() {
+        // Play a 1 byte DMC sample at the fastest rate, with its IRQ on
+        let mut cpu =
+            interrupts_cpu("lda #$8f\nsta $4010\nlda #0\nsta $4013\nlda #$10\nsta $4015\ncli");
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "on irq");
+        let stop = run_until_stop(&mut debugger, &mut cpu);
+        assert_eq!(stop, StopReason::Interrupt(Interrupt::Irq));
+        assert_eq!(cpu.pc(), debugger.address("irq").unwrap());
+        // 20 cycles of setup, then the DMA fetching the only byte, which ends the sample and
+        // raises the IRQ, and 7 cycles to take it
+        assert_eq!(cpu.cycles(), 31);
+    }
+
+    #[test]
+    fn break_on_brk() {
+        let mut cpu = interrupts_cpu("nop\nbrk\n.byte 0");
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "on brk");
+        let stop = run_until_stop(&mut debugger, &mut cpu);
+        assert_eq!(stop, StopReason::Interrupt(Interrupt::Brk));
+        assert_eq!(cpu.pc(), debugger.address("irq").unwrap());
+        // 2 cycles for the NOP and 7 for the BRK
+        assert_eq!(cpu.cycles(), 9);
+    }
+
+    #[test]
+    fn break_on_scanline() {
+        let mut cpu = interrupts_cpu("");
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "on line 100 50");
+        let stop = run_until_stop(&mut debugger, &mut cpu);
+        assert_eq!(
+            stop,
+            StopReason::Scanline {
+                scanline: 100,
+                dot: 50
+            }
+        );
+        // Checked after each instruction, so up to a JMP's 9 dots late
+        let (scanline, dot) = cpu.ppu_position();
+        assert_eq!(scanline, 100);
+        assert!((50..59).contains(&dot), "stopped at dot {dot}");
+        assert_eq!(cpu.frame_count(), 0);
+
+        // And again on the next frame
+        run_until_stop(&mut debugger, &mut cpu);
+        let (scanline, dot) = cpu.ppu_position();
+        assert_eq!(scanline, 100);
+        assert!((50..59).contains(&dot), "stopped at dot {dot}");
+        assert_eq!(cpu.frame_count(), 1);
+    }
+
+    #[test]
+    fn break_on_first_ppu_write() {
+        let mut cpu = interrupts_cpu(
+            "
+            wait:
+                bit $2002
+                bpl wait
+                lda #0
+                sta $2001
+                sta $2001
+                jmp wait
+            ",
+        );
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "on write ppumask");
+        let stop = run_until_stop(&mut debugger, &mut cpu);
+        assert_eq!(stop, StopReason::PpuWrite(0x2001));
+        // Just after the first write, once the loop sees vblank
+        assert_eq!(cpu.pc(), 0x800a);
+        assert_eq!(cpu.ppu_position().0, 241);
+        assert_eq!(cpu.frame_count(), 0);
+
+        // The second write that frame doesn't stop it, but the first on the next frame does
+        assert_eq!(
+            run_until_stop(&mut debugger, &mut cpu),
+            StopReason::PpuWrite(0x2001)
+        );
+        assert_eq!(cpu.pc(), 0x800a);
+        assert_eq!(cpu.ppu_position().0, 241);
+        assert_eq!(cpu.frame_count(), 1);
+    }
+
+    #[test]
+    fn step_over_subroutine() {
+        let mut cpu = testing::cpu(
+            "
+                ldx #0
+                jsr sub
+                inx
+            loop:
+                jmp loop
+            sub:
+                lda #$42
+                sta $10
+                jsr inner
+                rts
+            inner:
+                inc $11
+                rts
+            ",
+        );
+        let mut debugger = Debugger::new(&cpu);
+        execute(&mut debugger, &mut cpu, "b 8002");
+        assert_eq!(
+            debugger.run_frame(&mut cpu).unwrap(),
+            Some(StopReason::Breakpoint(0x8002))
+        );
+
+        // The whole subroutine runs, including the one it calls, stopping after the JSR
+        assert_eq!(execute(&mut debugger, &mut cpu, "n"), "8005  inx\n");
+        assert_eq!(cpu.pc(), 0x8005);
+        assert_eq!(cpu.peek_byte(0x10), 0x42);
+        assert_eq!(cpu.peek_byte(0x11), 1);
+
+        // Anything else is a single step
+        assert_eq!(execute(&mut debugger, &mut cpu, "n"), "8006  jmp $8006\n");
+    }
+
+    #[test]
+    fn step_over_subroutine_which_never_returns() {
+        let mut cpu = testing::cpu("jsr sub\nbrk\nsub: jmp sub");
+        let mut debugger = Debugger::new(&cpu);
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "n"),
+            "The subroutine hasn't returned after a second\n8004  jmp $8004\n"
+        );
+    }
+
+    /// A REPL reading the lines sent, and writing to the buffer
+    fn scripted_repl(cpu: &CPU) -> (StdinRepl, mpsc::Sender<String>, testing::SharedBuffer) {
+        let (sender, lines) = mpsc::channel();
+        let out = testing::SharedBuffer::default();
+        let repl = StdinRepl::with_lines(cpu, lines, Box::new(out.clone()));
+        (repl, sender, out)
+    }
+
+    /// Send the lines of `script`, then run a frame of the REPL, and return what it printed
+    fn type_lines(
+        repl: &mut StdinRepl,
+        cpu: &mut CPU,
+        sender: &mpsc::Sender<String>,
+        out: &testing::SharedBuffer,
+        script: &[&str],
+    ) -> (bool, String) {
+        let before = out.text().len();
+        for line in script {
+            sender.send(line.to_string()).unwrap();
+        }
+        let carry_on = repl.run_frame(cpu).unwrap();
+        (carry_on, out.text()[before..].to_string())
+    }
+
+    #[test]
+    fn repl_script() {
+        let mut cpu = testing::cpu("lda #$12\nsta $0300\nloop: jmp loop");
+        let (mut repl, sender, out) = scripted_repl(&cpu);
+        assert_eq!(
+            out.text(),
+            "Debugger: h for help, or a blank line to pause after c\n8000  lda #$12\n> "
+        );
+
+        // Addresses in hex with `0x`, `$` or nothing, or by name
+        let mut repl_lines =
+            |script: &[&str]| type_lines(&mut repl, &mut cpu, &sender, &out, script);
+        assert_eq!(
+            repl_lines(&["b 0x8005", "c"]),
+            (
+                true,
+                "> Stopped: breakpoint at $8005\n8005  jmp $8005\n> ".to_string()
+            )
+        );
+        assert_eq!(
+            repl_lines(&["d $0300 2", "m 301 ab", "d 300 2"]),
+            (true, "0300: 12 00\n> > 0300: 12 ab\n> ".to_string())
+        );
+        let (_, text) = repl_lines(&["u reset"]);
+        assert!(
+            text.starts_with("8000  lda #$12\n8002  sta $0300\n"),
+            "{text}"
+        );
+
+        // Mistakes are reported, and the REPL carries on
+        assert_eq!(
+            repl_lines(&["b xyz", "d 0300 lots", "frobnicate"]),
+            (
+                true,
+                "'xyz' is not an address\n> 'lots' is not a length\n> \
+                 unknown command 'frobnicate' (h for help)\n> "
+                    .to_string()
+            )
+        );
+
+        // And stops at q
+        assert_eq!(repl_lines(&["q"]), (false, String::new()));
+    }
+
+    #[test]
+    fn break_on_stack_wrap() {
+        let mut cpu = testing::cpu("ldx #$00\ntxs\npha\nloop: jmp loop");
+        let mut debugger = Debugger::new(&cpu);
+        // Breaking on it turns the check on
+        execute(&mut debugger, &mut cpu, "on stack");
+        assert!(cpu.stack_check());
+        let stop = debugger.run_frame(&mut cpu).unwrap();
+        let wrap = StackWrap {
+            pc: 0x8003,
+            overflow: true,
+            depth: 1,
+        };
+        assert_eq!(stop, Some(StopReason::StackWrap(wrap)));
+        assert_eq!(cpu.pc(), 0x8004);
+    }
+
+    #[test]
+    fn list_uninit_reads() {
+        let mut cpu = testing::cpu("lda $10\nsta $11\nlda $11\nlda $12");
+        let mut debugger = Debugger::new(&cpu);
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "uninit"),
+            "Checking for reads of uninitialized RAM from now on\n"
+        );
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "uninit"),
+            "No reads of uninitialized RAM\n"
+        );
+        testing::run(&mut cpu, 4);
+        assert_eq!(
+            execute(&mut debugger, &mut cpu, "uninit"),
+            "read of uninitialized $0010 at $8000\nread of uninitialized $0012 at $8006\n"
+        );
+    }
+}
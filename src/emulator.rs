@@ -0,0 +1,205 @@
+//! The emulator as a whole: the console (see `CPU`, which owns the rest of it) together with the
+//! settings for running it in a frontend, for using the crate as a library.
+
+use std::path::{Path, PathBuf};
+
+use crate::cart::{CartLoadResult, Rom};
+use crate::cpu::{CpuResult, CPU};
+use crate::filter::Frame;
+use crate::frontend::Frontend;
+use crate::RunOptions;
+
+pub struct Emulator {
+    cpu: CPU,
+    options: RunOptions,
+}
+
+impl Emulator {
+    /// Load a ROM file and power on with it. The path is kept in `options` for the reload
+    /// hotkey and savestates.
+    pub fn new(path: impl Into<PathBuf>, options: RunOptions) -> CartLoadResult<Self> {
+        let path = path.into();
+        let cpu = CPU::new(path.to_string_lossy().into_owned(), false)?;
+        Ok(Self::from_cpu(
+            cpu,
+            RunOptions {
+                rom_path: Some(path),
+                ..options
+            },
+        ))
+    }
+
+    /// Power on with a ROM image which is already in memory. There is no ROM file, so the
+    /// reload and savestate hotkeys do nothing.
+    pub fn from_bytes(rom: Vec<u8>, options: RunOptions) -> CartLoadResult<Self> {
+        Ok(Self::from_cpu(CPU::from_bytes(rom, false)?, options))
+    }
+
+    /// Wrap a console which has already been set up, e.g. with a palette or trace
+    pub fn from_cpu(cpu: CPU, options: RunOptions) -> Self {
+        Self { cpu, options }
+    }
+
+    /// Swap in another ROM file and power cycle with it, keeping the console's settings
+    pub fn load_rom(&mut self, path: impl Into<PathBuf>) -> CartLoadResult<()> {
+        let path = path.into();
+        let cart = Rom::from_file(&path)?.into_cart()?;
+        self.cpu.power_cycle(Some(cart));
+        self.options.rom_path = Some(path);
+        Ok(())
+    }
+
+    /// Emulate a frame without a frontend, and return it. Its audio is thrown away, as in
+    /// `run_headless`.
+    pub fn step_frame(&mut self) -> CpuResult<Frame> {
+        crate::run_headless(&mut self.cpu, 1)?;
+        Ok(self.cpu.frame())
+    }
+
+    /// Run the main loop with `frontend` until the user quits (see `run_frontend`)
+    pub fn run_frontend<F: Frontend>(&mut self, frontend: &mut F) -> CpuResult<()> {
+        crate::run_frontend(&mut self.cpu, frontend, &self.options)
+    }
+
+    /// Run in an SDL window until the user closes it (see `run`)
+    #[cfg(feature = "sdl")]
+    pub fn run(&mut self) -> Result<(), crate::RunError> {
+        crate::run(&mut self.cpu, &self.options)
+    }
+
+    /// The ROM file, if the emulator was loaded from one
+    pub fn rom_path(&self) -> Option<&Path> {
+        self.options.rom_path.as_deref()
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn options(&self) -> &RunOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut RunOptions {
+        &mut self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Palette;
+    use crate::testing;
+
+    /// Sets the backdrop to colour $16 once the PPU has warmed up, with rendering off
+    const BACKDROP: &str = "
+        warm_up1:
+            bit $2002
+            bpl warm_up1
+        warm_up2:
+            bit $2002
+            bpl warm_up2
+            lda #$3f
+            sta $2006
+            lda #$00
+            sta $2006
+            lda #$16
+            sta $2007
+        done:
+            jmp done";
+
+    #[test]
+    fn step_frame() {
+        let program = crate::assemble(BACKDROP).unwrap();
+        let mut emulator =
+            Emulator::from_bytes(testing::nrom(&program), RunOptions::default()).unwrap();
+        assert_eq!(emulator.rom_path(), None);
+
+        let frame = emulator.step_frame().unwrap();
+        assert_eq!(emulator.cpu().frame_count(), 1);
+        assert_eq!((frame.width, frame.height), (256, 240));
+        let red = Palette::default().colour(0x16, 0);
+        assert_ne!(frame.pixel(0, 0), red);
+
+        for frames in 2..=4 {
+            let frame = emulator.step_frame().unwrap();
+            assert_eq!(emulator.cpu().frame_count(), frames);
+            assert_eq!(frame, emulator.cpu().frame());
+        }
+        let frame = emulator.step_frame().unwrap();
+        assert_eq!(frame.pixel(0, 0), red);
+        assert_eq!(frame.pixel(255, 239), red);
+    }
+
+    /// Shows whether A was held at the last vblank, as a red backdrop
+    const SHOW_A: &str = "
+        wait:
+            bit $2002
+            bpl wait
+            lda #1
+            sta $4016
+            lda #0
+            sta $4016
+            lda $4016
+            and #1
+            tax
+            lda #$3f
+            sta $2006
+            lda #$00
+            sta $2006
+            lda colours,x
+            sta $2007
+            jmp wait
+        colours:
+            .byte $0f, $16
+    ";
+
+    /// What `WebEmulator` calls to run in the browser
+    #[test]
+    fn web_frontend_calls() {
+        let program = crate::assemble(SHOW_A).unwrap();
+        let mut emulator =
+            Emulator::from_bytes(testing::nrom(&program), RunOptions::default()).unwrap();
+        let red = Palette::default().colour(0x16, 0);
+
+        for (buttons, expected) in [(0, false), (crate::buttons::A, true), (0, false)] {
+            emulator.cpu_mut().set_player_input(0, buttons);
+            // The input is read on this frame, and shown from the next
+            for _ in 0..2 {
+                emulator.cpu_mut().run_frame().unwrap();
+            }
+            let rgba = emulator.cpu().frame().to_rgba();
+            assert_eq!(rgba.len(), 256 * 240 * 4);
+            assert_eq!(rgba[..4] == [red.0, red.1, red.2, 0xff], expected);
+
+            // The page takes the samples each frame. None are mixed until the APU's channels
+            // are implemented, but taking them must still drain the queue.
+            emulator.cpu_mut().take_audio_samples();
+            assert!(emulator.cpu_mut().take_audio_samples().is_empty());
+        }
+    }
+
+    #[test]
+    fn load_rom() {
+        let mut emulator = Emulator::from_bytes(testing::nrom(&[]), RunOptions::default()).unwrap();
+        emulator.step_frame().unwrap();
+
+        let program = crate::assemble(BACKDROP).unwrap();
+        let path = std::env::temp_dir().join(format!("rusty_nes_{}.nes", std::process::id()));
+        std::fs::write(&path, testing::nrom(&program)).unwrap();
+        let result = emulator.load_rom(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        // Power cycled with the new ROM
+        assert_eq!(emulator.rom_path(), Some(path.as_path()));
+        assert_eq!(emulator.cpu().frame_count(), 0);
+        assert_eq!(emulator.cpu().peek_byte(0x8000), program[0]);
+        emulator.step_frame().unwrap();
+        assert_eq!(emulator.cpu().frame_count(), 1);
+    }
+}
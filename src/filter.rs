@@ -0,0 +1,289 @@
+//! Post-processing filters, applied to the RGB output of the PPU before it is handed to the
+//! frontend.
+
+use std::str::FromStr;
+
+/// An RGB image with 3 bytes per pixel, stored row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Create a black frame
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * self.width + x) * 3;
+        (self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+
+    /// The pixels with an opaque alpha channel added, as browsers' `ImageData` and many
+    /// textures expect
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.width * self.height * 4);
+        for pixel in self.pixels.chunks_exact(3) {
+            rgba.extend_from_slice(pixel);
+            rgba.push(0xff);
+        }
+        rgba
+    }
+
+    /// Encode the frame as a PNG file
+    pub fn to_png(&self) -> Vec<u8> {
+        crate::png::encode(self)
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        let i = (y * self.width + x) * 3;
+        self.pixels[i] = r;
+        self.pixels[i + 1] = g;
+        self.pixels[i + 2] = b;
+    }
+}
+
+/// A post-processing stage. Filters may change the dimensions of the frame (e.g. scanlines
+/// doubles the vertical resolution), so the frontend should always use the dimensions of the
+/// returned frame.
+pub trait Filter {
+    fn name(&self) -> &'static str;
+    fn apply(&self, input: &Frame) -> Frame;
+}
+
+/// Passes the frame through untouched
+pub struct NoFilter;
+
+impl Filter for NoFilter {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn apply(&self, input: &Frame) -> Frame {
+        input.clone()
+    }
+}
+
+/// Doubles every line, darkening the second copy to mimic the gaps between CRT scanlines
+pub struct Scanlines {
+    /// How much to darken the odd lines, from 0.0 (not at all) to 1.0 (black)
+    intensity: f32,
+}
+
+impl Scanlines {
+    pub fn new(intensity: f32) -> Self {
+        Self {
+            intensity: intensity.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Filter for Scanlines {
+    fn name(&self) -> &'static str {
+        "scanlines"
+    }
+
+    fn apply(&self, input: &Frame) -> Frame {
+        let row_len = input.width * 3;
+        let scale = 1.0 - self.intensity;
+        let mut output = Frame::new(input.width, input.height * 2);
+        for (y, row) in input.pixels.chunks_exact(row_len).enumerate() {
+            let bright_start = 2 * y * row_len;
+            output.pixels[bright_start..bright_start + row_len].copy_from_slice(row);
+
+            let dark_start = bright_start + row_len;
+            for (out, &value) in output.pixels[dark_start..dark_start + row_len]
+                .iter_mut()
+                .zip(row)
+            {
+                *out = (value as f32 * scale) as u8;
+            }
+        }
+        output
+    }
+}
+
+/// A cheap approximation of the NTSC composite signal: chroma bleeds into neighbouring columns,
+/// and sharp luma edges ring depending on the colour subcarrier phase of the column.
+///
+/// The NES outputs 3 pixels per 2 colour subcarrier cycles, and the phase shifts by one pixel
+/// on every line, so the artifacts form the familiar diagonal pattern.
+///
+/// See: <https://www.nesdev.org/wiki/NTSC_video>
+pub struct Ntsc;
+
+impl Ntsc {
+    /// How strongly a luma edge overshoots, per subcarrier phase
+    const RINGING: [f32; 3] = [0.3, 0.15, 0.0];
+}
+
+impl Filter for Ntsc {
+    fn name(&self) -> &'static str {
+        "ntsc"
+    }
+
+    fn apply(&self, input: &Frame) -> Frame {
+        let mut output = Frame::new(input.width, input.height);
+        for y in 0..input.height {
+            let yiq: Vec<(f32, f32, f32)> = (0..input.width)
+                .map(|x| rgb_to_yiq(input.pixel(x, y)))
+                .collect();
+
+            for x in 0..input.width {
+                let left = yiq[x.saturating_sub(1)];
+                let centre = yiq[x];
+                let right = yiq[(x + 1).min(input.width - 1)];
+
+                let phase = (x + y) % 3;
+                let luma = centre.0 + Self::RINGING[phase] * (centre.0 - left.0);
+                let i = (left.1 + 2.0 * centre.1 + right.1) / 4.0;
+                let q = (left.2 + 2.0 * centre.2 + right.2) / 4.0;
+
+                output.set_pixel(x, y, yiq_to_rgb((luma, i, q)));
+            }
+        }
+        output
+    }
+}
+
+fn rgb_to_yiq((r, g, b): (u8, u8, u8)) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        0.596 * r - 0.274 * g - 0.322 * b,
+        0.211 * r - 0.523 * g + 0.312 * b,
+    )
+}
+
+fn yiq_to_rgb((y, i, q): (f32, f32, f32)) -> (u8, u8, u8) {
+    let to_u8 = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    (
+        to_u8(y + 0.956 * i + 0.621 * q),
+        to_u8(y - 0.272 * i - 0.647 * q),
+        to_u8(y - 1.106 * i + 1.703 * q),
+    )
+}
+
+/// The selectable filters, used for configuration and for cycling with a hotkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    None,
+    Scanlines,
+    Ntsc,
+}
+
+impl FilterKind {
+    /// The next filter in the hotkey cycle
+    pub fn next(self) -> Self {
+        match self {
+            FilterKind::None => FilterKind::Scanlines,
+            FilterKind::Scanlines => FilterKind::Ntsc,
+            FilterKind::Ntsc => FilterKind::None,
+        }
+    }
+
+    pub fn build(self, scanline_intensity: f32) -> Box<dyn Filter> {
+        match self {
+            FilterKind::None => Box::new(NoFilter),
+            FilterKind::Scanlines => Box::new(Scanlines::new(scanline_intensity)),
+            FilterKind::Ntsc => Box::new(Ntsc),
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FilterKind::None),
+            "scanlines" => Ok(FilterKind::Scanlines),
+            "ntsc" => Ok(FilterKind::Ntsc),
+            _ => Err(format!(
+                "unknown filter '{s}' (expected one of: none, scanlines, ntsc)"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with a white pixel at the left of each row and grey everywhere else
+    fn synthetic_frame() -> Frame {
+        let mut frame = Frame::new(8, 4);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let colour = if x == 0 {
+                    (255, 255, 255)
+                } else {
+                    (100, 100, 100)
+                };
+                frame.set_pixel(x, y, colour);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn no_filter() {
+        let frame = synthetic_frame();
+        assert_eq!(NoFilter.apply(&frame), frame);
+    }
+
+    #[test]
+    fn scanlines() {
+        let frame = synthetic_frame();
+        let output = Scanlines::new(0.5).apply(&frame);
+        assert_eq!((output.width, output.height), (8, 8));
+        for y in 0..frame.height {
+            assert_eq!(output.pixel(0, 2 * y), (255, 255, 255));
+            assert_eq!(output.pixel(0, 2 * y + 1), (127, 127, 127));
+            assert_eq!(output.pixel(3, 2 * y), (100, 100, 100));
+            assert_eq!(output.pixel(3, 2 * y + 1), (50, 50, 50));
+        }
+
+        // The intensity is clamped, so the odd lines can't go brighter or below black
+        let output = Scanlines::new(2.0).apply(&frame);
+        assert_eq!(output.pixel(0, 1), (0, 0, 0));
+    }
+
+    #[test]
+    fn ntsc() {
+        let frame = synthetic_frame();
+        let output = Ntsc.apply(&frame);
+        assert_eq!((output.width, output.height), (8, 4));
+        // Flat grey has no chroma to bleed and no edges to ring
+        assert_eq!(output.pixel(5, 0), (100, 100, 100));
+        // The edge after the white column undershoots, by how much depending on the phase,
+        // which moves along a column a line at a time
+        let edge: Vec<_> = (0..3).map(|y| output.pixel(1, y).0).collect();
+        assert_eq!(edge, vec![77, 100, 54]);
+    }
+
+    #[test]
+    fn filter_kind_from_str() {
+        assert_eq!("scanlines".parse(), Ok(FilterKind::Scanlines));
+        assert_eq!("ntsc".parse(), Ok(FilterKind::Ntsc));
+        assert_eq!(
+            "crt".parse::<FilterKind>(),
+            Err("unknown filter 'crt' (expected one of: none, scanlines, ntsc)".to_string())
+        );
+        assert!("Scanlines".parse::<FilterKind>().is_err());
+    }
+
+    #[test]
+    fn filter_kind_cycle() {
+        let kind = FilterKind::None;
+        assert_eq!(kind.next().next().next(), kind);
+        assert_eq!(kind.next().build(0.5).name(), "scanlines");
+    }
+}
@@ -0,0 +1,127 @@
+//! The interface between the emulator core and whatever presents it to the user.
+//!
+//! SDL is the only real frontend for now, but the main loop is written against the `Frontend`
+//! trait so that other backends (and a mock for testing) can drive the same code.
+
+use std::time::Duration;
+
+use crate::filter::Frame;
+
+/// Bits of the standard controller, in the order the NES reads them
+pub mod buttons {
+    pub const A: u8 = 0x01;
+    pub const B: u8 = 0x02;
+    pub const SELECT: u8 = 0x04;
+    pub const START: u8 = 0x08;
+    pub const UP: u8 = 0x10;
+    pub const DOWN: u8 = 0x20;
+    pub const LEFT: u8 = 0x40;
+    pub const RIGHT: u8 = 0x80;
+}
+
+/// Snapshot of the user's input, polled once per frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InputState {
+    /// Buttons held on each player's controller, see `buttons`
+    pub buttons: [u8; 2],
+
+    /// Keys held on the Family BASIC keyboard, one bit per key (see `famicom_key_index`)
+    pub keyboard: u128,
+
+    /// The user asked to close the emulator
+    pub quit: bool,
+
+    /// The user pressed the hotkey to switch to the next post-processing filter
+    pub cycle_filter: bool,
+
+    /// The user pressed the hotkeys to turn the volume up or down
+    pub volume_up: bool,
+    pub volume_down: bool,
+
+    /// The user pressed the hotkey to mute or unmute the audio
+    pub toggle_mute: bool,
+
+    /// The user pressed the hotkey to turn the 8 sprites per scanline limit on or off
+    pub toggle_sprite_limit: bool,
+
+    /// The user pressed the hotkeys to run faster or slower
+    pub speed_up: bool,
+    pub speed_down: bool,
+
+    /// The user pressed the hotkeys to save a savestate file next to the ROM, or load it back
+    pub save_state: bool,
+    pub load_state: bool,
+
+    /// The user pressed R or Escape. These only act as hotkeys once emulation has stopped on an
+    /// error, to reset or quit respectively.
+    pub reset: bool,
+    pub escape: bool,
+
+    /// The user pressed the hotkeys to press the reset button, turn the console off and on
+    /// again, or reload the ROM from disk
+    pub soft_reset: bool,
+    pub power_cycle: bool,
+    pub reload_rom: bool,
+}
+
+pub trait Frontend {
+    /// Display a finished (and post-processed) frame
+    fn present_frame(&mut self, frame: &Frame);
+
+    /// Queue audio samples for playback
+    fn push_audio(&mut self, samples: &[f32]);
+
+    /// Gather any input that has happened since the last poll
+    fn poll_input(&mut self) -> InputState;
+
+    /// Monotonic time, used to pace the emulation
+    fn now(&self) -> Duration;
+}
+
+/// A frontend which records everything it is given and replays a scripted sequence of inputs,
+/// for driving the emulator end-to-end in tests.
+///
+/// Each call to `poll_input` consumes one scripted input. Once the script runs out the frontend
+/// asks to quit. Time advances by exactly one frame per presented frame, so the main loop never
+/// needs to sleep.
+#[cfg(feature = "mock-frontend")]
+#[derive(Debug, Default)]
+pub struct MockFrontend {
+    script: std::collections::VecDeque<InputState>,
+    pub frames: Vec<Frame>,
+    pub audio: Vec<f32>,
+    time: Duration,
+}
+
+#[cfg(feature = "mock-frontend")]
+impl MockFrontend {
+    pub fn new(script: Vec<InputState>) -> Self {
+        Self {
+            script: script.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(feature = "mock-frontend")]
+impl Frontend for MockFrontend {
+    fn present_frame(&mut self, frame: &Frame) {
+        self.frames.push(frame.clone());
+        self.time += crate::FRAME_DURATION;
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        self.audio.extend_from_slice(samples);
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        self.script.pop_front().unwrap_or(InputState {
+            quit: true,
+            ..Default::default()
+        })
+    }
+
+    fn now(&self) -> Duration {
+        self.time
+    }
+}
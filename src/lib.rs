@@ -1,21 +1,611 @@
 mod apu;
+mod asm;
 mod cart;
+mod config;
+mod controller;
 mod cpu;
+mod debugger;
+mod emulator;
+mod filter;
+mod font;
+mod frontend;
+mod mapper;
+mod movie;
+mod osd;
+mod palette;
+#[cfg(feature = "sdl")]
+mod picker;
+mod png;
 mod ppu;
+mod region;
+mod regression;
+mod romdb;
+mod saves;
+#[cfg(feature = "sdl")]
 mod sdl;
+mod speed;
+mod state;
 mod system;
-mod video;
+mod test_rom;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod wav;
+mod window;
 
-pub use cart::{CartLoadError, CartLoadResult};
-pub use cpu::CPU;
+use std::fmt::Display;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+pub use apu::SAMPLE_RATE;
+pub use asm::{assemble, disassemble, AsmError, AsmResult};
+pub use cart::{Cart, CartKind, CartLoadError, CartLoadResult, Mirroring, Rom, VsPpu, VsSystem};
+pub use config::{
+    check_audio_buffer, default_config_path, Config, ConfigError, ConfigResult, DEFAULT_CONFIG,
+};
+pub use controller::{famicom_key_index, FamiBasicKeyboard, InputDevice, StandardController};
+pub use cpu::{
+    CpuError, CpuResult, Interrupt, JamKind, JamPolicy, OpcodeTrace, SaveState, StackWrap,
+    TraceColumns, UninitRead, UnknownOpcodePolicy, CPU,
+};
+pub use debugger::{Action, Command, Debugger, Delete, Event, StdinRepl, StopReason};
+pub use emulator::Emulator;
+pub use filter::{Filter, FilterKind, Frame};
+#[cfg(feature = "mock-frontend")]
+pub use frontend::MockFrontend;
+pub use frontend::{buttons, Frontend, InputState};
+pub use movie::{Movie, MovieError, MovieResult, MOVIE_VERSION};
+pub use palette::{Palette, PaletteError, PaletteResult, BUILTIN_PALETTES};
+pub use region::Region;
+pub use regression::{run_regression, RegressionError, RegressionReport, RegressionResult};
+pub use romdb::{sha1, RomDatabase, RomInfo};
+pub use saves::{output_path, prepare_save_dir, read_battery_save, save_path, write_battery_save};
+pub use speed::{MAX_SPEED, MIN_SPEED, UNLIMITED_SPEED};
+pub use state::{
+    diff_states, read_state_file, Difference, StateError, StateField, StateResult, StateValue,
+    STATE_VERSION,
+};
+pub use system::Quirks;
+pub use test_rom::{run_test_rom, TestResult};
+pub use window::{Overscan, WindowSize, DEFAULT_SCALE, MAX_SCALE};
+
+#[cfg(feature = "sdl")]
+pub use sdl::SdlError;
+#[cfg(feature = "sdl")]
 use sdl::SDL;
 
-const WINDOW_WIDTH: i32 = 600;
+use osd::Osd;
+
+/// How much the volume hotkeys change the volume by
+const VOLUME_STEP: f32 = 0.1;
+
+/// Time between frames on an NTSC NES (~60.1 Hz)
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_639_267);
+
+/// Output sample rates accepted, in Hz
+pub const SAMPLE_RATES: RangeInclusive<u32> = 8_000..=192_000;
+
+/// Audio buffer sizes accepted, in samples. SDL needs a power of two.
+pub const AUDIO_BUFFER_SIZES: RangeInclusive<u16> = 64..=16_384;
+
+/// Audio buffer size used unless one is given, ~23 ms at 44.1 kHz
+pub const DEFAULT_AUDIO_BUFFER: u16 = 1024;
+
+/// Number of frames in a row which can run over budget before run-ahead is turned off
+const RUN_AHEAD_SLOW_FRAMES: u32 = 30;
+
+/// Settings for the main loop
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub filter: FilterKind,
+    pub scanline_intensity: f32,
+
+    /// Number of frames to run ahead of the one presented, hiding that many frames of the
+    /// game's own input lag. 0 turns run-ahead off, as does tracing (see `CPU::is_tracing`).
+    ///
+    /// See: <https://docs.libretro.com/guides/runahead/>
+    pub run_ahead: usize,
+
+    /// Where the ROM was loaded from, for the reload hotkey
+    pub rom_path: Option<PathBuf>,
+
+    /// Where to keep savestates, instead of next to the ROM (see `save_path`)
+    pub save_dir: Option<PathBuf>,
+
+    /// Keep the cart's battery-backed RAM in a `.sav` file next to the ROM or in `save_dir`
+    /// across the reset, power cycle and reload hotkeys, which write it out first. Reading it on
+    /// start up and writing it on exit is left to the caller.
+    pub battery_saves: bool,
+
+    pub window_size: WindowSize,
+
+    /// Cover the whole screen, ignoring `window_size`
+    pub fullscreen: bool,
+
+    pub overscan: Overscan,
+
+    /// Play sound. The APU runs either way, as games can depend on its timing.
+    pub audio: bool,
+
+    /// Rate to play sound at, in Hz, within `SAMPLE_RATES`
+    pub sample_rate: u32,
+
+    /// Size of the audio device's buffer, in samples. Smaller buffers have less latency, but
+    /// may crackle on slower systems.
+    pub audio_buffer: u16,
+
+    /// Multiple of the console's frame rate to run at, from `MIN_SPEED` to `MAX_SPEED`, or
+    /// `UNLIMITED_SPEED`
+    pub speed: f32,
+
+    /// Start paused, with the debugger reading commands from stdin (see `StdinRepl`). Run-ahead
+    /// should be off, as it would run past breakpoints.
+    pub debug_repl: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            filter: FilterKind::None,
+            scanline_intensity: 0.5,
+            run_ahead: 1,
+            rom_path: None,
+            save_dir: None,
+            battery_saves: false,
+            window_size: WindowSize::default(),
+            fullscreen: false,
+            overscan: Overscan::default(),
+            audio: true,
+            sample_rate: SAMPLE_RATE,
+            audio_buffer: DEFAULT_AUDIO_BUFFER,
+            speed: 1.0,
+            debug_repl: false,
+        }
+    }
+}
+
+impl RunOptions {
+    /// The `.sav` file for the cart `cpu` has inserted, if keeping battery saves for a ROM file
+    pub fn battery_path(&self, cpu: &CPU) -> Option<PathBuf> {
+        let rom_path = self.rom_path.as_deref().filter(|_| self.battery_saves)?;
+        Some(save_path(
+            self.save_dir.as_deref(),
+            rom_path,
+            cpu.cart().crc32(),
+            "sav",
+        ))
+    }
+}
+
+/// Why running in an SDL window failed
+#[cfg(feature = "sdl")]
+#[derive(Debug)]
+pub enum RunError {
+    /// The window couldn't be opened
+    Sdl(SdlError),
+    Cpu(CpuError),
+    Io(io::Error),
+}
+
+#[cfg(feature = "sdl")]
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Sdl(err) => write!(f, "could not open a window ({})", err),
+            RunError::Cpu(err) => write!(f, "emulation stopped: {}", err),
+            RunError::Io(err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl std::error::Error for RunError {}
+
+#[cfg(feature = "sdl")]
+impl From<SdlError> for RunError {
+    fn from(err: SdlError) -> Self {
+        RunError::Sdl(err)
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<CpuError> for RunError {
+    fn from(err: CpuError) -> Self {
+        RunError::Cpu(err)
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<io::Error> for RunError {
+    fn from(err: io::Error) -> Self {
+        RunError::Io(err)
+    }
+}
 
-pub fn run() {
+/// Run the emulator in an SDL window until the user closes it
+#[cfg(feature = "sdl")]
+pub fn run(cpu: &mut CPU, options: &RunOptions) -> Result<(), RunError> {
     let mut sdl = SDL::construct();
-    sdl.init_video(WINDOW_WIDTH, WINDOW_WIDTH);
-    video::draw_frame(&sdl, WINDOW_WIDTH);
-    sdl.quit();
+    sdl.init_video(options.window_size, options.fullscreen)?;
+    sdl.set_overscan(options.overscan);
+    if options.audio {
+        // Frames are paced by the clock, so they run at the right speed without sound too
+        if let Err(err) = sdl.init_audio(options.sample_rate, options.audio_buffer) {
+            eprintln!("Warning: continuing without sound, as {}", err);
+        }
+    }
+    run_frontend(cpu, &mut sdl, options)?;
+    Ok(())
+}
+
+/// Open a window listing the ROMs in `dir` for the user to choose from. Returns `None` if they
+/// close it without choosing.
+#[cfg(feature = "sdl")]
+pub fn pick_rom(dir: &Path) -> Result<Option<PathBuf>, RunError> {
+    let mut sdl = SDL::construct();
+    sdl.init_video(WindowSize::default(), false)?;
+    Ok(picker::pick_rom(&mut sdl, dir)?)
+}
+
+/// Run the emulator without any output for a number of frames, e.g. for scripts which then
+/// inspect the final frame or RAM
+pub fn run_headless(cpu: &mut CPU, frames: u64) -> CpuResult<()> {
+    for _ in 0..frames {
+        cpu.run_frame()?;
+        // Nothing plays them, but this keeps them from piling up (and records them, if enabled)
+        cpu.take_audio_samples();
+    }
+    Ok(())
+}
+
+/// Throughput measured by `run_bench`
+#[derive(Debug, Clone, Copy)]
+pub struct BenchReport {
+    pub frames: u64,
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(f, "Frames: {}", self.frames)?;
+        writeln!(f, "Frames per second: {:.1}", self.frames as f64 / seconds)?;
+        writeln!(f, "Instructions: {}", self.instructions)?;
+        write!(
+            f,
+            "Instructions per second: {:.0}",
+            self.instructions as f64 / seconds
+        )
+    }
+}
+
+/// Run headless as fast as possible for (at least) `duration`, to measure how fast the core
+/// emulates. Frames are run by `run_headless`, so nothing is skipped that normal emulation
+/// would do, but tracing or recording audio will slow it down.
+pub fn run_bench(cpu: &mut CPU, duration: Duration) -> CpuResult<BenchReport> {
+    let start = std::time::Instant::now();
+    let instructions = cpu.instructions();
+    let mut frames = 0;
+    while start.elapsed() < duration {
+        run_headless(cpu, 1)?;
+        frames += 1;
+    }
+    Ok(BenchReport {
+        frames,
+        instructions: cpu.instructions() - instructions,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Run the emulator without a window, drawing the first nametable to stdout as ASCII art once
+/// per frame. See `CPU::nametable_text`.
+pub fn run_text_display(cpu: &mut CPU) -> CpuResult<()> {
+    let start = std::time::Instant::now();
+    let mut next_frame = Duration::ZERO;
+    loop {
+        cpu.run_frame()?;
+        // Move the cursor back to the top left, so each frame overwrites the last
+        print!("\x1b[H{}", cpu.nametable_text());
+
+        let interval = cpu.region().frame_duration();
+        spin_sleep::sleep(speed::schedule(&mut next_frame, interval, start.elapsed()));
+    }
+}
+
+/// The main loop: emulate a frame, present it, then wait until it is time for the next one.
+///
+/// If emulation fails, the loop stops emulating but keeps presenting the last frame, so the
+/// user can see what went wrong, and waits for them to reset or quit. The error is returned
+/// when they quit.
+pub fn run_frontend<F: Frontend>(
+    cpu: &mut CPU,
+    frontend: &mut F,
+    options: &RunOptions,
+) -> CpuResult<()> {
+    let mut filter_kind = options.filter;
+    let mut filter = filter_kind.build(options.scanline_intensity);
+    // The writers are shared with the savestate run-ahead rewinds to, so the frames it throws
+    // away would be traced as if they had run
+    let mut run_ahead = if cpu.is_tracing() {
+        0
+    } else {
+        options.run_ahead
+    };
+    let mut slow_frames = 0;
+    let mut frame = cpu.frame();
+    let mut error = None;
+    let mut speed = options.speed;
+    let mut osd = Osd::default();
+    let mut next_frame = frontend.now();
+    let mut repl = options.debug_repl.then(|| StdinRepl::new(cpu));
+    loop {
+        let mut input = frontend.poll_input();
+        if cpu.deterministic() {
+            // Only the movie can change how the game runs
+            input.reset = false;
+            input.soft_reset = false;
+            input.power_cycle = false;
+            input.reload_rom = false;
+            input.load_state = false;
+        }
+        if input.quit {
+            return error.map_or(Ok(()), Err);
+        }
+        if input.soft_reset || input.power_cycle || input.reload_rom {
+            // Any of these gets emulation going again after an error
+            error = None;
+        }
+        if input.soft_reset {
+            match flush_battery_save(cpu, options) {
+                Ok(()) => {
+                    cpu.reset();
+                    osd.show("Reset");
+                }
+                Err(err) => osd.show(format!("Could not reset: {}", err)),
+            }
+        }
+        if input.power_cycle {
+            match power_cycle_with_saves(cpu, None, options) {
+                Ok(()) => osd.show("Power cycled"),
+                Err(err) => osd.show(format!("Could not power cycle: {}", err)),
+            }
+        }
+        if input.reload_rom {
+            match &options.rom_path {
+                Some(path) => match reload_cart(cpu, path) {
+                    Ok((cart, warnings)) => {
+                        let message = match power_cycle_with_saves(cpu, Some(cart), options) {
+                            Ok(()) if warnings.is_empty() => format!("Reloaded {}", path.display()),
+                            Ok(()) => format!(
+                                "Reloaded {}. Warning: {}",
+                                path.display(),
+                                warnings.join("; ")
+                            ),
+                            Err(err) => format!("Could not reload {}: {}", path.display(), err),
+                        };
+                        osd.show(message);
+                    }
+                    Err(err) => osd.show(format!("Could not reload {}: {}", path.display(), err)),
+                },
+                None => osd.show("There is no ROM file to reload"),
+            }
+        }
+        if let Some(err) = error.take() {
+            if input.escape {
+                return Err(err);
+            }
+            if input.reset {
+                cpu.reset();
+                osd.show("Reset");
+            } else {
+                error = Some(err);
+            }
+        }
+        if input.cycle_filter {
+            filter_kind = filter_kind.next();
+            filter = filter_kind.build(options.scanline_intensity);
+            osd.show(format!("Filter: {}", filter.name()));
+        }
+        if input.volume_up || input.volume_down {
+            let step = if input.volume_up {
+                VOLUME_STEP
+            } else {
+                -VOLUME_STEP
+            };
+            cpu.set_volume(cpu.volume() + step);
+            osd.show(format!("Volume: {:.0}%", cpu.volume() * 100.0));
+        }
+        if input.toggle_mute {
+            cpu.set_muted(!cpu.muted());
+            osd.show(if cpu.muted() { "Muted" } else { "Unmuted" });
+        }
+        if input.toggle_sprite_limit {
+            cpu.set_sprite_limit(!cpu.sprite_limit());
+            osd.show(if cpu.sprite_limit() {
+                "Sprite limit on"
+            } else {
+                "Sprite limit off"
+            });
+        }
+        if input.speed_up || input.speed_down {
+            speed = if input.speed_up {
+                speed::faster(speed)
+            } else {
+                speed::slower(speed)
+            };
+            osd.show(format!("Speed: {}", speed::describe(speed)));
+        }
+        if input.save_state || input.load_state {
+            match &options.rom_path {
+                Some(rom_path) => {
+                    let path = save_path(
+                        options.save_dir.as_deref(),
+                        rom_path,
+                        cpu.cart().crc32(),
+                        "state",
+                    );
+                    let (result, action, done) = if input.save_state {
+                        let result = path
+                            .parent()
+                            .map_or(Ok(()), std::fs::create_dir_all)
+                            .and_then(|()| std::fs::write(&path, cpu.save_state_bytes()))
+                            .map_err(StateError::IoError);
+                        (result, "save", "Saved")
+                    } else {
+                        let result =
+                            read_state_file(&path).and_then(|bytes| cpu.load_state_bytes(&bytes));
+                        if result.is_ok() {
+                            // Like a reset, loading a state gets emulation going again
+                            error = None;
+                        }
+                        (result, "load", "Loaded")
+                    };
+                    match result {
+                        Ok(()) => osd.show(format!("{} {}", done, path.display())),
+                        Err(err) => {
+                            osd.show(format!("Could not {} {}: {}", action, path.display(), err))
+                        }
+                    }
+                }
+                None => osd.show("There is no ROM file to keep savestates next to"),
+            }
+        }
+        cpu.set_input(&input);
+
+        let started = frontend.now();
+        if error.is_none() {
+            let result = if let Some(repl) = &mut repl {
+                match repl.run_frame(cpu) {
+                    Ok(true) => Ok(cpu.frame()),
+                    Ok(false) => return Ok(()),
+                    Err(err) => Err(err),
+                }
+            } else if run_ahead > 0 {
+                run_ahead_frame(cpu, run_ahead)
+            } else {
+                cpu.run_frame().map(|()| cpu.frame())
+            };
+            match result {
+                Ok(new_frame) => frame = new_frame,
+                Err(err) => {
+                    report_fatal_error(cpu, &err);
+                    error = Some(err);
+                }
+            }
+        }
+        // At unlimited speed, every frame would count as too slow
+        if run_ahead > 0 && error.is_none() && speed != UNLIMITED_SPEED {
+            // Running ahead multiplies the work per frame, so give up if the host can't keep up
+            let frame_interval = speed::frame_interval(cpu.region().frame_duration(), speed);
+            if frontend.now().saturating_sub(started) > frame_interval {
+                slow_frames += 1;
+            } else {
+                slow_frames = 0;
+            }
+            if slow_frames == RUN_AHEAD_SLOW_FRAMES {
+                run_ahead = 0;
+                osd.show("Run-ahead disabled: frames are taking too long to emulate");
+            }
+        }
+        let mut output = filter.apply(&frame);
+        osd.draw(&mut output);
+        frontend.present_frame(&output);
+        // Always take the samples, even if they won't be played, so they don't pile up
+        let samples = cpu.take_audio_samples();
+        if let Some(samples) = speed::resample(&samples, speed, options.sample_rate) {
+            frontend.push_audio(&samples);
+        }
+
+        // `thread::sleep` can overshoot by a millisecond or more, which is a lot of a 16.6 ms
+        // frame, so spin for the last part of the wait
+        let interval = speed::frame_interval(cpu.region().frame_duration(), speed);
+        let wait = speed::schedule(&mut next_frame, interval, frontend.now());
+        if !wait.is_zero() {
+            spin_sleep::sleep(wait);
+        }
+    }
+}
+
+/// Read the ROM from disk again, along with warnings about any differences in the header which
+/// might make it a different game altogether
+fn reload_cart(cpu: &CPU, path: &Path) -> CartLoadResult<(Cart, Vec<String>)> {
+    let cart = Rom::from_file(path)?.into_cart()?;
+    let old_cart = cpu.cart();
+    let mut warnings = Vec::new();
+    if cart.mapper_number() != old_cart.mapper_number() {
+        warnings.push(format!(
+            "mapper changed from {} to {}",
+            old_cart.mapper_number(),
+            cart.mapper_number()
+        ));
+    }
+    if cart.prg_rom_len() != old_cart.prg_rom_len() {
+        warnings.push(format!(
+            "PRG ROM size changed from {} KiB to {} KiB",
+            old_cart.prg_rom_len() / 1024,
+            cart.prg_rom_len() / 1024
+        ));
+    }
+    Ok((cart, warnings))
+}
+
+/// Write the cart's battery-backed RAM out, if `options` keeps battery saves
+fn flush_battery_save(cpu: &CPU, options: &RunOptions) -> io::Result<()> {
+    match options.battery_path(cpu) {
+        Some(path) => write_battery_save(cpu, &path),
+        None => Ok(()),
+    }
+}
+
+/// Power cycle with `cart` (see `CPU::power_cycle`), writing the battery save out first and
+/// reading it back for the cart powered on, as its battery would have kept it
+fn power_cycle_with_saves(
+    cpu: &mut CPU,
+    cart: Option<Cart>,
+    options: &RunOptions,
+) -> io::Result<()> {
+    flush_battery_save(cpu, options)?;
+    cpu.power_cycle(cart);
+    match options.battery_path(cpu) {
+        Some(path) => read_battery_save(cpu, &path),
+        None => Ok(()),
+    }
+}
+
+/// Explain why emulation stopped, along with the last few instructions leading up to it
+fn report_fatal_error(cpu: &CPU, err: &CpuError) {
+    eprintln!("Emulation stopped: {}", err);
+    print_recent_instructions(cpu);
+    eprintln!("Press R to reset or Esc to quit");
+}
+
+/// Print the last few instructions run (see `CPU::recent_pcs`) to stderr, e.g. to show how
+/// emulation got to an error
+pub fn print_recent_instructions(cpu: &CPU) {
+    eprintln!("Last instructions:");
+    for pc in cpu.recent_pcs() {
+        let bytes = [0, 1, 2].map(|offset| cpu.peek_byte(pc.wrapping_add(offset)));
+        eprintln!(
+            "  {:04x}  {:02x} {:02x} {:02x}",
+            pc, bytes[0], bytes[1], bytes[2]
+        );
+    }
+}
+
+/// Emulate `frames` frames past the current one and return the last of them, then rewind and
+/// emulate only the current frame, so the game appears to react to input sooner. Only the audio
+/// of the current frame is kept.
+fn run_ahead_frame(cpu: &mut CPU, frames: usize) -> CpuResult<Frame> {
+    let state = cpu.save_state();
+    for _ in 0..=frames {
+        cpu.run_frame()?;
+    }
+    let frame = cpu.frame();
+    cpu.load_state(&state);
+    cpu.run_frame()?;
+    Ok(frame)
 }
@@ -1,35 +1,847 @@
-use rusty_nes::{CartLoadError, CPU};
+use rusty_nes::{
+    CartKind, Config, ConfigError, Emulator, FamiBasicKeyboard, FilterKind, JamPolicy, Movie,
+    Overscan, Palette, Quirks, Region, Rom, RunError, RunOptions, StateField, StateValue,
+    TraceColumns, UnknownOpcodePolicy, WindowSize, CPU, DEFAULT_AUDIO_BUFFER, MAX_SCALE, MAX_SPEED,
+    MIN_SPEED, SAMPLE_RATE, SAMPLE_RATES, UNLIMITED_SPEED,
+};
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
 
 #[derive(Parser)]
 struct RustyArgs {
     /// Filename of the ROM
-    filename: String,
+    #[arg(required_unless_present_any = ["picker", "regression"])]
+    filename: Option<String>,
+
+    /// Choose a ROM from the current directory in a window, instead of passing a filename
+    #[arg(long, action)]
+    picker: bool,
+
+    /// Config file to read settings from, instead of config.toml in the user's config directory.
+    /// Flags given here take precedence over it.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Print what was loaded from the ROM's header, and where the game starts, before running
+    #[arg(short, long, action)]
+    verbose: bool,
 
     /// Whether to disable the debugger mode
     #[arg(short, long, action)]
     nodebug: bool,
+
+    /// Print a nestest-format trace of every instruction to stdout
+    #[arg(long, action, conflicts_with_all = ["nodebug", "trace_file"])]
+    trace: bool,
+
+    /// Write a nestest-format trace of every instruction to a file
+    #[arg(long, conflicts_with = "nodebug")]
+    trace_file: Option<PathBuf>,
+
+    /// Write the debugger's trace of every instruction to a file, instead of stdout
+    #[arg(long, conflicts_with_all = ["nodebug", "trace", "trace_file"])]
+    log: Option<PathBuf>,
+
+    /// Stop tracing after this many instructions
+    #[arg(long)]
+    trace_limit: Option<u64>,
+
+    /// Columns to include in the trace after the disassembly, as a comma separated list of
+    /// registers, ppu, frame and cycles (default registers,ppu,cycles, as in nestest's log)
+    #[arg(long)]
+    trace_columns: Option<TraceColumns>,
+
+    /// Record the audio to a WAV file (32-bit float, mono)
+    #[arg(long)]
+    audio_dump: Option<PathBuf>,
+
+    /// Post-processing filter: none (the default), scanlines or ntsc (press F to cycle while
+    /// running)
+    #[arg(long)]
+    filter: Option<FilterKind>,
+
+    /// How much the scanlines filter darkens alternate lines, from 0.0 to 1.0 (default 0.5)
+    #[arg(long, value_parser = parse_intensity)]
+    scanline_intensity: Option<f32>,
+
+    /// Size of the window, as a multiple of the NES's 256x240 picture (default 3)
+    #[arg(long, conflicts_with = "window_size",
+          value_parser = clap::value_parser!(u32).range(1..=MAX_SCALE as i64))]
+    scale: Option<u32>,
+
+    /// Size of the window, e.g. 1024x768, instead of a --scale
+    #[arg(long)]
+    window_size: Option<WindowSize>,
+
+    /// Cover the whole screen, instead of opening a window
+    #[arg(long, action)]
+    fullscreen: bool,
+
+    /// Scanlines to crop from the top and bottom of the picture, as LINES or TOP,BOTTOM (TVs
+    /// usually hid about 8; default 0)
+    #[arg(long)]
+    overscan: Option<Overscan>,
+
+    /// Colours to display the picture with: a .pal file, or builtin:ntsc or builtin:pal
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// Speed to run at, as a multiple of normal like 0.5 or a percentage like 50% (press [ and ]
+    /// to step it down and up while running)
+    #[arg(long, value_parser = parse_speed)]
+    speed: Option<f32>,
+
+    /// Run as fast as possible, without sound
+    #[arg(long, alias = "no-frame-limit", action, conflicts_with = "speed")]
+    turbo: bool,
+
+    /// Don't play any sound
+    #[arg(long, action)]
+    no_audio: bool,
+
+    /// Draw every sprite on a scanline, rather than only the first 8 as the console does, so
+    /// sprites don't flicker (press S to toggle it while running)
+    #[arg(long, action)]
+    no_sprite_limit: bool,
+
+    /// What to do on an unknown opcode: panic, error (stop emulation and report it) or nop
+    #[arg(long, default_value = "error")]
+    unknown_opcode: UnknownOpcodePolicy,
+
+    /// What to do when the CPU gets stuck looping on one instruction it can't leave, or runs
+    /// into open bus: off, warn or error (stop emulation and report it)
+    #[arg(long, default_value = "off")]
+    jam_check: JamPolicy,
+
+    /// Stop emulation and report it on reaching a BRK instruction, which simple test programs use
+    /// to mark their end
+    #[arg(long, action)]
+    stop_on_brk: bool,
+
+    /// Warn about reads of RAM which hasn't been written since power on, once per address, with
+    /// the address of the instruction responsible
+    #[arg(long, action)]
+    warn_uninit: bool,
+
+    /// Warn when the stack pointer wraps around page one, usually from a stack overflow, with
+    /// the address of the instruction responsible
+    #[arg(long, action)]
+    stack_check: bool,
+
+    /// Plug the Family BASIC keyboard in place of controller 2
+    #[arg(long, action)]
+    family_basic: bool,
+
+    /// Emulate controller reads losing a bit when they coincide with a DMC DMA
+    #[arg(long, action)]
+    dma_controller_glitch: bool,
+
+    /// Ignore writes to some PPU registers for the first 29658 CPU cycles after power on, as the
+    /// PPU is still warming up
+    #[arg(long, action)]
+    ppu_warm_up: bool,
+
+    /// Console to emulate the timing of: ntsc, pal or dendy. By default it comes from the ROM's
+    /// header, or is NTSC if the header doesn't say.
+    #[arg(long)]
+    region: Option<Region>,
+
+    /// Rate to play sound at, in Hz (default 44100)
+    #[arg(long, value_parser = clap::value_parser!(u32)
+          .range(*SAMPLE_RATES.start() as i64..=*SAMPLE_RATES.end() as i64))]
+    sample_rate: Option<u32>,
+
+    /// Size of the audio buffer in samples, a power of two (default 1024). Smaller has less
+    /// latency, but may crackle.
+    #[arg(long, value_parser = parse_audio_buffer)]
+    audio_buffer: Option<u16>,
+
+    /// Master volume, from 0 to 100 (default 100; press -/+ to adjust and M to mute while
+    /// running)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    volume: Option<u8>,
+
+    /// Frames to run ahead to hide the game's input lag, or 0 to turn run-ahead off (default 1)
+    #[arg(long)]
+    run_ahead: Option<usize>,
+
+    /// Count how many times each opcode is executed, and print the counts on exit
+    #[arg(long, action)]
+    count_opcodes: bool,
+
+    /// Emulate this many frames before opening the window, e.g. to skip a game's start-up
+    #[arg(long, default_value_t = 0)]
+    skip_frames: u32,
+
+    /// Draw the background to the terminal as text instead of opening a window
+    #[arg(long, action)]
+    text_display: bool,
+
+    /// Start paused, with a debugger reading commands from stdin while the window runs. Type h
+    /// for a list of commands.
+    #[arg(long, action, conflicts_with_all = ["headless", "text_display", "bench", "trace"])]
+    debug_repl: bool,
+
+    /// Run without a window for the number of frames given by --frames, then exit
+    #[arg(long, action, requires = "frames")]
+    headless: bool,
+
+    /// Number of frames to run for in --headless mode
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Run without a window, sound or frame limit for this many seconds, then print how fast
+    /// the emulator ran
+    #[arg(long, conflicts_with_all = ["headless", "text_display", "trace", "trace_file", "audio_dump"],
+          value_parser = parse_seconds)]
+    bench: Option<Duration>,
+
+    /// In --headless mode, save the last frame as a PNG file
+    #[arg(long, requires = "headless")]
+    screenshot: Option<PathBuf>,
+
+    /// In --headless mode, save the contents of RAM ($0000-$07FF) to a file
+    #[arg(long, requires = "headless")]
+    dump_ram: Option<PathBuf>,
+
+    /// In --headless mode, print the addresses in RAM where these bytes are found, given in hex
+    /// like "a9 01"
+    #[arg(long, requires = "headless", value_parser = parse_byte_pattern)]
+    search_ram: Option<BytePattern>,
+
+    /// Record the buttons pressed on every frame to a movie file, to play back with --play
+    #[arg(long, conflicts_with_all = ["play", "state"])]
+    record: Option<PathBuf>,
+
+    /// Play back a movie file recorded with --record, from power on
+    #[arg(long, conflicts_with = "state")]
+    play: Option<PathBuf>,
+
+    /// Only take input from the movie being played, ignoring the controllers and the hotkeys which
+    /// reset or load a savestate, so that every run of the same ROM and movie is identical
+    #[arg(long, action)]
+    deterministic: bool,
+
+    /// Directory to keep savestates and battery saves in, in a subdirectory for each ROM, instead
+    /// of next to the ROM. Screenshots, movies and --save-state files given as a bare file name
+    /// go there too.
+    #[arg(long)]
+    save_dir: Option<PathBuf>,
+
+    /// Start from a savestate file, saved with F5 or --save-state, instead of powering on
+    #[arg(long)]
+    state: Option<PathBuf>,
+
+    /// Print every field of a savestate file for the ROM, then exit. RAM and the other blocks of
+    /// bytes are shown as a CRC-32, or in full with --verbose.
+    #[arg(long, conflicts_with = "diff_states")]
+    dump_state: Option<PathBuf>,
+
+    /// Print the fields which differ between two savestate files for the ROM, e.g. to find where
+    /// two runs diverged, then exit
+    #[arg(long, num_args = 2, value_names = ["A", "B"])]
+    diff_states: Option<Vec<PathBuf>>,
+
+    /// In --headless mode, save a savestate file at the end of the run
+    #[arg(long, requires = "headless")]
+    save_state: Option<PathBuf>,
+
+    /// In --headless mode, run a test ROM which reports its result at $6000 (as blargg's do)
+    /// until it finishes, for at most --frames frames, then print the result. Exits with an
+    /// error if the test failed.
+    #[arg(long, action, requires = "headless")]
+    test_rom: bool,
+
+    /// In --headless mode, print the registers, flags and cycle count at the end of the run,
+    /// even if emulation failed
+    #[arg(long, action, requires = "headless")]
+    print_registers: bool,
+
+    /// Run the rendering regression checks listed in a manifest (see the README) instead of a
+    /// ROM, then exit. Frames which don't match are saved to target/regression. Set
+    /// RUSTY_NES_UPDATE_HASHES=1 to record the hashes the ROMs render now instead.
+    #[arg(long, conflicts_with_all = ["filename", "picker"])]
+    regression: Option<PathBuf>,
+}
+
+/// Where the CPU's per-instruction log goes
+enum TraceOutput {
+    Off,
+    /// The human-readable debugger format, unless `--trace` asks for nestest format
+    Debug,
+    Stdout,
+    File(PathBuf),
+    /// The debugger format, to a file
+    Log(PathBuf),
+}
+
+impl RustyArgs {
+    fn trace_output(&self) -> TraceOutput {
+        if let Some(path) = &self.trace_file {
+            TraceOutput::File(path.clone())
+        } else if self.trace {
+            TraceOutput::Stdout
+        } else if let Some(path) = &self.log {
+            TraceOutput::Log(path.clone())
+        } else if self.nodebug || self.bench.is_some() || self.debug_repl {
+            TraceOutput::Off
+        } else {
+            TraceOutput::Debug
+        }
+    }
+
+    /// The settings given on the command line, which take precedence over the config file
+    fn cli_config(&self) -> Config {
+        let mut config = Config::default();
+        config.palette = self.palette.clone();
+        config.filter = self.filter;
+        config.scanline_intensity = self.scanline_intensity;
+        config.scale = self.scale;
+        config.window_size = self.window_size;
+        config.fullscreen = self.fullscreen.then_some(true);
+        config.overscan = self.overscan;
+        config.speed = self.speed;
+        config.volume = self.volume;
+        config.audio = self.no_audio.then_some(false);
+        config.sprite_limit = self.no_sprite_limit.then_some(false);
+        config.sample_rate = self.sample_rate;
+        config.audio_buffer = self.audio_buffer;
+        config.run_ahead = self.run_ahead;
+        config.save_dir = self.save_dir.clone();
+        config
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Read the config file given by `--config`, or else the one in the user's config directory,
+/// creating it if it doesn't exist yet
+fn load_config(path: Option<&Path>) -> Config {
+    let named = path.is_some();
+    let (path, result) = match path {
+        Some(path) => (path.to_path_buf(), Config::from_file(path)),
+        None => match rusty_nes::default_config_path() {
+            Some(path) => {
+                let result = Config::load_or_create(&path);
+                (path, result)
+            }
+            None => return Config::default(),
+        },
+    };
+    let config = match result {
+        Ok(config) => config,
+        // A missing or unwritable config directory shouldn't stop the emulator from running,
+        // unless the file was asked for by name
+        Err(ConfigError::IoError(err)) if !named => {
+            eprintln!(
+                "warning: could not read or create {}: {}",
+                path.display(),
+                err
+            );
+            Config::default()
+        }
+        Err(err) => exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display())),
+    };
+    for key in config.unknown_keys() {
+        eprintln!(
+            "warning: unknown setting '{}' in {}, which will be ignored",
+            key,
+            path.display()
+        );
+    }
+    config
+}
+
+fn parse_intensity(s: &str) -> Result<f32, String> {
+    let intensity: f32 = s.parse().map_err(|_| format!("'{s}' is not a number"))?;
+    if (0.0..=1.0).contains(&intensity) {
+        Ok(intensity)
+    } else {
+        Err("intensity must be between 0.0 and 1.0".to_string())
+    }
+}
+
+fn parse_speed(s: &str) -> Result<f32, String> {
+    let (number, scale) = match s.strip_suffix('%') {
+        Some(percent) => (percent, 100.0),
+        None => (s, 1.0),
+    };
+    let speed = number
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("'{s}' is not a number or percentage"))?
+        / scale;
+    if (MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "speed must be between {MIN_SPEED} and {MAX_SPEED} (or {}% and {}%)",
+            MIN_SPEED * 100.0,
+            MAX_SPEED * 100.0
+        ))
+    }
+}
+
+fn parse_audio_buffer(s: &str) -> Result<u16, String> {
+    let samples: u16 = s
+        .parse()
+        .map_err(|_| format!("'{s}' is not a number of samples"))?;
+    rusty_nes::check_audio_buffer(samples).map_err(|message| format!("audio buffer {message}"))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|_| format!("'{s}' is not a number"))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| "seconds must be a positive number".to_string())
+}
+
+/// A sequence of bytes to search memory for
+#[derive(Clone)]
+struct BytePattern(Vec<u8>);
+
+fn parse_byte_pattern(s: &str) -> Result<BytePattern, String> {
+    let invalid = || format!("'{s}' is not a sequence of hex bytes, e.g. \"a9 01\"");
+    let digits: String = s.split_whitespace().collect();
+    if digits.is_empty()
+        || !digits.chars().all(|c| c.is_ascii_hexdigit())
+        || !digits.len().is_multiple_of(2)
+    {
+        return Err(invalid());
+    }
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    Ok(BytePattern(bytes))
+}
+
+/// Exit status for a problem with the command line or the files it names, as clap also uses
+/// for unknown arguments
+const EXIT_USAGE: i32 = 2;
+
+/// Exit status for a failure after starting, e.g. emulation stopping or no window
+const EXIT_FAILURE: i32 = 1;
+
+/// Environment variable which makes `--regression` record the hashes the ROMs render now
+const UPDATE_HASHES_VAR: &str = "RUSTY_NES_UPDATE_HASHES";
+
+fn main() {
     let args = RustyArgs::parse();
+    let trace_output = args.trace_output();
+    let config = args.cli_config().or(load_config(args.config.as_deref()));
 
-    let mut cpu = CPU::new(args.filename, !args.nodebug).unwrap_or_else(|err| match err {
-        CartLoadError::FileNotARom => {
-            panic!("Not a valid ROM file.")
+    if let Some(path) = &args.regression {
+        run_regression(path);
+        return;
+    }
+
+    let filename = match args.filename {
+        Some(filename) => filename,
+        None => match rusty_nes::pick_rom(Path::new(".")) {
+            Ok(Some(path)) => path.to_string_lossy().into_owned(),
+            Ok(None) => return,
+            Err(err) => exit_with_error(EXIT_FAILURE, err),
+        },
+    };
+
+    let debug = matches!(trace_output, TraceOutput::Debug);
+    let mut cpu = CPU::new(filename.clone(), debug)
+        .unwrap_or_else(|err| exit_with_error(EXIT_USAGE, format!("{}: {}", err, filename)));
+    if let Some(region) = args.region {
+        cpu.set_region(region);
+    }
+    if args.verbose {
+        print_rom_details(&filename, &cpu);
+    }
+    cpu.set_unknown_opcode_policy(args.unknown_opcode);
+    cpu.set_stack_check(args.stack_check);
+    cpu.set_jam_policy(args.jam_check);
+    cpu.set_stop_on_brk(args.stop_on_brk);
+    cpu.set_deterministic(args.deterministic);
+    cpu.set_uninit_check(args.warn_uninit);
+    cpu.set_sprite_limit(config.sprite_limit.unwrap_or(true));
+    if let Some(spec) = &config.palette {
+        match Palette::load(spec) {
+            Ok(palette) => cpu.set_palette(palette),
+            Err(err) => exit_with_error(EXIT_USAGE, format!("{}: {}", err, spec)),
         }
-        CartLoadError::FileNotFound => {
-            panic!("ROM file not found.")
+    }
+    match trace_output {
+        TraceOutput::Stdout => {
+            let stdout = BufWriter::new(std::io::stdout());
+            cpu.set_trace_writer(Box::new(stdout), args.trace_limit);
+            cpu.set_trace_columns(args.trace_columns.unwrap_or_default());
         }
-        CartLoadError::IoError(io_err) => {
-            panic!("IO Error: {}", io_err);
+        TraceOutput::File(path) => {
+            if let Err(err) = cpu.set_trace_file(&path, args.trace_limit) {
+                exit_with_error(
+                    EXIT_USAGE,
+                    format!("could not create {}: {}", path.display(), err),
+                );
+            }
+            cpu.set_trace_columns(args.trace_columns.unwrap_or_default());
         }
+        TraceOutput::Log(path) => {
+            let file = create_file(&path);
+            cpu.set_debug_writer(Box::new(BufWriter::new(file)));
+        }
+        TraceOutput::Off | TraceOutput::Debug => {}
+    }
+    if let Some(path) = &args.audio_dump {
+        let result = File::create(path).and_then(|file| cpu.set_audio_dump(file));
+        if let Err(err) = result {
+            exit_with_error(
+                EXIT_USAGE,
+                format!("could not create {}: {}", path.display(), err),
+            );
+        }
+    }
+    if let CartKind::VsSystem(ppu, system) = cpu.cart().kind() {
+        eprintln!(
+            "Warning: this is a VS System ROM ({:?} PPU, {:?} board), but the VS System's copy \
+             protection isn't emulated",
+            ppu, system
+        );
+    }
+    cpu.set_opcode_counting(args.count_opcodes);
+    cpu.set_frame_hash_logging(args.headless);
+    cpu.set_volume(f32::from(config.volume.unwrap_or(100)) / 100.0);
+    cpu.set_quirks(Quirks {
+        dma_controller_glitch: args.dma_controller_glitch,
+        ppu_warm_up: args.ppu_warm_up,
     });
-    for _ in 1..100 {
-        cpu.run_opcode();
+    if args.family_basic {
+        cpu.connect_input_device(1, Box::new(FamiBasicKeyboard::new()));
+    }
+
+    if let Some(dir) = &config.save_dir {
+        if let Err(err) = rusty_nes::prepare_save_dir(dir) {
+            exit_with_error(
+                EXIT_USAGE,
+                format!(
+                    "could not write to save directory {}: {}",
+                    dir.display(),
+                    err
+                ),
+            );
+        }
+    }
+    let rom_path = PathBuf::from(&filename);
+    let rom_crc = cpu.cart().crc32();
+    let output =
+        |path: &Path| rusty_nes::output_path(config.save_dir.as_deref(), &rom_path, rom_crc, path);
+    let record_path = args.record.as_deref().map(output);
+    // A game's own saves would make a movie play differently from how it was recorded
+    let battery_saves = !(args.deterministic || args.play.is_some() || args.record.is_some());
+    let battery_path = battery_saves
+        .then(|| rusty_nes::save_path(config.save_dir.as_deref(), &rom_path, rom_crc, "sav"));
+    if let Some(path) = &battery_path {
+        if let Err(err) = rusty_nes::read_battery_save(&mut cpu, path) {
+            exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display()));
+        }
+    }
+
+    if let Some(path) = &args.state {
+        let result =
+            rusty_nes::read_state_file(path).and_then(|bytes| cpu.load_state_bytes(&bytes));
+        if let Err(err) = result {
+            exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display()));
+        }
+    }
+
+    if let Some(path) = &args.play {
+        let result = Movie::from_file(path).and_then(|movie| cpu.play_movie(movie));
+        if let Err(err) = result {
+            exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display()));
+        }
+    }
+    if args.record.is_some() {
+        cpu.record_movie();
+    }
+
+    for _ in 0..args.skip_frames {
+        if let Err(err) = cpu.run_frame() {
+            exit_with_error(EXIT_FAILURE, err);
+        }
+    }
+
+    if let Some(path) = &args.dump_state {
+        for field in read_state_fields(&cpu, path) {
+            println!("{}", field);
+            if let (true, StateValue::Bytes(bytes)) = (args.verbose, &field.value) {
+                print_hexdump(bytes);
+            }
+        }
+        return;
+    }
+    if let Some(paths) = &args.diff_states {
+        let a = read_state_fields(&cpu, &paths[0]);
+        let b = read_state_fields(&cpu, &paths[1]);
+        let differences = rusty_nes::diff_states(&a, &b);
+        if differences.is_empty() {
+            println!("The savestates are the same");
+        }
+        for difference in differences {
+            println!("{}", difference);
+        }
+        return;
+    }
+
+    if let Some(duration) = args.bench {
+        match rusty_nes::run_bench(&mut cpu, duration) {
+            Ok(report) => println!("{}", report),
+            Err(err) => exit_with_error(EXIT_FAILURE, err),
+        }
+        return;
+    }
+
+    if args.headless {
+        let frames = args.frames.unwrap_or_default();
+        let result = if args.test_rom {
+            rusty_nes::run_test_rom(&mut cpu, frames).map(Some)
+        } else {
+            rusty_nes::run_headless(&mut cpu, frames).map(|()| None)
+        };
+        finish_recordings(&mut cpu, record_path.as_deref(), battery_path.as_deref());
+        if args.print_registers {
+            println!("{}", cpu);
+        }
+        let test_result = result.unwrap_or_else(|err| {
+            eprintln!("error: {}", err);
+            rusty_nes::print_recent_instructions(&cpu);
+            std::process::exit(EXIT_FAILURE);
+        });
+        print_frame_hashes(cpu.frame_hash_log());
+        if let Some(path) = args.screenshot {
+            write_output(&output(&path), &cpu.frame().to_png());
+        }
+        if let Some(path) = args.dump_ram {
+            write_output(&path, cpu.ram());
+        }
+        if let Some(BytePattern(pattern)) = &args.search_ram {
+            for address in cpu.search_memory(pattern, 0x0000..=0x07ff) {
+                println!("Found at ${:04x}", address);
+            }
+        }
+        if let Some(path) = args.save_state {
+            write_output(&output(&path), &cpu.save_state_bytes());
+        }
+        if let Some(test_result) = test_result {
+            println!("{}", test_result);
+            if !test_result.passed() {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        return;
+    }
+
+    if args.text_display {
+        // Clear the terminal once; each frame then overwrites the last
+        print!("\x1b[2J");
+        let result = rusty_nes::run_text_display(&mut cpu);
+        finish_recordings(&mut cpu, record_path.as_deref(), battery_path.as_deref());
+        if let Err(err) = result {
+            exit_with_error(EXIT_FAILURE, err);
+        }
+        return;
+    }
+
+    let options = RunOptions {
+        rom_path: Some(rom_path),
+        battery_saves,
+        save_dir: config.save_dir.clone(),
+        filter: config.filter.unwrap_or(FilterKind::None),
+        scanline_intensity: config.scanline_intensity.unwrap_or(0.5),
+        // Run-ahead would run past breakpoints. It's also off while tracing (see
+        // `run_frontend`).
+        run_ahead: if args.debug_repl {
+            0
+        } else {
+            config.run_ahead.unwrap_or(1)
+        },
+        window_size: config.window_size(),
+        fullscreen: config.fullscreen.unwrap_or(false),
+        overscan: config.overscan.unwrap_or_default(),
+        audio: config.audio.unwrap_or(true),
+        sample_rate: config.sample_rate.unwrap_or(SAMPLE_RATE),
+        audio_buffer: config.audio_buffer.unwrap_or(DEFAULT_AUDIO_BUFFER),
+        speed: if args.turbo {
+            UNLIMITED_SPEED
+        } else {
+            config.speed.unwrap_or(1.0)
+        },
+        debug_repl: args.debug_repl,
+    };
+    let mut emulator = Emulator::from_cpu(cpu, options);
+    let result = emulator.run();
+    // The reload hotkey may have swapped in a ROM with another CRC, and so another save file
+    let battery_path = emulator.options().battery_path(emulator.cpu());
+    let cpu = emulator.cpu_mut();
+    finish_recordings(cpu, record_path.as_deref(), battery_path.as_deref());
+    if let Some(counts) = cpu.opcode_counts() {
+        print_opcode_counts(counts);
+    }
+    match result {
+        Err(RunError::Sdl(err)) => {
+            exit_with_error(
+                EXIT_FAILURE,
+                format!(
+                    "could not open a window ({}). Use --headless to run without one.",
+                    err
+                ),
+            );
+        }
+        Err(err) => exit_with_error(EXIT_FAILURE, err),
+        Ok(()) => {}
+    }
+}
+
+/// Create a file to write output to, exiting if it can't be
+fn create_file(path: &Path) -> File {
+    File::create(path).unwrap_or_else(|err| {
+        exit_with_error(
+            EXIT_USAGE,
+            format!("could not create {}: {}", path.display(), err),
+        )
+    })
+}
+
+/// Flush the trace, finish the audio recording, save the movie to `movie_path` if recording one
+/// and the cart's battery-backed RAM to `battery_path`, which exiting would otherwise cut short
+fn finish_recordings(cpu: &mut CPU, movie_path: Option<&Path>, battery_path: Option<&Path>) {
+    if let Err(err) = cpu.flush_trace() {
+        eprintln!("warning: could not write the trace: {}", err);
+    }
+    if let Err(err) = cpu.finish_audio_dump() {
+        eprintln!("warning: could not write the audio recording: {}", err);
+    }
+    if let (Some(path), Some(movie)) = (movie_path, cpu.finish_movie()) {
+        write_output(path, &movie.to_bytes());
+    }
+    if let Some(path) = battery_path {
+        if let Err(err) = rusty_nes::write_battery_save(cpu, path) {
+            eprintln!("warning: could not write {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Write a file, creating its directory if needed, e.g. a ROM's subdirectory of the save directory
+fn write_output(path: &Path, contents: &[u8]) {
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(path, contents));
+    if let Err(err) = result {
+        exit_with_error(
+            EXIT_FAILURE,
+            format!("could not write {}: {}", path.display(), err),
+        );
+    }
+}
+
+/// Print a one line message, in the same style as clap's, and exit
+fn exit_with_error(code: i32, err: impl Display) -> ! {
+    eprintln!("error: {}", err);
+    std::process::exit(code);
+}
+
+/// Run the regression checks in a manifest and print how each went, or exit if any failed
+fn run_regression(path: &Path) {
+    let update = std::env::var_os(UPDATE_HASHES_VAR).is_some_and(|value| value != "0");
+    let target_dir = std::env::var_os("CARGO_TARGET_DIR").unwrap_or("target".into());
+    let output_dir = Path::new(&target_dir).join("regression");
+    let reports = rusty_nes::run_regression(path, &output_dir, update)
+        .unwrap_or_else(|err| exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display())));
+    for report in &reports {
+        println!("{}", report);
+    }
+    let failures = reports.iter().filter(|report| !report.passed()).count();
+    if failures > 0 {
+        exit_with_error(
+            EXIT_FAILURE,
+            format!(
+                "{} of {} regression checks failed (set {}=1 to record the hashes they render now)",
+                failures,
+                reports.len(),
+                UPDATE_HASHES_VAR
+            ),
+        );
     }
+}
 
-    // rusty_nes::run();
-    Ok(())
+/// Print the hashes of a few frames spread through a headless run, to compare against a
+/// known-good run
+fn print_frame_hashes(hashes: &[u32]) {
+    for frame in [60, 120, 180] {
+        if let Some(hash) = hashes.get(frame - 1) {
+            println!("Frame {} hash: {:08x}", frame, hash);
+        }
+    }
+}
+
+/// Read a savestate file's fields (see `CPU::state_fields`), or exit if it can't be loaded
+fn read_state_fields(cpu: &CPU, path: &Path) -> Vec<StateField> {
+    rusty_nes::read_state_file(path)
+        .and_then(|bytes| cpu.state_file_fields(&bytes))
+        .unwrap_or_else(|err| exit_with_error(EXIT_USAGE, format!("{}: {}", err, path.display())))
+}
+
+/// Print bytes 16 to a line, after their offset
+fn print_hexdump(bytes: &[u8]) {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("  {:04x}: {}", line * 16, hex.join(" "));
+    }
+}
+
+/// Bytes shown from the reset vector by `print_rom_details`
+const RESET_VECTOR_BYTES: u16 = 8;
+
+/// Print the ROM's header, and what was made of it, for --verbose
+fn print_rom_details(filename: &str, cpu: &CPU) {
+    // The ROM already loaded, so the header can be read again
+    if let Ok(rom) = Rom::from_file(filename) {
+        let version = if rom.is_nes2() { "NES 2.0" } else { "iNES" };
+        println!("Header: {}", version);
+        println!("Mapper: {}", rom.mapper_number());
+        println!("PRG ROM: {} KiB", rom.prg_size_kb());
+        println!("CHR ROM: {} KiB", rom.chr_size_kb());
+        println!("Mirroring: {:?}", rom.mirroring());
+        println!("Battery: {}", if rom.has_battery() { "yes" } else { "no" });
+        println!("Trainer: {}", if rom.has_trainer() { "yes" } else { "no" });
+    }
+    let cart = cpu.cart();
+    println!(
+        "Using mapper {} ({})",
+        cart.mapper_number(),
+        cpu.mapper_name()
+    );
+    println!("Using mirroring: {:?}", cpu.mirroring());
+    println!("Region: {:?}", cpu.region());
+    let reset_vector = u16::from_le_bytes([cpu.peek_byte(0xfffc), cpu.peek_byte(0xfffd)]);
+    let bytes: Vec<String> = (0..RESET_VECTOR_BYTES)
+        .map(|offset| format!("{:02x}", cpu.peek_byte(reset_vector.wrapping_add(offset))))
+        .collect();
+    println!("Reset vector: ${:04x}: {}", reset_vector, bytes.join(" "));
+}
+
+/// Print the executed opcodes, most frequent first
+fn print_opcode_counts(counts: &[u64; 256]) {
+    let mut executed: Vec<(usize, u64)> = counts
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    executed.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    println!("Opcode counts:");
+    for (opcode, count) in executed {
+        println!("  {:02x}: {}", opcode, count);
+    }
 }
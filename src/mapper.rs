@@ -0,0 +1,904 @@
+//! Cartridge mappers, which decide what the CPU sees at `$4020-$FFFF`.
+//!
+//! Some iNES mapper numbers were given to more than one board before anyone noticed. Mapper 34
+//! is both BNROM and NINA-001, which are told apart by NINA-001 having CHR ROM and BNROM having
+//! CHR RAM.
+//!
+//! See: <https://www.nesdev.org/wiki/Mapper>
+
+use std::fmt::Debug;
+
+use crate::cart::{Cart, Mirroring};
+use crate::state::{StateReader, StateResult, StateWriter};
+
+pub trait Mapper: Debug {
+    /// A read of `$4020-$FFFF`
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8;
+
+    /// A write to `$4020-$FFFF`, e.g. to switch banks
+    fn cpu_write(&mut self, address: u16, value: u8);
+
+    /// A read of the pattern tables at `$0000-$1FFF` by the PPU (or of the nametables at
+    /// `$2800-$2FFF`, which only reaches `FourScreenVram`)
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8;
+
+    /// A write to the pattern tables by the PPU, which only does anything if they are CHR RAM
+    fn ppu_write(&mut self, _address: u16, _value: u8) {}
+
+    /// Whether the mapper is pulling the CPU's IRQ line low. The line is level-triggered, so it
+    /// stays asserted until the game acknowledges the interrupt by writing to the mapper, and the
+    /// CPU polls it after every instruction.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// How the mapper has set the nametables to be mirrored, if it controls that rather than the
+    /// cart's wiring (which makes the cart's mirroring `MapperControlled`). Checked after every
+    /// write to the mapper.
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    /// The board's usual name, e.g. `MMC1`
+    fn name(&self) -> &'static str;
+
+    /// Put the mapper back in a state the game's reset code can rely on, when the console is
+    /// reset. Banks are kept by default, as most boards can't tell that reset was pressed.
+    fn reset(&mut self) {}
+
+    /// Whether there is RAM at `$6000-$7FFF`
+    fn has_prg_ram(&self) -> bool {
+        self.prg_ram().is_some()
+    }
+
+    /// The RAM at `$6000-$7FFF`, if there is any, which is kept in a save file if the cart has a
+    /// battery
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Copy the mapper and its banking state, for savestates
+    fn box_clone(&self) -> Box<dyn Mapper>;
+
+    /// Save the banking state (and any RAM) to a savestate file. Mappers without any state
+    /// needn't implement this or `read_state`.
+    fn write_state(&self, _state: &mut StateWriter) {}
+
+    fn read_state(&mut self, _state: &mut StateReader) -> StateResult<()> {
+        Ok(())
+    }
+}
+
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// The mapper for an iNES mapper number, if it is supported. `has_chr_rom` tells apart the
+/// boards which share a number (see the module docs).
+pub fn new_mapper(number: u8, has_chr_rom: bool) -> Option<Box<dyn Mapper>> {
+    match number {
+        0 => Some(Box::new(Mapper0::new(has_chr_rom))),
+        1 => Some(Box::new(Mapper1::new(has_chr_rom))),
+        7 => Some(Box::new(Mapper7::default())),
+        11 => Some(Box::new(Mapper11::default())),
+        34 if has_chr_rom => Some(Box::new(Nina001::default())),
+        34 => Some(Box::new(Mapper34::default())),
+        _ => None,
+    }
+}
+
+/// Size of the CHR RAM on carts which have it instead of CHR ROM
+const CHR_RAM_SIZE: usize = 0x2000;
+
+/// Size of the PRG RAM at `$6000-$7FFF`, on carts which have it
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Read from PRG ROM viewed as banks of `bank_size` bytes. Bank numbers past the end wrap
+/// around, as they would on a cart which doesn't connect the higher address lines.
+fn read_prg_bank(cart: &Cart, bank_size: usize, bank: usize, offset: usize) -> u8 {
+    let banks = (cart.prg_rom_len() / bank_size).max(1);
+    cart.prg_rom_byte((bank % banks) * bank_size + offset % bank_size)
+}
+
+/// Read from CHR ROM viewed as banks of `bank_size` bytes, wrapping around like
+/// `read_prg_bank`
+fn read_chr_bank(cart: &Cart, bank_size: usize, bank: usize, offset: usize) -> u8 {
+    let banks = (cart.chr_rom_len() / bank_size).max(1);
+    cart.chr_rom_byte((bank % banks) * bank_size + offset % bank_size)
+}
+
+/// NROM: 16 or 32 KiB of PRG ROM at `$8000`, with a 16 KiB ROM mirrored at `$C000`. Few NROM
+/// carts have PRG RAM (Family BASIC does), but iNES headers can't say so, so it is always there;
+/// test ROMs like blargg's rely on it to report their results. Homebrew without any CHR ROM
+/// gets 8 KiB of CHR RAM instead.
+///
+/// See: <https://www.nesdev.org/wiki/NROM>
+#[derive(Debug, Clone)]
+pub struct Mapper0 {
+    prg_ram: Box<[u8]>,
+    /// The pattern tables, if the cart has CHR RAM instead of CHR ROM
+    chr_ram: Option<Box<[u8]>>,
+}
+
+impl Mapper0 {
+    pub fn new(has_chr_rom: bool) -> Self {
+        Self {
+            prg_ram: vec![0; PRG_RAM_SIZE].into_boxed_slice(),
+            chr_ram: (!has_chr_rom).then(|| vec![0; CHR_RAM_SIZE].into_boxed_slice()),
+        }
+    }
+}
+
+impl Mapper for Mapper0 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7fff => self.prg_ram[(address - 0x6000) as usize],
+            0x8000..=0xffff => {
+                let offset = (address - 0x8000) as usize;
+                read_prg_bank(cart, 0x4000, offset / 0x4000, offset)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7fff = address {
+            self.prg_ram[(address - 0x6000) as usize] = value;
+        }
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match &self.chr_ram {
+            Some(chr_ram) => chr_ram[address as usize % CHR_RAM_SIZE],
+            None => cart.chr_rom_byte(address as usize),
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        if let Some(chr_ram) = &mut self.chr_ram {
+            chr_ram[address as usize % CHR_RAM_SIZE] = value;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "NROM"
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bytes("prg_ram", &self.prg_ram);
+        if let Some(chr_ram) = &self.chr_ram {
+            state.bytes("chr_ram", chr_ram);
+        }
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        state.bytes(&mut self.prg_ram)?;
+        if let Some(chr_ram) = &mut self.chr_ram {
+            state.bytes(chr_ram)?;
+        }
+        Ok(())
+    }
+}
+
+/// MMC1 (SxROM): registers are written a bit at a time, through a shift register at
+/// `$8000-$FFFF`. The fifth write copies the bits into the register chosen by that write's address:
+/// control (`$8000`), the CHR banks for PPU `$0000` and `$1000` (`$A000` and `$C000`), or the PRG
+/// bank (`$E000`). Writing a value with bit 7 set clears the shift register instead.
+///
+/// See: <https://www.nesdev.org/wiki/MMC1>
+#[derive(Debug, Clone)]
+pub struct Mapper1 {
+    /// Bits written so far, arriving at bit 4 and shifting right. The 1 it starts with reaches
+    /// bit 0 when the fifth bit is written.
+    shift: u8,
+    /// Mirroring (bits 0-1), PRG ROM bank mode (bits 2-3) and CHR bank mode (bit 4)
+    control: u8,
+    chr_banks: [u8; 2],
+    /// The PRG ROM bank (bits 0-3), and whether PRG RAM is disabled (bit 4)
+    prg_bank: u8,
+    prg_ram: Box<[u8]>,
+    /// The pattern tables, if the cart has CHR RAM instead of CHR ROM
+    chr_ram: Option<Box<[u8]>>,
+}
+
+const MMC1_SHIFT_RESET: u8 = 0x10;
+/// PRG ROM bank mode 3, which fixes the last bank at `$C000`, is set at power on and by resets
+/// of the shift register
+const MMC1_CONTROL_PRG_FIX_LAST: u8 = 0x0c;
+const MMC1_CONTROL_CHR_4K: u8 = 0x10;
+const MMC1_PRG_RAM_DISABLE: u8 = 0x10;
+
+impl Mapper1 {
+    pub fn new(has_chr_rom: bool) -> Self {
+        Self {
+            shift: MMC1_SHIFT_RESET,
+            control: MMC1_CONTROL_PRG_FIX_LAST,
+            chr_banks: [0; 2],
+            prg_bank: 0,
+            prg_ram: vec![0; PRG_RAM_SIZE].into_boxed_slice(),
+            chr_ram: (!has_chr_rom).then(|| vec![0; CHR_RAM_SIZE].into_boxed_slice()),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_banks[0] = value,
+            0xc000..=0xdfff => self.chr_banks[1] = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    /// The 4 KiB CHR bank at PPU `address`. In 8 KiB mode, `chr_banks[0]` selects a pair of
+    /// banks, ignoring its lowest bit, and `chr_banks[1]` is unused.
+    fn chr_bank(&self, address: u16) -> usize {
+        let upper = address & 0x1000 != 0;
+        if self.control & MMC1_CONTROL_CHR_4K != 0 {
+            self.chr_banks[upper as usize] as usize
+        } else {
+            (self.chr_banks[0] & !1) as usize + upper as usize
+        }
+    }
+
+    fn chr_ram_offset(&self, address: u16) -> usize {
+        (self.chr_bank(address) * 0x1000 + (address as usize & 0xfff)) % CHR_RAM_SIZE
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & MMC1_PRG_RAM_DISABLE == 0
+    }
+}
+
+impl Mapper for Mapper1 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled() => self.prg_ram[(address - 0x6000) as usize],
+            0x8000..=0xffff => {
+                let offset = (address - 0x8000) as usize;
+                let bank = (self.prg_bank & 0xf) as usize;
+                let last_bank = (cart.prg_rom_len() / 0x4000).max(1) - 1;
+                let bank = match ((self.control >> 2) & 0x3, offset < 0x4000) {
+                    // 32 KiB at a time, ignoring the lowest bit of the bank number
+                    (0 | 1, lower) => (bank & !1) + !lower as usize,
+                    (2, true) => 0,
+                    (2, false) => bank,
+                    (_, true) => bank,
+                    (_, false) => last_bank,
+                };
+                read_prg_bank(cart, 0x4000, bank, offset)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                self.prg_ram[(address - 0x6000) as usize] = value;
+            }
+            0x8000..=0xffff if value & 0x80 != 0 => self.reset(),
+            0x8000..=0xffff => {
+                let full = self.shift & 1 != 0;
+                self.shift = (self.shift >> 1) | ((value & 1) << 4);
+                if full {
+                    self.write_register(address, self.shift);
+                    self.shift = MMC1_SHIFT_RESET;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match &self.chr_ram {
+            Some(chr_ram) => chr_ram[self.chr_ram_offset(address)],
+            None => read_chr_bank(cart, 0x1000, self.chr_bank(address), address as usize),
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let offset = self.chr_ram_offset(address);
+        if let Some(chr_ram) = &mut self.chr_ram {
+            chr_ram[offset] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0x3 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "MMC1"
+    }
+
+    /// The same as writing a value with bit 7 set, which fixes the last PRG ROM bank at `$C000`
+    /// so that the reset vector is mapped in
+    fn reset(&mut self) {
+        self.shift = MMC1_SHIFT_RESET;
+        self.control |= MMC1_CONTROL_PRG_FIX_LAST;
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8("shift", self.shift);
+        state.u8("control", self.control);
+        state.bytes("chr_banks", &self.chr_banks);
+        state.u8("prg_bank", self.prg_bank);
+        state.bytes("prg_ram", &self.prg_ram);
+        if let Some(chr_ram) = &self.chr_ram {
+            state.bytes("chr_ram", chr_ram);
+        }
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.shift = state.u8()?;
+        self.control = state.u8()?;
+        state.bytes(&mut self.chr_banks)?;
+        self.prg_bank = state.u8()?;
+        state.bytes(&mut self.prg_ram)?;
+        if let Some(chr_ram) = &mut self.chr_ram {
+            state.bytes(chr_ram)?;
+        }
+        Ok(())
+    }
+}
+
+/// AxROM: any write to `$8000-$FFFF` selects the 32 KiB PRG ROM bank there (bits 0-2) and
+/// which of the console's nametables is shown at all four nametable addresses (bit 4). The
+/// pattern tables are 8 KiB of CHR RAM.
+///
+/// See: <https://www.nesdev.org/wiki/AxROM>
+#[derive(Debug, Clone)]
+pub struct Mapper7 {
+    prg_bank: u8,
+    upper_nametable: bool,
+    chr_ram: Box<[u8]>,
+}
+
+impl Default for Mapper7 {
+    fn default() -> Self {
+        Self {
+            prg_bank: 0,
+            upper_nametable: false,
+            chr_ram: vec![0; CHR_RAM_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let offset = (address - 0x8000) as usize;
+        read_prg_bank(cart, 0x8000, self.prg_bank as usize, offset)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = value & 0x7;
+            self.upper_nametable = value & 0x10 != 0;
+        }
+    }
+
+    fn ppu_read(&self, _cart: &Cart, address: u16) -> u8 {
+        self.chr_ram[address as usize % CHR_RAM_SIZE]
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr_ram[address as usize % CHR_RAM_SIZE] = value;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.upper_nametable {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "AxROM"
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8("prg_bank", self.prg_bank);
+        state.bool("upper_nametable", self.upper_nametable);
+        state.bytes("chr_ram", &self.chr_ram);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.prg_bank = state.u8()?;
+        self.upper_nametable = state.bool()?;
+        state.bytes(&mut self.chr_ram)
+    }
+}
+
+/// Color Dreams: any write to `$8000-$FFFF` selects the 32 KiB PRG ROM bank there (bits 0-1)
+/// and the 8 KiB CHR ROM bank for the pattern tables (bits 4-7)
+///
+/// See: <https://www.nesdev.org/wiki/Color_Dreams>
+#[derive(Debug, Clone, Default)]
+pub struct Mapper11 {
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Mapper for Mapper11 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let offset = (address - 0x8000) as usize;
+        read_prg_bank(cart, 0x8000, self.prg_bank as usize, offset)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = value & 0x3;
+            self.chr_bank = value >> 4;
+        }
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        read_chr_bank(cart, 0x2000, self.chr_bank as usize, address as usize)
+    }
+
+    fn name(&self) -> &'static str {
+        "Color Dreams"
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8("prg_bank", self.prg_bank);
+        state.u8("chr_bank", self.chr_bank);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.prg_bank = state.u8()?;
+        self.chr_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+/// The 2 KiB of VRAM on boards with four-screen mirroring, which holds the third and fourth
+/// nametables, in front of the board's mapper. The PPU reaches it at `$2800-$2FFF` through
+/// `ppu_read` and `ppu_write`, as it does the pattern tables.
+///
+/// See: <https://www.nesdev.org/wiki/Mirroring#4-Screen>
+#[derive(Debug, Clone)]
+pub struct FourScreenVram {
+    mapper: Box<dyn Mapper>,
+    vram: Box<[u8]>,
+}
+
+const FOUR_SCREEN_VRAM_SIZE: usize = 0x800;
+
+impl FourScreenVram {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        Self {
+            mapper,
+            vram: vec![0; FOUR_SCREEN_VRAM_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Mapper for FourScreenVram {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        self.mapper.cpu_read(cart, address)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        self.mapper.cpu_write(address, value);
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match address {
+            0x2800..=0x2fff => self.vram[(address - 0x2800) as usize],
+            _ => self.mapper.ppu_read(cart, address),
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x2800..=0x2fff => self.vram[(address - 0x2800) as usize] = value,
+            _ => self.mapper.ppu_write(address, value),
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mapper.mirroring()
+    }
+
+    fn name(&self) -> &'static str {
+        self.mapper.name()
+    }
+
+    fn reset(&mut self) {
+        self.mapper.reset();
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        self.mapper.prg_ram()
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.mapper.prg_ram_mut()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        self.mapper.write_state(state);
+        state.bytes("four_screen_vram", &self.vram);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.mapper.read_state(state)?;
+        state.bytes(&mut self.vram)
+    }
+}
+
+/// BNROM: any write to `$8000-$FFFF` selects the 32 KiB PRG ROM bank there. The pattern tables
+/// are 8 KiB of CHR RAM.
+///
+/// See: <https://www.nesdev.org/wiki/INES_Mapper_034>
+#[derive(Debug, Clone)]
+pub struct Mapper34 {
+    prg_bank: u8,
+    chr_ram: Box<[u8]>,
+}
+
+impl Default for Mapper34 {
+    fn default() -> Self {
+        Self {
+            prg_bank: 0,
+            chr_ram: vec![0; CHR_RAM_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Mapper for Mapper34 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let offset = (address - 0x8000) as usize;
+        read_prg_bank(cart, 0x8000, self.prg_bank as usize, offset)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = value;
+        }
+    }
+
+    fn ppu_read(&self, _cart: &Cart, address: u16) -> u8 {
+        self.chr_ram[address as usize % CHR_RAM_SIZE]
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr_ram[address as usize % CHR_RAM_SIZE] = value;
+    }
+
+    fn name(&self) -> &'static str {
+        "BNROM"
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8("prg_bank", self.prg_bank);
+        state.bytes("chr_ram", &self.chr_ram);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.prg_bank = state.u8()?;
+        state.bytes(&mut self.chr_ram)
+    }
+}
+
+/// NINA-001, which shares mapper 34 with BNROM: `$7FFD` selects the 32 KiB PRG ROM bank, and
+/// `$7FFE` and `$7FFF` the 4 KiB CHR ROM banks at PPU `$0000` and `$1000`. The registers sit on
+/// top of 8 KiB of PRG RAM at `$6000-$7FFF`, so writes to them are stored in RAM too.
+///
+/// See: <https://www.nesdev.org/wiki/INES_Mapper_034>
+#[derive(Debug, Clone)]
+pub struct Nina001 {
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+    prg_ram: Box<[u8]>,
+}
+
+impl Default for Nina001 {
+    fn default() -> Self {
+        Self {
+            prg_bank: 0,
+            chr_banks: [0; 2],
+            prg_ram: vec![0; PRG_RAM_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl Mapper for Nina001 {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7fff => self.prg_ram[(address - 0x6000) as usize],
+            0x8000..=0xffff => {
+                let offset = (address - 0x8000) as usize;
+                read_prg_bank(cart, 0x8000, self.prg_bank as usize, offset)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7fff = address {
+            self.prg_ram[(address - 0x6000) as usize] = value;
+        }
+        match address {
+            0x7ffd => self.prg_bank = value & 0x1,
+            0x7ffe => self.chr_banks[0] = value & 0xf,
+            0x7fff => self.chr_banks[1] = value & 0xf,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        let bank = self.chr_banks[(address as usize / 0x1000) & 0x1];
+        read_chr_bank(cart, 0x1000, bank as usize, address as usize)
+    }
+
+    fn name(&self) -> &'static str {
+        "NINA-001"
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8("prg_bank", self.prg_bank);
+        state.bytes("chr_banks", &self.chr_banks);
+        state.bytes("prg_ram", &self.prg_ram);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.prg_bank = state.u8()?;
+        state.bytes(&mut self.chr_banks)?;
+        state.bytes(&mut self.prg_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn nrom_prg_pages() {
+        let mapper = Mapper0::new(true);
+        let cart = testing::banked_cart(0, 2, 1);
+        let reads = [0x8000, 0xbfff, 0xc000, 0xffff].map(|address| mapper.cpu_read(&cart, address));
+        assert_eq!(reads, [0, 0, 1, 1]);
+
+        // A 16 KiB ROM is mirrored at `$C000`
+        let cart = testing::banked_cart(0, 1, 1);
+        assert_eq!(mapper.cpu_read(&cart, 0xc000), 0);
+        assert_eq!(mapper.cpu_read(&cart, 0xfffc), 0);
+    }
+
+    #[test]
+    fn nrom_ignores_extra_prg_pages() {
+        // NROM can't address more than 32 KiB, so a header claiming more pages still shows the
+        // first two, rather than indexing past them
+        let mapper = Mapper0::new(true);
+        let cart = testing::banked_cart(0, 4, 1);
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 0);
+        assert_eq!(mapper.cpu_read(&cart, 0xffff), 1);
+    }
+
+    /// Write a value to an MMC1 register a bit at a time, lowest first
+    fn mmc1_write(mapper: &mut dyn Mapper, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(address, value >> bit & 1);
+        }
+    }
+
+    #[test]
+    fn mmc1_chr_rom() {
+        let cart = testing::banked_cart(1, 2, 4);
+        let mut mapper = new_mapper(1, true).unwrap();
+
+        // 4 KiB mode switches each half of the pattern tables separately
+        mmc1_write(mapper.as_mut(), 0x8000, 0x1c);
+        mmc1_write(mapper.as_mut(), 0xa000, 5);
+        mmc1_write(mapper.as_mut(), 0xc000, 2);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 5);
+        assert_eq!(mapper.ppu_read(&cart, 0x1fff), 2);
+        // Banks past the end of the ROM wrap around
+        mmc1_write(mapper.as_mut(), 0xa000, 9);
+        assert_eq!(mapper.ppu_read(&cart, 0x0fff), 1);
+
+        // 8 KiB mode uses the first register's bank and the one after it, ignoring its lowest
+        // bit, and ignores the second register
+        mmc1_write(mapper.as_mut(), 0x8000, 0x0c);
+        mmc1_write(mapper.as_mut(), 0xa000, 5);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 4);
+        assert_eq!(mapper.ppu_read(&cart, 0x1000), 5);
+        mmc1_write(mapper.as_mut(), 0xc000, 7);
+        assert_eq!(mapper.ppu_read(&cart, 0x1fff), 5);
+
+        // CHR ROM can't be written
+        mapper.ppu_write(0x0000, 0xaa);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 4);
+    }
+
+    #[test]
+    fn mmc1_chr_ram() {
+        let cart = testing::banked_cart(1, 2, 0);
+        let mut mapper = new_mapper(1, false).unwrap();
+
+        // 4 KiB mode, with both halves showing the second 4 KiB of the 8 KiB of RAM
+        mmc1_write(mapper.as_mut(), 0x8000, 0x1c);
+        mmc1_write(mapper.as_mut(), 0xa000, 1);
+        mmc1_write(mapper.as_mut(), 0xc000, 1);
+        mapper.ppu_write(0x0010, 0xaa);
+        assert_eq!(mapper.ppu_read(&cart, 0x1010), 0xaa);
+
+        // 8 KiB mode maps it all in order
+        mmc1_write(mapper.as_mut(), 0x8000, 0x0c);
+        mmc1_write(mapper.as_mut(), 0xa000, 0);
+        assert_eq!(mapper.ppu_read(&cart, 0x0010), 0);
+        assert_eq!(mapper.ppu_read(&cart, 0x1010), 0xaa);
+        mapper.ppu_write(0x0020, 0xbb);
+        assert_eq!(mapper.ppu_read(&cart, 0x0020), 0xbb);
+        assert_eq!(mapper.ppu_read(&cart, 0x1020), 0);
+    }
+
+    #[test]
+    fn bnrom() {
+        let cart = testing::banked_cart(34, 8, 0);
+        let mut mapper = new_mapper(34, false).unwrap();
+        assert_eq!(mapper.name(), "BNROM");
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 0);
+        assert_eq!(mapper.cpu_read(&cart, 0xc000), 1);
+
+        for bank in [3, 1, 0] {
+            mapper.cpu_write(0x8000 + bank as u16, bank);
+            assert_eq!(mapper.cpu_read(&cart, 0x8000), bank * 2, "bank {bank}");
+            assert_eq!(mapper.cpu_read(&cart, 0xffff), bank * 2 + 1, "bank {bank}");
+        }
+        // Banks past the end of the ROM wrap around
+        mapper.cpu_write(0xffff, 5);
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 2);
+
+        // The pattern tables are RAM
+        mapper.ppu_write(0x1234, 0x56);
+        assert_eq!(mapper.ppu_read(&cart, 0x1234), 0x56);
+    }
+
+    #[test]
+    fn color_dreams() {
+        let cart = testing::banked_cart(11, 8, 16);
+        let mut mapper = new_mapper(11, false).unwrap();
+        assert_eq!(mapper.name(), "Color Dreams");
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 0);
+        assert_eq!(mapper.ppu_read(&cart, 0x1fff), 1);
+
+        // PRG ROM from the low bits and CHR ROM from the high ones, from a write anywhere
+        for (address, value) in [(0x8000, 0x52), (0xffff, 0xf3), (0xc123, 0x01)] {
+            mapper.cpu_write(address, value);
+            let (prg, chr) = (value & 0x3, value >> 4);
+            assert_eq!(mapper.cpu_read(&cart, 0x8000), prg * 2, "write {value:02x}");
+            assert_eq!(
+                mapper.cpu_read(&cart, 0xffff),
+                prg * 2 + 1,
+                "write {value:02x}"
+            );
+            assert_eq!(mapper.ppu_read(&cart, 0x0000), chr * 2, "write {value:02x}");
+            assert_eq!(
+                mapper.ppu_read(&cart, 0x1fff),
+                chr * 2 + 1,
+                "write {value:02x}"
+            );
+        }
+
+        // Writes below $8000 don't switch banks
+        mapper.cpu_write(0x6000, 0x22);
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 2);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 0);
+
+        // Banks past the end of the ROM wrap around
+        let cart = testing::banked_cart(11, 4, 4);
+        mapper.cpu_write(0x8000, 0x63);
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 2);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 4);
+    }
+
+    #[test]
+    fn nina_001() {
+        let cart = testing::banked_cart(34, 4, 4);
+        let mut mapper = new_mapper(34, true).unwrap();
+        assert_eq!(mapper.name(), "NINA-001");
+
+        mapper.cpu_write(0x7ffd, 1);
+        assert_eq!(mapper.cpu_read(&cart, 0x8000), 2);
+        assert_eq!(mapper.cpu_read(&cart, 0xc000), 3);
+        // The registers are RAM too
+        assert_eq!(mapper.cpu_read(&cart, 0x7ffd), 1);
+
+        // Each 4 KiB of the pattern tables switches separately
+        mapper.cpu_write(0x7ffe, 5);
+        mapper.cpu_write(0x7fff, 2);
+        assert_eq!(mapper.ppu_read(&cart, 0x0000), 5);
+        assert_eq!(mapper.ppu_read(&cart, 0x1fff), 2);
+    }
+
+    #[test]
+    fn prg_banks_wrap() {
+        let cart = testing::banked_cart(0, 4, 1);
+        assert_eq!(read_prg_bank(&cart, 0x4000, 3, 0), 3);
+        // Bank numbers past the end wrap around, with any size of bank
+        assert_eq!(read_prg_bank(&cart, 0x4000, 5, 0x3fff), 1);
+        assert_eq!(read_prg_bank(&cart, 0x8000, 1, 0x4000), 3);
+        assert_eq!(read_prg_bank(&cart, 0x8000, 2, 0), 0);
+    }
+}
@@ -0,0 +1,183 @@
+//! Movies: the buttons held on both controllers on every frame since power on, which play back
+//! to exactly the same run, as the emulation is deterministic.
+//!
+//! A file starts with `MAGIC`, the format version, the CRC-32 of the ROM and the region it was
+//! recorded with, followed by two bytes per frame with the buttons of player 1 and 2 (see
+//! `buttons`). RAM always powers on cleared, so there is no power-on state to store. Resets and
+//! the Family BASIC keyboard aren't recorded.
+
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::region::Region;
+
+/// The start of every movie file
+const MAGIC: &[u8; 8] = b"RNESMOVI";
+
+/// Bumped whenever the layout changes
+pub const MOVIE_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 4 + 4 + 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    FileNotFound,
+    IoError(std::io::Error),
+    NotAMovie,
+    UnsupportedVersion(u32),
+    /// Recorded with a different ROM, going by the CRC-32 of its PRG and CHR ROM
+    WrongRom {
+        expected: u32,
+        actual: u32,
+    },
+    /// Recorded on a console with different timing, so it would desync
+    WrongRegion {
+        expected: Region,
+        actual: Region,
+    },
+}
+
+impl Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovieError::FileNotFound => write!(f, "movie file not found"),
+            MovieError::IoError(err) => write!(f, "IO error: {}", err),
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "movie is from format version {}, but only version {} is supported",
+                version, MOVIE_VERSION
+            ),
+            MovieError::WrongRom { expected, actual } => write!(
+                f,
+                "movie is for a different ROM (CRC {:08x}, but this ROM's is {:08x})",
+                actual, expected
+            ),
+            MovieError::WrongRegion { expected, actual } => write!(
+                f,
+                "movie was recorded on {:?}, but this ROM runs on {:?}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+pub type MovieResult<T> = Result<T, MovieError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    rom_crc: u32,
+    region: Region,
+
+    /// Buttons of player 1 and 2 on each frame
+    frames: Vec<[u8; 2]>,
+}
+
+impl Movie {
+    /// An empty movie, to record a ROM with the given CRC-32 into
+    pub fn new(rom_crc: u32, region: Region) -> Self {
+        Self {
+            rom_crc,
+            region,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MovieResult<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        let version = u32_at(MAGIC.len());
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let region = match bytes[MAGIC.len() + 8] {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            _ => return Err(MovieError::NotAMovie),
+        };
+        let frames = bytes[HEADER_SIZE..]
+            .chunks_exact(2)
+            .map(|frame| [frame[0], frame[1]])
+            .collect();
+        Ok(Self {
+            rom_crc: u32_at(MAGIC.len() + 4),
+            region,
+            frames,
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> MovieResult<Self> {
+        let bytes = std::fs::read(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => MovieError::FileNotFound,
+            _ => MovieError::IoError(err),
+        })?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.frames.len() * 2);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_crc.to_le_bytes());
+        bytes.push(match self.region {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::Dendy => 2,
+        });
+        bytes.extend(self.frames.iter().flatten());
+        bytes
+    }
+
+    /// Check the movie was recorded with a ROM with the given CRC-32, on the given region
+    pub fn check(&self, rom_crc: u32, region: Region) -> MovieResult<()> {
+        if self.rom_crc != rom_crc {
+            return Err(MovieError::WrongRom {
+                expected: rom_crc,
+                actual: self.rom_crc,
+            });
+        }
+        if self.region != region {
+            return Err(MovieError::WrongRegion {
+                expected: region,
+                actual: self.region,
+            });
+        }
+        Ok(())
+    }
+
+    /// Number of frames recorded
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Buttons of player 1 and 2 on a frame, if the movie is that long
+    pub fn frame(&self, frame: u64) -> Option<[u8; 2]> {
+        self.frames.get(frame as usize).copied()
+    }
+
+    /// Drop any frames after the first `len`
+    pub fn truncate(&mut self, len: u64) {
+        self.frames.truncate(len as usize);
+    }
+
+    /// Record the buttons on a frame, replacing anything recorded from then on. Frames are
+    /// sometimes run more than once, e.g. by run-ahead, and the last run is the one that counts.
+    pub fn record_frame(&mut self, frame: u64, buttons: [u8; 2]) {
+        self.frames.truncate(frame as usize);
+        // Any frames skipped over are recorded with nothing held
+        self.frames.resize(frame as usize, [0; 2]);
+        self.frames.push(buttons);
+    }
+}
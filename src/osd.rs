@@ -0,0 +1,43 @@
+//! On-screen display of short messages, e.g. to confirm a hotkey, drawn over the picture.
+
+use crate::filter::Frame;
+use crate::font::{draw_text, ADVANCE_Y};
+
+/// How long a message stays on screen, ~2 seconds
+const MESSAGE_FRAMES: u32 = 120;
+
+const MARGIN: usize = 4;
+const TEXT_COLOUR: (u8, u8, u8) = (236, 238, 236);
+const SHADOW_COLOUR: (u8, u8, u8) = (0, 0, 0);
+
+#[derive(Debug, Default)]
+pub struct Osd {
+    message: Option<String>,
+    frames_left: u32,
+}
+
+impl Osd {
+    /// Show a message, replacing any already shown. It is printed too, for the terminal.
+    pub fn show(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        println!("{}", message);
+        self.message = Some(message);
+        self.frames_left = MESSAGE_FRAMES;
+    }
+
+    /// Draw the message in the bottom left corner of a frame, and count down to hiding it
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let Some(message) = &self.message else {
+            return;
+        };
+        let y = frame.height.saturating_sub(MARGIN + ADVANCE_Y);
+        // A drop shadow keeps the text readable on light backgrounds
+        draw_text(frame, MARGIN + 1, y + 1, message, SHADOW_COLOUR);
+        draw_text(frame, MARGIN, y, message, TEXT_COLOUR);
+
+        self.frames_left -= 1;
+        if self.frames_left == 0 {
+            self.message = None;
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! The RGB colours the PPU's palette indexes are displayed as, which can be replaced by a
+//! `.pal` file to match a particular TV or PPU.
+//!
+//! See: <https://www.nesdev.org/wiki/.pal>
+
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// The default RGB value of each of the 64 colours the PPU can output
+///
+/// See: <https://www.nesdev.org/wiki/PPU_palettes>
+#[rustfmt::skip]
+const DEFAULT_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136),
+    (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+    (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0),
+    (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228),
+    (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+    (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40),
+    (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236),
+    (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+    (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108),
+    (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236),
+    (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180),
+    (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
+/// Number of colours in a palette without emphasis
+const COLOURS: usize = 64;
+
+/// Number of combinations of the PPUMASK colour emphasis bits
+const EMPHASIS_LEVELS: usize = 8;
+
+/// How much emphasising a colour channel dims the other two, roughly as measured on an NTSC PPU
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/// Names accepted by `Palette::builtin`
+pub const BUILTIN_PALETTES: [&str; 2] = ["ntsc", "pal"];
+
+#[derive(Debug)]
+pub enum PaletteError {
+    /// The file must be 64 colours, or 64 for each of the 8 emphasis combinations
+    WrongSize(usize),
+    UnknownBuiltin(String),
+    FileNotFound,
+    IoError(std::io::Error),
+}
+
+impl Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::WrongSize(size) => write!(
+                f,
+                "palette is {} bytes, but should be {} or {}",
+                size,
+                COLOURS * 3,
+                COLOURS * EMPHASIS_LEVELS * 3
+            ),
+            PaletteError::UnknownBuiltin(name) => write!(
+                f,
+                "unknown built-in palette '{}' (expected one of: {})",
+                name,
+                BUILTIN_PALETTES.join(", ")
+            ),
+            PaletteError::FileNotFound => write!(f, "palette file not found"),
+            PaletteError::IoError(err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+pub type PaletteResult<T> = Result<T, PaletteError>;
+
+/// The RGB colour of every palette index, for each combination of the emphasis bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// Indexed by emphasis bits (PPUMASK bits 5-7) * 64 + palette index
+    colours: Box<[(u8, u8, u8)]>,
+}
+
+impl Palette {
+    /// One of the `BUILTIN_PALETTES`
+    pub fn builtin(name: &str) -> PaletteResult<Self> {
+        match name {
+            "ntsc" => Ok(Self::with_emphasis(&DEFAULT_PALETTE, [0, 1, 2])),
+            // The 2C07 PAL PPU has much the same colours, but swaps the red and green emphasis
+            // bits
+            "pal" => Ok(Self::with_emphasis(&DEFAULT_PALETTE, [1, 0, 2])),
+            _ => Err(PaletteError::UnknownBuiltin(name.to_string())),
+        }
+    }
+
+    /// Parse a `.pal` file: RGB triples for the 64 colours, optionally followed by the 64
+    /// colours for each other combination of emphasis bits. Without them, emphasis is
+    /// approximated.
+    pub fn from_bytes(data: &[u8]) -> PaletteResult<Self> {
+        let colours: Vec<(u8, u8, u8)> = data
+            .chunks_exact(3)
+            .map(|rgb| (rgb[0], rgb[1], rgb[2]))
+            .collect();
+        if data.len() == COLOURS * EMPHASIS_LEVELS * 3 {
+            Ok(Self {
+                colours: colours.into_boxed_slice(),
+            })
+        } else if data.len() == COLOURS * 3 {
+            Ok(Self::with_emphasis(&colours, [0, 1, 2]))
+        } else {
+            Err(PaletteError::WrongSize(data.len()))
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> PaletteResult<Self> {
+        let data = std::fs::read(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => PaletteError::FileNotFound,
+            _ => PaletteError::IoError(err),
+        })?;
+        Self::from_bytes(&data)
+    }
+
+    /// `builtin:NAME` for one of the `BUILTIN_PALETTES`, or else the path of a `.pal` file
+    pub fn load(spec: &str) -> PaletteResult<Self> {
+        match spec.strip_prefix("builtin:") {
+            Some(name) => Self::builtin(name),
+            None => Self::from_file(spec),
+        }
+    }
+
+    /// Extend 64 colours with the other emphasis combinations, by dimming the channels which
+    /// aren't emphasised. `channels` is the RGB channel emphasised by each of the 3 bits.
+    fn with_emphasis(base: &[(u8, u8, u8)], channels: [usize; 3]) -> Self {
+        let mut colours = Vec::with_capacity(COLOURS * EMPHASIS_LEVELS);
+        for emphasis in 0..EMPHASIS_LEVELS {
+            let mut scale = [1.0; 3];
+            for (bit, &channel) in channels.iter().enumerate() {
+                if emphasis & (1 << bit) != 0 {
+                    for (other, value) in scale.iter_mut().enumerate() {
+                        if other != channel {
+                            *value *= EMPHASIS_ATTENUATION;
+                        }
+                    }
+                }
+            }
+            let dim = |value: u8, channel: usize| (value as f32 * scale[channel]) as u8;
+            for &(r, g, b) in base {
+                colours.push((dim(r, 0), dim(g, 1), dim(b, 2)));
+            }
+        }
+        Self {
+            colours: colours.into_boxed_slice(),
+        }
+    }
+
+    /// The colour of a palette index, with the emphasis bits from PPUMASK (shifted down to bits
+    /// 0-2)
+    pub fn colour(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        let emphasis = (emphasis as usize) % EMPHASIS_LEVELS;
+        self.colours[emphasis * COLOURS + (index as usize & 0x3f)]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::with_emphasis(&DEFAULT_PALETTE, [0, 1, 2])
+    }
+}
+
+/// 64 colours, with emphasis approximated as for a 64 colour `.pal` file
+impl From<[(u8, u8, u8); 64]> for Palette {
+    fn from(colours: [(u8, u8, u8); 64]) -> Self {
+        Self::with_emphasis(&colours, [0, 1, 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    /// The default palette with colour $00 replaced by pure red
+    fn red_palette() -> Vec<u8> {
+        let mut data: Vec<u8> = DEFAULT_PALETTE
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect();
+        data[..3].copy_from_slice(&[255, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn palette_file() {
+        let path = std::env::temp_dir().join(format!("rusty_nes_{}.pal", std::process::id()));
+        std::fs::write(&path, red_palette()).unwrap();
+        let palette = Palette::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let palette = palette.unwrap();
+        assert_eq!(palette.colour(0x00, 0), (255, 0, 0));
+        assert_eq!(palette.colour(0x01, 0), DEFAULT_PALETTE[1]);
+
+        // Nothing has been drawn, so the whole frame is colour $00
+        let mut cpu = CPU::new_empty();
+        cpu.set_palette(palette);
+        assert_eq!(cpu.frame().pixel(0, 0), (255, 0, 0));
+    }
+
+    #[test]
+    fn palette_with_emphasis() {
+        let mut data = red_palette();
+        for emphasis in 1..EMPHASIS_LEVELS as u8 {
+            data.extend((0..COLOURS).flat_map(|_| [emphasis, emphasis, emphasis]));
+        }
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.colour(0x00, 0), (255, 0, 0));
+        assert_eq!(palette.colour(0x3f, 5), (5, 5, 5));
+
+        // With only 64 colours, emphasising red dims green and blue
+        let palette = Palette::from_bytes(&red_palette()).unwrap();
+        let (r, g, b) = palette.colour(0x30, 1);
+        assert_eq!(r, 236);
+        assert!(g < 238 && b < 236);
+    }
+
+    #[test]
+    fn palette_errors() {
+        assert_eq!(
+            Palette::from_bytes(&[0; 100]).unwrap_err().to_string(),
+            "palette is 100 bytes, but should be 192 or 1536"
+        );
+        assert_eq!(
+            Palette::load("builtin:secam").unwrap_err().to_string(),
+            "unknown built-in palette 'secam' (expected one of: ntsc, pal)"
+        );
+        assert!(matches!(
+            Palette::load("no/such/file.pal"),
+            Err(PaletteError::FileNotFound)
+        ));
+        for name in BUILTIN_PALETTES {
+            assert!(Palette::load(&format!("builtin:{name}")).is_ok(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! A minimal ROM browser, listing the `.nes` files in a directory so the emulator can be
+//! started without a terminal.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::filter::Frame;
+use crate::font::{draw_text, ADVANCE_X, ADVANCE_Y};
+use crate::frontend::{buttons, Frontend};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const MARGIN: usize = 8;
+const TEXT_COLOUR: (u8, u8, u8) = (160, 162, 160);
+const SELECTED_COLOUR: (u8, u8, u8) = (236, 238, 236);
+
+/// The `.nes` files in `dir`, sorted by name
+fn list_roms(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_rom = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("nes"));
+        if is_rom && path.is_file() {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+fn draw_list(roms: &[PathBuf], selected: usize) -> Frame {
+    let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+    draw_text(&mut frame, MARGIN, MARGIN, "SELECT A ROM", SELECTED_COLOUR);
+    if roms.is_empty() {
+        let y = MARGIN + 2 * ADVANCE_Y;
+        draw_text(&mut frame, MARGIN, y, "NO .NES FILES FOUND", TEXT_COLOUR);
+        return frame;
+    }
+
+    // Scroll so that the selection is always visible
+    let visible_lines = (SCREEN_HEIGHT - 2 * MARGIN) / ADVANCE_Y - 2;
+    let first = selected.saturating_sub(visible_lines - 1);
+    let max_chars = (SCREEN_WIDTH - 2 * MARGIN) / ADVANCE_X - 2;
+
+    for (line, (index, rom)) in roms.iter().enumerate().skip(first).enumerate() {
+        if line == visible_lines {
+            break;
+        }
+        let name = rom.file_name().unwrap_or_default().to_string_lossy();
+        let name: String = name.chars().take(max_chars).collect();
+        let (text, colour) = if index == selected {
+            (format!("> {name}"), SELECTED_COLOUR)
+        } else {
+            (format!("  {name}"), TEXT_COLOUR)
+        };
+        let y = MARGIN + (line + 2) * ADVANCE_Y;
+        draw_text(&mut frame, MARGIN, y, &text, colour);
+    }
+    frame
+}
+
+/// Show the ROMs in `dir` and let the user pick one with the arrow keys and Enter. Returns
+/// `None` if they quit (with Escape or by closing the window) instead.
+pub fn pick_rom<F: Frontend>(frontend: &mut F, dir: &Path) -> io::Result<Option<PathBuf>> {
+    let roms = list_roms(dir)?;
+    let mut selected: usize = 0;
+    let mut held = 0;
+    loop {
+        let input = frontend.poll_input();
+        if input.quit || input.escape {
+            return Ok(None);
+        }
+
+        // Only act on the frame a button goes down, rather than every frame it is held
+        let pressed = input.buttons[0] & !held;
+        held = input.buttons[0];
+        if pressed & buttons::UP != 0 {
+            selected = selected.saturating_sub(1);
+        }
+        if pressed & buttons::DOWN != 0 && selected + 1 < roms.len() {
+            selected += 1;
+        }
+        if pressed & buttons::START != 0 && !roms.is_empty() {
+            return Ok(Some(roms[selected].clone()));
+        }
+
+        frontend.present_frame(&draw_list(&roms, selected));
+        std::thread::sleep(crate::FRAME_DURATION);
+    }
+}
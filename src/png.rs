@@ -0,0 +1,86 @@
+//! A minimal PNG encoder for screenshots, which stores the image uncompressed so it doesn't
+//! need a deflate implementation.
+//!
+//! See: <https://www.w3.org/TR/png/>
+
+use crate::filter::Frame;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Largest block of uncompressed data a deflate stream can hold
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+/// CRC-32 as used by PNG (and zip, gzip, etc.)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap data in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        stream.push(is_final as u8);
+        let length = block.len() as u16;
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+/// Encode an RGB frame as a PNG file
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(frame.width as u32).to_be_bytes());
+    header.extend_from_slice(&(frame.height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression and filtering, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row starts with the filter type, which is always 0 (none)
+    let mut image = Vec::with_capacity((frame.width * 3 + 1) * frame.height);
+    for row in frame.pixels.chunks_exact(frame.width * 3) {
+        image.push(0);
+        image.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&image));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
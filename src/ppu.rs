@@ -1,16 +1,1205 @@
+use std::rc::Rc;
+
+use crate::cart::Mirroring;
+use crate::filter::Frame;
+use crate::palette::Palette;
+use crate::region::Region;
+use crate::state::{StateReader, StateResult, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+
+/// Size of the nametables in tiles
+const NAMETABLE_COLUMNS: usize = 32;
+const NAMETABLE_ROWS: usize = 30;
+
+/// Number of sprites the PPU can draw on one scanline
+const SPRITES_PER_SCANLINE: usize = 8;
+
+/// PPUSTATUS flags
+const STATUS_SPRITE_OVERFLOW: u8 = 0x20;
+const STATUS_VBLANK: u8 = 0x80;
+
+/// PPUCTRL flag to generate an NMI at the start of vblank
+const CTRL_NMI: u8 = 0x80;
+/// PPUCTRL flags to fetch 8x8 sprites' and background tiles from the pattern table at `$1000`,
+/// and to make sprites 8x16
+const CTRL_SPRITE_TABLE: u8 = 0x08;
+const CTRL_BACKGROUND_TABLE: u8 = 0x10;
+const CTRL_TALL_SPRITES: u8 = 0x20;
+
+/// PPUMASK flags to show the background and sprites, and whether to show them in the leftmost
+/// 8 pixels
+const MASK_BACKGROUND: u8 = 0x08;
+const MASK_BACKGROUND_LEFT: u8 = 0x02;
+const MASK_SPRITES: u8 = 0x10;
+const MASK_SPRITES_LEFT: u8 = 0x04;
+
+/// Sprite attribute flags, for byte 2 of each sprite in OAM
+const SPRITE_BEHIND_BACKGROUND: u8 = 0x20;
+const SPRITE_FLIP_HORIZONTAL: u8 = 0x40;
+const SPRITE_FLIP_VERTICAL: u8 = 0x80;
+
+/// The bits of `v` and `t` holding the horizontal scroll (coarse X and the horizontal
+/// nametable), which are copied from `t` to `v` at the end of each scanline, and the rest
+/// (fine Y, coarse Y and the vertical nametable), which are copied on the pre-render line
+const HORIZONTAL_SCROLL: u16 = 0x041f;
+const VERTICAL_SCROLL: u16 = 0x7be0;
+
 /// Picture Processing Unit (PPU)
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
-pub struct PPU {}
+#[derive(Debug, Clone)]
+pub struct PPU {
+    /// Current position of the beam. The last scanline of the frame (261 on NTSC) is the
+    /// pre-render line.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_rendering>
+    scanline: u16,
+    dot: u16,
+
+    /// Number of frames completed since power up
+    frame: u64,
+
+    scanlines_per_frame: u16,
+    vblank_scanline: u16,
+
+    /// PPUCTRL ($2000)
+    ctrl: u8,
+
+    /// PPUMASK ($2001)
+    mask: u8,
+
+    /// PPUSTATUS ($2002). Only the vblank and sprite overflow flags are implemented so far.
+    status: u8,
+
+    /// Whether an NMI has been signalled which the CPU hasn't taken yet (see `take_nmi`)
+    ///
+    /// See: <https://www.nesdev.org/wiki/NMI>
+    nmi_pending: bool,
+
+    /// OAMADDR ($2003)
+    oam_address: u8,
+
+    /// Object attribute memory: the Y position, tile, attributes and X position of 64 sprites
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_OAM>
+    oam: Box<[u8]>,
+
+    /// Shared by PPUSCROLL and PPUADDR to tell the first and second writes apart
+    write_latch: bool,
+
+    /// Address accessed through PPUDATA, set by PPUADDR. This is the 15-bit `v` register, which
+    /// also holds the scroll position while rendering (fine Y in the top 3 bits, then the
+    /// nametable, coarse Y and coarse X), so only the low 14 bits are the address.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_scrolling#PPU_internal_registers>
+    vram_address: u16,
+
+    /// The `t` register, which PPUCTRL, PPUSCROLL and PPUADDR write to, laid out like `v`. It
+    /// holds the scroll position for the top left of the screen, and is copied to `v` by the
+    /// second PPUADDR write and while rendering.
+    temp_address: u16,
+
+    /// The `x` register, from the low 3 bits of the first PPUSCROLL write: the pixel within the
+    /// first tile that each scanline starts at
+    fine_x: u8,
+
+    /// Reads of PPUDATA below the palettes return the value fetched by the previous read
+    read_buffer: u8,
+
+    /// The 2 KiB of VRAM inside the console, which holds two of the four nametables
+    nametables: Box<[u8]>,
+    mirroring: Mirroring,
+
+    palette_ram: [u8; 32],
+
+    /// The RGB colours of the palette indexes, shared with savestates
+    palette: Rc<Palette>,
+
+    /// Palette index of each pixel of the frame being drawn
+    framebuffer: Box<[u8]>,
+
+    /// OAM indexes of the sprites found by `evaluate_sprites` for the next scanline, in priority
+    /// order
+    line_sprites: Vec<u8>,
+
+    /// Whether only the first 8 sprites on a scanline are drawn, as on the console, which makes
+    /// games flicker sprites to show them all. Not part of the savestate, as it's a setting.
+    sprite_limit: bool,
+}
 
 impl PPU {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(mirroring: Mirroring, region: Region) -> Self {
+        Self {
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            scanlines_per_frame: region.scanlines_per_frame(),
+            vblank_scanline: region.vblank_scanline(),
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            nmi_pending: false,
+            oam_address: 0,
+            oam: vec![0; 0x100].into_boxed_slice(),
+            write_latch: false,
+            vram_address: 0,
+            temp_address: 0,
+            fine_x: 0,
+            read_buffer: 0,
+            nametables: vec![0; 0x800].into_boxed_slice(),
+            mirroring,
+            palette_ram: [0; 32],
+            palette: Rc::new(Palette::default()),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+            line_sprites: Vec::with_capacity(64),
+            sprite_limit: true,
+        }
+    }
+
+    /// Enter the state after the console's reset button is pressed. PPUCTRL, PPUMASK, the write
+    /// latch and the PPUDATA read buffer are cleared; VRAM, OAM, palette RAM, PPUADDR and the
+    /// position of the beam are left as they were.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_power_up_state>
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.nmi_pending = false;
+        self.write_latch = false;
+        self.read_buffer = 0;
+    }
+
+    /// Save everything but the mirroring and region, which come from the cart
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.u16("scanline", self.scanline);
+        state.u16("dot", self.dot);
+        state.u64("frame", self.frame);
+        state.u8("ctrl", self.ctrl);
+        state.u8("mask", self.mask);
+        state.u8("status", self.status);
+        state.bool("nmi_pending", self.nmi_pending);
+        state.u8("oam_address", self.oam_address);
+        state.bytes("oam", &self.oam);
+        state.bool("write_latch", self.write_latch);
+        state.u16("vram_address", self.vram_address);
+        state.u16("temp_address", self.temp_address);
+        state.u8("fine_x", self.fine_x);
+        state.u8("read_buffer", self.read_buffer);
+        state.bytes("nametables", &self.nametables);
+        state.bytes("palette_ram", &self.palette_ram);
+        state.bytes("framebuffer", &self.framebuffer);
+    }
+
+    pub fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        self.scanline = state.u16()?;
+        self.dot = state.u16()?;
+        self.frame = state.u64()?;
+        self.ctrl = state.u8()?;
+        self.mask = state.u8()?;
+        self.status = state.u8()?;
+        self.nmi_pending = state.bool()?;
+        self.oam_address = state.u8()?;
+        state.bytes(&mut self.oam)?;
+        self.write_latch = state.bool()?;
+        self.vram_address = state.u16()?;
+        self.temp_address = state.u16()?;
+        self.fine_x = state.u8()?;
+        self.read_buffer = state.u8()?;
+        state.bytes(&mut self.nametables)?;
+        state.bytes(&mut self.palette_ram)?;
+        state.bytes(&mut self.framebuffer)
+    }
+
+    /// Read a register. `chr` reads the pattern tables from the cart, and the nametables at
+    /// `$2800-$2FFF` with four-screen mirroring.
+    pub fn read_address(&mut self, address: u16, chr: impl Fn(u16) -> u8) -> u8 {
+        let value = self.peek_address(address);
+        match address & 0x7 {
+            0x2 => {
+                // Reading PPUSTATUS acknowledges vblank and resets the write latch
+                self.status &= !STATUS_VBLANK;
+                self.write_latch = false;
+            }
+            0x7 => {
+                // Palette reads aren't buffered, but still refill the buffer from the nametable
+                // "underneath" them
+                let address = match self.vram_address & 0x3fff {
+                    address @ 0x3f00.. => address & 0x2fff,
+                    address => address,
+                };
+                self.read_buffer = self.read_vram(address, chr);
+                self.increment_vram_address();
+            }
+            _ => {}
+        }
+        value
+    }
+
+    /// Return what `read_address` would, without any of the side effects of reading a register
+    pub fn peek_address(&self, address: u16) -> u8 {
+        match address & 0x7 {
+            0x2 => self.status,
+            0x4 => self.oam[self.oam_address as usize],
+            0x7 if self.vram_address & 0x3fff >= 0x3f00 => self.read_palette(self.vram_address),
+            0x7 => self.read_buffer,
+            _ => 0,
+        }
+    }
+
+    /// Set the state behind a register directly, e.g. from the debugger, without any of the side
+    /// effects of writing it: OAMDATA and PPUDATA don't increment their address. PPUSCROLL and
+    /// PPUADDR are ignored, as they only feed the write latch.
+    pub fn poke_address(&mut self, address: u16, value: u8, chr_write: impl FnMut(u16, u8)) {
+        match address & 0x7 {
+            0x0 => self.ctrl = value,
+            0x1 => self.mask = value,
+            0x2 => self.status = value,
+            0x3 => self.oam_address = value,
+            0x4 => self.oam[self.oam_address as usize] = value,
+            0x7 => self.write_vram(self.vram_address, value, chr_write),
+            _ => {}
+        }
+    }
+
+    /// Write a register. `chr_write` writes the pattern tables on the cart, if they are RAM, and
+    /// the nametables at `$2800-$2FFF` with four-screen mirroring.
+    pub fn write_address(&mut self, address: u16, value: u8, chr_write: impl FnMut(u16, u8)) {
+        match address & 0x7 {
+            0x0 => {
+                // Turning NMIs on during vblank generates one straight away
+                let enabling_nmi = value & CTRL_NMI != 0 && self.ctrl & CTRL_NMI == 0;
+                if enabling_nmi && self.status & STATUS_VBLANK != 0 {
+                    self.nmi_pending = true;
+                }
+                self.ctrl = value;
+                // The base nametable is part of the scroll position
+                self.temp_address = (self.temp_address & !0x0c00) | (value as u16 & 0x3) << 10;
+            }
+            0x1 => self.mask = value,
+            0x3 => self.oam_address = value,
+            0x4 => {
+                self.oam[self.oam_address as usize] = value;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            // The first write is the X scroll, split into coarse and fine X, and the second is
+            // the Y scroll, split into coarse and fine Y
+            //
+            // See: <https://www.nesdev.org/wiki/PPU_scrolling#Register_controls>
+            0x5 => {
+                if self.write_latch {
+                    self.temp_address = (self.temp_address & !0x73e0)
+                        | (value as u16 & 0x7) << 12
+                        | (value as u16 & 0xf8) << 2;
+                } else {
+                    self.temp_address = (self.temp_address & !0x001f) | value as u16 >> 3;
+                    self.fine_x = value & 0x7;
+                }
+                self.write_latch = !self.write_latch;
+            }
+            // PPUADDR goes through `t` too, which is only copied to `v` once both bytes are
+            // written. The first write clears bit 14 as well.
+            0x6 => {
+                if self.write_latch {
+                    self.temp_address = (self.temp_address & 0x7f00) | value as u16;
+                    self.vram_address = self.temp_address;
+                } else {
+                    self.temp_address = (self.temp_address & 0x00ff) | (value as u16 & 0x3f) << 8;
+                }
+                self.write_latch = !self.write_latch;
+            }
+            0x7 => {
+                self.write_vram(self.vram_address, value, chr_write);
+                self.increment_vram_address();
+            }
+            _ => {}
+        }
+    }
+
+    /// PPUDATA accesses move across a row of the nametable, or down a column if PPUCTRL bit 2
+    /// is set. While rendering, `v` is the scroll position, and the access instead makes the
+    /// PPU step it to the next tile and the next pixel row at once, as it does during rendering.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_scrolling#$2007_(PPUDATA)_reads_and_writes>
+    fn increment_vram_address(&mut self) {
+        if self.rendering_enabled() && self.rendering_scanline() {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            let increment = if self.ctrl & 0x4 != 0 { 32 } else { 1 };
+            self.vram_address = self.vram_address.wrapping_add(increment) & 0x7fff;
+        }
+    }
+
+    /// Whether the beam is on a visible scanline or the pre-render line, where the PPU fetches
+    /// tiles and so moves `v` along
+    fn rendering_scanline(&self) -> bool {
+        (self.scanline as usize) < SCREEN_HEIGHT || self.scanline == self.scanlines_per_frame - 1
+    }
+
+    /// Move `v` to the next tile across, into the horizontally adjacent nametable after the
+    /// 32nd
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_scrolling#Coarse_X_increment>
+    fn increment_coarse_x(&mut self) {
+        if self.vram_address & 0x001f == 31 {
+            self.vram_address &= !0x001f;
+            self.vram_address ^= 0x0400;
+        } else {
+            self.vram_address += 1;
+        }
+    }
+
+    /// Move `v` down a pixel row, into the next row of tiles after the 8th, and into the
+    /// vertically adjacent nametable after the 30th row of tiles. Rows 30 and 31 are the
+    /// attribute table, and wrap to 0 without switching nametables.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_scrolling#Y_increment>
+    fn increment_y(&mut self) {
+        if self.vram_address & 0x7000 != 0x7000 {
+            self.vram_address += 0x1000;
+            return;
+        }
+        self.vram_address &= !0x7000;
+        let coarse_y = match (self.vram_address & 0x03e0) >> 5 {
+            29 => {
+                self.vram_address ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.vram_address = (self.vram_address & !0x03e0) | (coarse_y << 5);
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Change how the nametables are mirrored, as some mappers can at any time
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    /// Index into `nametables` of a nametable address, after mirroring, or `None` if the
+    /// nametable is in VRAM on the cart, as the third and fourth are with four-screen mirroring
+    ///
+    /// See: <https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring>
+    fn nametable_index(&self, address: u16) -> Option<usize> {
+        let address = (address as usize - 0x2000) & 0xfff;
+        let (table, offset) = (address / 0x400, address % 0x400);
+        let physical_table = match self.mirroring {
+            Mirroring::Vertical => table & 0x1,
+            // The system resolves mapper controlled mirroring before passing it on
+            Mirroring::Horizontal | Mirroring::MapperControlled => table >> 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen if table < 2 => table,
+            Mirroring::FourScreen => return None,
+        };
+        Some(physical_table * 0x400 + offset)
+    }
+
+    /// Read a nametable, from the console's VRAM or through `cart` at `$2800-$2FFF`
+    fn read_nametable(&self, address: u16, cart: impl Fn(u16) -> u8) -> u8 {
+        match self.nametable_index(address) {
+            Some(index) => self.nametables[index],
+            None => cart(0x2000 | (address & 0x0fff)),
+        }
+    }
+
+    /// Index into `palette_ram` of a palette address. The backdrop colour of each sprite
+    /// palette ($3F10/$3F14/$3F18/$3F1C) is shared with the background palette's.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_palettes#Memory_Map>
+    fn palette_index(address: u16) -> usize {
+        let index = address as usize & 0x1f;
+        if index & 0x13 == 0x10 {
+            index & 0x0f
+        } else {
+            index
+        }
+    }
+
+    fn read_palette(&self, address: u16) -> u8 {
+        self.palette_ram[Self::palette_index(address)]
+    }
+
+    fn read_vram(&self, address: u16, chr: impl Fn(u16) -> u8) -> u8 {
+        let address = address & 0x3fff;
+        if address < 0x2000 {
+            chr(address)
+        } else if address < 0x3f00 {
+            self.read_nametable(address, chr)
+        } else {
+            self.read_palette(address)
+        }
+    }
+
+    fn write_vram(&mut self, address: u16, value: u8, mut chr_write: impl FnMut(u16, u8)) {
+        let address = address & 0x3fff;
+        if address < 0x2000 {
+            chr_write(address, value);
+        } else if address < 0x3f00 {
+            match self.nametable_index(address) {
+                Some(index) => self.nametables[index] = value,
+                None => chr_write(0x2000 | (address & 0x0fff), value),
+            }
+        } else {
+            self.palette_ram[Self::palette_index(address)] = value;
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & 0x18 != 0
+    }
+
+    /// Draw the current scanline into the framebuffer: the background, from the scroll position
+    /// in `v` and `fine_x`, then the sprites `evaluate_sprites` found on the line before. The
+    /// hardware fetches each tile 16 dots before drawing it; this fetches the whole line at
+    /// once, so changes to the nametables or scroll part way through a line only show up on the
+    /// next.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_rendering>
+    fn render_line(&mut self, chr: impl Fn(u16) -> u8) {
+        // Palette RAM indexes, where a multiple of 4 is transparent
+        let mut line = [0; SCREEN_WIDTH];
+        if self.mask & MASK_BACKGROUND != 0 {
+            self.render_background(&mut line, &chr);
+        }
+        if self.mask & MASK_SPRITES != 0 {
+            self.render_sprites(&mut line, &chr);
+        }
+
+        let backdrop = self.palette_ram[0];
+        let row = self.scanline as usize * SCREEN_WIDTH;
+        for (pixel, &index) in self.framebuffer[row..row + SCREEN_WIDTH]
+            .iter_mut()
+            .zip(&line)
+        {
+            *pixel = if index & 0x3 == 0 {
+                backdrop
+            } else {
+                self.palette_ram[index as usize]
+            };
+        }
+    }
+
+    /// Draw the background into `line`, as palette RAM indexes
+    fn render_background(&self, line: &mut [u8; SCREEN_WIDTH], chr: impl Fn(u16) -> u8) {
+        let pattern_table: u16 = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = self.vram_address >> 12;
+        let mut address = self.vram_address;
+        let mut pixels = [0; SCREEN_WIDTH + 8];
+        for tile_pixels in pixels.chunks_exact_mut(8) {
+            let tile = self.read_nametable(0x2000 | (address & 0x0fff), &chr);
+            // Each byte of the attribute table covers 4x4 tiles, 2 bits to each 2x2 of them
+            let attribute_address =
+                0x23c0 | (address & 0x0c00) | ((address >> 4) & 0x38) | ((address >> 2) & 0x07);
+            let attribute = self.read_nametable(attribute_address, &chr);
+            let shift = ((address >> 4) & 0x4) | (address & 0x2);
+            let palette = (attribute >> shift) & 0x3;
+
+            let pattern_address = pattern_table + tile as u16 * 16 + fine_y;
+            let (low, high) = (chr(pattern_address), chr(pattern_address + 8));
+            for (bit, pixel) in tile_pixels.iter_mut().enumerate() {
+                let colour = ((low >> (7 - bit)) & 0x1) | ((high >> (7 - bit)) & 0x1) << 1;
+                *pixel = palette << 2 | colour;
+            }
+
+            // A copy of `increment_coarse_x`, as `v` itself is moved on in `tick`
+            if address & 0x001f == 31 {
+                address = (address & !0x001f) ^ 0x0400;
+            } else {
+                address += 1;
+            }
+        }
+
+        let start = self.fine_x as usize;
+        line.copy_from_slice(&pixels[start..start + SCREEN_WIDTH]);
+        if self.mask & MASK_BACKGROUND_LEFT == 0 {
+            line[..8].fill(0);
+        }
+    }
+
+    /// Draw the sprites in `line_sprites` over (or under) the background in `line`. Where they
+    /// overlap, the first sprite in OAM with a pixel there wins, even if it's behind the
+    /// background and so doesn't show.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_OAM>
+    fn render_sprites(&self, line: &mut [u8; SCREEN_WIDTH], chr: impl Fn(u16) -> u8) {
+        let height = if self.ctrl & CTRL_TALL_SPRITES != 0 {
+            16
+        } else {
+            8
+        };
+        let left = if self.mask & MASK_SPRITES_LEFT != 0 {
+            0
+        } else {
+            8
+        };
+        let mut covered = [false; SCREEN_WIDTH];
+        for &sprite in &self.line_sprites {
+            let [y, tile, attributes, x] = self.oam[sprite as usize * 4..][..4] else {
+                unreachable!()
+            };
+            // Sprites are drawn a line below their Y, as they were found on the line before
+            let mut row = self.scanline.wrapping_sub(y as u16 + 1);
+            // Or may not be, if rendering was only just turned on
+            if row >= height {
+                continue;
+            }
+            if attributes & SPRITE_FLIP_VERTICAL != 0 {
+                row = height - 1 - row;
+            }
+            let pattern_address = if height == 16 {
+                // Tall sprites pick their pattern table with bit 0 of the tile number
+                let table = (tile as u16 & 0x1) * 0x1000;
+                table + (tile as u16 & !0x1) * 16 + (row & 0x8) * 2 + (row & 0x7)
+            } else {
+                let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                    0x1000
+                } else {
+                    0
+                };
+                table + tile as u16 * 16 + row
+            };
+            let (low, high) = (chr(pattern_address), chr(pattern_address + 8));
+
+            for bit in 0..8 {
+                let column = x as usize + bit;
+                if column >= SCREEN_WIDTH {
+                    break;
+                }
+                let shift = if attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+                    bit
+                } else {
+                    7 - bit
+                };
+                let colour = ((low >> shift) & 0x1) | ((high >> shift) & 0x1) << 1;
+                if colour == 0 || column < left || covered[column] {
+                    continue;
+                }
+                covered[column] = true;
+                if attributes & SPRITE_BEHIND_BACKGROUND == 0 || line[column] & 0x3 == 0 {
+                    line[column] = 0x10 | (attributes & 0x3) << 2 | colour;
+                }
+            }
+        }
+    }
+
+    /// Find the sprites on the current scanline (to be drawn on the next), setting the sprite
+    /// overflow flag if there are more than 8. Only the first 8 are kept, unless the sprite
+    /// limit is off; the overflow flag is set the same either way, as games rely on it.
+    ///
+    /// This reproduces the hardware bug in the overflow check: after the 8th sprite is found,
+    /// the PPU moves on to the next byte of each sprite as well as to the next sprite, so it
+    /// compares tile numbers, attributes or X positions as if they were Y positions. It can
+    /// both miss a 9th sprite and report one which isn't there.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_sprite_evaluation>
+    fn evaluate_sprites(&mut self) {
+        let height = if self.ctrl & CTRL_TALL_SPRITES != 0 {
+            16
+        } else {
+            8
+        };
+        let in_range = |y: u8| (self.scanline.wrapping_sub(y as u16)) < height;
+
+        let mut sprite = 0;
+        let mut line_sprites = std::mem::take(&mut self.line_sprites);
+        line_sprites.clear();
+        while sprite < 64 && line_sprites.len() < SPRITES_PER_SCANLINE {
+            if in_range(self.oam[sprite * 4]) {
+                line_sprites.push(sprite as u8);
+            }
+            sprite += 1;
+        }
+        if !self.sprite_limit {
+            line_sprites.extend(
+                (sprite..64)
+                    .filter(|&i| in_range(self.oam[i * 4]))
+                    .map(|i| i as u8),
+            );
+        }
+        self.line_sprites = line_sprites;
+
+        let mut byte = 0;
+        while sprite < 64 {
+            if in_range(self.oam[sprite * 4 + byte]) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            // The bug: this should only move to the next sprite
+            sprite += 1;
+            byte = (byte + 1) % 4;
+        }
+    }
+
+    /// Draw the first nametable as text, one character per tile, by sampling the centre pixel
+    /// of each tile: ' ', '.', '#' and '@' for colours 0 to 3 of the tile's palette.
+    pub fn nametable_text(&self, chr: impl Fn(u16) -> u8) -> String {
+        const SHADES: [char; 4] = [' ', '.', '#', '@'];
+        let pattern_table: u16 = if self.ctrl & 0x10 != 0 { 0x1000 } else { 0 };
+
+        let mut text = String::with_capacity((NAMETABLE_COLUMNS + 1) * NAMETABLE_ROWS);
+        for row in 0..NAMETABLE_ROWS {
+            for column in 0..NAMETABLE_COLUMNS {
+                let offset = (row * NAMETABLE_COLUMNS + column) as u16;
+                let tile = self.read_nametable(0x2000 + offset, &chr);
+                // Each tile is 16 bytes: the low bit plane of its 8 rows, then the high
+                let row_address = pattern_table + tile as u16 * 16 + 4;
+                let low = (chr(row_address) >> 3) & 0x1;
+                let high = (chr(row_address + 8) >> 3) & 0x1;
+                text.push(SHADES[(high << 1 | low) as usize]);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Advance the beam by the given number of dots. `chr` reads the pattern tables (and
+    /// nametables, see `read_address`) from the cart, to draw the picture.
+    pub fn tick(&mut self, dots: u64, chr: impl Fn(u16) -> u8) {
+        for _ in 0..dots {
+            self.dot += 1;
+            let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+            let pre_render = self.scanline == self.scanlines_per_frame - 1;
+            if self.dot == 1 && self.scanline == self.vblank_scanline {
+                self.status |= STATUS_VBLANK;
+                if self.ctrl & CTRL_NMI != 0 {
+                    self.nmi_pending = true;
+                }
+            } else if self.dot == 1 && pre_render {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_OVERFLOW);
+                // Nothing is evaluated on the pre-render line, so no sprites are on line 0
+                self.line_sprites.clear();
+            } else if self.dot == 256 && visible {
+                self.render_line(&chr);
+            }
+
+            // Move `v` on as the tile fetches do: down a row at the end of each line, then
+            // back to the left edge, and back to the top on the pre-render line
+            //
+            // See: <https://www.nesdev.org/wiki/PPU_scrolling#Wrapping_around>
+            if self.rendering_enabled() && (visible || pre_render) {
+                match self.dot {
+                    256 => {
+                        if visible {
+                            self.evaluate_sprites();
+                        }
+                        self.increment_y();
+                    }
+                    257 => {
+                        self.vram_address = (self.vram_address & !HORIZONTAL_SCROLL)
+                            | (self.temp_address & HORIZONTAL_SCROLL);
+                    }
+                    304 if pre_render => {
+                        self.vram_address = (self.vram_address & !VERTICAL_SCROLL)
+                            | (self.temp_address & VERTICAL_SCROLL);
+                    }
+                    _ => {}
+                }
+            }
+
+            if self.dot == DOTS_PER_SCANLINE {
+                self.dot = 0;
+                self.scanline += 1;
+                if self.scanline == self.scanlines_per_frame {
+                    self.scanline = 0;
+                    self.frame += 1;
+                }
+            }
+        }
+    }
+
+    /// Whether an NMI has been signalled since the last call, for the CPU to take after the
+    /// current instruction
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    /// Whether PPUCTRL has NMIs at the start of vblank turned on
+    pub fn nmi_enabled(&self) -> bool {
+        self.ctrl & CTRL_NMI != 0
     }
 
-    pub fn read_address(&self, _address: u16) -> u8 {
-        0
+    /// Scanline (-1 for pre-render) and dot about to be drawn
+    pub fn position(&self) -> (i16, u16) {
+        let scanline = if self.scanline == self.scanlines_per_frame - 1 {
+            -1
+        } else {
+            self.scanline as i16
+        };
+        (scanline, self.dot)
     }
 
-    pub fn write_address(&self, _address: u16, _value: u8) {}
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn palette(&self) -> &Rc<Palette> {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Rc<Palette>) {
+        self.palette = palette;
+    }
+
+    /// Convert the framebuffer to RGB using the palette, with PPUMASK's greyscale and emphasis
+    /// bits applied.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_registers#Color_effects>
+    pub fn rgb_frame(&self) -> Frame {
+        // TODO: emphasis can change mid-frame, so should be stored with each pixel as it's drawn
+        let emphasis = self.mask >> 5;
+        // Greyscale keeps only the brightness, from the grey column of the palette
+        let index_mask = if self.mask & 0x01 != 0 { 0x30 } else { 0x3f };
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        for (pixel, &index) in frame
+            .pixels
+            .chunks_exact_mut(3)
+            .zip(self.framebuffer.iter())
+        {
+            let (r, g, b) = self.palette.colour(index & index_mask, emphasis);
+            pixel.copy_from_slice(&[r, g, b]);
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOTS_PER_FRAME: u64 = DOTS_PER_SCANLINE as u64 * 262;
+
+    /// Pattern tables where tile 1 is solid colour 1, and tile 2 has only its leftmost column
+    /// in colour 3
+    fn chr(address: u16) -> u8 {
+        match (address / 16, address % 16) {
+            (1, 0..=7) => 0xff,
+            (2, _) => 0x80,
+            _ => 0,
+        }
+    }
+
+    fn write_vram(ppu: &mut PPU, address: u16, values: &[u8]) {
+        ppu.write_address(0x2006, (address >> 8) as u8, |_, _| {});
+        ppu.write_address(0x2006, address as u8, |_, _| {});
+        for &value in values {
+            ppu.write_address(0x2007, value, |_, _| {});
+        }
+    }
+
+    /// A PPU with the background on, and palettes 0 and 1 set
+    fn ppu() -> PPU {
+        let mut ppu = PPU::new(Mirroring::Vertical, Region::Ntsc);
+        write_vram(
+            &mut ppu,
+            0x3f00,
+            &[0x0f, 0x01, 0x02, 0x03, 0x0f, 0x11, 0x12, 0x13],
+        );
+        ppu.write_address(0x2001, MASK_BACKGROUND | MASK_BACKGROUND_LEFT, |_, _| {});
+        ppu
+    }
+
+    /// Scroll to `(x, y)` in the first nametable, as games do after writing to VRAM, which
+    /// leaves the PPUADDR writes in `t`
+    fn scroll(ppu: &mut PPU, x: u8, y: u8) {
+        ppu.write_address(0x2000, 0, |_, _| {});
+        ppu.write_address(0x2005, x, |_, _| {});
+        ppu.write_address(0x2005, y, |_, _| {});
+    }
+
+    /// Run until the next frame has been drawn, from the top left of the scroll position
+    fn render(ppu: &mut PPU) {
+        ppu.tick(DOTS_PER_FRAME, chr);
+        ppu.tick(DOTS_PER_FRAME, chr);
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn background_tiles() {
+        let mut ppu = ppu();
+        // Tile 1 in the top left 2x2 tiles, which use palette 1
+        write_vram(&mut ppu, 0x2000, &[1]);
+        write_vram(&mut ppu, 0x2020, &[1]);
+        write_vram(&mut ppu, 0x23c0, &[0x01]);
+        // Tile 2 in the top right 2x2 of the same attribute byte, which use palette 0, and in
+        // the top left 2x2 of the next byte, which use palette 1
+        write_vram(&mut ppu, 0x2023, &[2, 2]);
+        write_vram(&mut ppu, 0x23c1, &[0x01]);
+        scroll(&mut ppu, 0, 0);
+        render(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0x11);
+        assert_eq!(pixel(&ppu, 7, 7), 0x11);
+        assert_eq!(pixel(&ppu, 8, 0), 0x0f);
+        assert_eq!(pixel(&ppu, 0, 8), 0x11);
+        assert_eq!(pixel(&ppu, 24, 8), 0x03);
+        assert_eq!(pixel(&ppu, 25, 8), 0x0f);
+        assert_eq!(pixel(&ppu, 32, 8), 0x13);
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x2000 + 32 + 31, &[2]);
+        write_vram(&mut ppu, 0x2400 + 32, &[2]);
+        // Fine X and Y, and coarse X up to the edge of the first nametable
+        scroll(&mut ppu, 8 * 31 + 3, 2);
+        render(&mut ppu);
+
+        // The 2nd row of tiles starts 2 pixels up, and the tiles 3 pixels to the left, with
+        // the second nametable to the right of the first
+        assert_eq!(pixel(&ppu, 0, 6), 0x0f);
+        assert_eq!(pixel(&ppu, 5, 6), 0x03);
+        assert_eq!(pixel(&ppu, 5, 5), 0x0f);
+        assert_eq!(pixel(&ppu, 5, 13), 0x03);
+        assert_eq!(pixel(&ppu, 5, 14), 0x0f);
+    }
+
+    #[test]
+    fn background_left_column() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x2000, &[1, 1]);
+        scroll(&mut ppu, 0, 0);
+        ppu.write_address(0x2001, MASK_BACKGROUND, |_, _| {});
+        render(&mut ppu);
+        assert_eq!(pixel(&ppu, 7, 0), 0x0f);
+        assert_eq!(pixel(&ppu, 8, 0), 0x01);
+
+        // With the background off there is only the backdrop
+        ppu.write_address(0x2001, 0, |_, _| {});
+        render(&mut ppu);
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == 0x0f));
+    }
+
+    #[test]
+    fn four_screen_nametables_on_cart() {
+        let mut ppu = PPU::new(Mirroring::FourScreen, Region::Ntsc);
+        assert_eq!(ppu.nametables.len(), 0x800);
+        let mut cart_writes = Vec::new();
+        for address in [0x2000, 0x2400, 0x2800, 0x2c00, 0x3bff] {
+            ppu.write_address(0x2006, (address >> 8) as u8, |_, _| {});
+            ppu.write_address(0x2006, address as u8, |_, _| {});
+            ppu.write_address(0x2007, 0x12, |address, value| {
+                cart_writes.push((address, value))
+            });
+        }
+        // Only the third and fourth nametables (and their mirrors) go to the cart
+        assert_eq!(
+            cart_writes,
+            [(0x2800, 0x12), (0x2c00, 0x12), (0x2bff, 0x12)]
+        );
+        let cart = |address| (address >> 8) as u8;
+        ppu.write_address(0x2006, 0x2c, |_, _| {});
+        ppu.write_address(0x2006, 0x10, |_, _| {});
+        ppu.read_address(0x2007, cart);
+        assert_eq!(ppu.read_address(0x2007, cart), 0x2c);
+    }
+
+    #[test]
+    fn rgb_frame() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x2000, &[1]);
+        write_vram(&mut ppu, 0x23c0, &[0x01]);
+        scroll(&mut ppu, 0, 0);
+        render(&mut ppu);
+
+        let frame = ppu.rgb_frame();
+        assert_eq!((frame.width, frame.height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(frame.pixels.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        // Colour $11, and the backdrop, $0F
+        assert_eq!(frame.pixel(0, 0), (8, 76, 196));
+        assert_eq!(frame.pixel(8, 0), (0, 0, 0));
+
+        // Greyscale takes the colour from the grey column, $10
+        ppu.write_address(
+            0x2001,
+            MASK_BACKGROUND | MASK_BACKGROUND_LEFT | 0x01,
+            |_, _| {},
+        );
+        assert_eq!(ppu.rgb_frame().pixel(0, 0), (152, 150, 152));
+        // Emphasis goes through the palette
+        ppu.write_address(
+            0x2001,
+            MASK_BACKGROUND | MASK_BACKGROUND_LEFT | 0x20,
+            |_, _| {},
+        );
+        assert_eq!(ppu.rgb_frame().pixel(0, 0), ppu.palette().colour(0x11, 1));
+        assert_ne!(ppu.rgb_frame().pixel(0, 0), (8, 76, 196));
+    }
+
+    fn read_vram(ppu: &mut PPU, address: u16) -> u8 {
+        ppu.write_address(0x2006, (address >> 8) as u8, |_, _| {});
+        ppu.write_address(0x2006, address as u8, |_, _| {});
+        ppu.read_address(0x2007, chr)
+    }
+
+    /// Where `v` ends up after reading PPUDATA `reads` times from `address`, on scanline
+    /// `scanline` with PPUCTRL and PPUMASK set to `ctrl` and `mask`
+    fn v_after_reads(scanline: u64, ctrl: u8, mask: u8, address: u16, reads: usize) -> u16 {
+        let mut ppu = PPU::new(Mirroring::Vertical, Region::Ntsc);
+        ppu.write_address(0x2000, ctrl, |_, _| {});
+        ppu.write_address(0x2001, mask, |_, _| {});
+        // Part way through the line, away from the dots where rendering moves `v` itself
+        ppu.tick(DOTS_PER_SCANLINE as u64 * scanline + 100, chr);
+        ppu.write_address(0x2006, (address >> 8) as u8, |_, _| {});
+        ppu.write_address(0x2006, address as u8, |_, _| {});
+        for _ in 0..reads {
+            ppu.read_address(0x2007, chr);
+        }
+        ppu.vram_address
+    }
+
+    fn v_after_read(scanline: u64, ctrl: u8, mask: u8, address: u16) -> u16 {
+        v_after_reads(scanline, ctrl, mask, address, 1)
+    }
+
+    #[test]
+    fn ppudata_while_rendering() {
+        let rendering = MASK_BACKGROUND | MASK_SPRITES;
+        for ctrl in [0x00, 0x04] {
+            // Coarse X and fine Y both go up, rather than v going up by 1 or 32
+            assert_eq!(v_after_read(10, ctrl, rendering, 0x2021), 0x3022);
+            // Fine Y wraps into coarse Y, and coarse X into the next nametable. PPUADDR can only
+            // set fine Y up to 3, so it takes a few reads to get to 7.
+            assert_eq!(v_after_reads(10, ctrl, rendering, 0x3021, 4), 0x7025);
+            assert_eq!(v_after_reads(10, ctrl, rendering, 0x3021, 5), 0x0046);
+            assert_eq!(v_after_read(10, ctrl, rendering, 0x201f), 0x3400);
+            // As on the pre-render line
+            assert_eq!(v_after_read(261, ctrl, rendering, 0x2021), 0x3022);
+        }
+
+        // Otherwise v goes up by 1, or 32 with PPUCTRL bit 2 set
+        assert_eq!(v_after_read(10, 0x00, 0, 0x2021), 0x2022);
+        assert_eq!(v_after_read(10, 0x04, 0, 0x2021), 0x2041);
+        // Including with rendering on but in vblank
+        assert_eq!(v_after_read(245, 0x00, rendering, 0x2021), 0x2022);
+        assert_eq!(v_after_read(245, 0x04, rendering, 0x2021), 0x2041);
+    }
+
+    #[test]
+    fn palette_mirroring() {
+        let mut ppu = PPU::new(Mirroring::Vertical, Region::Ntsc);
+        for (sprite, background) in [(0x3f10, 0x3f00), (0x3f14, 0x3f04), (0x3f1c, 0x3f0c)] {
+            write_vram(&mut ppu, sprite, &[0x21]);
+            assert_eq!(read_vram(&mut ppu, background), 0x21, "{sprite:04x}");
+            write_vram(&mut ppu, background, &[0x22]);
+            assert_eq!(read_vram(&mut ppu, sprite), 0x22, "{background:04x}");
+        }
+
+        // The other sprite palette entries are their own
+        write_vram(&mut ppu, 0x3f01, &[0x01]);
+        write_vram(&mut ppu, 0x3f11, &[0x11]);
+        assert_eq!(read_vram(&mut ppu, 0x3f01), 0x01);
+        assert_eq!(read_vram(&mut ppu, 0x3f11), 0x11);
+
+        // Palette RAM repeats every 32 bytes up to $3FFF
+        assert_eq!(read_vram(&mut ppu, 0x3f31), 0x11);
+        write_vram(&mut ppu, 0x3ff0, &[0x30]);
+        assert_eq!(read_vram(&mut ppu, 0x3f00), 0x30);
+    }
+
+    /// Everything on (see `show`)
+    const SHOW_ALL: u8 = MASK_BACKGROUND | MASK_BACKGROUND_LEFT | MASK_SPRITES | MASK_SPRITES_LEFT;
+
+    /// A PPU with sprite palettes 0 and 1 set as well (see `ppu`), and OAM set to `oam`, padded
+    /// with sprites below the screen. Rendering is off until `show`, as writes to PPUDATA while
+    /// rendering move `v` the way rendering does.
+    fn sprite_ppu(oam: &[u8]) -> PPU {
+        let mut ppu = ppu();
+        ppu.write_address(0x2001, 0, |_, _| {});
+        write_vram(
+            &mut ppu,
+            0x3f10,
+            &[0x0f, 0x21, 0x22, 0x23, 0x0f, 0x31, 0x32, 0x33],
+        );
+        ppu.write_address(0x2003, 0, |_, _| {});
+        for index in 0..0x100 {
+            let value = oam.get(index).copied().unwrap_or(0xf0);
+            ppu.write_address(0x2004, value, |_, _| {});
+        }
+        ppu
+    }
+
+    /// Turn on rendering with PPUMASK set to `mask`, and draw a frame from the top left of the
+    /// first nametable
+    fn show(ppu: &mut PPU, mask: u8) {
+        scroll(ppu, 0, 0);
+        ppu.write_address(0x2001, mask, |_, _| {});
+        render(ppu);
+    }
+
+    #[test]
+    fn sprites() {
+        let mut ppu = sprite_ppu(&[
+            // Tile 1 with palette 1, at (16, 20) as it's drawn a line below its Y
+            19,
+            1,
+            0x01,
+            16, //
+            // Tile 2 flipped, so its column of colour 3 is on the right
+            19,
+            2,
+            SPRITE_FLIP_HORIZONTAL,
+            40, //
+            // Behind the background, over tile 1 and the transparent tile 0
+            39,
+            1,
+            SPRITE_BEHIND_BACKGROUND,
+            4,
+        ]);
+        write_vram(&mut ppu, 0x20a0, &[1]);
+        show(&mut ppu, SHOW_ALL);
+
+        assert_eq!(pixel(&ppu, 16, 19), 0x0f);
+        assert_eq!(pixel(&ppu, 16, 20), 0x31);
+        assert_eq!(pixel(&ppu, 23, 27), 0x31);
+        assert_eq!(pixel(&ppu, 24, 20), 0x0f);
+        assert_eq!(pixel(&ppu, 16, 28), 0x0f);
+        assert_eq!(pixel(&ppu, 40, 20), 0x0f);
+        assert_eq!(pixel(&ppu, 47, 20), 0x23);
+        assert_eq!(pixel(&ppu, 7, 40), 0x01);
+        assert_eq!(pixel(&ppu, 8, 40), 0x21);
+
+        // The first sprite in OAM wins where they overlap, even behind the background
+        let mut ppu = sprite_ppu(&[
+            9,
+            1,
+            SPRITE_BEHIND_BACKGROUND,
+            0, //
+            9,
+            1,
+            0x01,
+            4,
+        ]);
+        write_vram(&mut ppu, 0x2020, &[1]);
+        show(&mut ppu, SHOW_ALL);
+        assert_eq!(pixel(&ppu, 4, 10), 0x01);
+        assert_eq!(pixel(&ppu, 8, 10), 0x31);
+        // Below the background tile, the first sprite shows through
+        assert_eq!(pixel(&ppu, 4, 16), 0x21);
+        assert_eq!(pixel(&ppu, 8, 18), 0x0f);
+
+        // And the leftmost 8 pixels can be hidden
+        show(&mut ppu, MASK_BACKGROUND | MASK_SPRITES);
+        assert_eq!(pixel(&ppu, 7, 10), 0x0f);
+        assert_eq!(pixel(&ppu, 8, 10), 0x31);
+    }
+
+    /// The pixels where the 9th of 9 sprites in a row on line 20 would be, and whether the
+    /// sprite overflow flag was set
+    fn ninth_sprite(sprite_limit: bool) -> (u8, bool) {
+        let oam: Vec<u8> = (0..9).flat_map(|i| [19, 1, 0x01, i * 8]).collect();
+        let mut ppu = sprite_ppu(&oam);
+        ppu.set_sprite_limit(sprite_limit);
+        show(&mut ppu, SHOW_ALL);
+        let ninth = pixel(&ppu, 64, 20);
+        ppu.tick(DOTS_PER_SCANLINE as u64 * 240, chr);
+        (
+            ninth,
+            ppu.peek_address(0x2002) & STATUS_SPRITE_OVERFLOW != 0,
+        )
+    }
+
+    #[test]
+    fn sprite_limit() {
+        assert_eq!(ninth_sprite(true), (0x0f, true));
+        assert_eq!(ninth_sprite(false), (0x31, true));
+    }
+
+    /// Whether the sprite overflow flag is set after evaluating scanline 10 with the given OAM,
+    /// padded with bytes which are never in range
+    fn overflow_with(oam: &[u8]) -> bool {
+        let mut ppu = PPU::new(Mirroring::Vertical, Region::Ntsc);
+        ppu.write_address(0x2001, 0x18, |_, _| {});
+        for index in 0..0x100 {
+            let value = oam.get(index).copied().unwrap_or(0xf0);
+            ppu.write_address(0x2004, value, |_, _| {});
+        }
+        ppu.tick(DOTS_PER_SCANLINE as u64 * 11, chr);
+        ppu.peek_address(0x2002) & STATUS_SPRITE_OVERFLOW != 0
+    }
+
+    /// `count` sprites with every byte 10, so on scanline 10 whichever byte is compared
+    fn sprites_on_line(count: usize) -> Vec<u8> {
+        vec![10; count * 4]
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        assert!(!overflow_with(&sprites_on_line(8)));
+        assert!(overflow_with(&sprites_on_line(9)));
+
+        // The flag is the same with the limit off, and cleared on the pre-render line
+        let mut ppu = PPU::new(Mirroring::Vertical, Region::Ntsc);
+        ppu.set_sprite_limit(false);
+        ppu.write_address(0x2001, 0x18, |_, _| {});
+        for value in (0..0x100).map(|index| if index < 9 * 4 { 10 } else { 0xf0 }) {
+            ppu.write_address(0x2004, value, |_, _| {});
+        }
+        ppu.tick(DOTS_PER_SCANLINE as u64 * 11, chr);
+        assert_eq!(ppu.line_sprites.len(), 9);
+        assert_ne!(ppu.peek_address(0x2002) & STATUS_SPRITE_OVERFLOW, 0);
+        ppu.tick(DOTS_PER_SCANLINE as u64 * 251 + 2, chr);
+        assert_eq!(ppu.peek_address(0x2002) & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn sprite_overflow_false_negative() {
+        // After the 8th sprite, sprite 8's Y is compared, then sprite 9's tile, sprite 10's
+        // attributes and so on, diagonally through OAM. Sprite 9 is on the line, but its tile
+        // number isn't, so it's missed.
+        let mut oam = sprites_on_line(8);
+        oam.extend([0xf0, 0xf0, 0xf0, 0xf0]);
+        oam.extend([10, 0xf0, 0xf0, 0xf0]);
+        assert!(!overflow_with(&oam));
+    }
+
+    #[test]
+    fn sprite_overflow_false_positive() {
+        // Only 8 sprites are on the line, but sprite 9's tile number, sprite 10's attributes
+        // or sprite 11's X position look like Ys which are
+        for byte in 1..4 {
+            let mut oam = sprites_on_line(8);
+            oam.extend([0xf0; 4]);
+            for sprite in 9..12 {
+                let mut bytes = [0xf0; 4];
+                if sprite - 8 == byte {
+                    bytes[byte] = 10;
+                }
+                oam.extend(bytes);
+            }
+            assert!(overflow_with(&oam), "byte {byte}");
+        }
+        // Sprite 12 is back to the Y byte
+        let mut oam = sprites_on_line(8);
+        oam.extend([0xf0; 16]);
+        oam.extend([10, 0xf0, 0xf0, 0xf0]);
+        assert!(overflow_with(&oam));
+    }
 }
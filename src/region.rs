@@ -0,0 +1,68 @@
+//! Timing differences between the NTSC and PAL consoles, and the Dendy famiclone.
+//!
+//! See: <https://www.nesdev.org/wiki/Cycle_reference_chart>
+
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// The Dendy and other famiclones sold in Eastern Europe and Russia: PAL's frame rate and
+    /// scanline count, but NTSC's CPU to PPU clock ratio, with vblank 50 lines later than either
+    /// so that NTSC games' timing still works
+    ///
+    /// See: <https://www.nesdev.org/wiki/Dendy>
+    Dendy,
+}
+
+impl Region {
+    /// Scanlines per frame, including vblank and the pre-render line
+    pub fn scanlines_per_frame(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// The scanline vblank starts on (at its second dot)
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// PPU dots per CPU cycle, as a fraction (numerator, denominator)
+    pub fn ppu_dots_per_cpu_cycle(self) -> (u64, u64) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    /// Time between frames (~60.1 Hz on NTSC, ~50.0 Hz on PAL and Dendy)
+    pub fn frame_duration(self) -> Duration {
+        match self {
+            Region::Ntsc => crate::FRAME_DURATION,
+            Region::Pal | Region::Dendy => Duration::from_nanos(19_997_209),
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!(
+                "unknown region '{s}' (expected one of: ntsc, pal, dendy)"
+            )),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Rendering regression checks: ROMs run headless for a number of frames, with the hash of the
+//! last frame compared against a known-good one, so changes to the PPU which break a game's
+//! picture are caught without anyone having to look.
+//!
+//! Cases are listed in a manifest, one per line, as the ROM's path (relative to the manifest),
+//! the number of frames, and the expected hash in hex, e.g. `demo.nes 300 1a2b3c4d`. Blank lines
+//! and lines starting with `#` are ignored. The hash is the CRC-32 of the PPU's framebuffer, as
+//! printed by `--headless`, so it doesn't change with the palette or filters. A case without a
+//! hash fails until one is recorded, which only happens when asked to (see `run_regression`),
+//! so a new case can't pass by recording whatever it happens to render.
+
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+
+#[derive(Debug)]
+pub enum RegressionError {
+    IoError(io::Error),
+    /// A line of the manifest which isn't a case, numbered from 1
+    BadLine {
+        line: usize,
+        message: String,
+    },
+}
+
+impl Display for RegressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegressionError::IoError(err) => write!(f, "IO error: {}", err),
+            RegressionError::BadLine { line, message } => {
+                write!(f, "line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegressionError {}
+
+impl From<io::Error> for RegressionError {
+    fn from(err: io::Error) -> Self {
+        RegressionError::IoError(err)
+    }
+}
+
+pub type RegressionResult<T> = Result<T, RegressionError>;
+
+/// A line of the manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Case {
+    line: usize,
+    rom: String,
+    frames: u64,
+    expected: Option<u32>,
+}
+
+fn parse_case(line: usize, text: &str) -> RegressionResult<Option<Case>> {
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('#') {
+        return Ok(None);
+    }
+    let bad_line = |message: String| RegressionError::BadLine { line, message };
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let (rom, frames, expected) = match fields[..] {
+        [rom, frames] => (rom, frames, None),
+        [rom, frames, hash] => (rom, frames, Some(hash)),
+        _ => return Err(bad_line("expected a ROM, a frame count and a hash".into())),
+    };
+    let frames = frames
+        .parse()
+        .map_err(|_| bad_line(format!("invalid frame count '{}'", frames)))?;
+    let expected = expected
+        .map(|hash| u32::from_str_radix(hash, 16))
+        .transpose()
+        .map_err(|_| bad_line(format!("invalid hash '{}'", expected.unwrap_or_default())))?;
+    Ok(Some(Case {
+        line,
+        rom: rom.to_string(),
+        frames,
+        expected,
+    }))
+}
+
+/// How a case went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegressionReport {
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub expected: Option<u32>,
+
+    /// The hash of the last frame, or why the ROM couldn't be run that far
+    pub actual: Result<u32, String>,
+
+    /// Where the last frame was saved, if it didn't match
+    pub screenshot: Option<PathBuf>,
+
+    /// Whether `actual` was written into the manifest as the new expected hash
+    pub recorded: bool,
+}
+
+impl RegressionReport {
+    /// Whether the frame matched, or its hash was recorded
+    pub fn passed(&self) -> bool {
+        match (&self.actual, self.expected) {
+            (Ok(actual), Some(expected)) => *actual == expected,
+            (Ok(_), None) => self.recorded,
+            (Err(_), _) => false,
+        }
+    }
+}
+
+impl Display for RegressionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at frame {}: ", self.rom.display(), self.frames)?;
+        match (&self.actual, self.expected) {
+            (Err(err), _) => write!(f, "error: {}", err)?,
+            (Ok(actual), Some(expected)) if *actual == expected => {
+                write!(f, "ok ({:08x})", actual)?
+            }
+            (Ok(actual), Some(expected)) => write!(
+                f,
+                "MISMATCH (expected {:08x}, got {:08x})",
+                expected, actual
+            )?,
+            (Ok(actual), None) if self.recorded => write!(f, "recorded {:08x}", actual)?,
+            (Ok(actual), None) => write!(f, "no expected hash (got {:08x})", actual)?,
+        }
+        if let Some(path) = &self.screenshot {
+            write!(f, "\n  frame saved to {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Run a ROM for `frames` frames from power on, with nothing but the ROM to affect it (see
+/// `CPU::set_deterministic`), and hash the last frame
+fn run_case(rom: &Path, frames: u64) -> Result<CPU, String> {
+    let mut cpu =
+        CPU::new(rom.to_string_lossy().into_owned(), false).map_err(|err| err.to_string())?;
+    cpu.set_deterministic(true);
+    crate::run_headless(&mut cpu, frames).map_err(|err| err.to_string())?;
+    Ok(cpu)
+}
+
+/// Run every case in the manifest at `path`. Frames which don't match are saved as PNGs in
+/// `output_dir`, named after the ROM and frame count.
+///
+/// Cases without a hash fail. With `update`, every case has the hash the ROM renders now
+/// written into the manifest instead, for new cases or when a change to the picture is intended.
+pub fn run_regression(
+    path: &Path,
+    output_dir: &Path,
+    update: bool,
+) -> RegressionResult<Vec<RegressionReport>> {
+    let manifest = std::fs::read_to_string(path)?;
+    let mut lines: Vec<String> = manifest.lines().map(String::from).collect();
+    let cases = lines
+        .iter()
+        .enumerate()
+        .map(|(i, text)| parse_case(i + 1, text))
+        .collect::<RegressionResult<Vec<_>>>()?;
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut reports = Vec::new();
+    let mut recorded = false;
+    for case in cases.into_iter().flatten() {
+        let rom = base.join(&case.rom);
+        let result = run_case(&rom, case.frames);
+        let actual = result
+            .as_ref()
+            .map(|cpu| cpu.frame_hash())
+            .map_err(Clone::clone);
+
+        let mut report = RegressionReport {
+            rom,
+            frames: case.frames,
+            expected: if update { None } else { case.expected },
+            actual,
+            screenshot: None,
+            recorded: false,
+        };
+        if let (Ok(cpu), Ok(actual)) = (&result, &report.actual) {
+            if update {
+                recorded = true;
+                report.recorded = true;
+                lines[case.line - 1] = format!("{} {} {:08x}", case.rom, case.frames, actual);
+            } else if !report.passed() {
+                let stem = report.rom.file_stem().unwrap_or_default().to_string_lossy();
+                let screenshot = output_dir.join(format!("{}-{}.png", stem, case.frames));
+                std::fs::create_dir_all(output_dir)?;
+                std::fs::write(&screenshot, cpu.frame().to_png())?;
+                report.screenshot = Some(screenshot);
+            }
+        }
+        reports.push(report);
+    }
+
+    if recorded {
+        let mut manifest = lines.join("\n");
+        manifest.push('\n');
+        std::fs::write(path, manifest)?;
+    }
+    Ok(reports)
+}
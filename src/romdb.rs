@@ -0,0 +1,138 @@
+//! Corrections for ROMs with wrong headers, which are common in early dumps, e.g. the wrong mapper
+//! number. ROMs are identified by the SHA-1 of their PRG ROM, as the header says it is, so that a
+//! dump is recognised whatever its header says about the mapper, and whether or not it has CHR
+//! ROM, a trainer or junk after the end.
+
+/// What a ROM's header should have said
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomInfo {
+    pub mapper: u8,
+    pub submapper: u8,
+
+    /// Sizes of the ROMs in bytes
+    pub prg_size: u32,
+    pub chr_size: u32,
+}
+
+/// Known ROMs, as the SHA-1 of their PRG ROM in lower case hex. Entries should only be added for
+/// dumps whose headers are known to be wrong.
+const BUILTIN_ENTRIES: &[(&str, RomInfo)] = &[];
+
+#[derive(Debug, Clone, Copy)]
+pub struct RomDatabase {
+    entries: &'static [(&'static str, RomInfo)],
+}
+
+impl RomDatabase {
+    /// The database compiled into the emulator
+    pub fn builtin() -> Self {
+        Self::new(BUILTIN_ENTRIES)
+    }
+
+    /// A database of the given ROMs, as the SHA-1 of their PRG ROM in lower case hex
+    pub fn new(entries: &'static [(&'static str, RomInfo)]) -> Self {
+        Self { entries }
+    }
+
+    /// The correct header for a ROM, given its PRG ROM, if it is known
+    pub fn lookup(&self, prg_rom: &[u8]) -> Option<RomInfo> {
+        let hash: String = sha1(prg_rom).iter().map(|b| format!("{:02x}", b)).collect();
+        self.entries
+            .iter()
+            .find(|(sha1, _)| *sha1 == hash)
+            .map(|&(_, info)| info)
+    }
+}
+
+/// SHA-1 of `data`, which is how ROM databases like No-Intro's identify ROMs
+///
+/// See: <https://en.wikipedia.org/wiki/SHA-1#SHA-1_pseudocode>
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // Pad with a 1 bit, then zeroes up to 8 bytes short of a 64 byte block, then the length
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+
+    let mut hash = [0; 20];
+    for (bytes, word) in hash.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_hex(data: &[u8]) -> String {
+        sha1(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Long enough that the padding takes a second block
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            sha1_hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn lookup() {
+        const INFO: RomInfo = RomInfo {
+            mapper: 1,
+            submapper: 0,
+            prg_size: 0x8000,
+            chr_size: 0,
+        };
+        let database = RomDatabase::new(&[("a9993e364706816aba3e25717850c26c9cd0d89d", INFO)]);
+        assert_eq!(database.lookup(b"abc"), Some(INFO));
+        assert_eq!(database.lookup(b"abd"), None);
+        assert_eq!(RomDatabase::builtin().lookup(b"abc"), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Where files the emulator names itself, like the savestates from the save hotkey and the `.sav`
+//! files of carts with a battery, are kept. They go next to the ROM, unless a save directory is
+//! given, e.g. as the ROMs are on a read-only share.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+
+/// The file with the given extension to save for a ROM. With a save directory, each ROM has its
+/// own subdirectory in it named after the ROM and its CRC-32, so ROMs with the same filename in
+/// different places don't overwrite each other's saves.
+pub fn save_path(
+    save_dir: Option<&Path>,
+    rom_path: &Path,
+    rom_crc: u32,
+    extension: &str,
+) -> PathBuf {
+    match save_dir {
+        Some(dir) => {
+            let stem = rom_path.file_stem().unwrap_or(rom_path.as_os_str());
+            // Not `with_extension`, which would replace the part of a stem like `Zelda v1.1`
+            // after its last dot
+            let mut name = stem.to_os_string();
+            name.push(format!(".{}", extension));
+            rom_save_dir(dir, rom_path, rom_crc).join(name)
+        }
+        None => rom_path.with_extension(extension),
+    }
+}
+
+/// Where to write a file named on the command line, like a screenshot or movie. With a save
+/// directory, a bare file name goes in the ROM's subdirectory, as the savestates do; any other
+/// path is used as it is.
+pub fn output_path(save_dir: Option<&Path>, rom_path: &Path, rom_crc: u32, path: &Path) -> PathBuf {
+    match save_dir {
+        Some(dir) if path.parent() == Some(Path::new("")) => {
+            rom_save_dir(dir, rom_path, rom_crc).join(path)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+fn rom_save_dir(dir: &Path, rom_path: &Path, rom_crc: u32) -> PathBuf {
+    let stem = rom_path.file_stem().unwrap_or(rom_path.as_os_str());
+    let mut subdir = stem.to_os_string();
+    subdir.push(format!("-{:08x}", rom_crc));
+    dir.join(subdir)
+}
+
+/// Create the save directory if needed, and check that files can be written to it, so that a
+/// bad path is reported on start up rather than on the first save
+pub fn prepare_save_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(".rusty-nes-write-test");
+    std::fs::write(&probe, [])?;
+    std::fs::remove_file(probe)
+}
+
+/// Restore the cart's battery-backed RAM from `path`, if the cart has a battery and the file
+/// exists. The first run of a game has no save file yet, which isn't an error.
+pub fn read_battery_save(cpu: &mut CPU, path: &Path) -> io::Result<()> {
+    if cpu.battery_ram().is_none() {
+        return Ok(());
+    }
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            cpu.load_battery_ram(&bytes);
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the cart's battery-backed RAM to `path`, if it has a battery, creating the ROM's
+/// subdirectory of the save directory if needed
+pub fn write_battery_save(cpu: &CPU, path: &Path) -> io::Result<()> {
+    match cpu.battery_ram() {
+        Some(ram) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, ram)
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn next_to_rom_without_save_dir() {
+        let path = save_path(None, Path::new("roms/Zelda v1.1.nes"), 0x1234abcd, "state");
+        assert_eq!(path, Path::new("roms/Zelda v1.1.state"));
+    }
+
+    #[test]
+    fn subdirectory_per_rom_in_save_dir() {
+        let dir = Path::new("saves");
+        let path = save_path(Some(dir), Path::new("roms/smb.nes"), 0x1234abcd, "sav");
+        assert_eq!(path, Path::new("saves/smb-1234abcd/smb.sav"));
+
+        // The stem's own dots are kept
+        let path = save_path(Some(dir), Path::new("Zelda v1.1.nes"), 0xcafe, "state");
+        assert_eq!(
+            path,
+            Path::new("saves/Zelda v1.1-0000cafe/Zelda v1.1.state")
+        );
+    }
+
+    #[test]
+    fn output_path_in_save_dir() {
+        let rom = Path::new("roms/smb.nes");
+        let dir = Some(Path::new("saves"));
+        assert_eq!(
+            output_path(dir, rom, 0x1234abcd, Path::new("shot.png")),
+            Path::new("saves/smb-1234abcd/shot.png")
+        );
+        assert_eq!(
+            output_path(dir, rom, 0x1234abcd, Path::new("out/shot.png")),
+            Path::new("out/shot.png")
+        );
+        assert_eq!(
+            output_path(None, rom, 0x1234abcd, Path::new("shot.png")),
+            Path::new("shot.png")
+        );
+    }
+
+    #[test]
+    fn battery_save_round_trip() {
+        let program = crate::asm::assemble("lda #$42\nsta $6000\nloop: jmp loop").unwrap();
+        let mut image = testing::nrom(&program);
+        image[6] |= 0x2;
+        let mut cpu = CPU::from_bytes(image.clone(), false).unwrap();
+        testing::run(&mut cpu, 3);
+
+        let path = std::env::temp_dir()
+            .join(format!("rusty_nes_{}", std::process::id()))
+            .join("battery.sav");
+        write_battery_save(&cpu, &path).unwrap();
+        let mut cpu = CPU::from_bytes(image, false).unwrap();
+        read_battery_save(&mut cpu, &path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(cpu.battery_ram().unwrap()[0], 0x42);
+
+        // Without a battery there's nothing to save
+        let cpu = testing::cpu("lda #$42\nsta $6000");
+        assert_eq!(cpu.battery_ram(), None);
+    }
+}
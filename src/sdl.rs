@@ -1,28 +1,220 @@
 use fermium::{
-    prelude::{SDL_Event, SDL_PollEvent, SDL_KEYDOWN, SDL_QUIT},
+    audio::{
+        SDL_AudioDeviceID, SDL_AudioSpec, SDL_ClearQueuedAudio, SDL_CloseAudioDevice,
+        SDL_GetQueuedAudioSize, SDL_OpenAudioDevice, SDL_PauseAudioDevice, SDL_QueueAudio,
+        AUDIO_F32SYS,
+    },
+    error::SDL_GetError,
+    pixels::SDL_PIXELFORMAT_RGB24,
+    prelude::{SDL_Event, SDL_PollEvent, SDL_KEYDOWN, SDL_KEYUP, SDL_QUIT},
+    rect::SDL_Rect,
     renderer::{
-        SDL_CreateRenderer, SDL_DestroyRenderer, SDL_RenderClear, SDL_RenderDrawPoint,
-        SDL_RenderPresent, SDL_Renderer, SDL_SetRenderDrawColor,
+        SDL_CreateRenderer, SDL_CreateTexture, SDL_DestroyRenderer, SDL_DestroyTexture,
+        SDL_GetRendererOutputSize, SDL_RenderClear, SDL_RenderCopy, SDL_RenderPresent,
+        SDL_Renderer, SDL_SetRenderDrawColor, SDL_Texture, SDL_UpdateTexture,
+        SDL_RENDERER_ACCELERATED, SDL_RENDERER_SOFTWARE, SDL_TEXTUREACCESS_STREAMING,
     },
-    scancode::{SDL_SCANCODE_DOWN, SDL_SCANCODE_LEFT, SDL_SCANCODE_RIGHT, SDL_SCANCODE_UP},
+    scancode::*,
     video::{
         SDL_CreateWindow, SDL_DestroyWindow, SDL_Window, SDL_WINDOWPOS_CENTERED,
-        SDL_WINDOW_ALLOW_HIGHDPI, SDL_WINDOW_OPENGL,
+        SDL_WINDOW_ALLOW_HIGHDPI, SDL_WINDOW_FULLSCREEN_DESKTOP, SDL_WINDOW_OPENGL,
     },
-    SDL_Init, SDL_Quit, SDL_INIT_VIDEO,
+    SDL_Init, SDL_InitSubSystem, SDL_Quit, SDL_INIT_AUDIO, SDL_INIT_VIDEO,
 };
 
+use std::ffi::{c_char, CStr};
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::controller::famicom_key_index;
+use crate::filter::Frame;
+use crate::frontend::{buttons, Frontend, InputState};
+use crate::window::{picture_rect, Overscan, WindowSize};
+
+/// Most audio to queue ahead of the device, in seconds
+const MAX_QUEUED_AUDIO: f32 = 0.1;
+
 pub enum Key {
     Up,
     Down,
     Left,
     Right,
+    Z,
+    X,
+    Return,
+    RShift,
+    F,
+    Minus,
+    Equals,
+    M,
+    R,
+    S,
+    Escape,
+    LeftBracket,
+    RightBracket,
+    F5,
+    F7,
+}
+
+impl Key {
+    fn from_scancode(scancode: SDL_Scancode) -> Option<Self> {
+        match scancode {
+            SDL_SCANCODE_UP => Some(Key::Up),
+            SDL_SCANCODE_DOWN => Some(Key::Down),
+            SDL_SCANCODE_LEFT => Some(Key::Left),
+            SDL_SCANCODE_RIGHT => Some(Key::Right),
+            SDL_SCANCODE_Z => Some(Key::Z),
+            SDL_SCANCODE_X => Some(Key::X),
+            SDL_SCANCODE_RETURN => Some(Key::Return),
+            SDL_SCANCODE_RSHIFT => Some(Key::RShift),
+            SDL_SCANCODE_F => Some(Key::F),
+            SDL_SCANCODE_MINUS | SDL_SCANCODE_KP_MINUS => Some(Key::Minus),
+            SDL_SCANCODE_EQUALS | SDL_SCANCODE_KP_PLUS => Some(Key::Equals),
+            SDL_SCANCODE_M => Some(Key::M),
+            SDL_SCANCODE_R => Some(Key::R),
+            SDL_SCANCODE_S => Some(Key::S),
+            SDL_SCANCODE_ESCAPE => Some(Key::Escape),
+            SDL_SCANCODE_LEFTBRACKET => Some(Key::LeftBracket),
+            SDL_SCANCODE_RIGHTBRACKET => Some(Key::RightBracket),
+            SDL_SCANCODE_F5 => Some(Key::F5),
+            SDL_SCANCODE_F7 => Some(Key::F7),
+            _ => None,
+        }
+    }
+
+    /// The controller button this key is bound to for player 1
+    fn button(&self) -> Option<u8> {
+        match self {
+            Key::Up => Some(buttons::UP),
+            Key::Down => Some(buttons::DOWN),
+            Key::Left => Some(buttons::LEFT),
+            Key::Right => Some(buttons::RIGHT),
+            Key::Z => Some(buttons::B),
+            Key::X => Some(buttons::A),
+            Key::Return => Some(buttons::START),
+            Key::RShift => Some(buttons::SELECT),
+            Key::F
+            | Key::Minus
+            | Key::Equals
+            | Key::M
+            | Key::R
+            | Key::S
+            | Key::Escape
+            | Key::LeftBracket
+            | Key::RightBracket
+            | Key::F5
+            | Key::F7 => None,
+        }
+    }
+}
+
+/// Name of the Family BASIC key (see `controller::KEY_MATRIX`) each PC key is mapped to
+fn famicom_key(scancode: SDL_Scancode) -> Option<&'static str> {
+    let name = match scancode {
+        SDL_SCANCODE_A => "A",
+        SDL_SCANCODE_B => "B",
+        SDL_SCANCODE_C => "C",
+        SDL_SCANCODE_D => "D",
+        SDL_SCANCODE_E => "E",
+        SDL_SCANCODE_F => "F",
+        SDL_SCANCODE_G => "G",
+        SDL_SCANCODE_H => "H",
+        SDL_SCANCODE_I => "I",
+        SDL_SCANCODE_J => "J",
+        SDL_SCANCODE_K => "K",
+        SDL_SCANCODE_L => "L",
+        SDL_SCANCODE_M => "M",
+        SDL_SCANCODE_N => "N",
+        SDL_SCANCODE_O => "O",
+        SDL_SCANCODE_P => "P",
+        SDL_SCANCODE_Q => "Q",
+        SDL_SCANCODE_R => "R",
+        SDL_SCANCODE_S => "S",
+        SDL_SCANCODE_T => "T",
+        SDL_SCANCODE_U => "U",
+        SDL_SCANCODE_V => "V",
+        SDL_SCANCODE_W => "W",
+        SDL_SCANCODE_X => "X",
+        SDL_SCANCODE_Y => "Y",
+        SDL_SCANCODE_Z => "Z",
+        SDL_SCANCODE_0 => "0",
+        SDL_SCANCODE_1 => "1",
+        SDL_SCANCODE_2 => "2",
+        SDL_SCANCODE_3 => "3",
+        SDL_SCANCODE_4 => "4",
+        SDL_SCANCODE_5 => "5",
+        SDL_SCANCODE_6 => "6",
+        SDL_SCANCODE_7 => "7",
+        SDL_SCANCODE_8 => "8",
+        SDL_SCANCODE_9 => "9",
+        SDL_SCANCODE_RETURN => "RETURN",
+        SDL_SCANCODE_SPACE => "SPACE",
+        SDL_SCANCODE_BACKSPACE => "DEL",
+        SDL_SCANCODE_INSERT => "INS",
+        SDL_SCANCODE_HOME => "CLR HOME",
+        SDL_SCANCODE_ESCAPE => "ESC",
+        SDL_SCANCODE_LCTRL => "CTR",
+        SDL_SCANCODE_LSHIFT => "LSHIFT",
+        SDL_SCANCODE_RSHIFT => "RSHIFT",
+        SDL_SCANCODE_UP => "UP",
+        SDL_SCANCODE_DOWN => "DOWN",
+        SDL_SCANCODE_LEFT => "LEFT",
+        SDL_SCANCODE_RIGHT => "RIGHT",
+        SDL_SCANCODE_MINUS => "-",
+        SDL_SCANCODE_SEMICOLON => ";",
+        SDL_SCANCODE_APOSTROPHE => ":",
+        SDL_SCANCODE_COMMA => ",",
+        SDL_SCANCODE_PERIOD => ".",
+        SDL_SCANCODE_SLASH => "/",
+        SDL_SCANCODE_LEFTBRACKET => "[",
+        SDL_SCANCODE_RIGHTBRACKET => "]",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// A failed SDL call, with SDL's description of what went wrong
+#[derive(Debug)]
+pub struct SdlError {
+    /// The SDL function which failed
+    pub function: &'static str,
+    pub message: String,
+}
+
+impl SdlError {
+    /// Capture the error from the last failed SDL call
+    fn last(function: &'static str) -> Self {
+        unsafe { Self::from_ptr(function, SDL_GetError()) }
+    }
+
+    /// # Safety
+    ///
+    /// `error` must be null or point to a nul-terminated string, as `SDL_GetError` returns
+    unsafe fn from_ptr(function: &'static str, error: *const c_char) -> Self {
+        let message = if error.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(error).to_string_lossy().into_owned()
+        };
+        Self { function, message }
+    }
+}
+
+impl Display for SdlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{} failed", self.function)
+        } else {
+            write!(f, "{} failed: {}", self.function, self.message)
+        }
+    }
 }
 
+impl std::error::Error for SdlError {}
+
 pub enum Event {
-    #[allow(dead_code)] // TODO: Will KeyUp be necessary?
-    KeyUp(Key),
-    KeyDown(Key),
+    KeyUp(SDL_Scancode),
+    KeyDown(SDL_Scancode),
     Quit,
 }
 
@@ -30,6 +222,27 @@ pub enum Event {
 pub struct SDL {
     window: *mut SDL_Window,
     renderer: *mut SDL_Renderer,
+
+    /// Holds the frame being presented, and is recreated when the frame size changes (e.g. by
+    /// switching filter)
+    texture: *mut SDL_Texture,
+    texture_size: (usize, usize),
+
+    overscan: Overscan,
+
+    /// 0 if no audio device is open
+    audio_device: SDL_AudioDeviceID,
+    /// `MAX_QUEUED_AUDIO` of mono `f32` samples at the device's rate
+    max_queued_audio_bytes: usize,
+
+    /// Buttons currently held, tracked from key down/up events
+    buttons: u8,
+    keyboard: u128,
+
+    /// Either Ctrl key is held, for the hotkeys which need it
+    ctrl_held: bool,
+
+    start_time: Instant,
 }
 
 impl SDL {
@@ -37,23 +250,75 @@ impl SDL {
         Self {
             window: std::ptr::null_mut::<SDL_Window>(),
             renderer: std::ptr::null_mut::<SDL_Renderer>(),
+            texture: std::ptr::null_mut::<SDL_Texture>(),
+            texture_size: (0, 0),
+            overscan: Overscan::default(),
+            audio_device: 0,
+            max_queued_audio_bytes: 0,
+            buttons: 0,
+            keyboard: 0,
+            ctrl_held: false,
+            start_time: Instant::now(),
         }
     }
 
-    // TODO: can this
-    pub fn init_video(&mut self, width: i32, height: i32) {
+    /// Open the window, or cover the desktop if `fullscreen` is set. If there is no hardware
+    /// accelerated renderer, this falls back to a software one.
+    pub fn init_video(&mut self, size: WindowSize, fullscreen: bool) -> Result<(), SdlError> {
+        let mut flags = SDL_WINDOW_OPENGL | SDL_WINDOW_ALLOW_HIGHDPI;
+        if fullscreen {
+            flags = flags | SDL_WINDOW_FULLSCREEN_DESKTOP;
+        }
         unsafe {
-            SDL_Init(SDL_INIT_VIDEO);
+            if SDL_Init(SDL_INIT_VIDEO) != 0 {
+                return Err(SdlError::last("SDL_Init"));
+            }
             self.window = SDL_CreateWindow(
-                b"rusty-nes".as_ptr().cast(),
+                c"rusty-nes".as_ptr(),
                 SDL_WINDOWPOS_CENTERED,
                 SDL_WINDOWPOS_CENTERED,
-                width,
-                height,
-                (SDL_WINDOW_OPENGL | SDL_WINDOW_ALLOW_HIGHDPI).0,
+                size.width as i32,
+                size.height as i32,
+                flags.0,
             );
-            self.renderer = SDL_CreateRenderer(self.window, 0, 0);
+            if self.window.is_null() {
+                return Err(SdlError::last("SDL_CreateWindow"));
+            }
+            self.renderer = SDL_CreateRenderer(self.window, -1, SDL_RENDERER_ACCELERATED.0);
+            if self.renderer.is_null() {
+                self.renderer = SDL_CreateRenderer(self.window, -1, SDL_RENDERER_SOFTWARE.0);
+            }
+            if self.renderer.is_null() {
+                return Err(SdlError::last("SDL_CreateRenderer"));
+            }
         }
+        Ok(())
+    }
+
+    /// Open the default audio device for `push_audio` to play through, at `sample_rate` Hz with
+    /// a buffer of `buffer_samples`. Without one, samples are dropped.
+    pub fn init_audio(&mut self, sample_rate: u32, buffer_samples: u16) -> Result<(), SdlError> {
+        let want = SDL_AudioSpec {
+            freq: sample_rate as i32,
+            format: AUDIO_F32SYS,
+            channels: 1,
+            samples: buffer_samples,
+            ..Default::default()
+        };
+        unsafe {
+            if SDL_InitSubSystem(SDL_INIT_AUDIO) != 0 {
+                return Err(SdlError::last("SDL_InitSubSystem"));
+            }
+            let mut have = SDL_AudioSpec::default();
+            self.audio_device = SDL_OpenAudioDevice(std::ptr::null(), 0, &want, &mut have, 0);
+            if self.audio_device == 0 {
+                return Err(SdlError::last("SDL_OpenAudioDevice"));
+            }
+            SDL_PauseAudioDevice(self.audio_device, 0);
+        }
+        // SDL converts to the device's own format, so the rate is always the one asked for
+        self.max_queued_audio_bytes = (sample_rate as f32 * MAX_QUEUED_AUDIO) as usize * 4;
+        Ok(())
     }
 
     pub fn set_render_draw_color(&self, r: u8, g: u8, b: u8, a: u8) {
@@ -68,9 +333,58 @@ impl SDL {
         }
     }
 
-    pub fn render_draw_point(&self, x: i32, y: i32) {
+    /// Crop the edges of every frame presented from now on
+    pub fn set_overscan(&mut self, overscan: Overscan) {
+        self.overscan = overscan;
+    }
+
+    /// Draw an RGB frame scaled to fit the window, minus the overscan. Frames of any size fill the same area, so
+    /// filters which add detail (like doubling the lines) don't change the picture's shape.
+    pub fn render_frame(&mut self, frame: &Frame) {
         unsafe {
-            SDL_RenderDrawPoint(self.renderer, x, y);
+            if self.texture.is_null() || self.texture_size != (frame.width, frame.height) {
+                if !self.texture.is_null() {
+                    SDL_DestroyTexture(self.texture);
+                }
+                self.texture = SDL_CreateTexture(
+                    self.renderer,
+                    SDL_PIXELFORMAT_RGB24.0,
+                    SDL_TEXTUREACCESS_STREAMING.0,
+                    frame.width as i32,
+                    frame.height as i32,
+                );
+                self.texture_size = (frame.width, frame.height);
+            }
+            SDL_UpdateTexture(
+                self.texture,
+                std::ptr::null(),
+                frame.pixels.as_ptr().cast(),
+                (frame.width * 3) as i32,
+            );
+
+            // The output size is in pixels, which on high-DPI displays is bigger than the
+            // window size
+            let (mut output_width, mut output_height) = (0, 0);
+            SDL_GetRendererOutputSize(self.renderer, &mut output_width, &mut output_height);
+            let (x, y, w, h) = picture_rect(
+                output_width as u32,
+                output_height as u32,
+                self.overscan.visible_height(),
+            );
+            let (first_row, rows) = self.overscan.visible_rows(frame.height);
+            let source = SDL_Rect {
+                x: 0,
+                y: first_row as i32,
+                w: frame.width as i32,
+                h: rows as i32,
+            };
+            let destination = SDL_Rect {
+                x: x as i32,
+                y: y as i32,
+                w: w as i32,
+                h: h as i32,
+            };
+            SDL_RenderCopy(self.renderer, self.texture, &source, &destination);
         }
     }
 
@@ -80,36 +394,163 @@ impl SDL {
         }
     }
 
-    pub fn poll_event(&self) -> Event {
+    /// Return the next pending event, without blocking
+    pub fn poll_event(&self) -> Option<Event> {
         unsafe {
             let mut event: SDL_Event = SDL_Event::default();
-            loop {
-                SDL_PollEvent(&mut event);
+            while SDL_PollEvent(&mut event) != 0 {
                 match event.type_ {
-                    SDL_KEYDOWN => {
-                        let potential_key = match event.key.keysym.scancode {
-                            SDL_SCANCODE_UP => Some(Key::Up),
-                            SDL_SCANCODE_DOWN => Some(Key::Down),
-                            SDL_SCANCODE_LEFT => Some(Key::Left),
-                            SDL_SCANCODE_RIGHT => Some(Key::Right),
-                            _ => None,
-                        };
-                        if let Some(key) = potential_key {
-                            return Event::KeyDown(key);
-                        }
-                    }
-                    SDL_QUIT => return Event::Quit,
+                    SDL_KEYDOWN => return Some(Event::KeyDown(event.key.keysym.scancode)),
+                    SDL_KEYUP => return Some(Event::KeyUp(event.key.keysym.scancode)),
+                    SDL_QUIT => return Some(Event::Quit),
                     _ => {}
                 }
             }
+            None
         }
     }
 
-    pub fn quit(&self) {
+    /// Close the window and shut SDL down. Safe to call more than once, or after `init_video`
+    /// failed part way through.
+    pub fn quit(&mut self) {
         unsafe {
-            SDL_DestroyRenderer(self.renderer);
-            SDL_DestroyWindow(self.window);
+            if self.audio_device != 0 {
+                SDL_CloseAudioDevice(self.audio_device);
+                self.audio_device = 0;
+            }
+            if !self.texture.is_null() {
+                SDL_DestroyTexture(self.texture);
+                self.texture = std::ptr::null_mut();
+            }
+            if !self.renderer.is_null() {
+                SDL_DestroyRenderer(self.renderer);
+                self.renderer = std::ptr::null_mut();
+            }
+            if !self.window.is_null() {
+                SDL_DestroyWindow(self.window);
+                self.window = std::ptr::null_mut();
+            }
             SDL_Quit();
         }
     }
 }
+
+impl Drop for SDL {
+    fn drop(&mut self) {
+        self.quit();
+    }
+}
+
+impl Frontend for SDL {
+    fn present_frame(&mut self, frame: &Frame) {
+        self.set_render_draw_color(0, 0, 0, 0);
+        self.render_clear();
+        self.render_frame(frame);
+        self.render_present();
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        if self.audio_device == 0 {
+            return;
+        }
+        let bytes = std::mem::size_of_val(samples);
+        unsafe {
+            // Frames are paced by the clock rather than the audio, so if the two drift apart the
+            // queue can grow; start again rather than let the sound lag further and further
+            if SDL_GetQueuedAudioSize(self.audio_device) as usize > self.max_queued_audio_bytes {
+                SDL_ClearQueuedAudio(self.audio_device);
+            }
+            SDL_QueueAudio(self.audio_device, samples.as_ptr().cast(), bytes as u32);
+        }
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        let mut input = InputState::default();
+        while let Some(event) = self.poll_event() {
+            let (scancode, pressed) = match event {
+                Event::KeyDown(scancode) => (scancode, true),
+                Event::KeyUp(scancode) => (scancode, false),
+                Event::Quit => {
+                    input.quit = true;
+                    continue;
+                }
+            };
+
+            // The keyboard shares keys with the controller, as a Famicom player would have to
+            if let Some(index) = famicom_key(scancode).and_then(famicom_key_index) {
+                if pressed {
+                    self.keyboard |= 1 << index;
+                } else {
+                    self.keyboard &= !(1 << index);
+                }
+            }
+
+            if scancode == SDL_SCANCODE_LCTRL || scancode == SDL_SCANCODE_RCTRL {
+                self.ctrl_held = pressed;
+            }
+            if self.ctrl_held && pressed {
+                let hotkey = match scancode {
+                    SDL_SCANCODE_R => Some(&mut input.soft_reset),
+                    SDL_SCANCODE_T => Some(&mut input.power_cycle),
+                    SDL_SCANCODE_L => Some(&mut input.reload_rom),
+                    _ => None,
+                };
+                if let Some(hotkey) = hotkey {
+                    *hotkey = true;
+                    continue;
+                }
+            }
+
+            match Key::from_scancode(scancode) {
+                Some(Key::F) => input.cycle_filter |= pressed,
+                Some(Key::Minus) => input.volume_down |= pressed,
+                Some(Key::Equals) => input.volume_up |= pressed,
+                Some(Key::M) => input.toggle_mute |= pressed,
+                Some(Key::S) => input.toggle_sprite_limit |= pressed,
+                Some(Key::R) => input.reset |= pressed,
+                Some(Key::Escape) => input.escape |= pressed,
+                Some(Key::LeftBracket) => input.speed_down |= pressed,
+                Some(Key::RightBracket) => input.speed_up |= pressed,
+                Some(Key::F5) => input.save_state |= pressed,
+                Some(Key::F7) => input.load_state |= pressed,
+                Some(key) => {
+                    let button = key.button().unwrap_or(0);
+                    if pressed {
+                        self.buttons |= button;
+                    } else {
+                        self.buttons &= !button;
+                    }
+                }
+                None => {}
+            }
+        }
+        input.buttons[0] = self.buttons;
+        input.keyboard = self.keyboard;
+        input
+    }
+
+    fn now(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_message_from_sdl() {
+        // What `SDL_GetError` returns after a failure
+        let err =
+            unsafe { SdlError::from_ptr("SDL_OpenAudioDevice", c"No audio devices".as_ptr()) };
+        assert_eq!(err.message, "No audio devices");
+        assert_eq!(
+            err.to_string(),
+            "SDL_OpenAudioDevice failed: No audio devices"
+        );
+
+        // And when it has nothing to say
+        let err = unsafe { SdlError::from_ptr("SDL_Init", std::ptr::null()) };
+        assert_eq!(err.to_string(), "SDL_Init failed");
+    }
+}
@@ -0,0 +1,273 @@
+//! Running faster or slower than the real console. Only the pacing of frames and the audio
+//! output change, so the emulation itself (and anything recorded from it) is unaffected.
+
+use std::time::Duration;
+
+use crate::apu::SAMPLE_RATE;
+
+pub const MIN_SPEED: f32 = 0.1;
+pub const MAX_SPEED: f32 = 8.0;
+
+/// Run as fast as the host can, e.g. for `--turbo`. The audio is muted, as at any speed outside
+/// `AUDIBLE_SPEEDS`.
+pub const UNLIMITED_SPEED: f32 = f32::INFINITY;
+
+/// How many frames emulation can fall behind, e.g. while the host is busy, before it stops
+/// trying to catch up
+const MAX_FRAMES_BEHIND: u32 = 3;
+
+/// Speeds the hotkeys step through
+const SPEED_STEPS: [f32; 9] = [0.25, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0, 4.0];
+
+/// Outside this range the audio is too distorted to be worth hearing, so it is muted
+const AUDIBLE_SPEEDS: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+
+/// The next step up from `speed`, or `speed` if it is already the fastest
+pub fn faster(speed: f32) -> f32 {
+    SPEED_STEPS
+        .into_iter()
+        .find(|&step| step > speed)
+        .unwrap_or(speed.max(SPEED_STEPS[SPEED_STEPS.len() - 1]))
+}
+
+/// The next step down from `speed`, or `speed` if it is already the slowest
+pub fn slower(speed: f32) -> f32 {
+    SPEED_STEPS
+        .into_iter()
+        .rev()
+        .find(|&step| step < speed)
+        .unwrap_or(speed.min(SPEED_STEPS[0]))
+}
+
+/// Time to wait between frames at `speed` times the console's frame rate
+pub fn frame_interval(frame_duration: Duration, speed: f32) -> Duration {
+    frame_duration.div_f32(speed)
+}
+
+/// Move `next_frame` on by `interval`, and return how long to wait from `now` until then. If
+/// emulation has fallen more than `MAX_FRAMES_BEHIND` frames behind, the schedule starts again
+/// from now, rather than rushing through frames to catch up (which would only fall further
+/// behind if the host can't keep up).
+pub fn schedule(next_frame: &mut Duration, interval: Duration, now: Duration) -> Duration {
+    *next_frame += interval;
+    if now > *next_frame + interval * MAX_FRAMES_BEHIND {
+        *next_frame = now;
+    }
+    next_frame.saturating_sub(now)
+}
+
+/// The speed as shown to the user, e.g. "150%"
+pub fn describe(speed: f32) -> String {
+    if speed == UNLIMITED_SPEED {
+        "unlimited".to_string()
+    } else {
+        format!("{}%", (speed * 100.0).round())
+    }
+}
+
+/// Stretch or squash a frame's audio from the APU to play at `sample_rate` in the time the frame
+/// is shown for at `speed`, so the sound keeps up with the picture. The pitch stays as it is at
+/// normal speed (see `stretch`). Returns `None` if the speed is too far from normal for the audio
+/// to be played at all.
+pub fn resample(samples: &[f32], speed: f32, sample_rate: u32) -> Option<Vec<f32>> {
+    if !AUDIBLE_SPEEDS.contains(&speed) {
+        return None;
+    }
+    let converted = interpolate(samples, SAMPLE_RATE as f32 / sample_rate as f32);
+    if speed == 1.0 {
+        Some(converted)
+    } else {
+        Some(stretch(&converted, speed))
+    }
+}
+
+/// Samples a grain of audio is long when time-stretching, ~6 ms. Shorter grains follow fast
+/// changes in the sound better, but muddy low notes.
+const GRAIN: usize = 256;
+
+/// How far either way from where it would be read from a grain can be moved to line up with the
+/// one before, which covers a cycle of notes down to ~350 Hz
+const GRAIN_SEARCH: usize = GRAIN / 4;
+
+/// Make `samples` `speed` times shorter without changing their pitch, by overlapping and adding
+/// windowed grains of them, which are read about `speed` times as far apart as they are written.
+/// Each grain is moved to where it best lines up with the one before it (WSOLA), so the waves
+/// don't cancel out where they overlap. The first and last grains are taken from the very start
+/// and end, so one frame's audio still runs on into the next.
+///
+/// See: <https://en.wikipedia.org/wiki/Audio_time_stretching_and_pitch_scaling>
+fn stretch(samples: &[f32], speed: f32) -> Vec<f32> {
+    let len = (samples.len() as f32 / speed).round() as usize;
+    if samples.len() <= GRAIN || len <= GRAIN {
+        // Too short to take grains from, so change the pitch instead
+        return interpolate(samples, speed);
+    }
+
+    let hop = GRAIN / 2;
+    let mut output = vec![0.0; len];
+    let mut weights = vec![0.0; len];
+    let last_start = samples.len() - GRAIN;
+    let grains = (len - GRAIN).div_ceil(hop) + 1;
+    let mut previous_start = 0;
+    for grain in 0..grains {
+        let out_start = (grain * hop).min(len - GRAIN);
+        let mut in_start = out_start * last_start / (len - GRAIN);
+        if grain > 0 && out_start < len - GRAIN {
+            // What followed the previous grain, which this one overlaps
+            let follows = &samples[previous_start + hop..previous_start + GRAIN];
+            let similarity = |start: usize| -> f32 {
+                let candidate = &samples[start..start + hop];
+                candidate.iter().zip(follows).map(|(a, b)| a * b).sum()
+            };
+            let search =
+                in_start.saturating_sub(GRAIN_SEARCH)..=(in_start + GRAIN_SEARCH).min(last_start);
+            in_start = search
+                .map(|start| (start, similarity(start)))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(in_start, |(start, _)| start);
+        }
+        previous_start = in_start;
+        let window = (0..GRAIN).map(|index| {
+            // Hann, offset by half a sample so that no sample has a weight of 0
+            let phase = std::f32::consts::PI * (index as f32 + 0.5) / GRAIN as f32;
+            phase.sin().powi(2)
+        });
+        let input = &samples[in_start..in_start + GRAIN];
+        let out = output[out_start..]
+            .iter_mut()
+            .zip(&mut weights[out_start..]);
+        for ((out, weight), (sample, window)) in out.zip(input.iter().zip(window)) {
+            *out += sample * window;
+            *weight += window;
+        }
+    }
+    // The windows add up to about 1 where grains overlap, but not at the ends, or where the
+    // last grain was moved back to fit
+    for (out, weight) in output.iter_mut().zip(weights) {
+        *out /= weight;
+    }
+    output
+}
+
+/// Resample by reading `step` input samples per output sample, interpolating linearly between
+/// the two nearest, which changes the pitch along with the length
+fn interpolate(samples: &[f32], step: f32) -> Vec<f32> {
+    if step == 1.0 || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let len = (samples.len() as f32 / step).round() as usize;
+    (0..len)
+        .map(|index| {
+            let position = index as f32 * step;
+            let before = position.floor() as usize;
+            let after = (before + 1).min(samples.len() - 1);
+            let fraction = position - before as f32;
+            let before = samples[before.min(samples.len() - 1)];
+            before + (samples[after] - before) * fraction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_micros(16_639);
+
+    #[test]
+    fn frame_interval_scales() {
+        assert_eq!(frame_interval(FRAME, 1.0), FRAME);
+        assert_eq!(frame_interval(FRAME, 2.0), FRAME / 2);
+        assert_eq!(frame_interval(FRAME, 0.5), FRAME * 2);
+        assert_eq!(frame_interval(FRAME, UNLIMITED_SPEED), Duration::ZERO);
+    }
+
+    #[test]
+    fn schedule_waits_and_gives_up_catching_up() {
+        let interval = frame_interval(FRAME, 2.0);
+        let mut next_frame = Duration::ZERO;
+
+        // On time, wait out the rest of the interval
+        let now = Duration::from_millis(2);
+        assert_eq!(schedule(&mut next_frame, interval, now), interval - now);
+        assert_eq!(next_frame, interval);
+
+        // A little behind, don't wait, but keep to the schedule to catch up
+        let now = interval * 3;
+        assert_eq!(schedule(&mut next_frame, interval, now), Duration::ZERO);
+        assert_eq!(next_frame, interval * 2);
+
+        // Too far behind, start again from now
+        let now = interval * 10;
+        assert_eq!(schedule(&mut next_frame, interval, now), Duration::ZERO);
+        assert_eq!(next_frame, now);
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(faster(1.0), 1.25);
+        assert_eq!(slower(1.0), 0.75);
+        assert_eq!(faster(1.1), 1.25);
+        assert_eq!(slower(1.1), 1.0);
+        assert_eq!(faster(4.0), 4.0);
+        assert_eq!(slower(0.25), 0.25);
+        // Speeds from the command line beyond the steps stay put
+        assert_eq!(faster(6.0), 6.0);
+        assert_eq!(slower(0.1), 0.1);
+        assert_eq!(slower(6.0), 4.0);
+    }
+
+    /// Zero crossings per sample, which is twice the frequency in cycles per sample
+    fn crossing_rate(samples: &[f32]) -> f32 {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        crossings as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn resample_keeps_pitch() {
+        // A frame of a 441 Hz sine wave from the APU
+        let period = SAMPLE_RATE as f32 / 441.0;
+        let samples: Vec<f32> = (0..735)
+            .map(|index| (std::f32::consts::TAU * index as f32 / period).sin())
+            .collect();
+        let expected = crossing_rate(&samples);
+
+        for speed in [0.5, 0.75, 1.0, 1.5, 2.0] {
+            let resampled = resample(&samples, speed, SAMPLE_RATE).unwrap();
+            let len = (samples.len() as f32 / speed).round() as usize;
+            assert_eq!(resampled.len(), len, "length at {speed}x");
+            let rate = crossing_rate(&resampled);
+            assert!(
+                (rate - expected).abs() < expected * 0.15,
+                "pitch at {speed}x: {rate} crossings per sample, expected {expected}"
+            );
+            assert!(resampled.iter().all(|sample| sample.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn resample_to_sample_rate() {
+        // A frame's worth of samples from the APU, a sixtieth of a second
+        let samples = [0.5; SAMPLE_RATE as usize / 60];
+        for rate in [44_100, 48_000, 96_000, 32_000] {
+            let resampled = resample(&samples, 1.0, rate).unwrap();
+            assert_eq!(resampled.len(), rate as usize / 60, "at {rate} Hz");
+            assert!(resampled.iter().all(|&sample| sample == 0.5));
+        }
+        // And at other speeds too, within a sample of rounding
+        let resampled = resample(&samples, 2.0, 48_000).unwrap();
+        assert_eq!(resampled.len(), 400);
+    }
+
+    #[test]
+    fn resample_mutes_far_from_normal_speed() {
+        let samples = [0.5; 735];
+        assert_eq!(resample(&samples, 0.25, SAMPLE_RATE), None);
+        assert_eq!(resample(&samples, 4.0, SAMPLE_RATE), None);
+        assert_eq!(resample(&samples, UNLIMITED_SPEED, SAMPLE_RATE), None);
+    }
+}
@@ -0,0 +1,424 @@
+//! Savestate files: a snapshot of the machine (see `CPU::save_state`) written out as bytes, so
+//! it can be loaded in a later session.
+//!
+//! A file starts with `MAGIC`, the format version and the CRC-32 of the ROM it was saved with,
+//! followed by the state of each component in turn, all little-endian. Settings which aren't
+//! part of the console (volume, palette, tracing, etc.) aren't saved, and neither are the
+//! controllers, whose state only lasts until the game next strobes them.
+//!
+//! Every value is written with a name, so the code which saves a state can also list its fields
+//! (see `CPU::state_fields`) for comparing two states with `diff_states`.
+
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// The start of every savestate file
+const MAGIC: &[u8; 8] = b"RUSTYNES";
+
+/// Bumped whenever the layout changes, as older states can't be read by newer versions
+pub const STATE_VERSION: u32 = 9;
+
+#[derive(Debug)]
+pub enum StateError {
+    FileNotFound,
+    IoError(std::io::Error),
+    NotAState,
+    UnsupportedVersion(u32),
+    /// Saved with a different ROM, going by the CRC-32 of its PRG and CHR ROM
+    WrongRom {
+        expected: u32,
+        actual: u32,
+    },
+    /// Ended part way through, or had bytes left over, so was probably cut short or corrupted
+    WrongLength,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::FileNotFound => write!(f, "savestate file not found"),
+            StateError::IoError(err) => write!(f, "IO error: {}", err),
+            StateError::NotAState => write!(f, "not a savestate file"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "savestate is from format version {}, but only version {} is supported",
+                version, STATE_VERSION
+            ),
+            StateError::WrongRom { expected, actual } => write!(
+                f,
+                "savestate is for a different ROM (CRC {:08x}, but this ROM's is {:08x})",
+                actual, expected
+            ),
+            StateError::WrongLength => write!(f, "savestate is truncated or corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub type StateResult<T> = Result<T, StateError>;
+
+/// Read a savestate file, to pass to `CPU::load_state_bytes`
+pub fn read_state_file(path: impl AsRef<Path>) -> StateResult<Vec<u8>> {
+    std::fs::read(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => StateError::FileNotFound,
+        _ => StateError::IoError(err),
+    })
+}
+
+/// A value in a savestate, as written by one of `StateWriter`'s methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Bytes(Vec<u8>),
+}
+
+impl Display for StateValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateValue::Bool(value) => write!(f, "{}", value),
+            StateValue::U8(value) => write!(f, "{:02x}", value),
+            StateValue::U16(value) => write!(f, "{:04x}", value),
+            StateValue::U32(value) => write!(f, "{:08x}", value),
+            StateValue::U64(value) => write!(f, "{}", value),
+            StateValue::Bytes(bytes) => write!(
+                f,
+                "{} bytes, CRC-32 {:08x}",
+                bytes.len(),
+                crate::png::crc32(bytes)
+            ),
+        }
+    }
+}
+
+/// A named value in a savestate, e.g. `ppu.ctrl`, for comparing states (see `diff_states`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateField {
+    pub name: String,
+    pub value: StateValue,
+}
+
+impl Display for StateField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = {}", self.name, self.value)
+    }
+}
+
+/// Byte differences shown by a `Difference`'s `Display`, before the rest are just counted
+const DIFFERENT_BYTES_SHOWN: usize = 8;
+
+/// How a field differs between two savestates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Value {
+        field: String,
+        a: StateValue,
+        b: StateValue,
+    },
+    /// The bytes which differ in a field of bytes, e.g. RAM, as (offset, a, b)
+    Bytes {
+        field: String,
+        changes: Vec<(usize, u8, u8)>,
+    },
+}
+
+impl Difference {
+    pub fn field(&self) -> &str {
+        match self {
+            Difference::Value { field, .. } | Difference::Bytes { field, .. } => field,
+        }
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Value { field, a, b } => write!(f, "{}: {} -> {}", field, a, b),
+            Difference::Bytes { field, changes } => {
+                write!(f, "{}: {} bytes differ:", field, changes.len())?;
+                for (offset, a, b) in changes.iter().take(DIFFERENT_BYTES_SHOWN) {
+                    write!(f, " ${:04x} {:02x} -> {:02x},", offset, a, b)?;
+                }
+                if changes.len() > DIFFERENT_BYTES_SHOWN {
+                    write!(f, " ...")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Every field which differs between two states' fields (see `CPU::state_fields`), in the order
+/// they are saved. Both should come from the same ROM, so that they have the same fields.
+pub fn diff_states(a: &[StateField], b: &[StateField]) -> Vec<Difference> {
+    a.iter()
+        .zip(b)
+        .filter(|(a, b)| a != b)
+        .map(|(a, b)| match (&a.value, &b.value) {
+            (StateValue::Bytes(a_bytes), StateValue::Bytes(b_bytes))
+                if a.name == b.name && a_bytes.len() == b_bytes.len() =>
+            {
+                let changes = a_bytes
+                    .iter()
+                    .zip(b_bytes)
+                    .enumerate()
+                    .filter(|(_, (a, b))| a != b)
+                    .map(|(offset, (&a, &b))| (offset, a, b))
+                    .collect();
+                Difference::Bytes {
+                    field: a.name.clone(),
+                    changes,
+                }
+            }
+            _ => Difference::Value {
+                field: a.name.clone(),
+                a: a.value.clone(),
+                b: b.value.clone(),
+            },
+        })
+        .collect()
+}
+
+/// Builds up the bytes of a savestate. Each value is named, so that the same code which writes
+/// a state can also describe it, field by field (see `with_fields`).
+#[derive(Debug)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+    /// The fields written so far, if they are being kept
+    fields: Option<Vec<StateField>>,
+    /// The sections the next field is in, outermost first, e.g. `apu` then `dmc`
+    sections: Vec<String>,
+}
+
+impl StateWriter {
+    /// Start a state for the ROM with the given CRC-32
+    pub fn new(rom_crc: u32) -> Self {
+        let mut writer = Self {
+            bytes: Vec::new(),
+            fields: None,
+            sections: Vec::new(),
+        };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        writer.bytes.extend_from_slice(&rom_crc.to_le_bytes());
+        writer
+    }
+
+    /// Also keep each field written, for `finish_fields`
+    pub fn with_fields(mut self) -> Self {
+        self.fields = Some(Vec::new());
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The fields written, if `with_fields` was used
+    pub fn finish_fields(self) -> Vec<StateField> {
+        self.fields.unwrap_or_default()
+    }
+
+    /// Write fields with names starting `name.`, e.g. for one chip
+    pub fn section(&mut self, name: &str, write: impl FnOnce(&mut Self)) {
+        self.sections.push(name.to_string());
+        write(self);
+        self.sections.pop();
+    }
+
+    fn field(&mut self, name: &str, value: impl FnOnce() -> StateValue) {
+        if let Some(fields) = &mut self.fields {
+            let mut full_name: String = self.sections.iter().map(|s| format!("{s}.")).collect();
+            full_name.push_str(name);
+            fields.push(StateField {
+                name: full_name,
+                value: value(),
+            });
+        }
+    }
+
+    pub fn u8(&mut self, name: &str, value: u8) {
+        self.field(name, || StateValue::U8(value));
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, name: &str, value: bool) {
+        self.field(name, || StateValue::Bool(value));
+        self.bytes.push(value as u8);
+    }
+
+    pub fn u16(&mut self, name: &str, value: u16) {
+        self.field(name, || StateValue::U16(value));
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, name: &str, value: u32) {
+        self.field(name, || StateValue::U32(value));
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, name: &str, value: u64) {
+        self.field(name, || StateValue::U64(value));
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, name: &str, value: &[u8]) {
+        self.field(name, || StateValue::Bytes(value.to_vec()));
+        self.bytes.extend_from_slice(value);
+    }
+}
+
+/// Reads back the bytes written by a `StateWriter`, in the same order
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Check the header of a state, which must be for the ROM with the given CRC-32
+    pub fn new(bytes: &'a [u8], rom_crc: u32) -> StateResult<Self> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let mut reader = Self {
+            bytes: &bytes[MAGIC.len()..],
+        };
+        let version = reader.u32()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let actual = reader.u32()?;
+        if actual != rom_crc {
+            return Err(StateError::WrongRom {
+                expected: rom_crc,
+                actual,
+            });
+        }
+        Ok(reader)
+    }
+
+    /// Check that everything has been read
+    pub fn finish(self) -> StateResult<()> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::WrongLength)
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> StateResult<[u8; N]> {
+        let mut value = [0; N];
+        self.bytes(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn u8(&mut self) -> StateResult<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> StateResult<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> StateResult<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> StateResult<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> StateResult<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    /// Fill `value` with the next bytes
+    pub fn bytes(&mut self, value: &mut [u8]) -> StateResult<()> {
+        if self.bytes.len() < value.len() {
+            return Err(StateError::WrongLength);
+        }
+        let (head, tail) = self.bytes.split_at(value.len());
+        value.copy_from_slice(head);
+        self.bytes = tail;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Counts up through `$10-$11` as fast as it can, so every cycle changes the state
+    const COUNTER: &str = "
+        loop:
+            inc $10
+            bne loop
+            inc $11
+            jmp loop
+    ";
+
+    #[test]
+    fn wrong_rom() {
+        let other = testing::cpu("lda #1\nloop: jmp loop");
+        let mut cpu = testing::cpu(COUNTER);
+        testing::run(&mut cpu, 10);
+        let before = cpu.save_state_bytes();
+
+        let result = cpu.load_state_bytes(&other.save_state_bytes());
+        match result {
+            Err(StateError::WrongRom { expected, actual }) => {
+                assert_eq!(expected, cpu.cart().crc32());
+                assert_eq!(actual, other.cart().crc32());
+            }
+            result => panic!("expected WrongRom, got {result:?}"),
+        }
+        // The machine is left as it was
+        assert_eq!(cpu.save_state_bytes(), before);
+    }
+
+    #[test]
+    fn old_version() {
+        let mut cpu = testing::cpu(COUNTER);
+        let mut bytes = cpu.save_state_bytes();
+        let version = MAGIC.len()..MAGIC.len() + 4;
+        bytes[version].copy_from_slice(&(STATE_VERSION - 1).to_le_bytes());
+        assert!(matches!(
+            cpu.load_state_bytes(&bytes),
+            Err(StateError::UnsupportedVersion(version)) if version == STATE_VERSION - 1
+        ));
+
+        // Cut short, or not a state at all
+        let bytes = cpu.save_state_bytes();
+        assert!(matches!(
+            cpu.load_state_bytes(&bytes[..bytes.len() - 1]),
+            Err(StateError::WrongLength)
+        ));
+        assert!(matches!(
+            cpu.load_state_bytes(b"NES\x1a"),
+            Err(StateError::NotAState)
+        ));
+    }
+
+    #[test]
+    fn resume_headless() {
+        let mut cpu = testing::cpu(COUNTER);
+        crate::run_headless(&mut cpu, 2).unwrap();
+        let saved = cpu.save_state_bytes();
+        crate::run_headless(&mut cpu, 3).unwrap();
+
+        // A fresh console carries on from the state exactly as the original did
+        let mut resumed = testing::cpu(COUNTER);
+        resumed.load_state_bytes(&saved).unwrap();
+        assert_eq!(resumed.save_state_bytes(), saved);
+        crate::run_headless(&mut resumed, 3).unwrap();
+        assert_eq!(resumed.ram(), cpu.ram());
+        assert_eq!(resumed.frame().pixels, cpu.frame().pixels);
+        assert_eq!(resumed.save_state_bytes(), cpu.save_state_bytes());
+    }
+}
@@ -1,53 +1,389 @@
+use std::rc::Rc;
+
 use crate::apu::APU;
-use crate::cart::{self, Cart, CartLoadResult};
+use crate::cart::{self, Cart, CartLoadResult, Mirroring};
+use crate::controller::{InputDevice, StandardController};
+use crate::filter::Frame;
+use crate::frontend::InputState;
+use crate::mapper::{new_mapper, FourScreenVram, Mapper};
+use crate::palette::Palette;
 use crate::ppu::PPU;
+use crate::region::Region;
+use crate::state::{StateReader, StateResult, StateWriter};
+
+/// Optional hardware behaviours which some games depend on, but which are off by default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Quirks {
+    /// A controller read which the DMC's DMA halts the CPU on is repeated once the DMA is done,
+    /// which clocks the controller's shift register an extra time and so drops a bit. OAM DMA
+    /// can't cause this, as it always halts the CPU on the opcode fetch after writing `$4014`.
+    ///
+    /// See: <https://www.nesdev.org/wiki/DMA#Register_conflicts>
+    pub dma_controller_glitch: bool,
+
+    /// Writes to PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR are ignored for the first
+    /// `PPU_WARM_UP_CYCLES` CPU cycles after power on, while the PPU warms up. Reset doesn't
+    /// start it again.
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_power_up_state>
+    pub ppu_warm_up: bool,
+}
 
-#[derive(Debug)]
+/// CPU cycles from power on until the PPU accepts writes to all its registers (see
+/// `Quirks::ppu_warm_up`), as measured on NTSC consoles
+pub const PPU_WARM_UP_CYCLES: u64 = 29658;
+
+#[derive(Debug, Clone)]
 pub struct System {
     scratch_ram: Box<[u8]>,
     ppu: PPU,
+
+    /// Fraction of a PPU dot left over from the last CPU cycle, on regions where there isn't a
+    /// whole number of dots per cycle
+    ppu_dots_remainder: u64,
     apu: APU,
-    cart: Cart,
+    cart: Rc<Cart>,
+    mapper: Box<dyn Mapper>,
+
+    /// Devices plugged into the two controller ports
+    ports: [Box<dyn InputDevice>; 2],
+
+    /// Whether OAM or DMC DMA currently has the bus
+    dma_active: bool,
+
+    /// Controller port read by the instruction being run, if any, which a DMC DMA can land on
+    /// (see `Quirks::dma_controller_glitch`)
+    port_read: Option<usize>,
+
+    /// Whether the DMC is waiting for a sample byte, which the CPU stalls for
+    /// `dmc_dma_cycles` to fetch (see `take_dmc_dma`)
+    dmc_dma_pending: bool,
+    dmc_dma_cycles: u8,
+
+    /// PPU register written most recently, since `take_ppu_write`
+    ppu_write: Option<u16>,
+
+    /// Page written to OAMDMA by the current instruction, for the CPU to copy to OAM once it
+    /// finishes (see `take_oam_dma`)
+    oam_dma_request: Option<u8>,
+
+    quirks: Quirks,
+
+    /// CPU cycles run since power on, up to `PPU_WARM_UP_CYCLES`, which is all that matters
+    cycles_since_power_on: u64,
+
+    /// Which bytes of RAM have been written since power on, if watching for reads of those
+    /// which haven't (see `set_uninit_check`)
+    uninit_check: Option<UninitCheck>,
+}
+
+/// Size of the RAM inside the console, which is mirrored up to `$1FFF`
+const SCRATCH_RAM_SIZE: usize = 0x800;
+/// Size of the PRG RAM at `$6000-$7FFF` tracked by `UninitCheck`
+const PRG_RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone)]
+struct UninitCheck {
+    /// A flag for each byte of scratch RAM, followed by each byte of PRG RAM
+    written: Box<[bool]>,
+    /// Bytes already read before being written, so each is only reported once
+    reported: Box<[bool]>,
+    /// Whether the cart has PRG RAM to check
+    prg_ram: bool,
+    /// Addresses read before being written, since `take_uninit_reads`
+    reads: Vec<u16>,
+}
+
+impl UninitCheck {
+    fn new(prg_ram: bool) -> Self {
+        Self {
+            written: vec![false; SCRATCH_RAM_SIZE + PRG_RAM_SIZE].into_boxed_slice(),
+            reported: vec![false; SCRATCH_RAM_SIZE + PRG_RAM_SIZE].into_boxed_slice(),
+            prg_ram,
+            reads: Vec::new(),
+        }
+    }
+
+    /// Index into `written` for a CPU address, if it is RAM
+    fn index(&self, address: u16) -> Option<usize> {
+        match address {
+            0x0000..=0x1fff => Some(address as usize % SCRATCH_RAM_SIZE),
+            0x6000..=0x7fff if self.prg_ram => Some(SCRATCH_RAM_SIZE + (address - 0x6000) as usize),
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, address: u16) {
+        if let Some(index) = self.index(address) {
+            if !self.written[index] && !self.reported[index] {
+                self.reported[index] = true;
+                self.reads.push(address);
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16) {
+        if let Some(index) = self.index(address) {
+            self.written[index] = true;
+        }
+    }
 }
 
 impl System {
     pub fn new(filename: String) -> CartLoadResult<Self> {
-        let cart = cart::load_to_cart(filename)?;
+        Ok(Self::from_cart(cart::load_to_cart(filename)?))
+    }
+
+    /// A system with an empty NROM cart inserted (see `Cart::empty`), for testing the PPU, APU
+    /// and memory map without a ROM file
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_empty() -> Self {
+        Self::from_cart(Cart::empty())
+    }
+
+    pub fn from_cart(cart: Cart) -> Self {
+        Self::from_shared_cart(Rc::new(cart))
+    }
+
+    fn from_shared_cart(cart: Rc<Cart>) -> Self {
+        let mut mapper = new_mapper(cart.mapper_number(), cart.chr_rom_len() > 0)
+            .expect("unsupported mappers are rejected when the cart is loaded");
+        if cart.mirroring() == Mirroring::FourScreen {
+            mapper = Box::new(FourScreenVram::new(mapper));
+        }
 
         // TODO: power-on state of `scratch_ram` is funkier than this
-        Ok(System {
-            scratch_ram: Box::new([0; 0x800]),
-            ppu: PPU::new(),
-            apu: APU::new(),
+        let mut system = System {
+            scratch_ram: Box::new([0; SCRATCH_RAM_SIZE]),
+            ppu: PPU::new(cart.mirroring(), cart.region()),
+            ppu_dots_remainder: 0,
+            apu: APU::new(cart.region()),
             cart,
-        })
+            mapper,
+            ports: [
+                Box::new(StandardController::new(0)),
+                Box::new(StandardController::new(1)),
+            ],
+            dma_active: false,
+            port_read: None,
+            dmc_dma_pending: false,
+            dmc_dma_cycles: 0,
+            ppu_write: None,
+            oam_dma_request: None,
+            quirks: Quirks::default(),
+            cycles_since_power_on: 0,
+            uninit_check: None,
+        };
+        system.update_mirroring();
+        system
+    }
+
+    /// Swap in a mapper made for a test, e.g. one which raises IRQs
+    #[cfg(test)]
+    pub(crate) fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+        self.mapper = mapper;
+    }
+
+    /// A freshly powered-on system with `cart` inserted (or the current cart, if `None`), and
+    /// the same devices and settings as this one
+    pub fn power_cycled(&self, cart: Option<Cart>) -> Self {
+        let cart = cart.map_or_else(|| Rc::clone(&self.cart), Rc::new);
+        let mut system = Self::from_shared_cart(cart);
+        system.ports = self.ports.clone();
+        system.quirks = self.quirks;
+        system.set_uninit_check(self.uninit_check.is_some());
+        system.apu.set_volume(self.apu.volume());
+        system.apu.set_muted(self.apu.muted());
+        system.ppu.set_palette(Rc::clone(self.ppu.palette()));
+        system.ppu.set_sprite_limit(self.ppu.sprite_limit());
+        system
+    }
+
+    /// Reset the PPU, APU and mapper, as when the console's reset button is pressed. RAM, the
+    /// cart and the controllers are left as they were; see `PPU::reset`, `APU::reset` and
+    /// `Mapper::reset` for what happens to the others.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.mapper.reset();
+        self.update_mirroring();
+    }
+
+    /// Save RAM and the state of each chip. The cart is identified by the header instead (see
+    /// `CPU::save_state_bytes`), and the controllers aren't saved.
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.bytes("scratch_ram", &self.scratch_ram);
+        state.u64("ppu_dots_remainder", self.ppu_dots_remainder);
+        state.bool("dma_active", self.dma_active);
+        state.bool("dmc_dma_pending", self.dmc_dma_pending);
+        state.u8("dmc_dma_cycles", self.dmc_dma_cycles);
+        state.u64("cycles_since_power_on", self.cycles_since_power_on);
+        state.section("ppu", |state| self.ppu.write_state(state));
+        state.section("apu", |state| self.apu.write_state(state));
+        state.section("mapper", |state| self.mapper.write_state(state));
+    }
+
+    pub fn read_state(&mut self, state: &mut StateReader) -> StateResult<()> {
+        state.bytes(&mut self.scratch_ram)?;
+        self.ppu_dots_remainder = state.u64()?;
+        self.dma_active = state.bool()?;
+        self.dmc_dma_pending = state.bool()?;
+        self.dmc_dma_cycles = state.u8()?;
+        self.cycles_since_power_on = state.u64()?;
+        self.ppu.read_state(state)?;
+        self.apu.read_state(state)?;
+        self.mapper.read_state(state)?;
+        self.update_mirroring();
+        Ok(())
+    }
+
+    /// Tell the PPU how the nametables are mirrored now, which the mapper can change if the cart
+    /// leaves it up to the mapper
+    fn update_mirroring(&mut self) {
+        let mirroring = match self.cart.mirroring() {
+            Mirroring::MapperControlled => self
+                .mapper
+                .mirroring()
+                .expect("carts are only mapper controlled if the mapper sets mirroring"),
+            mirroring => mirroring,
+        };
+        self.ppu.set_mirroring(mirroring);
     }
 
-    pub fn read_byte(&self, address: u16) -> u8 {
+    pub fn region(&self) -> Region {
+        self.cart.region()
+    }
+
+    /// How the nametables are mirrored now. Unlike `Cart::mirroring`, this is never
+    /// `MapperControlled`, but whatever the mapper last chose.
+    pub fn mirroring(&self) -> Mirroring {
+        self.ppu.mirroring()
+    }
+
+    /// The 2 KiB of RAM inside the console
+    pub fn ram(&self) -> &[u8] {
+        &self.scratch_ram
+    }
+
+    pub fn cart(&self) -> &Cart {
+        &self.cart
+    }
+
+    /// The cart's PRG RAM, if it has a battery to keep it
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if self.cart.has_battery() {
+            self.mapper.prg_ram()
+        } else {
+            None
+        }
+    }
+
+    /// Restore the cart's battery-backed PRG RAM from a save file. A file of the wrong size fills
+    /// as much of the RAM as it can.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if !self.cart.has_battery() {
+            return;
+        }
+        if let Some(ram) = self.mapper.prg_ram_mut() {
+            let len = ram.len().min(data.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    /// The name of the board the mapper emulates, e.g. `MMC1`
+    pub fn mapper_name(&self) -> &'static str {
+        self.mapper.name()
+    }
+
+    pub fn read_byte(&mut self, address: u16) -> u8 {
+        if let Some(check) = &mut self.uninit_check {
+            check.read(address);
+        }
+        if address < 0x2000 {
+            self.scratch_ram[(address & 0x7ff) as usize]
+        } else if address < 0x4000 {
+            let (cart, mapper) = (&self.cart, &self.mapper);
+            self.ppu
+                .read_address(address, |address| mapper.ppu_read(cart, address))
+        } else if address == 0x4016 || address == 0x4017 {
+            self.read_port((address - 0x4016) as usize)
+        } else if address < 0x4020 {
+            self.apu.read_address(address)
+        } else {
+            self.mapper.cpu_read(&self.cart, address)
+        }
+    }
+
+    fn read_port(&mut self, port: usize) -> u8 {
+        self.port_read = Some(port);
+        self.ports[port].read()
+    }
+
+    /// Read a byte without triggering any side effects, e.g. for the debugger to inspect memory
+    /// without acknowledging vblank or shifting the controllers
+    pub fn read_byte_no_side_effects(&self, address: u16) -> u8 {
         if address < 0x2000 {
             self.scratch_ram[(address & 0x7ff) as usize]
         } else if address < 0x4000 {
-            self.ppu.read_address(address)
+            self.ppu.peek_address(address)
+        } else if address == 0x4016 || address == 0x4017 {
+            self.ports[(address - 0x4016) as usize].peek()
         } else if address < 0x4020 {
             self.apu.read_address(address)
         } else {
-            self.read_mapper_byte(address)
+            self.mapper.cpu_read(&self.cart, address)
         }
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(check) = &mut self.uninit_check {
+            check.write(address);
+        }
         if address < 0x2000 {
             self.scratch_ram[(address & 0x7ff) as usize] = value;
         } else if address < 0x4000 {
-            self.ppu.write_address(address, value);
+            self.ppu_write = Some(0x2000 | address & 0x7);
+            let ignored = matches!(address & 0x7, 0x0 | 0x1 | 0x5 | 0x6);
+            if ignored && self.quirks.ppu_warm_up && self.ppu_warming_up() {
+                return;
+            }
+            let mapper = &mut self.mapper;
+            self.ppu.write_address(address, value, |address, value| {
+                mapper.ppu_write(address, value)
+            });
+        } else if address == 0x4014 {
+            self.oam_dma_request = Some(value);
+        } else if address == 0x4016 {
+            for port in self.ports.iter_mut() {
+                port.write(value);
+            }
         } else if address < 0x4020 {
             self.apu.write_address(address, value);
         } else {
-            self.write_mapper_byte(address, value);
+            self.mapper.cpu_write(address, value);
+            self.update_mirroring();
+        }
+    }
+
+    /// Write a byte without triggering any side effects, e.g. for the debugger to modify memory
+    /// without moving the PPU's write latch or strobing the controllers. Writes which only have
+    /// side effects, such as to the controller ports, mapper registers or APU (which doesn't
+    /// keep copies of its registers yet), are ignored.
+    pub fn write_byte_raw(&mut self, address: u16, value: u8) {
+        if let Some(check) = &mut self.uninit_check {
+            check.write(address);
+        }
+        if address < 0x2000 {
+            self.scratch_ram[(address & 0x7ff) as usize] = value;
+        } else if address < 0x4000 {
+            let mapper = &mut self.mapper;
+            self.ppu.poke_address(address, value, |address, value| {
+                mapper.ppu_write(address, value)
+            });
         }
     }
 
-    pub fn read_word(&self, address: u16) -> u16 {
+    pub fn read_word(&mut self, address: u16) -> u16 {
         let mut output: u16 = 0;
         output += self.read_byte(address + 1) as u16;
         output <<= 8;
@@ -55,16 +391,573 @@ impl System {
         output
     }
 
-    fn read_mapper_byte(&self, address: u16) -> u8 {
-        if (0x8000..=0xbfff).contains(&address) {
-            // We know that `address` is in the first page
-            self.cart.prg_rom_pages[0][address as usize - 0x8000]
-        } else if address >= 0xc000 {
-            self.cart.prg_rom_pages[self.cart.prg_rom_pages.len() - 1][address as usize - 0xc000]
-        } else {
-            panic!("Cannot read byte at '{}' address from mapper", address);
+    /// Read a pointer from the zero page, as the `(zp,X)` and `(zp),Y` addressing modes do. A
+    /// pointer at `$FF` wraps around to take its high byte from `$00`, not `$0100`.
+    pub fn read_word_zero_page(&mut self, address: u8) -> u16 {
+        let low = self.read_byte(address as u16);
+        let high = self.read_byte(address.wrapping_add(1) as u16);
+        u16::from_le_bytes([low, high])
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Watch for reads of RAM or PRG RAM which hasn't been written since power on (or since
+    /// the check was enabled), collecting them for `take_uninit_reads`. Registers and ROM are
+    /// set by the hardware, so aren't checked.
+    pub fn set_uninit_check(&mut self, enabled: bool) {
+        self.uninit_check = enabled.then(|| UninitCheck::new(self.mapper.has_prg_ram()));
+    }
+
+    pub fn uninit_check(&self) -> bool {
+        self.uninit_check.is_some()
+    }
+
+    /// Addresses first read before being written since the last call, in the order read
+    pub fn take_uninit_reads(&mut self) -> Vec<u16> {
+        match &mut self.uninit_check {
+            Some(check) if !check.reads.is_empty() => std::mem::take(&mut check.reads),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Called by the DMA units while they are holding the bus
+    pub fn set_dma_active(&mut self, active: bool) {
+        self.dma_active = active;
+    }
+
+    /// The page to copy to OAM, if OAMDMA has been written since the last call
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_registers#OAMDMA>
+    pub fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma_request.take()
+    }
+
+    /// Replace the device plugged into a controller port (0 or 1)
+    pub fn connect(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.ports[port] = device;
+    }
+
+    /// Set the buttons held on the controller in a port, bypassing the frontend
+    pub fn set_player_input(&mut self, player: usize, buttons: u8) {
+        self.ports[player].set_buttons(buttons);
+    }
+
+    /// The buttons held on the controller in a port, or 0 if it isn't a controller
+    pub fn player_input(&self, player: usize) -> u8 {
+        self.ports[player].buttons()
+    }
+
+    pub fn set_input(&mut self, input: &InputState) {
+        for port in self.ports.iter_mut() {
+            port.set_input(input);
         }
     }
 
-    fn write_mapper_byte(&self, _address: u16, _value: u8) {}
+    /// Whether the PPU is still ignoring some register writes after power on (see
+    /// `Quirks::ppu_warm_up`)
+    ///
+    /// See: <https://www.nesdev.org/wiki/PPU_power_up_state>
+    fn ppu_warming_up(&self) -> bool {
+        self.cycles_since_power_on < PPU_WARM_UP_CYCLES
+    }
+
+    /// Run the PPU and APU for as long as the CPU took to run `cycles` cycles
+    pub fn tick(&mut self, cycles: u64) {
+        self.cycles_since_power_on = (self.cycles_since_power_on + cycles).min(PPU_WARM_UP_CYCLES);
+        let (numerator, denominator) = self.cart.region().ppu_dots_per_cpu_cycle();
+        let dots = cycles * numerator + self.ppu_dots_remainder;
+        self.ppu_dots_remainder = dots % denominator;
+        let (cart, mapper) = (&self.cart, &self.mapper);
+        self.ppu
+            .tick(dots / denominator, |address| mapper.ppu_read(cart, address));
+
+        for cycle in 0..cycles {
+            self.apu.tick(1);
+            if self.dmc_dma_pending || self.apu.dmc_address().is_none() {
+                continue;
+            }
+            self.dmc_dma_pending = true;
+            // Usually a cycle to halt, a dummy cycle, one to align with a read cycle, and the
+            // read. OAM DMA has already halted the CPU, so stealing from it takes two.
+            self.dmc_dma_cycles = if self.dma_active { 2 } else { 4 };
+
+            // The CPU halts on the read after the DMC asks for the bus. Asking in the cycle
+            // before an instruction's last halts it on that cycle, which for a load from a
+            // controller port is the read, and the CPU reads it again after the DMA.
+            if let Some(port) = self.port_read {
+                if self.quirks.dma_controller_glitch && cycle + 2 == cycles {
+                    self.ports[port].read();
+                }
+            }
+        }
+        self.port_read = None;
+    }
+
+    /// Whether the CPU should stall for the DMC to fetch a sample byte (see `take_dmc_dma`)
+    pub fn dmc_dma_pending(&self) -> bool {
+        self.dmc_dma_pending
+    }
+
+    /// The address of the sample byte the DMC is waiting for, and how many cycles the CPU
+    /// stalls while it is fetched, if it is waiting. The caller does the read, and hands the
+    /// byte to `load_dmc_sample`.
+    ///
+    /// See: <https://www.nesdev.org/wiki/APU_DMC#Memory_reader>
+    pub fn take_dmc_dma(&mut self) -> Option<(u16, u8)> {
+        if !std::mem::take(&mut self.dmc_dma_pending) {
+            return None;
+        }
+        // The sample may have been stopped since
+        self.apu
+            .dmc_address()
+            .map(|address| (address, self.dmc_dma_cycles))
+    }
+
+    pub fn load_dmc_sample(&mut self, value: u8) {
+        self.apu.load_dmc_sample(value);
+    }
+
+    /// Whether the PPU has signalled an NMI since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+
+    pub fn nmi_enabled(&self) -> bool {
+        self.ppu.nmi_enabled()
+    }
+
+    /// Whether there is anything for the CPU to run at `address`, rather than registers or
+    /// open bus
+    pub fn is_executable(&self, address: u16) -> bool {
+        match address {
+            0x0000..=0x1fff | 0x8000..=0xffff => true,
+            0x6000..=0x7fff => self.mapper.has_prg_ram(),
+            _ => false,
+        }
+    }
+
+    /// The PPU register ($2000-$2007) written most recently, if any have been since the last
+    /// call
+    pub fn take_ppu_write(&mut self) -> Option<u16> {
+        self.ppu_write.take()
+    }
+
+    /// Whether anything is asserting the CPU's IRQ line: the mapper, or the DMC
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending() || self.apu.irq_pending()
+    }
+
+    /// Scanline (-1 for pre-render) and dot the PPU is about to draw
+    pub fn ppu_position(&self) -> (i16, u16) {
+        self.ppu.position()
+    }
+
+    /// The first nametable drawn as text, see `PPU::nametable_text`
+    pub fn nametable_text(&self) -> String {
+        self.ppu
+            .nametable_text(|address| self.mapper.ppu_read(&self.cart, address))
+    }
+
+    pub fn ppu_frame(&self) -> u64 {
+        self.ppu.frame()
+    }
+
+    pub fn ppu_framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.ppu.set_palette(Rc::new(palette));
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.ppu.sprite_limit()
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.ppu.set_sprite_limit(enabled);
+    }
+
+    pub fn ppu_rgb_frame(&self) -> Frame {
+        self.ppu.rgb_frame()
+    }
+
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.apu.volume()
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.apu.set_volume(volume);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.apu.muted()
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.apu.set_muted(muted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::buttons;
+    use crate::testing;
+
+    /// Run the PPU to the start of vblank
+    fn run_to_vblank(system: &mut System) {
+        while system.read_byte_no_side_effects(0x2002) & 0x80 == 0 {
+            system.tick(1);
+        }
+    }
+
+    /// CPU cycles per frame in `region`, averaged over 10 frames, and the scanline vblank starts
+    /// on
+    fn frame_timing(region: Region) -> (f64, i16) {
+        let mut cart = Cart::empty();
+        cart.set_region(region);
+        let mut system = System::from_cart(cart);
+        let mut cycles = 0;
+        while system.ppu_frame() < 1 {
+            system.tick(1);
+        }
+        while system.ppu_frame() < 11 {
+            system.tick(1);
+            cycles += 1;
+        }
+        run_to_vblank(&mut system);
+        (cycles as f64 / 10.0, system.ppu_position().0)
+    }
+
+    #[test]
+    fn region_timing() {
+        // NTSC: 262 lines of 341 dots, at 3 dots per cycle
+        let (cycles, vblank) = frame_timing(Region::Ntsc);
+        assert!((cycles - 29780.7).abs() < 0.2, "{cycles}");
+        assert_eq!(vblank, 241);
+        // PAL: 312 lines at 3.2 dots per cycle
+        let (cycles, vblank) = frame_timing(Region::Pal);
+        assert!((cycles - 33247.5).abs() < 0.2, "{cycles}");
+        assert_eq!(vblank, 241);
+        // Dendy: 312 lines at 3 dots per cycle, with vblank 50 lines later
+        let (cycles, vblank) = frame_timing(Region::Dendy);
+        assert!((cycles - 35464.0).abs() < 0.2, "{cycles}");
+        assert_eq!(vblank, 291);
+    }
+
+    #[test]
+    fn new_empty() {
+        let mut system = System::new_empty();
+        for address in [0x8000, 0xc000, 0xfffc, 0xffff] {
+            assert_eq!(system.read_byte(address), 0xff, "${address:04x}");
+        }
+        assert!((0..0x0800).all(|address| system.read_byte(address) == 0));
+        assert_eq!(system.read_byte(0x4015), 0);
+
+        // RAM and the PPU work as usual, including CHR RAM in place of the missing CHR ROM
+        system.write_byte(0x0123, 0x45);
+        assert_eq!(system.read_byte(0x0923), 0x45);
+        for (address, value) in [(0x0010, 0x67), (0x2000, 0x89)] {
+            set_ppuaddr(&mut system, address);
+            system.write_byte(0x2007, value);
+            assert_eq!(read_ppudata(&mut system, address), value);
+        }
+    }
+
+    #[test]
+    fn peek_ppustatus() {
+        let mut system = System::new_empty();
+        run_to_vblank(&mut system);
+        assert_eq!(system.read_byte_no_side_effects(0x2002) & 0x80, 0x80);
+        assert_eq!(system.read_byte_no_side_effects(0x2002) & 0x80, 0x80);
+        // A real read acknowledges vblank
+        assert_eq!(system.read_byte(0x2002) & 0x80, 0x80);
+        assert_eq!(system.read_byte_no_side_effects(0x2002) & 0x80, 0);
+    }
+
+    #[test]
+    fn peek_controller() {
+        let mut system = System::new_empty();
+        system.set_player_input(0, buttons::A);
+        system.write_byte(0x4016, 1);
+        system.write_byte(0x4016, 0);
+        assert_eq!(system.read_byte_no_side_effects(0x4016) & 1, 1);
+        assert_eq!(system.read_byte_no_side_effects(0x4016) & 1, 1);
+        // A real read shifts the next button (B) out
+        assert_eq!(system.read_byte(0x4016) & 1, 1);
+        assert_eq!(system.read_byte_no_side_effects(0x4016) & 1, 0);
+    }
+
+    /// Read the controller over and over while the DMC fetches a looping sample as fast as it
+    /// can, counting the reads which saw Right (the last button) in the wrong place at `$10`
+    const READ_DURING_DMC_DMA: &str = "
+            lda #$4f
+            sta $4010
+            lda #$ff
+            sta $4013
+            lda #$10
+            sta $4015
+        poll:
+            lda #1
+            sta $4016
+            lda #0
+            sta $4016
+            sta $11
+            ldx #8
+        read:
+            lda $4016
+            and #1
+            clc
+            adc $11
+            asl a
+            sta $11
+            dex
+            bne read
+            lda $11
+            cmp #$02
+            beq poll
+            inc $10
+            jmp poll
+    ";
+
+    #[test]
+    fn dma_controller_glitch() {
+        for glitch in [false, true] {
+            let mut cpu = testing::cpu(READ_DURING_DMC_DMA);
+            cpu.set_quirks(Quirks {
+                dma_controller_glitch: glitch,
+                ..Default::default()
+            });
+            cpu.set_player_input(0, buttons::RIGHT);
+            crate::run_headless(&mut cpu, 2).unwrap();
+            let bad_reads = cpu.peek_byte(0x10);
+            assert_eq!(bad_reads > 0, glitch, "{} bad reads", bad_reads);
+        }
+    }
+
+    #[test]
+    fn peek_ppudata() {
+        let mut system = System::new_empty();
+        system.write_byte(0x2006, 0x20);
+        system.write_byte(0x2006, 0x00);
+        system.write_byte(0x2007, 0x12);
+        system.write_byte(0x2007, 0x34);
+        system.write_byte(0x2006, 0x20);
+        system.write_byte(0x2006, 0x00);
+        // Peeking neither fills the read buffer nor moves the address along
+        let peeked = system.read_byte_no_side_effects(0x2007);
+        assert_eq!(system.read_byte_no_side_effects(0x2007), peeked);
+        system.read_byte(0x2007);
+        assert_eq!(system.read_byte(0x2007), 0x12);
+        assert_eq!(system.read_byte(0x2007), 0x34);
+    }
+
+    fn set_ppuaddr(system: &mut System, address: u16) {
+        system.write_byte(0x2006, (address >> 8) as u8);
+        system.write_byte(0x2006, address as u8);
+    }
+
+    /// A system with the warm up quirk on, which has run for `cycles` since power on
+    fn warming_up(cycles: u64) -> System {
+        let mut system = System::new_empty();
+        system.set_quirks(Quirks {
+            ppu_warm_up: true,
+            ..Default::default()
+        });
+        system.tick(cycles);
+        system
+    }
+
+    /// Whether writing PPUCTRL and PPUMASK take effect
+    fn ctrl_and_mask_work(system: &mut System) -> (bool, bool) {
+        system.write_byte(0x2000, 0x80);
+        // Emphasising red dims the green of the backdrop
+        let green = system.ppu_rgb_frame().pixel(0, 0).1;
+        system.write_byte(0x2001, 0x20);
+        let mask = system.ppu_rgb_frame().pixel(0, 0).1 != green;
+        (system.nmi_enabled(), mask)
+    }
+
+    fn read_ppudata(system: &mut System, address: u16) -> u8 {
+        set_ppuaddr(system, address);
+        system.read_byte(0x2007);
+        system.read_byte(0x2007)
+    }
+
+    #[test]
+    fn ppu_warm_up() {
+        let mut system = warming_up(PPU_WARM_UP_CYCLES - 1);
+        assert_eq!(ctrl_and_mask_work(&mut system), (false, false));
+        // A lone PPUSCROLL write would leave the write latch half way through PPUADDR
+        system.write_byte(0x2005, 0x00);
+        set_ppuaddr(&mut system, 0x2100);
+        system.write_byte(0x2007, 0x12);
+        // Other registers work, e.g. OAMADDR and OAMDATA
+        system.write_byte(0x2003, 0x10);
+        system.write_byte(0x2004, 0x34);
+        system.write_byte(0x2003, 0x10);
+        assert_eq!(system.read_byte(0x2004), 0x34);
+
+        system.tick(1);
+        assert_eq!(ctrl_and_mask_work(&mut system), (true, true));
+        // PPUADDR was ignored, so PPUDATA went to $0000 instead
+        assert_eq!(read_ppudata(&mut system, 0x2100), 0x00);
+        set_ppuaddr(&mut system, 0x2100);
+        system.write_byte(0x2007, 0x56);
+        assert_eq!(read_ppudata(&mut system, 0x2100), 0x56);
+
+        // Without the quirk, the registers work straight away
+        let mut system = System::new_empty();
+        assert_eq!(ctrl_and_mask_work(&mut system), (true, true));
+    }
+
+    #[test]
+    fn ppu_warm_up_from_power_on() {
+        // Resetting doesn't restart the warm up, but power cycling does
+        let mut system = warming_up(PPU_WARM_UP_CYCLES);
+        system.reset();
+        assert_eq!(ctrl_and_mask_work(&mut system), (true, true));
+        let mut system = system.power_cycled(None);
+        assert_eq!(ctrl_and_mask_work(&mut system), (false, false));
+    }
+
+    /// Write a value to an MMC1 register a bit at a time, lowest first
+    fn mmc1_write(system: &mut System, address: u16, value: u8) {
+        for bit in 0..5 {
+            system.write_byte(address, value >> bit & 1);
+        }
+    }
+
+    /// Write a different value to each of the four nametables in turn, then read them back, so
+    /// the ones which share VRAM all show the last value written to it
+    fn nametables(system: &mut System) -> [u8; 4] {
+        for (i, address) in [0x2000, 0x2400, 0x2800, 0x2c00].into_iter().enumerate() {
+            set_ppuaddr(system, address);
+            system.write_byte(0x2007, i as u8 + 1);
+        }
+        [0x2000, 0x2400, 0x2800, 0x2c00].map(|address| read_ppudata(system, address))
+    }
+
+    #[test]
+    fn mapper_controlled_mirroring() {
+        let mut system = System::from_cart(testing::banked_cart(1, 2, 0));
+        assert_eq!(system.cart.mirroring(), Mirroring::MapperControlled);
+        // MMC1 powers on with single screen mirroring
+        assert_eq!(nametables(&mut system), [4; 4]);
+
+        // Vertical: $2000 and $2400 are split, and $2800 aliases $2000
+        mmc1_write(&mut system, 0x8000, 0x02);
+        assert_eq!(nametables(&mut system), [3, 4, 3, 4]);
+        assert_eq!(system.mirroring(), Mirroring::Vertical);
+        // Horizontal: $2000 and $2400 alias
+        mmc1_write(&mut system, 0x8000, 0x03);
+        assert_eq!(nametables(&mut system), [2, 2, 4, 4]);
+        assert_eq!(system.mirroring(), Mirroring::Horizontal);
+        assert_eq!(system.cart.mirroring(), Mirroring::MapperControlled);
+
+        // Each single screen mode uses its own nametable
+        mmc1_write(&mut system, 0x8000, 0x01);
+        assert_eq!(nametables(&mut system), [4; 4]);
+        mmc1_write(&mut system, 0x8000, 0x00);
+        set_ppuaddr(&mut system, 0x2400);
+        system.write_byte(0x2007, 0x56);
+        assert_eq!(read_ppudata(&mut system, 0x2c00), 0x56);
+        mmc1_write(&mut system, 0x8000, 0x01);
+        assert_eq!(read_ppudata(&mut system, 0x2400), 4);
+    }
+
+    #[test]
+    fn four_screen_mirroring() {
+        let mut image = testing::nrom(&[]);
+        image[6] |= 0x08;
+        let mut system = System::from_cart(cart::load_cart_from_bytes(image).unwrap());
+        assert_eq!(system.cart.mirroring(), Mirroring::FourScreen);
+        // Each nametable address has its own nametable, the last two on the cart
+        assert_eq!(nametables(&mut system), [1, 2, 3, 4]);
+        assert_eq!(system.mapper.ppu_read(&system.cart, 0x2c00), 4);
+        // $3000-$3EFF mirrors them
+        assert_eq!(read_ppudata(&mut system, 0x3800), 3);
+    }
+
+    #[test]
+    fn reset_mapper() {
+        let mut system = System::from_cart(testing::banked_cart(1, 4, 0));
+        // PRG ROM bank mode 2, which fixes the first bank at $8000 and switches $C000, with
+        // bank 1 switched in and a write to the shift register left half done
+        mmc1_write(&mut system, 0x8000, 0x08);
+        mmc1_write(&mut system, 0xe000, 1);
+        system.write_byte(0x8000, 1);
+        system.write_byte(0x8000, 1);
+        assert_eq!(system.read_byte(0x8000), 0);
+        assert_eq!(system.read_byte(0xc000), 1);
+
+        // Resetting fixes the last bank at $C000 again, keeping the switched bank, and starts
+        // the shift register afresh
+        system.reset();
+        assert_eq!(system.read_byte(0x8000), 1);
+        assert_eq!(system.read_byte(0xc000), 3);
+        mmc1_write(&mut system, 0xe000, 2);
+        assert_eq!(system.read_byte(0x8000), 2);
+    }
+
+    #[test]
+    fn write_raw_ram() {
+        let mut system = System::new_empty();
+        system.write_byte_raw(0x0812, 0x34);
+        assert_eq!(system.read_byte(0x0012), 0x34);
+    }
+
+    #[test]
+    fn write_raw_ppu_registers() {
+        let mut system = System::new_empty();
+        // Neither PPUADDR nor PPUSCROLL move the write latch, so this is still the second write
+        system.write_byte(0x2006, 0x21);
+        system.write_byte_raw(0x2006, 0x55);
+        system.write_byte_raw(0x2005, 0x55);
+        system.write_byte(0x2006, 0x00);
+        // PPUDATA writes VRAM without moving the address along
+        system.write_byte_raw(0x2007, 0xab);
+        system.write_byte(0x2007, 0xcd);
+        set_ppuaddr(&mut system, 0x2100);
+        system.read_byte(0x2007);
+        assert_eq!(system.read_byte(0x2007), 0xcd);
+        assert_eq!(system.read_byte(0x2007), 0x00);
+
+        set_ppuaddr(&mut system, 0x2200);
+        system.write_byte_raw(0x2007, 0xab);
+        set_ppuaddr(&mut system, 0x2200);
+        system.read_byte(0x2007);
+        assert_eq!(system.read_byte(0x2007), 0xab);
+    }
+
+    #[test]
+    fn write_raw_ppuctrl() {
+        let mut system = System::new_empty();
+        run_to_vblank(&mut system);
+        // Turning on NMIs during vblank doesn't signal one
+        system.write_byte_raw(0x2000, 0x80);
+        assert!(system.nmi_enabled());
+        assert!(!system.take_nmi());
+
+        system.write_byte(0x2000, 0x00);
+        system.write_byte(0x2000, 0x80);
+        assert!(system.take_nmi());
+    }
+
+    #[test]
+    fn write_raw_controller() {
+        let mut system = System::new_empty();
+        system.set_player_input(0, buttons::A);
+        // The strobe isn't written, so the buttons aren't loaded into the shift register
+        system.write_byte_raw(0x4016, 1);
+        system.write_byte_raw(0x4016, 0);
+        assert_eq!(system.read_byte(0x4016) & 1, 0);
+    }
 }
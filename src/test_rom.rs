@@ -0,0 +1,121 @@
+//! Running test ROMs which report their results in PRG RAM, as blargg's do (e.g. `ppu_vbl_nmi`,
+//! which checks the vblank and NMI timing). Once `$6001-$6003` hold the signature `DE B0 61`,
+//! `$6000` is the status and `$6004` starts a zero-terminated message, which says which sub-test
+//! failed if any.
+//!
+//! See: <https://www.nesdev.org/wiki/Emulator_tests>
+
+use std::fmt::Display;
+
+use crate::cpu::{CpuResult, CPU};
+
+const SIGNATURE_ADDRESS: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const STATUS_ADDRESS: u16 = 0x6000;
+const MESSAGE_ADDRESS: u16 = 0x6004;
+
+/// Statuses from `0x80` up mean the test hasn't finished; anything lower is the result code,
+/// where 0 is a pass
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET_REQUESTED: u8 = 0x81;
+
+/// Frames to wait before pressing reset when a test asks for it, which must be at least 100 ms
+const RESET_DELAY_FRAMES: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    Passed {
+        message: String,
+    },
+    Failed {
+        code: u8,
+        message: String,
+    },
+    /// The test didn't report a result in time, or never wrote the signature at all
+    Unfinished {
+        message: String,
+    },
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self, TestResult::Passed { .. })
+    }
+
+    /// The test's own description of the result, e.g. `Failed #3` and why
+    pub fn message(&self) -> &str {
+        match self {
+            TestResult::Passed { message }
+            | TestResult::Failed { message, .. }
+            | TestResult::Unfinished { message } => message,
+        }
+    }
+}
+
+impl Display for TestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestResult::Passed { .. } => write!(f, "Passed")?,
+            TestResult::Failed { code, .. } => write!(f, "Failed with code {}", code)?,
+            TestResult::Unfinished { .. } => write!(f, "Did not finish")?,
+        }
+        let message = self.message().trim();
+        if !message.is_empty() {
+            write!(f, "\n{}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The test's status, if it has written the signature yet
+fn status(cpu: &CPU) -> Option<u8> {
+    let signature = [0, 1, 2].map(|i| cpu.peek_byte(SIGNATURE_ADDRESS + i));
+    (signature == SIGNATURE).then(|| cpu.peek_byte(STATUS_ADDRESS))
+}
+
+/// The message at `$6004` so far, which tests write to as they go
+fn message(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (MESSAGE_ADDRESS..=0x7fff)
+        .map(|address| cpu.peek_byte(address))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Run a test ROM headless (see `run_headless`) until it reports a result, for up to `frames`
+/// frames. Tests which ask for the console to be reset part way through are reset.
+pub fn run_test_rom(cpu: &mut CPU, frames: u64) -> CpuResult<TestResult> {
+    let mut reset_countdown = None;
+    for _ in 0..frames {
+        crate::run_headless(cpu, 1)?;
+        match status(cpu) {
+            Some(STATUS_RESET_REQUESTED) => {
+                let countdown = reset_countdown.get_or_insert(RESET_DELAY_FRAMES);
+                *countdown -= 1;
+                if *countdown == 0 {
+                    reset_countdown = None;
+                    cpu.reset();
+                }
+            }
+            Some(0) => {
+                return Ok(TestResult::Passed {
+                    message: message(cpu),
+                })
+            }
+            Some(code) if code < STATUS_RUNNING => {
+                return Ok(TestResult::Failed {
+                    code,
+                    message: message(cpu),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(TestResult::Unfinished {
+        message: if status(cpu).is_some() {
+            message(cpu)
+        } else {
+            String::new()
+        },
+    })
+}
@@ -0,0 +1,135 @@
+//! Helpers for the unit tests, which run small programs written with the assembler (see
+//! `asm::assemble`) on an NROM cart
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::cart::{self, Cart};
+use crate::cpu::CPU;
+use crate::mapper::{Mapper, Mapper0};
+
+/// An NROM image with `program` at `$8000`, and 8 KiB of CHR RAM. The PRG ROM is 16 KiB, or
+/// 32 KiB if the program doesn't fit, and is padded with `NOP`s. Unless the program reaches the
+/// vectors itself (e.g. with `.org $fffa`), reset jumps to `$8000`.
+pub(crate) fn nrom(program: &[u8]) -> Vec<u8> {
+    let pages = if program.len() <= 0x4000 { 1 } else { 2 };
+    let mut prg = vec![0xea; pages * 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    if program.len() <= prg.len() - 4 {
+        let reset_vector = prg.len() - 4;
+        prg[reset_vector..reset_vector + 2].copy_from_slice(&[0x00, 0x80]);
+    }
+
+    let mut image = vec![b'N', b'E', b'S', 0x1a, pages as u8, 0];
+    image.resize(16, 0);
+    image.extend(prg);
+    image
+}
+
+/// A console powered on with `source` assembled into an NROM cart (see `nrom`)
+pub(crate) fn cpu(source: &str) -> CPU {
+    let program = crate::asm::assemble(source).expect("test program should assemble");
+    CPU::from_bytes(nrom(&program), false).expect("test ROM should load")
+}
+
+/// Run `count` instructions (or DMA cycles, see `CPU::run_opcode`)
+pub(crate) fn run(cpu: &mut CPU, count: usize) {
+    for _ in 0..count {
+        cpu.run_opcode().expect("test program should run");
+    }
+}
+
+/// A cart for `mapper` with `prg_pages` 16 KiB pages of PRG ROM and `chr_pages` 8 KiB pages of
+/// CHR ROM (or CHR RAM if there are none), for testing bank switching. Each byte of PRG ROM is
+/// the number of the 16 KiB page it is in, and each byte of CHR ROM the number of the 4 KiB
+/// bank it is in.
+pub(crate) fn banked_cart(mapper: u8, prg_pages: u8, chr_pages: u8) -> Cart {
+    let mut image = vec![
+        b'N',
+        b'E',
+        b'S',
+        0x1a,
+        prg_pages,
+        chr_pages,
+        mapper << 4,
+        mapper & 0xf0,
+    ];
+    image.resize(16, 0);
+    for page in 0..prg_pages {
+        image.extend(std::iter::repeat_n(page, 0x4000));
+    }
+    for bank in 0..chr_pages * 2 {
+        image.extend(std::iter::repeat_n(bank, 0x1000));
+    }
+    cart::load_cart_from_bytes(image).expect("test ROM should load")
+}
+
+/// A writer which keeps what is written, for checking the trace (see `CPU::set_trace_writer`)
+/// after handing the CPU a clone
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// NROM with an IRQ line, for testing how the CPU polls it: writing `$E001` pulls the line low,
+/// and writing `$E000` acknowledges the interrupt, releasing it
+#[derive(Debug, Clone)]
+pub(crate) struct IrqMapper {
+    nrom: Mapper0,
+    irq: bool,
+}
+
+impl IrqMapper {
+    pub(crate) fn new() -> Self {
+        Self {
+            nrom: Mapper0::new(false),
+            irq: false,
+        }
+    }
+}
+
+impl Mapper for IrqMapper {
+    fn cpu_read(&self, cart: &Cart, address: u16) -> u8 {
+        self.nrom.cpu_read(cart, address)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        match address {
+            0xe000 => self.irq = false,
+            0xe001 => self.irq = true,
+            _ => self.nrom.cpu_write(address, value),
+        }
+    }
+
+    fn ppu_read(&self, cart: &Cart, address: u16) -> u8 {
+        self.nrom.ppu_read(cart, address)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn name(&self) -> &'static str {
+        "IRQ test"
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,63 @@
+//! Bindings for running the emulator in the browser.
+//!
+//! Everything here is driven by JavaScript: loading the ROM, calling `run_frame` on each
+//! animation frame, and playing the audio samples all happen on the JS side, so the core never
+//! needs the filesystem, threads or a clock. See `www/index.html` for an example page.
+
+use wasm_bindgen::prelude::*;
+
+use crate::emulator::Emulator;
+use crate::RunOptions;
+
+#[wasm_bindgen]
+pub struct WebEmulator {
+    emulator: Emulator,
+}
+
+#[wasm_bindgen]
+impl WebEmulator {
+    /// Load an iNES ROM image
+    pub fn new_from_bytes(rom: &[u8]) -> Result<WebEmulator, JsValue> {
+        let emulator = Emulator::from_bytes(rom.to_vec(), RunOptions::default())
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(Self { emulator })
+    }
+
+    /// Run one frame and return it as RGBA, ready for `ImageData`
+    pub fn run_frame(&mut self) -> Result<Vec<u8>, JsValue> {
+        self.emulator
+            .cpu_mut()
+            .run_frame()
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(self.frame_rgba())
+    }
+
+    /// The last frame completed, as RGBA, without running another
+    pub fn frame_rgba(&self) -> Vec<u8> {
+        self.emulator.cpu().frame().to_rgba()
+    }
+
+    pub fn frame_width(&self) -> usize {
+        crate::ppu::SCREEN_WIDTH
+    }
+
+    pub fn frame_height(&self) -> usize {
+        crate::ppu::SCREEN_HEIGHT
+    }
+
+    /// Press the console's reset button
+    pub fn reset(&mut self) {
+        self.emulator.cpu_mut().reset();
+    }
+
+    /// Set the buttons held on a player's controller (0 or 1). See `frontend::buttons` for the
+    /// bit layout.
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        self.emulator.cpu_mut().set_player_input(player, buttons);
+    }
+
+    /// Take the audio samples generated since the last call
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.emulator.cpu_mut().take_audio_samples()
+    }
+}
@@ -0,0 +1,69 @@
+//! A minimal WAV writer, for recording the APU's output to a file.
+//!
+//! See: <http://soundfile.sapp.org/doc/WaveFormat/>
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::apu::SAMPLE_RATE;
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 4;
+
+/// `WAVE_FORMAT_IEEE_FLOAT`, as opposed to 1 for integer PCM
+const FORMAT_FLOAT: u16 = 3;
+
+/// Streams mono 32-bit float samples at `SAMPLE_RATE` to a WAV file. The sizes in the header
+/// are only correct after `finish`.
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Write a header for an empty file, to be filled in by `finish`
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&header(0))?;
+        Ok(Self { inner, data_len: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.inner.write_all(&sample.to_le_bytes())?;
+        }
+        let len = samples.len() as u32 * BYTES_PER_SAMPLE;
+        self.data_len = self.data_len.saturating_add(len);
+        Ok(())
+    }
+
+    /// Fill in the header with the length of the samples written so far, and flush. More
+    /// samples can still be written afterwards, as long as this is called again.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header(self.data_len))?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()
+    }
+}
+
+fn header(data_len: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8).saturating_add(data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&FORMAT_FLOAT.to_le_bytes());
+    // Mono
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    // Bytes per second, then per frame of all channels
+    header.extend_from_slice(&(SAMPLE_RATE * BYTES_PER_SAMPLE).to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE as u16).to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE as u16 * 8).to_le_bytes());
+
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
@@ -0,0 +1,211 @@
+//! Sizing of the window, and of the picture inside it.
+
+use std::str::FromStr;
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Window scale used when neither a scale nor a size is given
+pub const DEFAULT_SCALE: u32 = 3;
+
+/// Largest scale or window dimension accepted, well beyond any real display
+pub const MAX_SCALE: u32 = 16;
+const MAX_DIMENSION: u32 = SCREEN_WIDTH as u32 * MAX_SCALE;
+
+/// Initial size of the window, in screen coordinates (which on high-DPI displays may be smaller
+/// than pixels)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl WindowSize {
+    /// A window showing the picture at a whole multiple of its size
+    pub fn scaled(scale: u32) -> Self {
+        Self {
+            width: SCREEN_WIDTH as u32 * scale,
+            height: SCREEN_HEIGHT as u32 * scale,
+        }
+    }
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        Self::scaled(DEFAULT_SCALE)
+    }
+}
+
+/// Parses `WxH`, e.g. `1024x960`
+impl FromStr for WindowSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid window size '{s}' (expected WIDTHxHEIGHT, e.g. 768x720)");
+        let (width, height) = s.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+        let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            return Err(format!(
+                "window size must be between 1x1 and {MAX_DIMENSION}x{MAX_DIMENSION}"
+            ));
+        }
+        Ok(Self { width, height })
+    }
+}
+
+/// Largest overscan accepted at each edge, a quarter of the picture
+const MAX_OVERSCAN: u32 = SCREEN_HEIGHT as u32 / 4;
+
+/// Scanlines to crop from the top and bottom of the picture, which most TVs hid behind the
+/// bezel. Games often leave garbage there, e.g. from scrolling.
+///
+/// See: <https://www.nesdev.org/wiki/Overscan>
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Overscan {
+    pub top: u32,
+    pub bottom: u32,
+}
+
+impl Overscan {
+    /// Scanlines left in the picture after cropping
+    pub fn visible_height(self) -> u32 {
+        SCREEN_HEIGHT as u32 - self.top - self.bottom
+    }
+
+    /// First row and number of rows to show of a frame `frame_height` rows high. Filters can
+    /// make frames taller than the NES picture, so the crop is scaled to match.
+    pub fn visible_rows(self, frame_height: usize) -> (usize, usize) {
+        let scale = |lines: u32| lines as usize * frame_height / SCREEN_HEIGHT;
+        (scale(self.top), scale(self.visible_height()))
+    }
+}
+
+/// Parses `N` to crop `N` scanlines from both the top and bottom, or `TOP,BOTTOM`
+impl FromStr for Overscan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid overscan '{s}' (expected LINES or TOP,BOTTOM, e.g. 8)");
+        let (top, bottom) = s.split_once(',').unwrap_or((s, s));
+        let top: u32 = top.trim().parse().map_err(|_| invalid())?;
+        let bottom: u32 = bottom.trim().parse().map_err(|_| invalid())?;
+        if top > MAX_OVERSCAN || bottom > MAX_OVERSCAN {
+            return Err(format!(
+                "overscan must be at most {MAX_OVERSCAN} scanlines at each edge"
+            ));
+        }
+        Ok(Self { top, bottom })
+    }
+}
+
+/// Where to draw a picture of `picture_height` NES scanlines in an output of the given size in
+/// pixels, as `(x, y, width, height)`. The picture keeps its aspect ratio and is centred, and is
+/// scaled by a whole number when the output is big enough, so that every NES pixel is the same
+/// size.
+#[cfg(feature = "sdl")]
+pub(crate) fn picture_rect(
+    output_width: u32,
+    output_height: u32,
+    picture_height: u32,
+) -> (u32, u32, u32, u32) {
+    let picture_width = SCREEN_WIDTH as u32;
+    let scale = (output_width / picture_width).min(output_height / picture_height);
+    let (width, height) = if scale > 0 {
+        (picture_width * scale, picture_height * scale)
+    } else if output_width * picture_height < output_height * picture_width {
+        // Smaller than the picture, so shrink it to fit whichever side is tighter
+        (output_width, output_width * picture_height / picture_width)
+    } else {
+        (
+            output_height * picture_width / picture_height,
+            output_height,
+        )
+    };
+    (
+        (output_width - width) / 2,
+        (output_height - height) / 2,
+        width,
+        height,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{FilterKind, Frame};
+
+    #[test]
+    fn parse_window_size() {
+        assert_eq!(
+            "1024x768".parse(),
+            Ok(WindowSize {
+                width: 1024,
+                height: 768
+            })
+        );
+        assert_eq!("768X720".parse(), Ok(WindowSize::scaled(3)));
+        assert_eq!("512 x 480".parse(), Ok(WindowSize::scaled(2)));
+
+        let too_big =
+            format!("window size must be between 1x1 and {MAX_DIMENSION}x{MAX_DIMENSION}");
+        assert_eq!("0x0".parse::<WindowSize>(), Err(too_big.clone()));
+        assert_eq!("640x0".parse::<WindowSize>(), Err(too_big.clone()));
+        assert_eq!(
+            format!("{}x480", MAX_DIMENSION + 1).parse::<WindowSize>(),
+            Err(too_big)
+        );
+        assert!("1024".parse::<WindowSize>().is_err());
+        assert!("bigxsmall".parse::<WindowSize>().is_err());
+        assert!("-1x480".parse::<WindowSize>().is_err());
+    }
+
+    #[test]
+    fn overscan_visible_rows() {
+        assert_eq!(Overscan::default().visible_rows(240), (0, 240));
+        let overscan: Overscan = "8".parse().unwrap();
+        assert_eq!(overscan.visible_height(), 224);
+        assert_eq!(overscan.visible_rows(240), (8, 224));
+
+        // Frames from filters which make them taller are cropped to the same part of the picture
+        let uneven: Overscan = "8,16".parse().unwrap();
+        assert_eq!(uneven.visible_height(), 216);
+        for kind in [FilterKind::None, FilterKind::Scanlines, FilterKind::Ntsc] {
+            let frame = kind
+                .build(0.5)
+                .apply(&Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT));
+            let scale = frame.height / SCREEN_HEIGHT;
+            assert_eq!(
+                uneven.visible_rows(frame.height),
+                (8 * scale, 216 * scale),
+                "{kind:?}"
+            );
+        }
+        assert_eq!(uneven.visible_rows(480), (16, 432));
+
+        assert!("61".parse::<Overscan>().is_err());
+        assert!("8,".parse::<Overscan>().is_err());
+    }
+
+    #[cfg(feature = "sdl")]
+    #[test]
+    fn picture_rect_scales_by_whole_numbers() {
+        // An exact fit
+        assert_eq!(picture_rect(768, 720, 240), (0, 0, 768, 720));
+        // Room for 2x but not 3x either way, centred in the space left
+        assert_eq!(picture_rect(700, 600, 240), (94, 60, 512, 480));
+        // The tighter side decides the scale
+        assert_eq!(picture_rect(1920, 480, 240), (704, 0, 512, 480));
+        // Fewer scanlines after cropping overscan scale up just the same
+        assert_eq!(picture_rect(768, 720, 224), (0, 24, 768, 672));
+    }
+
+    #[cfg(feature = "sdl")]
+    #[test]
+    fn picture_rect_shrinks_below_1x() {
+        // Narrower than the picture, so the width decides
+        assert_eq!(picture_rect(128, 240, 240), (0, 60, 128, 120));
+        // Shorter than the picture, so the height decides
+        assert_eq!(picture_rect(256, 120, 240), (64, 0, 128, 120));
+        assert_eq!(picture_rect(0, 0, 240), (0, 0, 0, 0));
+    }
+}
@@ -0,0 +1,138 @@
+//! The `rusty-nes` binary, run headless on the bundled test ROMs
+
+#![cfg(feature = "sdl")]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A scratch directory for a test, emptied first
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The binary, with its config directory in `dir` so the user's config isn't read or written
+fn rusty_nes(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rusty-nes"));
+    command
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("APPDATA", dir);
+    command
+}
+
+fn rom(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/roms")
+        .join(name)
+}
+
+#[test]
+fn headless_run() {
+    let dir = scratch_dir("cli_headless_run");
+    let screenshot = dir.join("shot.png");
+    let ram = dir.join("ram.bin");
+    let output = rusty_nes(&dir)
+        .args(["--nodebug", "--headless", "--frames", "60", "--screenshot"])
+        .arg(&screenshot)
+        .arg("--dump-ram")
+        .arg(&ram)
+        .arg(rom("stripes.nes"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Frame 60 hash: "), "{stdout}");
+
+    // A 256x240 PNG of the last frame
+    let png = std::fs::read(&screenshot).unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), 256);
+    assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()), 240);
+
+    // And all 2 KiB of RAM
+    assert_eq!(std::fs::read(&ram).unwrap().len(), 0x800);
+}
+
+#[test]
+fn missing_rom() {
+    let dir = scratch_dir("cli_missing_rom");
+    let missing = dir.join("missing.nes");
+    let output = rusty_nes(&dir)
+        .args(["--nodebug", "--headless", "--frames", "1"])
+        .arg(&missing)
+        .output()
+        .unwrap();
+    // A problem with the command line, as for a bad flag
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        format!("error: ROM file not found: {}\n", missing.display())
+    );
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn bench() {
+    let dir = scratch_dir("cli_bench");
+    let output = rusty_nes(&dir)
+        .args(["--bench", "0.2"])
+        .arg(rom("stripes.nes"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    // A `Name: number` line for each counter, all of which went up
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let report: Vec<(&str, f64)> = stdout
+        .lines()
+        .map(|line| {
+            let (name, value) = line.split_once(": ").unwrap();
+            (name, value.parse().unwrap())
+        })
+        .collect();
+    let names: Vec<&str> = report.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "Frames",
+            "Frames per second",
+            "Instructions",
+            "Instructions per second"
+        ]
+    );
+    assert!(report.iter().all(|(_, value)| *value > 0.0), "{stdout}");
+}
+
+#[test]
+fn print_registers() {
+    let dir = scratch_dir("cli_print_registers");
+    let program = rusty_nes::assemble("lda #$10\nclc\nadc #$32\nloop: jmp loop").unwrap();
+    let path = dir.join("add.nes");
+    std::fs::write(&path, common::nrom(&program)).unwrap();
+    let output = rusty_nes(&dir)
+        .args([
+            "--nodebug",
+            "--headless",
+            "--frames",
+            "2",
+            "--print-registers",
+        ])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    // $10 + $32 left in the accumulator, waiting in the loop two frames' worth of cycles later
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "pc: 8005  a: 42 x: 00 y: 00 s: fd  -----I--  cycles: 59562\n"
+    );
+}
@@ -0,0 +1,31 @@
+//! Helpers shared by the integration tests, for running small programs written with
+//! `rusty_nes::assemble` on an NROM cart
+
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use rusty_nes::CPU;
+
+/// An NROM image with `program` at `$8000`, and 8 KiB of CHR RAM. The PRG ROM is 16 KiB, or
+/// 32 KiB if the program doesn't fit, and is padded with `NOP`s. Unless the program reaches the
+/// vectors itself (e.g. with `.org $fffa`), reset jumps to `$8000`.
+pub fn nrom(program: &[u8]) -> Vec<u8> {
+    let pages = if program.len() <= 0x4000 { 1 } else { 2 };
+    let mut prg = vec![0xea; pages * 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    if program.len() <= prg.len() - 4 {
+        let reset_vector = prg.len() - 4;
+        prg[reset_vector..reset_vector + 2].copy_from_slice(&[0x00, 0x80]);
+    }
+
+    let mut image = vec![b'N', b'E', b'S', 0x1a, pages as u8, 0];
+    image.resize(16, 0);
+    image.extend(prg);
+    image
+}
+
+/// A console powered on with `source` assembled into an NROM cart (see `nrom`)
+pub fn cpu(source: &str) -> CPU {
+    let program = rusty_nes::assemble(source).expect("test program should assemble");
+    CPU::from_bytes(nrom(&program), false).expect("test ROM should load")
+}
@@ -0,0 +1,275 @@
+//! The main loop driven end to end by `MockFrontend`
+#![cfg(feature = "mock-frontend")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use rusty_nes::{buttons, run_frontend, Frame, Frontend, InputState, MockFrontend, RunOptions};
+
+/// Reads the first controller once per frame, when vblank starts, and keeps each frame's A
+/// button from `$20` on
+const POLL_EACH_FRAME: &str = "
+        ldx #0
+    wait:
+        bit $2002
+        bpl wait
+        lda #1
+        sta $4016
+        lda #0
+        sta $4016
+        lda $4016
+        and #1
+        sta $20,x
+        inx
+        jmp wait
+";
+
+#[test]
+fn scripted_input_and_hotkeys() {
+    let mut cpu = common::cpu(POLL_EACH_FRAME);
+    let press_a = InputState {
+        buttons: [buttons::A, 0],
+        ..Default::default()
+    };
+    let mut frontend = MockFrontend::new(vec![
+        press_a,
+        InputState::default(),
+        InputState {
+            cycle_filter: true,
+            ..press_a
+        },
+        InputState::default(),
+    ]);
+    run_frontend(&mut cpu, &mut frontend, &RunOptions::default()).unwrap();
+
+    // The script ran out after 4 frames, and the game saw each frame's buttons
+    assert_eq!(frontend.frames.len(), 4);
+    let seen: Vec<u8> = (0x20..0x25).map(|address| cpu.peek_byte(address)).collect();
+    assert_eq!(seen, vec![1, 0, 1, 0, 0]);
+
+    // The filter hotkey switched to scanlines, which doubles the height
+    let heights: Vec<usize> = frontend.frames.iter().map(|frame| frame.height).collect();
+    assert_eq!(heights[2], 2 * heights[0]);
+    assert_eq!(heights[1], heights[0]);
+}
+
+#[test]
+fn error_keeps_presenting_until_quit() {
+    let mut cpu = common::cpu("lda #1\n.byte $02");
+    cpu.set_unknown_opcode_policy(rusty_nes::UnknownOpcodePolicy::Error);
+    let mut frontend = MockFrontend::new(vec![InputState::default(); 3]);
+    let result = run_frontend(&mut cpu, &mut frontend, &RunOptions::default());
+    assert!(result.is_err());
+    assert_eq!(frontend.frames.len(), 3);
+}
+
+/// Turns on the first pulse channel and loads its length counter, reads `$4015` at each vblank
+/// into `$20` on, and turns the channel off again after two frames
+const READ_APU_STATUS: &str = "
+        lda #$01
+        sta $4015
+        lda #$08
+        sta $4003
+        ldx #0
+    wait:
+        bit $2002
+        bpl wait
+        lda $4015
+        sta $20,x
+        inx
+        cpx #2
+        bne wait
+        lda #0
+        sta $4015
+        jmp wait
+";
+
+/// `MockFrontend` on the wall clock, so the main loop has to wait for each frame's turn
+struct RealTime {
+    mock: MockFrontend,
+    start: Instant,
+}
+
+impl Frontend for RealTime {
+    fn present_frame(&mut self, frame: &Frame) {
+        self.mock.present_frame(frame);
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        self.mock.push_audio(samples);
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        self.mock.poll_input()
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+#[test]
+fn paced_without_audio() {
+    let mut cpu = common::cpu(READ_APU_STATUS);
+    let options = RunOptions {
+        audio: false,
+        ..Default::default()
+    };
+    let mut frontend = RealTime {
+        mock: MockFrontend::new(vec![InputState::default(); 6]),
+        start: Instant::now(),
+    };
+    run_frontend(&mut cpu, &mut frontend, &options).unwrap();
+
+    // Nothing drives the timing but the clock, and that still holds each frame back to its turn
+    let elapsed = frontend.start.elapsed();
+    assert_eq!(frontend.mock.frames.len(), 6);
+    assert!(
+        elapsed >= cpu.region().frame_duration() * 5,
+        "{:?}",
+        elapsed
+    );
+
+    // The APU still ran: the channel's length counter showed in the status until it was disabled
+    let status: Vec<u8> = (0x20..0x25).map(|address| cpu.peek_byte(address)).collect();
+    assert_eq!(status, vec![1, 1, 0, 0, 0]);
+}
+
+/// Shows whether A was held at the last vblank, as a red backdrop, with rendering off
+const SHOW_A: &str = "
+    wait:
+        bit $2002
+        bpl wait
+        lda #1
+        sta $4016
+        lda #0
+        sta $4016
+        lda $4016
+        and #1
+        tax
+        lda #$3f
+        sta $2006
+        lda #$00
+        sta $2006
+        lda colours,x
+        sta $2007
+        jmp wait
+    colours:
+        .byte $0f, $16
+";
+
+/// The first frame presented with A showing, after A is held from the 8th frame on
+fn first_frame_showing_a(cpu: &mut rusty_nes::CPU, run_ahead: usize) -> usize {
+    let mut script = vec![InputState::default(); 7];
+    script.extend(vec![
+        InputState {
+            buttons: [buttons::A, 0],
+            ..Default::default()
+        };
+        5
+    ]);
+    let mut frontend = MockFrontend::new(script);
+    let options = RunOptions {
+        run_ahead,
+        ..Default::default()
+    };
+    run_frontend(cpu, &mut frontend, &options).unwrap();
+    let red = rusty_nes::Palette::default().colour(0x16, 0);
+    let first = frontend
+        .frames
+        .iter()
+        .position(|frame| frame.pixel(0, 0) == red)
+        .expect("A should show");
+    assert!(first >= 7, "A showed at frame {first} before it was held");
+    first
+}
+
+#[test]
+fn run_ahead_shows_input_a_frame_sooner() {
+    let without = first_frame_showing_a(&mut common::cpu(SHOW_A), 0);
+    let with = first_frame_showing_a(&mut common::cpu(SHOW_A), 1);
+    assert_eq!(with + 1, without);
+
+    // Run-ahead stays off while tracing, as the frames it throws away would be traced
+    let mut cpu = common::cpu(SHOW_A);
+    let trace = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("run_ahead.trace");
+    cpu.set_trace_file(&trace, None).unwrap();
+    assert!(cpu.is_tracing());
+    assert_eq!(first_frame_showing_a(&mut cpu, 1), without);
+}
+
+/// Counts starts, from power on or reset, in RAM at `$10` and in the cart's PRG RAM at `$6000`
+const COUNT_STARTS: &str = "
+        inc $10
+        inc $6000
+    loop:
+        jmp loop
+";
+
+/// Run a frame, then each hotkey pressed for a frame, then a last frame
+fn press_hotkeys(cpu: &mut rusty_nes::CPU, options: &RunOptions, hotkeys: &[InputState]) {
+    let mut script = vec![InputState::default()];
+    for hotkey in hotkeys {
+        script.extend([*hotkey, InputState::default()]);
+    }
+    let mut frontend = MockFrontend::new(script);
+    run_frontend(cpu, &mut frontend, options).unwrap();
+}
+
+#[test]
+fn reset_keeps_ram_and_power_cycle_clears_it() {
+    let options = RunOptions {
+        run_ahead: 0,
+        ..Default::default()
+    };
+    let mut cpu = common::cpu(COUNT_STARTS);
+    let soft_reset = InputState {
+        soft_reset: true,
+        ..Default::default()
+    };
+    press_hotkeys(&mut cpu, &options, &[soft_reset]);
+    assert_eq!(cpu.peek_byte(0x10), 2);
+
+    // RAM powers on cleared, and the program starts counting again
+    let mut cpu = common::cpu(COUNT_STARTS);
+    let power_cycle = InputState {
+        power_cycle: true,
+        ..Default::default()
+    };
+    press_hotkeys(&mut cpu, &options, &[power_cycle]);
+    assert_eq!(cpu.peek_byte(0x10), 1);
+}
+
+#[test]
+fn battery_save_kept_across_power_cycle_and_reload() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("battery_save");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("game.nes");
+    let mut image = common::nrom(&rusty_nes::assemble(COUNT_STARTS).unwrap());
+    image[6] |= 0x2;
+    std::fs::write(&rom_path, image).unwrap();
+
+    let mut cpu = rusty_nes::CPU::new(rom_path.to_string_lossy().into_owned(), false).unwrap();
+    let options = RunOptions {
+        run_ahead: 0,
+        rom_path: Some(rom_path),
+        battery_saves: true,
+        ..Default::default()
+    };
+    let power_cycle = InputState {
+        power_cycle: true,
+        ..Default::default()
+    };
+    let reload_rom = InputState {
+        reload_rom: true,
+        ..Default::default()
+    };
+    press_hotkeys(&mut cpu, &options, &[power_cycle, reload_rom]);
+
+    // Each hotkey saved PRG RAM before powering off, and loaded it back, unlike RAM
+    assert_eq!(cpu.peek_byte(0x10), 1);
+    assert_eq!(cpu.peek_byte(0x6000), 3);
+    assert_eq!(std::fs::read(dir.join("game.sav")).unwrap()[0], 2);
+}
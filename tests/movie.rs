@@ -0,0 +1,126 @@
+//! Recording and playing back movies, and running deterministically from one
+
+mod common;
+
+use rusty_nes::{sha1, Movie, MovieError};
+
+/// Reads the first controller when each vblank starts, adds the buttons up at `$01`, and shows
+/// the total as the backdrop colour, so both RAM and the picture depend on every frame's input
+const ADD_UP_BUTTONS: &str = "
+    wait:
+        bit $2002
+        bpl wait
+        lda #1
+        sta $4016
+        lda #0
+        sta $4016
+        ldx #8
+    read:
+        lda $4016
+        lsr a
+        rol $00
+        dex
+        bne read
+        lda $00
+        clc
+        adc $01
+        sta $01
+        lda #$3f
+        sta $2006
+        lda #$00
+        sta $2006
+        lda $01
+        and #$3f
+        sta $2007
+        jmp wait
+";
+
+/// Buttons which change from frame to frame
+fn buttons(frame: u64) -> u8 {
+    (frame as u8).wrapping_mul(37) ^ 0x5a
+}
+
+/// Record a movie of `frames` frames of `buttons`
+fn record(frames: u64) -> Movie {
+    let mut cpu = common::cpu(ADD_UP_BUTTONS);
+    cpu.record_movie();
+    for frame in 0..frames {
+        cpu.set_player_input(0, buttons(frame));
+        cpu.run_frame().unwrap();
+    }
+    cpu.finish_movie().unwrap()
+}
+
+/// The hash of each frame and of RAM after it, playing `movie` deterministically
+fn play(movie: &Movie, frames: u64, input: u8) -> Vec<(u32, [u8; 20])> {
+    let mut cpu = common::cpu(ADD_UP_BUTTONS);
+    cpu.set_deterministic(true);
+    cpu.set_frame_hash_logging(true);
+    cpu.play_movie(movie.clone()).unwrap();
+    let mut hashes = Vec::new();
+    for _ in 0..frames {
+        // Deterministic runs ignore the frontend's input
+        cpu.set_player_input(0, input);
+        cpu.run_frame().unwrap();
+        let frame_hash = *cpu.frame_hash_log().last().unwrap();
+        hashes.push((frame_hash, sha1(cpu.ram())));
+    }
+    hashes
+}
+
+#[test]
+fn deterministic_runs_match() {
+    let movie = record(20);
+    // Past the end of the movie, no buttons are held
+    let a = play(&movie, 30, 0x00);
+    let b = play(&movie, 30, 0xff);
+    assert_eq!(a, b);
+
+    // The input did make a difference, so it's the same because of the movie
+    let frames: std::collections::HashSet<_> = a.iter().map(|(frame, _)| frame).collect();
+    assert!(frames.len() > 10);
+    let mut cpu = common::cpu(ADD_UP_BUTTONS);
+    cpu.set_frame_hash_logging(true);
+    for _ in 0..30 {
+        cpu.set_player_input(0, 0xff);
+        cpu.run_frame().unwrap();
+    }
+    let played: Vec<u32> = a.iter().map(|&(frame, _)| frame).collect();
+    assert_ne!(cpu.frame_hash_log(), played);
+}
+
+#[test]
+fn record_and_play_back() {
+    const FRAMES: u64 = 20;
+    let mut cpu = common::cpu(ADD_UP_BUTTONS);
+    cpu.record_movie();
+    for frame in 0..FRAMES {
+        cpu.set_player_input(0, buttons(frame));
+        rusty_nes::run_headless(&mut cpu, 1).unwrap();
+    }
+    let recorded_ram = sha1(cpu.ram());
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("record_and_play_back.movie");
+    std::fs::write(&path, cpu.finish_movie().unwrap().to_bytes()).unwrap();
+
+    // Playing it back from power on, without any input, ends up in the same place
+    let movie = Movie::from_file(&path).unwrap();
+    assert_eq!(movie.len(), FRAMES as usize);
+    let mut cpu = common::cpu(ADD_UP_BUTTONS);
+    cpu.play_movie(movie.clone()).unwrap();
+    rusty_nes::run_headless(&mut cpu, FRAMES).unwrap();
+    assert_eq!(sha1(cpu.ram()), recorded_ram);
+
+    // Which it wouldn't without the movie
+    let mut unrecorded = common::cpu(ADD_UP_BUTTONS);
+    rusty_nes::run_headless(&mut unrecorded, FRAMES).unwrap();
+    assert_ne!(sha1(unrecorded.ram()), recorded_ram);
+
+    // But not on another ROM
+    let mut other = common::cpu("loop: jmp loop");
+    let result = other.play_movie(movie);
+    assert!(
+        matches!(result, Err(MovieError::WrongRom { expected, actual })
+            if expected == other.cart().crc32() && actual == cpu.cart().crc32()),
+        "{result:?}"
+    );
+}
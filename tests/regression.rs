@@ -0,0 +1,109 @@
+//! The rendering regression checks (see `run_regression`), run on the ROMs in `tests/roms`.
+//!
+//! Each ROM there is assembled from the `.s` file of the same name (see `common::nrom`), and
+//! `regression.txt` lists the frame each is checked at. After changing a source, set
+//! `RUSTY_NES_UPDATE_HASHES=1` when running these tests to rebuild its ROM and record its hash.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use rusty_nes::run_regression;
+
+const ROMS: &[&str] = &["stripes", "sprites"];
+
+fn roms_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms")
+}
+
+fn update() -> bool {
+    std::env::var_os("RUSTY_NES_UPDATE_HASHES").is_some_and(|value| value != "0")
+}
+
+/// A directory of its own for a test, emptied first
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn bundled_roms() {
+    for name in ROMS {
+        let source = std::fs::read_to_string(roms_dir().join(format!("{name}.s"))).unwrap();
+        let program = rusty_nes::assemble(&source).unwrap();
+        let rom_path = roms_dir().join(format!("{name}.nes"));
+        if update() {
+            std::fs::write(&rom_path, common::nrom(&program)).unwrap();
+        }
+        let rom = std::fs::read(&rom_path).unwrap();
+        assert!(rom == common::nrom(&program), "{name}.nes is out of date");
+    }
+
+    let reports = run_regression(
+        &roms_dir().join("regression.txt"),
+        &scratch_dir("regression"),
+        update(),
+    )
+    .unwrap();
+    assert_eq!(reports.len(), ROMS.len());
+    for report in &reports {
+        assert!(report.passed(), "{report}");
+    }
+}
+
+/// A manifest with a case for the stripes ROM, with `hash` after it if there is one
+fn manifest(dir: &Path, hash: Option<&str>) -> PathBuf {
+    std::fs::copy(roms_dir().join("stripes.nes"), dir.join("stripes.nes")).unwrap();
+    let path = dir.join("regression.txt");
+    let line = match hash {
+        Some(hash) => format!("stripes.nes 10 {hash}\n"),
+        None => "stripes.nes 10\n".to_string(),
+    };
+    std::fs::write(&path, format!("# A comment\n\n{line}")).unwrap();
+    path
+}
+
+#[test]
+fn missing_hash_fails_unless_updating() {
+    let dir = scratch_dir("missing_hash");
+    let path = manifest(&dir, None);
+    let reports = run_regression(&path, &dir.join("frames"), false).unwrap();
+    assert!(!reports[0].passed());
+    assert!(!reports[0].recorded);
+    assert!(reports[0].to_string().contains("no expected hash"));
+    // The manifest is left alone
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# A comment\n\nstripes.nes 10\n"
+    );
+
+    let reports = run_regression(&path, &dir.join("frames"), true).unwrap();
+    assert!(reports[0].passed());
+    assert!(reports[0].recorded);
+    let hash = *reports[0].actual.as_ref().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        format!("# A comment\n\nstripes.nes 10 {hash:08x}\n")
+    );
+
+    // And now it passes without updating
+    let reports = run_regression(&path, &dir.join("frames"), false).unwrap();
+    assert!(reports[0].passed(), "{}", reports[0]);
+    assert!(!reports[0].recorded);
+}
+
+#[test]
+fn mismatch_saves_frame() {
+    let dir = scratch_dir("mismatch");
+    let path = manifest(&dir, Some("12345678"));
+    let reports = run_regression(&path, &dir.join("frames"), false).unwrap();
+    assert!(!reports[0].passed());
+    assert!(reports[0]
+        .to_string()
+        .contains("MISMATCH (expected 12345678"));
+    let screenshot = dir.join("frames/stripes-10.png");
+    assert_eq!(reports[0].screenshot.as_deref(), Some(screenshot.as_path()));
+    assert!(std::fs::read(&screenshot).unwrap().starts_with(b"\x89PNG"));
+}
@@ -0,0 +1,131 @@
+; A row of sprites over a background of diagonal stripes, scrolled by a few pixels each way
+
+reset:
+    sei
+    ldx #$ff
+    txs
+    lda #0
+    sta $2000
+    sta $2001
+
+    ; Let the PPU warm up
+    bit $2002
+warm_up1:
+    bit $2002
+    bpl warm_up1
+warm_up2:
+    bit $2002
+    bpl warm_up2
+
+    ; The background and sprite palettes
+    lda #$3f
+    sta $2006
+    lda #$00
+    sta $2006
+    ldx #0
+copy_palette:
+    lda palette,x
+    sta $2007
+    inx
+    cpx #32
+    bne copy_palette
+
+    ; Tile 0 is blank, and tile 1 a diagonal line in colour 1 with colour 3 above it
+    lda #$00
+    sta $2006
+    sta $2006
+    ldx #0
+copy_tiles:
+    lda tiles,x
+    sta $2007
+    inx
+    cpx #32
+    bne copy_tiles
+
+    ; The first nametable, all tile 1, with the second palette for the bottom half
+    lda #$20
+    sta $2006
+    lda #$00
+    sta $2006
+    lda #1
+    ldy #4
+fill_rows:
+    ldx #240
+fill_tile:
+    sta $2007
+    dex
+    bne fill_tile
+    dey
+    bne fill_rows
+    ldx #0
+fill_attributes:
+    lda attributes,x
+    sta $2007
+    inx
+    cpx #64
+    bne fill_attributes
+
+    ; Eight sprites of tile 1 in a row, each with the next sprite palette, and every other one
+    ; flipped
+    ldx #0
+    ldy #0
+place_sprites:
+    lda #100
+    sta $0200,y
+    lda #1
+    sta $0201,y
+    txa
+    and #3
+    sta $00
+    txa
+    and #1
+    ror a
+    ror a
+    ora $00
+    sta $0202,y
+    txa
+    asl a
+    asl a
+    asl a
+    asl a
+    adc #48
+    sta $0203,y
+    iny
+    iny
+    iny
+    iny
+    inx
+    cpx #8
+    bne place_sprites
+    ; The rest are off the bottom of the screen
+    lda #$f0
+hide_sprites:
+    sta $0200,y
+    iny
+    bne hide_sprites
+    lda #2
+    sta $4014
+
+    ; Scroll 3 pixels right and 5 down, then show everything
+    lda #3
+    sta $2005
+    lda #5
+    sta $2005
+    lda #0
+    sta $2000
+    lda #$1e
+    sta $2001
+done:
+    jmp done
+
+palette:
+    .byte $0f, $21, $0f, $11, $0f, $27, $0f, $17, $0f, $0f, $0f, $0f, $0f, $0f, $0f, $0f
+    .byte $0f, $30, $15, $05, $0f, $30, $19, $09, $0f, $30, $11, $01, $0f, $30, $28, $18
+tiles:
+    .byte $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    .byte $ff, $7f, $3f, $1f, $0f, $07, $03, $01, $7f, $3f, $1f, $0f, $07, $03, $01, $00
+attributes:
+    .byte $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    .byte $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    .byte $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55
+    .byte $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55, $55
@@ -0,0 +1,97 @@
+; Vertical stripes, two tiles wide, in each of the four background colours
+
+reset:
+    sei
+    ldx #$ff
+    txs
+    lda #0
+    sta $2000
+    sta $2001
+
+    ; Let the PPU warm up
+    bit $2002
+warm_up1:
+    bit $2002
+    bpl warm_up1
+warm_up2:
+    bit $2002
+    bpl warm_up2
+
+    ; The background palette
+    lda #$3f
+    sta $2006
+    lda #$00
+    sta $2006
+    ldx #0
+copy_palette:
+    lda palette,x
+    sta $2007
+    inx
+    cpx #4
+    bne copy_palette
+
+    ; Tiles 0-3 in CHR RAM, each filled with the colour of the same number
+    lda #$00
+    sta $2006
+    sta $2006
+    ldy #0
+copy_tile:
+    ldx #8
+    lda plane0,y
+copy_plane0:
+    sta $2007
+    dex
+    bne copy_plane0
+    ldx #8
+    lda plane1,y
+copy_plane1:
+    sta $2007
+    dex
+    bne copy_plane1
+    iny
+    cpy #4
+    bne copy_tile
+
+    ; The first nametable, as 4 runs of 240 tiles, which are whole rows, so the tile follows
+    ; the column
+    lda #$20
+    sta $2006
+    lda #$00
+    sta $2006
+    ldy #4
+fill_rows:
+    ldx #0
+fill_tile:
+    txa
+    lsr a
+    and #3
+    sta $2007
+    inx
+    cpx #240
+    bne fill_tile
+    dey
+    bne fill_rows
+
+    ; Every attribute picks the first palette
+    lda #0
+    ldx #64
+fill_attributes:
+    sta $2007
+    dex
+    bne fill_attributes
+
+    ; Show the background, including the leftmost 8 pixels
+    sta $2005
+    sta $2005
+    sta $2000
+    lda #$0a
+    sta $2001
+done:
+    jmp done
+
+palette:
+    .byte $0f, $16, $2a, $12
+plane0:
+    .byte $00, $ff, $00, $ff
+plane1:
+    .byte $00, $00, $ff, $ff
@@ -0,0 +1,173 @@
+//! Vblank and NMI timing, checked by a test ROM which reports its result in PRG RAM the way
+//! blargg's `ppu_vbl_nmi` does (see `run_test_rom`).
+//!
+//! This isn't blargg's ROM, which isn't bundled with the repo. It's a small one written for
+//! these tests, which uses the same protocol and checks the same kind of thing, less
+//! thoroughly: `$6001-$6003` get the signature `DE B0 61`, `$6000` stays `$80` while it runs and
+//! ends up as 0 or the number of the sub-test which failed, and `$6004` says `Passed` or
+//! `Failed #` and that number.
+
+mod common;
+
+use rusty_nes::{run_test_rom, TestResult};
+
+const VBL_NMI: &str = "
+    reset:
+        sei
+        ldx #$ff
+        txs
+        lda #$80
+        sta $6000
+        lda #$de
+        sta $6001
+        lda #$b0
+        sta $6002
+        lda #$61
+        sta $6003
+        lda #0
+        sta $2000
+        sta $10
+
+        ; Let the PPU warm up
+        bit $2002
+    warm_up1:
+        bit $2002
+        bpl warm_up1
+    warm_up2:
+        bit $2002
+        bpl warm_up2
+
+        ; #2: Reading PPUSTATUS clears the vblank flag it just returned
+        lda #2
+        sta $11
+        lda $2002
+        bpl frame_length
+        jmp fail
+
+        ; #3: A frame is 29780.5 cycles, so about 1985 trips round a 15 cycle loop, which
+        ; counts to $07xx
+    frame_length:
+        lda #3
+        sta $11
+        lda #0
+        sta $12
+        sta $13
+    count:
+        inc $12
+        bne counted
+        inc $13
+    counted:
+        bit $2002
+        bpl count
+        lda $13
+        cmp #$07
+        beq nmi_at_vblank
+        jmp fail
+
+        ; #4: Enabling NMIs makes one happen at the start of the next vblank, when the flag is
+        ; set
+    nmi_at_vblank:
+        lda #4
+        sta $11
+        lda #$80
+        sta $2000
+    wait_nmi:
+        lda $10
+        beq wait_nmi
+        lda $14
+        bmi nmi_disabled
+        jmp fail
+
+        ; #5: No NMIs happen while they are disabled
+    nmi_disabled:
+        lda #5
+        sta $11
+        lda #0
+        sta $2000
+        sta $10
+    vblank1:
+        bit $2002
+        bpl vblank1
+    vblank2:
+        bit $2002
+        bpl vblank2
+        lda $10
+        beq pass
+        jmp fail
+
+    pass:
+        ldx #0
+    copy_passed:
+        lda passed,x
+        sta $6004,x
+        inx
+        cpx #7
+        bne copy_passed
+        lda #0
+        sta $6000
+    done:
+        jmp done
+
+    fail:
+        ldx #0
+    copy_failed:
+        lda failed,x
+        sta $6004,x
+        inx
+        cpx #8
+        bne copy_failed
+        lda $11
+        ora #$30
+        sta $600c
+        lda #0
+        sta $600d
+        lda $11
+        sta $6000
+        jmp done
+
+        ; Keep PPUSTATUS as the handler found it, and count the NMIs
+    nmi:
+        pha
+        lda $2002
+        sta $14
+        inc $10
+        pla
+        rti
+
+    passed:
+        .byte $50, $61, $73, $73, $65, $64, $00
+    failed:
+        .byte $46, $61, $69, $6c, $65, $64, $20, $23
+
+        .org $fffa
+        .word nmi, reset, reset
+";
+
+#[test]
+fn vbl_nmi() {
+    let mut cpu = common::cpu(VBL_NMI);
+    let result = run_test_rom(&mut cpu, 60).unwrap();
+    // The status has left $80, and is 0
+    assert_eq!(
+        result,
+        TestResult::Passed {
+            message: "Passed".into()
+        },
+        "{result}"
+    );
+}
+
+#[test]
+fn vbl_nmi_reports_failures() {
+    // Sub-test #2 sees the vblank flag still set
+    let source = VBL_NMI.replacen("lda $2002\n        bpl", "lda #$80\n        bpl", 1);
+    let mut cpu = common::cpu(&source);
+    let result = run_test_rom(&mut cpu, 60).unwrap();
+    assert_eq!(
+        result,
+        TestResult::Failed {
+            code: 2,
+            message: "Failed #2".into()
+        }
+    );
+}